@@ -1,45 +1,296 @@
+use std::io::BufRead;
+use std::path::Path;
+use std::process::exit;
 
-use linux_embedded_hal::{Spidev, Pin};
-use spidev::{SpidevOptions, SpidevTransfer};
-use rust_gpiozero::OutputDevice;
-use std::thread::sleep;
-use std::time::Duration;
+use juicelib::backup::{create_backup, default_sources, restore_backup};
+use juicelib::config::{Config, DEFAULT_CONFIG_PATH};
+use juicelib::config_history::{diff_snapshots, roll_back, snapshots};
+use juicelib::ev_sim::{run_ev_sim, EvSimCommand, EvSimHardwareImpl, DEFAULT_CHARGE_PIN, DEFAULT_CONNECT_PIN};
+use juicelib::evse::{run_machine, EVSEHardwareImpl};
+use juicelib::provisioning::{
+    find_provisioning_file, is_provisioned, load_settings, provision, record_load_test, DEFAULT_CONFIG_DIR,
+    DEFAULT_MEDIA_DIR,
+};
+use juicelib::replay::{load_recording, replay};
+use juicelib::schema::{all_schemas, payload_schema, PAYLOADS};
+use juicelib::store::{Store, StoreError, DEFAULT_STORE_PATH};
 
-// Configure SPI
-fn configure_spi() -> Spidev {
-    let mut spi = Spidev::open("/dev/spidev0.0").unwrap();
-    let options = SpidevOptions::new()
-        .bits_per_word(8)
-        .max_speed_hz(1_000_000)
-        .mode(spidev::SpiModeFlags::SPI_MODE_0)
-        .build();
-    spi.configure(&options).unwrap();
-    spi
+// The passphrase of a backup comes from JUICED_BACKUP_PASSPHRASE or the
+// first line of stdin.
+fn backup_passphrase() -> String {
+    if let Ok(passphrase) = std::env::var("JUICED_BACKUP_PASSPHRASE") {
+        return passphrase;
+    }
+    let mut passphrase = String::new();
+    if std::io::stdin().lock().read_line(&mut passphrase).is_err() || passphrase.trim().is_empty() {
+        eprintln!("No backup passphrase given");
+        exit(2);
+    }
+    passphrase.trim_end_matches(['\r', '\n']).to_string()
+}
+
+// juiced backup <file> | juiced restore <file>
+// juiced must not be running while a backup is restored.
+fn backup_command(command: &str, path: Option<&String>) -> ! {
+    let Some(path) = path else {
+        eprintln!("Usage: juiced {} <file>", command);
+        exit(2);
+    };
+    let passphrase = backup_passphrase();
+    let result = if command == "backup" {
+        create_backup(&default_sources(), &passphrase, Path::new(path))
+    } else {
+        restore_backup(&default_sources(), &passphrase, Path::new(path))
+    };
+    match result {
+        Ok(files) => {
+            eprintln!("{}: {} files", command, files);
+            exit(0)
+        }
+        Err(error) => {
+            eprintln!("{} failed: {:?}", command, error);
+            exit(1)
+        }
+    }
 }
 
-fn read_adc(spi: &mut Spidev, chan: u8) -> u16 {
-    let mut buf = [0u8; 3];
-    let tx_buf = [1, 0x80 | (chan << 4), 0xff];
-    let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut buf);
-    spi.transfer(&mut transfer).unwrap();
-    // let val = ((buf[1] & 0x3) << 8) | buf[2] would overflow
-    let val = ((buf[1] as u16 & 0x3) << 8) | buf[2] as u16;
-    val as u16
+fn env_pin(name: &str, default: u8) -> u8 {
+    std::env::var(name).ok().and_then(|pin| pin.parse().ok()).unwrap_or(default)
 }
 
-// Test the pilot voltage
-fn test_pilot(spi: &mut Spidev) {
-    for _ in 0..500 {
-        let val = read_adc(spi, 0);
-        if val < 184 || val > 932 {
-            println!("Error: Unexpected pilot voltage. ADC reading is {}", val);
-            return;
+// juiced ev-sim: emulates a vehicle to test another EVSE. Reads commands
+// (plug, charge, stop-charge, unplug) from stdin, one per line, and writes
+// a JSON report per pilot reading to stdout.
+fn ev_sim_command() -> ! {
+    let connect_pin = env_pin("JUICED_EV_CONNECT_PIN", DEFAULT_CONNECT_PIN);
+    let charge_pin = env_pin("JUICED_EV_CHARGE_PIN", DEFAULT_CHARGE_PIN);
+    let hardware = EvSimHardwareImpl::new(connect_pin, charge_pin).expect("Failed to initialize the emulator hardware");
+
+    let (command_tx, command_rx) = crossbeam_channel::unbounded();
+    let (report_tx, report_rx) = crossbeam_channel::unbounded();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let command = match line.as_deref().map(str::trim) {
+                Ok("plug") => EvSimCommand::Plug,
+                Ok("charge") => EvSimCommand::RequestCharge,
+                Ok("stop-charge") => EvSimCommand::StopCharge,
+                Ok("unplug") => EvSimCommand::Unplug,
+                Ok("") => continue,
+                Ok(other) => {
+                    eprintln!("Unknown command: {}", other);
+                    continue;
+                }
+                Err(_) => break,
+            };
+            if command_tx.send(command).is_err() {
+                return;
+            }
+        }
+        let _ = command_tx.send(EvSimCommand::Stop);
+    });
+    let printer = std::thread::spawn(move || {
+        for report in report_rx {
+            println!("{}", serde_json::to_string(&report).unwrap());
+        }
+    });
+
+    let result = run_ev_sim(hardware, command_rx, report_tx);
+    let _ = printer.join();
+    match result {
+        Ok(()) => exit(0),
+        Err(error) => {
+            eprintln!("ev-sim failed: {:?}", error);
+            exit(1)
+        }
+    }
+}
+
+// juiced replay <file>: feeds a black box recording through the state
+// machine of this build and prints what it decides, marking where that
+// differs from what the station did. Exits with 1 if it differs.
+fn replay_command(path: Option<&String>) -> ! {
+    let Some(path) = path else {
+        eprintln!("Usage: juiced replay <black box file>");
+        exit(2);
+    };
+    match load_recording(Path::new(path)) {
+        Ok(recording) => {
+            let replay = replay(&recording);
+            println!("{}", replay);
+            exit(if replay.divergences() == 0 { 0 } else { 1 })
+        }
+        Err(error) => {
+            eprintln!("Failed to read {}: {}", path, error);
+            exit(1)
+        }
+    }
+}
+
+// juiced config list | diff <from> <to> | rollback <id>: the snapshots of
+// the site settings as applied. A rollback applies on the next start.
+fn config_command(args: &[String]) -> ! {
+    let store_path = Path::new(DEFAULT_STORE_PATH);
+    let id = |index: usize| args.get(index).and_then(|id| id.parse::<i64>().ok());
+    let result = match (args.first().map(String::as_str), id(1), id(2)) {
+        (Some("list"), _, _) => snapshots(store_path).map(|snapshots| {
+            for snapshot in snapshots {
+                println!("{}", snapshot);
+            }
+        }),
+        (Some("diff"), Some(from), Some(to)) => diff_snapshots(store_path, from, to).map(|changes| {
+            for change in changes {
+                println!("{}", change);
+            }
+        }),
+        (Some("rollback"), Some(id), _) => roll_back(store_path, Path::new(DEFAULT_CONFIG_DIR), id)
+            .map(|_| eprintln!("Settings of snapshot #{} written, restart juiced to apply them", id)),
+        _ => {
+            eprintln!("Usage: juiced config list | diff <from> <to> | rollback <id>");
+            exit(2);
+        }
+    };
+    match result {
+        Ok(()) => exit(0),
+        Err(error) => {
+            eprintln!("config failed: {}", error);
+            exit(1)
+        }
+    }
+}
+
+// juiced schema [payload]: the JSON Schema of a payload, or all of them by
+// name, for generating clients.
+fn schema_command(payload: Option<&String>) -> ! {
+    let schema = match payload {
+        None => serde_json::to_string_pretty(&all_schemas()),
+        Some(payload) => match payload_schema(payload) {
+            Some(schema) => serde_json::to_string_pretty(&schema),
+            None => {
+                eprintln!("Usage: juiced schema [{}]", PAYLOADS.join(" | "));
+                exit(2);
+            }
+        },
+    };
+    println!("{}", schema.expect("schemas serialize"));
+    exit(0)
+}
+
+// juiced load-test: the test of the rated current, with the service stopped
+// and a test load or a vehicle plugged in. Added to the commissioning
+// report.
+fn load_test_command(evse: &mut EVSEHardwareImpl) -> ! {
+    match record_load_test(evse, Path::new(DEFAULT_CONFIG_DIR)) {
+        Ok(signed) => {
+            let report = signed.report.load_test.expect("the load test was just run");
+            println!("{}", serde_json::to_string_pretty(&report).expect("reports serialize"));
+            exit(if report.passed { 0 } else { 1 })
+        }
+        Err(error) => {
+            eprintln!("Load test not recorded: {:?}", error);
+            exit(1)
         }
     }
-    println!("Pilot voltage is within expected range.");
 }
 
 fn main() {
-    let mut spi = configure_spi();
-    test_pilot(&mut spi);
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(command) = args.get(1).filter(|command| *command == "backup" || *command == "restore") {
+        backup_command(command, args.get(2));
+    }
+    if args.get(1).is_some_and(|command| command == "ev-sim") {
+        ev_sim_command();
+    }
+    if args.get(1).is_some_and(|command| command == "replay") {
+        replay_command(args.get(2));
+    }
+    if args.get(1).is_some_and(|command| command == "config") {
+        config_command(&args[2..]);
+    }
+    if args.get(1).is_some_and(|command| command == "schema") {
+        schema_command(args.get(2));
+    }
+
+    // Migrates the store on the first start after an upgrade. A store from
+    // a newer juiced is not touched: run that version or restore a backup.
+    match Store::open(Path::new(DEFAULT_STORE_PATH)) {
+        Ok(_) => {}
+        Err(error @ StoreError::NewerSchema { .. }) => {
+            eprintln!("{}", error);
+            exit(1);
+        }
+        Err(error) => eprintln!("Store unavailable: {}", error),
+    }
+
+    // The board's pins and limits. A config for another board must not be
+    // taken for the hat's.
+    let config = match Config::load(Path::new(DEFAULT_CONFIG_PATH)) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}: {}", DEFAULT_CONFIG_PATH, error);
+            exit(1);
+        }
+    };
+    let mut builder = EVSEHardwareImpl::builder().config(&config);
+    // GPIO the power good output of an optional UPS hat is wired to.
+    if let Some(pin) = std::env::var("JUICED_POWER_GOOD_PIN").ok().and_then(|pin| pin.parse().ok()) {
+        builder = builder.power_good_pin(pin);
+    }
+    // GPIO of the enclosure door/tamper switch, if fitted.
+    if let Some(pin) = std::env::var("JUICED_TAMPER_PIN").ok().and_then(|pin| pin.parse().ok()) {
+        builder = builder.tamper_pin(pin);
+    }
+    // GPIO of the alarm output, for a fault LED or a building alarm panel.
+    if let Some(pin) = std::env::var("JUICED_ALARM_PIN").ok().and_then(|pin| pin.parse().ok()) {
+        builder = builder.alarm_pin(pin);
+    }
+    // GPIO of the control contact of the grid operator (§14a EnWG) or an
+    // energy management system.
+    if let Some(pin) = std::env::var("JUICED_CONTROL_CONTACT_PIN").ok().and_then(|pin| pin.parse().ok()) {
+        builder = builder.control_contact_pin(pin);
+    }
+    // "momentary" for GFI boards that reset themselves.
+    if let Ok(driver) = std::env::var("JUICED_GFI_DRIVER") {
+        match driver.parse() {
+            Ok(driver) => builder = builder.gfi_driver(driver),
+            Err(error) => eprintln!("{}", error),
+        }
+    }
+    // "pigpio:<gpio>" on Pis whose hardware PWM is taken by the audio.
+    if let Ok(driver) = std::env::var("JUICED_PILOT_DRIVER") {
+        match driver.parse() {
+            Ok(driver) => builder = builder.pilot_driver(driver),
+            Err(error) => eprintln!("{}", error),
+        }
+    }
+    // Highest rate in A/s at which the pilot offer goes up.
+    if let Some(rate) = std::env::var("JUICED_PILOT_SLEW_RATE").ok().and_then(|rate| rate.parse().ok()) {
+        builder = builder.pilot_slew_rate(rate);
+    }
+    // Boards other than the hat list their ADC inputs in the site settings.
+    if let Some(channels) = load_settings(Path::new(DEFAULT_CONFIG_DIR))
+        .ok()
+        .and_then(|settings| settings.adc_channels)
+    {
+        builder = builder.channel_registry(channels);
+    }
+    let mut evse = builder.build().expect("Failed to initialize the EVSE hardware");
+    if args.get(1).is_some_and(|command| command == "load-test") {
+        load_test_command(&mut evse);
+    }
+
+    // First boot: provision from a USB stick if one is plugged in.
+    let config_dir = Path::new(DEFAULT_CONFIG_DIR);
+    if !is_provisioned(config_dir) {
+        match find_provisioning_file(Path::new(DEFAULT_MEDIA_DIR)) {
+            Some(file) => {
+                let signed = provision(&mut evse, &file, config_dir).expect("Provisioning failed");
+                if !signed.report.passed() {
+                    panic!("Commissioning failed: {:?}", signed.report.steps);
+                }
+            }
+            None => eprintln!("Station is not provisioned"),
+        }
+    }
+
+    exit(run_machine(evse, &config).code());
 }
@@ -0,0 +1,21 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use juicelib::latency;
+
+// The control loop latencies of src/latency.rs. Each iteration starts a
+// machine of its own; only the latency itself is timed.
+fn control_loop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("control_loop");
+    group.bench_function("plug_in_to_offer", |b| {
+        b.iter_custom(|iterations| (0..iterations).map(|_| latency::plug_in_to_offer()).sum())
+    });
+    group.bench_function("fault_to_contactor_open", |b| {
+        b.iter_custom(|iterations| (0..iterations).map(|_| latency::fault_to_contactor_open()).sum())
+    });
+    group.bench_function("limit_change_to_offer", |b| {
+        b.iter_custom(|iterations| (0..iterations).map(|_| latency::limit_change_to_offer()).sum())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, control_loop);
+criterion_main!(benches);
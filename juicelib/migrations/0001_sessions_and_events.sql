@@ -0,0 +1,15 @@
+CREATE TABLE sessions (
+    id INTEGER PRIMARY KEY,
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER,
+    energy_wh REAL NOT NULL DEFAULT 0
+);
+
+CREATE TABLE events (
+    id INTEGER PRIMARY KEY,
+    unix_time INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    detail TEXT
+);
+
+CREATE INDEX events_by_time ON events (unix_time);
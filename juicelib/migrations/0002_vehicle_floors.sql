@@ -0,0 +1,5 @@
+CREATE TABLE vehicle_floors (
+    vehicle TEXT PRIMARY KEY,
+    lowest_charging REAL,
+    highest_refused REAL
+);
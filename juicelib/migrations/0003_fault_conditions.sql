@@ -0,0 +1,3 @@
+ALTER TABLE events ADD COLUMN cause TEXT;
+ALTER TABLE events ADD COLUMN temperature_c REAL;
+ALTER TABLE events ADD COLUMN mains_volts REAL;
@@ -0,0 +1,10 @@
+CREATE TABLE guest_tokens (
+    secret_hash TEXT PRIMARY KEY,
+    id TEXT NOT NULL,
+    label TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    max_current REAL,
+    max_energy_wh REAL
+);
+
+ALTER TABLE sessions ADD COLUMN guest_token TEXT;
@@ -0,0 +1,5 @@
+CREATE TABLE pilot_cable_estimates (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    unix_time INTEGER NOT NULL,
+    series_ohms REAL NOT NULL
+);
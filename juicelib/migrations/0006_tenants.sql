@@ -0,0 +1,14 @@
+CREATE TABLE tenants (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    max_current REAL,
+    monthly_energy_wh REAL,
+    price_per_kwh REAL
+);
+
+CREATE TABLE tenant_credentials (
+    credential_hash TEXT PRIMARY KEY,
+    tenant_id TEXT NOT NULL REFERENCES tenants (id)
+);
+
+ALTER TABLE sessions ADD COLUMN tenant_id TEXT;
@@ -0,0 +1,3 @@
+ALTER TABLE sessions ADD COLUMN session_id TEXT;
+
+CREATE UNIQUE INDEX sessions_by_session_id ON sessions (session_id);
@@ -0,0 +1,8 @@
+CREATE TABLE charging_curves (
+    session_id TEXT NOT NULL,
+    minute INTEGER NOT NULL,
+    unix_time INTEGER NOT NULL,
+    power_w REAL NOT NULL,
+    amps REAL NOT NULL,
+    PRIMARY KEY (session_id, minute)
+);
@@ -0,0 +1,5 @@
+CREATE TABLE daily_peaks (
+    day TEXT PRIMARY KEY,
+    offered_amps REAL NOT NULL,
+    measured_amps REAL
+);
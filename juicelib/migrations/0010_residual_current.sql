@@ -0,0 +1,2 @@
+ALTER TABLE events ADD COLUMN residual_ma REAL;
+ALTER TABLE events ADD COLUMN residual_peak_ma REAL;
@@ -0,0 +1,6 @@
+CREATE TABLE config_snapshots (
+    id INTEGER PRIMARY KEY,
+    applied_at INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    settings TEXT NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE events ADD COLUMN vehicle TEXT;
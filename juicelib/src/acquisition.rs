@@ -0,0 +1,409 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::channels::Signal;
+use crate::evse::{EVSEMachineState, PilotReading};
+
+// Health of the pilot acquisition loop: the sample rate it achieves, the
+// largest gap in the sampling and how often the SPI reads fail. A rate
+// that drops or a gap that grows is the first sign of something starving
+// the loop, lock contention on the SPI bus for instance.
+//
+// The acquisition also reads the current sense after every pilot window,
+// averaging more conversions in the states where the current matters.
+//
+// The zero offset of the current sense drifts with temperature, hall
+// sensors in particular. While the contactor is open no current can flow,
+// so the readings taken then, once the contactor has been open for
+// ZERO_SETTLE, are the offset: it follows them as a slow moving average and
+// is taken off every reading. A reading beyond MAX_ZERO_OFFSET_AMPS is no
+// offset, a welded contactor for instance, and is not learned.
+//
+// The acquisition thread owns the ADC; nothing else touches the SPI bus.
+// It records what it computed in AcquisitionMetrics, and everybody else
+// reads the latest values through an AcquisitionReader. A read waits at
+// most for a value being recorded, never for a conversion.
+
+pub const ZERO_SETTLE: Duration = Duration::from_secs(2);
+pub const MAX_ZERO_OFFSET_AMPS: f64 = 1.0;
+// The weight of a new reading in the offset, once there are enough for a
+// plain mean to be steadier. At 10 readings a second, the offset takes
+// about 20 s to follow a drift.
+const ZERO_OFFSET_WEIGHT: f64 = 0.005;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, JsonSchema)]
+pub struct AcquisitionHealth {
+    // Windows read, failed ones included.
+    pub windows: u64,
+    pub samples: u64,
+    // Sample rate of the last window and the lowest of any window.
+    pub sample_rate_hz: f64,
+    pub min_sample_rate_hz: f64,
+    // Largest time from the last sample of a window to the first of the
+    // next. The samples of a window are read back to back, so this is the
+    // largest gap between samples; nominally the sampling interval.
+    pub max_gap_ms: u64,
+    pub spi_errors: u64,
+    // Conversions averaged into the last current reading.
+    pub current_oversampling: usize,
+    // The zero offset taken off the current readings, and the readings it
+    // was learned from.
+    pub current_zero_offset_amps: f64,
+    pub current_zero_readings: u64,
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    health: AcquisitionHealth,
+    last_window_end: Option<Instant>,
+    pilot: Option<PilotReading>,
+    current_amps: Option<f64>,
+    signals: BTreeMap<Signal, f64>,
+    mains_frequency_hz: Option<f64>,
+}
+
+impl Metrics {
+    fn start_window(&mut self, start: Instant) {
+        if let Some(end) = self.last_window_end {
+            let gap = start.saturating_duration_since(end).as_millis() as u64;
+            self.health.max_gap_ms = self.health.max_gap_ms.max(gap);
+        }
+        self.health.windows += 1;
+    }
+}
+
+// Kept by the acquisition thread, which records. The others get a reader.
+#[derive(Debug, Clone, Default)]
+pub struct AcquisitionMetrics {
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl AcquisitionMetrics {
+    pub fn reader(&self) -> AcquisitionReader {
+        AcquisitionReader { metrics: self.clone() }
+    }
+
+    // The reading of the last pilot window, also sent to the machine.
+    pub fn record_pilot(&self, reading: PilotReading) {
+        self.metrics.lock().unwrap().pilot = Some(reading);
+    }
+
+    pub fn pilot(&self) -> Option<PilotReading> {
+        self.metrics.lock().unwrap().pilot
+    }
+    pub fn record_window(&self, start: Instant, end: Instant, samples: usize) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.start_window(start);
+        metrics.last_window_end = Some(end);
+
+        let health = &mut metrics.health;
+        health.samples += samples as u64;
+        let elapsed = end.saturating_duration_since(start).as_secs_f64();
+        if elapsed > 0.0 {
+            health.sample_rate_hz = samples as f64 / elapsed;
+            health.min_sample_rate_hz = if health.min_sample_rate_hz == 0.0 {
+                health.sample_rate_hz
+            } else {
+                health.min_sample_rate_hz.min(health.sample_rate_hz)
+            };
+        }
+    }
+
+    // A window that failed to read. The gap runs on to the next good one.
+    pub fn record_error(&self, start: Instant) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.start_window(start);
+        metrics.health.spi_errors += 1;
+    }
+
+    pub fn health(&self) -> AcquisitionHealth {
+        self.metrics.lock().unwrap().health
+    }
+
+    // A raw reading of the current sense. None forgets the last reading,
+    // e.g. once the current is no longer read or its read failed.
+    pub fn record_current(&self, amps: Option<f64>, oversampling: usize) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.current_amps = amps.map(|amps| amps - metrics.health.current_zero_offset_amps);
+        metrics.health.current_oversampling = oversampling;
+    }
+
+    // A raw reading of the current sense while no current flows.
+    pub fn calibrate_zero(&self, amps: f64) {
+        if amps.is_nan() || amps.abs() > MAX_ZERO_OFFSET_AMPS {
+            return;
+        }
+        let health = &mut self.metrics.lock().unwrap().health;
+        health.current_zero_readings += 1;
+        let weight = (1.0 / health.current_zero_readings as f64).max(ZERO_OFFSET_WEIGHT);
+        health.current_zero_offset_amps += (amps - health.current_zero_offset_amps) * weight;
+    }
+
+    pub fn current_amps(&self) -> Option<f64> {
+        self.metrics.lock().unwrap().current_amps
+    }
+
+    // The last reading of another signal of the channel registry. None
+    // forgets it, as for the current.
+    pub fn record_signal(&self, signal: Signal, value: Option<f64>) {
+        let mut metrics = self.metrics.lock().unwrap();
+        match value {
+            Some(value) => metrics.signals.insert(signal, value),
+            None => metrics.signals.remove(&signal),
+        };
+    }
+
+    pub fn signal(&self, signal: Signal) -> Option<f64> {
+        self.metrics.lock().unwrap().signals.get(&signal).copied()
+    }
+
+    // The frequency of the last mains waveform, see mains.rs. None forgets
+    // it.
+    pub fn record_mains_frequency(&self, hz: Option<f64>) {
+        self.metrics.lock().unwrap().mains_frequency_hz = hz;
+    }
+
+    pub fn mains_frequency_hz(&self) -> Option<f64> {
+        self.metrics.lock().unwrap().mains_frequency_hz
+    }
+}
+
+// The latest values of the acquisition, for any thread to read. Cheap to
+// clone.
+#[derive(Debug, Clone)]
+pub struct AcquisitionReader {
+    metrics: AcquisitionMetrics,
+}
+
+impl AcquisitionReader {
+    pub fn health(&self) -> AcquisitionHealth {
+        self.metrics.health()
+    }
+
+    // None until the first window is read.
+    pub fn pilot(&self) -> Option<PilotReading> {
+        self.metrics.pilot()
+    }
+
+    pub fn current_amps(&self) -> Option<f64> {
+        self.metrics.current_amps()
+    }
+
+    pub fn signal(&self, signal: Signal) -> Option<f64> {
+        self.metrics.signal(signal)
+    }
+
+    pub fn mains_frequency_hz(&self) -> Option<f64> {
+        self.metrics.mains_frequency_hz()
+    }
+}
+
+// How many conversions of the current sense are averaged into a reading,
+// by machine state. The current is billed while charging, so it is read
+// with the most conversions then. Otherwise it is only watched for a
+// contactor that did not open, and in Standby the few conversions save
+// the CPU. 0 does not read the current at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct OversamplingPolicy {
+    pub standby: usize,
+    pub charging: usize,
+    // Every other state.
+    pub other: usize,
+}
+
+impl OversamplingPolicy {
+    // For samplers without a current sense.
+    pub const NONE: Self = Self {
+        standby: 0,
+        charging: 0,
+        other: 0,
+    };
+
+    pub fn conversions(&self, state: EVSEMachineState) -> usize {
+        match state {
+            EVSEMachineState::Standby => self.standby,
+            EVSEMachineState::Charging => self.charging,
+            _ => self.other,
+        }
+    }
+}
+
+impl Default for OversamplingPolicy {
+    // 64 conversions take under 2 ms at the 1 MHz SPI clock of the hat,
+    // little next to the pilot window.
+    fn default() -> Self {
+        Self {
+            standby: 4,
+            charging: 64,
+            other: 16,
+        }
+    }
+}
+
+// Tells the acquisition thread how to sample in the state the machine is
+// in. The machine sets the state, the acquisition reads the conversions
+// at the start of every window, and stops once the schedule is stopped.
+#[derive(Debug, Clone)]
+pub struct AcquisitionSchedule {
+    policy: OversamplingPolicy,
+    conversions: Arc<AtomicUsize>,
+    contactor_closed: Arc<AtomicBool>,
+    // When the contactor last opened. None while it has not been closed.
+    contactor_opened_at: Arc<Mutex<Option<Instant>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl AcquisitionSchedule {
+    // Starts out in Standby.
+    pub fn new(policy: OversamplingPolicy) -> Self {
+        Self {
+            policy,
+            conversions: Arc::new(AtomicUsize::new(policy.conversions(EVSEMachineState::Standby))),
+            contactor_closed: Arc::new(AtomicBool::new(false)),
+            contactor_opened_at: Arc::new(Mutex::new(None)),
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn set_state(&self, state: EVSEMachineState) {
+        self.conversions.store(self.policy.conversions(state), Ordering::Relaxed);
+    }
+
+    pub fn current_conversions(&self) -> usize {
+        self.conversions.load(Ordering::Relaxed)
+    }
+
+    // Set before the contactor closes and after it opened.
+    pub fn set_contactor(&self, closed: bool) {
+        let was_closed = self.contactor_closed.swap(closed, Ordering::SeqCst);
+        if was_closed && !closed {
+            *self.contactor_opened_at.lock().unwrap() = Some(Instant::now());
+        }
+    }
+
+    // Whether the current sense reads its zero offset now.
+    pub fn reads_zero(&self) -> bool {
+        !self.contactor_closed.load(Ordering::SeqCst)
+            && self
+                .contactor_opened_at
+                .lock()
+                .unwrap()
+                .is_none_or(|opened_at| opened_at.elapsed() >= ZERO_SETTLE)
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = AcquisitionMetrics::default();
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+
+        metrics.record_window(t0, t0 + ms(10), 100);
+        metrics.record_window(t0 + ms(210), t0 + ms(230), 100);
+        let health = metrics.health();
+        assert_eq!(health.windows, 2);
+        assert_eq!(health.samples, 200);
+        assert!((health.sample_rate_hz - 5000.0).abs() < 1e-6);
+        assert!((health.min_sample_rate_hz - 5000.0).abs() < 1e-6);
+        assert_eq!(health.max_gap_ms, 200);
+
+        // The failed window stretches the gap to the next good one.
+        metrics.record_error(t0 + ms(430));
+        metrics.record_window(t0 + ms(730), t0 + ms(740), 100);
+        let health = metrics.health();
+        assert_eq!(health.windows, 4);
+        assert_eq!(health.spi_errors, 1);
+        assert_eq!(health.max_gap_ms, 500);
+        assert!((health.sample_rate_hz - 10000.0).abs() < 1e-6);
+        assert!((health.min_sample_rate_hz - 5000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_reader() {
+        let metrics = AcquisitionMetrics::default();
+        let reader = metrics.reader();
+        assert_eq!(reader.pilot(), None);
+
+        let reading = PilotReading {
+            high: 9.0,
+            low: -12.0,
+            duty_cycle: 0.5,
+            frequency: 1000.0,
+        };
+        let recorder = metrics.clone();
+        std::thread::spawn(move || {
+            recorder.record_pilot(reading);
+            recorder.record_current(Some(16.0), 64);
+            recorder.record_signal(Signal::MainsV, Some(230.0));
+        })
+        .join()
+        .unwrap();
+        let reader = reader.clone();
+        assert_eq!(reader.pilot(), Some(reading));
+        assert_eq!(reader.current_amps(), Some(16.0));
+        assert_eq!(reader.signal(Signal::MainsV), Some(230.0));
+        assert_eq!(reader.health().current_oversampling, 64);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule = AcquisitionSchedule::new(OversamplingPolicy::default());
+        let acquisition = schedule.clone();
+        assert_eq!(acquisition.current_conversions(), 4);
+        schedule.set_state(EVSEMachineState::Charging);
+        assert_eq!(acquisition.current_conversions(), 64);
+        schedule.set_state(EVSEMachineState::SuspendedEV);
+        assert_eq!(acquisition.current_conversions(), 16);
+        assert_eq!(AcquisitionSchedule::new(OversamplingPolicy::NONE).current_conversions(), 0);
+    }
+
+    #[test]
+    fn test_zero_offset() {
+        let schedule = AcquisitionSchedule::new(OversamplingPolicy::default());
+        assert!(schedule.reads_zero());
+        schedule.set_contactor(true);
+        assert!(!schedule.reads_zero());
+        // Not until the contactor has settled.
+        schedule.set_contactor(false);
+        assert!(!schedule.reads_zero());
+
+        let metrics = AcquisitionMetrics::default();
+        metrics.calibrate_zero(0.3);
+        metrics.calibrate_zero(0.5);
+        // A welded contactor.
+        metrics.calibrate_zero(12.0);
+        let health = metrics.health();
+        assert!((health.current_zero_offset_amps - 0.4).abs() < 1e-9);
+        assert_eq!(health.current_zero_readings, 2);
+        metrics.record_current(Some(10.4), 64);
+        assert!((metrics.current_amps().unwrap() - 10.0).abs() < 1e-9);
+
+        // Once settled, follows a drift slowly.
+        for _ in 0..300 {
+            metrics.calibrate_zero(0.4);
+        }
+        for _ in 0..100 {
+            metrics.calibrate_zero(0.6);
+        }
+        let offset = metrics.health().current_zero_offset_amps;
+        assert!(offset > 0.45 && offset < 0.5, "{}", offset);
+    }
+}
@@ -0,0 +1,309 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+
+use crate::hal::pwm::Error as PwmError;
+use crate::interlock::PilotInterlock;
+use crate::pilot::{Pilot, PilotSignal};
+
+// Pilot actuator thread. Writes to the kernel PWM sysfs files now and then
+// block for tens of milliseconds. The state machine hands the pilot
+// signals to this thread instead of writing them itself, so a slow write
+// never delays its response to a fault: it goes on to open the contactor
+// while the pilot write is still pending.
+//
+// The queue is short and a write that takes too long marks the actuator
+// stalled. Both are reported on the next signal, as is a write that failed.
+//
+// With a slew rate set, a higher offer is reached in steps so the duty
+// cycle does not jump, which confuses some onboard chargers when the solar
+// mode tracks a passing cloud. The rate applies to whoever asks for the
+// offer. Lower offers and taking the offer away still take effect at once:
+// they may protect a breaker or the supply.
+//
+// Writes wait while the GFI self test owns the pilot, see interlock.rs.
+
+const QUEUE_DEPTH: usize = 4;
+pub const ACTUATOR_TIMEOUT: Duration = Duration::from_millis(500);
+// How often a ramp steps up.
+const RAMP_STEP: Duration = Duration::from_millis(100);
+
+// What the actuator writes to. Implemented by the pilot PWM.
+pub trait PilotOutput: Send + 'static {
+    fn set_signal(&mut self, signal: PilotSignal) -> Result<(), PwmError>;
+}
+
+impl PilotOutput for Pilot {
+    fn set_signal(&mut self, signal: PilotSignal) -> Result<(), PwmError> {
+        Pilot::set_signal(self, signal)
+    }
+}
+
+#[derive(Debug)]
+pub enum ActuatorError {
+    // The queue is full; the writes do not keep up.
+    QueueFull,
+    // A write has been going on for this long.
+    Stalled(Duration),
+    // An earlier write failed.
+    WriteFailed(PwmError),
+    Stopped,
+}
+
+#[derive(Default)]
+struct ActuatorStatus {
+    // When the write in progress started.
+    busy_since: Option<Instant>,
+    // The last failed write, until it is reported.
+    error: Option<PwmError>,
+}
+
+pub struct PilotActuator {
+    signal_tx: Sender<PilotSignal>,
+    status: Arc<Mutex<ActuatorStatus>>,
+    timeout: Duration,
+}
+
+impl PilotActuator {
+    // Runs until the actuator is dropped. The slew rate is in A/s; None
+    // writes every offer at once.
+    pub fn start<P: PilotOutput>(output: P, slew_rate: Option<f64>) -> Self {
+        Self::with_timeout(output, slew_rate, ACTUATOR_TIMEOUT)
+    }
+
+    // Holds the writes while the self test owns the pilot.
+    pub fn interlocked<P: PilotOutput>(output: P, slew_rate: Option<f64>, interlock: PilotInterlock) -> Self {
+        Self::spawn(output, slew_rate, ACTUATOR_TIMEOUT, interlock)
+    }
+
+    pub fn with_timeout<P: PilotOutput>(output: P, slew_rate: Option<f64>, timeout: Duration) -> Self {
+        Self::spawn(output, slew_rate, timeout, PilotInterlock::default())
+    }
+
+    fn spawn<P: PilotOutput>(output: P, slew_rate: Option<f64>, timeout: Duration, interlock: PilotInterlock) -> Self {
+        let (signal_tx, signal_rx) = bounded::<PilotSignal>(QUEUE_DEPTH);
+        let status = Arc::new(Mutex::new(ActuatorStatus::default()));
+        let thread_status = status.clone();
+        thread::spawn(move || Self::run(output, slew_rate, signal_rx, thread_status, interlock));
+        Self {
+            signal_tx,
+            status,
+            timeout,
+        }
+    }
+
+    fn run<P: PilotOutput>(
+        mut output: P,
+        slew_rate: Option<f64>,
+        signal_rx: Receiver<PilotSignal>,
+        status: Arc<Mutex<ActuatorStatus>>,
+        interlock: PilotInterlock,
+    ) {
+        let mut written: Option<(PilotSignal, Instant)> = None;
+        // The signal being ramped to.
+        let mut target = None;
+        loop {
+            let received = match target {
+                Some(_) => signal_rx.recv_timeout(RAMP_STEP).map_err(|error| error.is_disconnected()),
+                None => signal_rx.recv().map_err(|_| true),
+            };
+            match received {
+                Ok(signal) => target = Some(signal),
+                Err(true) => return,
+                Err(false) => {}
+            }
+            let Some(goal) = target else {
+                continue;
+            };
+            let signal = match (written, goal, slew_rate) {
+                (Some((PilotSignal::OfferAmps(from), at)), PilotSignal::OfferAmps(to), Some(rate)) if to > from => {
+                    let step = rate * at.elapsed().min(RAMP_STEP).as_secs_f64();
+                    PilotSignal::OfferAmps(to.min(from + step))
+                }
+                _ => goal,
+            };
+            if signal == goal {
+                target = None;
+            }
+
+            // Not a stall: the write has not started.
+            interlock.wait_released();
+            status.lock().unwrap().busy_since = Some(Instant::now());
+            let result = output.set_signal(signal);
+            let mut status = status.lock().unwrap();
+            status.busy_since = None;
+            match result {
+                Ok(()) => written = Some((signal, Instant::now())),
+                Err(error) => status.error = Some(error),
+            }
+        }
+    }
+
+    // Queues the signal and returns without waiting for the write.
+    pub fn set_signal(&self, signal: PilotSignal) -> Result<(), ActuatorError> {
+        {
+            let mut status = self.status.lock().unwrap();
+            if let Some(error) = status.error.take() {
+                return Err(ActuatorError::WriteFailed(error));
+            }
+            if let Some(busy) = status.busy_since.map(|since| since.elapsed()) {
+                if busy > self.timeout {
+                    return Err(ActuatorError::Stalled(busy));
+                }
+            }
+        }
+        self.signal_tx.try_send(signal).map_err(|error| match error {
+            TrySendError::Full(_) => ActuatorError::QueueFull,
+            TrySendError::Disconnected(_) => ActuatorError::Stopped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::unbounded;
+    use std::io;
+
+    // Writes take as long as the gate keeps them waiting.
+    struct SlowOutput {
+        gate: Receiver<()>,
+        written: Sender<PilotSignal>,
+        fail: bool,
+    }
+
+    impl PilotOutput for SlowOutput {
+        fn set_signal(&mut self, signal: PilotSignal) -> Result<(), PwmError> {
+            self.gate.recv().unwrap();
+            self.written.send(signal).unwrap();
+            if self.fail {
+                return Err(PwmError::Io(io::Error::other("write failed")));
+            }
+            Ok(())
+        }
+    }
+
+    fn slow_actuator(fail: bool, timeout: Duration) -> (PilotActuator, Sender<()>, Receiver<PilotSignal>) {
+        let (gate_tx, gate) = unbounded();
+        let (written, written_rx) = unbounded();
+        let actuator = PilotActuator::with_timeout(SlowOutput { gate, written, fail }, None, timeout);
+        (actuator, gate_tx, written_rx)
+    }
+
+    #[test]
+    fn test_writes_in_order_without_waiting() {
+        let (actuator, gate_tx, written_rx) = slow_actuator(false, Duration::from_secs(10));
+        let start = Instant::now();
+        actuator.set_signal(PilotSignal::OfferAmps(16.0)).unwrap();
+        actuator.set_signal(PilotSignal::SteadyPlus12).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        gate_tx.send(()).unwrap();
+        gate_tx.send(()).unwrap();
+        assert_eq!(written_rx.recv().unwrap(), PilotSignal::OfferAmps(16.0));
+        assert_eq!(written_rx.recv().unwrap(), PilotSignal::SteadyPlus12);
+    }
+
+    struct RecordingOutput {
+        written: Sender<(PilotSignal, Instant)>,
+    }
+
+    impl PilotOutput for RecordingOutput {
+        fn set_signal(&mut self, signal: PilotSignal) -> Result<(), PwmError> {
+            self.written.send((signal, Instant::now())).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_slew_rate() {
+        let (written, written_rx) = unbounded();
+        let actuator = PilotActuator::start(RecordingOutput { written }, Some(20.0));
+        actuator.set_signal(PilotSignal::OfferAmps(6.0)).unwrap();
+        actuator.set_signal(PilotSignal::OfferAmps(10.0)).unwrap();
+
+        // 2 A per 100 ms step.
+        let (first, start) = written_rx.recv().unwrap();
+        assert_eq!(first, PilotSignal::OfferAmps(6.0));
+        let mut offers = Vec::new();
+        let end = loop {
+            let (signal, at) = written_rx.recv().unwrap();
+            let PilotSignal::OfferAmps(amps) = signal else {
+                panic!("{:?}", signal);
+            };
+            offers.push(amps);
+            if amps == 10.0 {
+                break at;
+            }
+        };
+        assert!(offers.len() >= 2, "{:?}", offers);
+        assert!(offers.windows(2).all(|pair| pair[1] - pair[0] <= 2.0 + 1e-9), "{:?}", offers);
+        assert!(end - start >= Duration::from_millis(150));
+
+        // Lower offers and no offer are immediate.
+        actuator.set_signal(PilotSignal::OfferAmps(6.0)).unwrap();
+        actuator.set_signal(PilotSignal::SteadyPlus12).unwrap();
+        assert_eq!(written_rx.recv().unwrap().0, PilotSignal::OfferAmps(6.0));
+        assert_eq!(written_rx.recv().unwrap().0, PilotSignal::SteadyPlus12);
+    }
+
+    #[test]
+    fn test_writes_wait_for_self_test() {
+        let (written, written_rx) = unbounded();
+        let interlock = PilotInterlock::default();
+        let actuator = PilotActuator::interlocked(RecordingOutput { written }, None, interlock.clone());
+        let token = interlock.take();
+        actuator.set_signal(PilotSignal::OfferAmps(16.0)).unwrap();
+        assert!(written_rx.recv_timeout(Duration::from_millis(100)).is_err());
+        // Waiting for the test is no stall.
+        actuator.set_signal(PilotSignal::OfferAmps(10.0)).unwrap();
+        drop(token);
+        assert_eq!(written_rx.recv().unwrap().0, PilotSignal::OfferAmps(16.0));
+        assert_eq!(written_rx.recv().unwrap().0, PilotSignal::OfferAmps(10.0));
+    }
+
+    #[test]
+    fn test_stalled_write() {
+        let (actuator, gate_tx, written_rx) = slow_actuator(false, Duration::from_millis(20));
+        actuator.set_signal(PilotSignal::SteadyPlus12).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(matches!(
+            actuator.set_signal(PilotSignal::ErrorMinus12),
+            Err(ActuatorError::Stalled(_))
+        ));
+
+        // The queue fills up behind the stuck write.
+        let mut result = Ok(());
+        let mut actuator = actuator;
+        actuator.timeout = Duration::from_secs(10);
+        for _ in 0..=QUEUE_DEPTH {
+            result = actuator.set_signal(PilotSignal::ErrorMinus12);
+        }
+        assert!(matches!(result, Err(ActuatorError::QueueFull)));
+
+        gate_tx.send(()).unwrap();
+        assert_eq!(written_rx.recv().unwrap(), PilotSignal::SteadyPlus12);
+    }
+
+    #[test]
+    fn test_failed_write_is_reported() {
+        let (actuator, gate_tx, written_rx) = slow_actuator(true, Duration::from_secs(10));
+        actuator.set_signal(PilotSignal::SteadyPlus12).unwrap();
+        gate_tx.send(()).unwrap();
+        written_rx.recv().unwrap();
+        let start = Instant::now();
+        while actuator.status.lock().unwrap().error.is_none() {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert!(matches!(
+            actuator.set_signal(PilotSignal::ErrorMinus12),
+            Err(ActuatorError::WriteFailed(_))
+        ));
+        // Reported once.
+        assert!(actuator.set_signal(PilotSignal::ErrorMinus12).is_ok());
+    }
+}
@@ -1,25 +1,86 @@
-use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+use std::collections::BTreeMap;
+
+use crate::channels::{ChannelEntry, ChannelRegistry, Conversion, Signal};
+use crate::config::SpiConfig;
+use crate::hal::spi::{Bus, Error as SpiError, Mode, SlaveSelect, Spi};
+use crate::mcp::{LibError, Mcp3004, Mcp3004Channel};
+use crate::scan::{compare_scans, ChannelScan, ScanComparison};
 
 // This file defines a private (to this crate) struct called Adc. It has a
 // public method called new() which returns a Result<Adc, AdcError>. The
 // The ADC uses a mcp3008 chip which is connected to the Raspberry Pi via SPI.
 // The mcp3008 is connected to the Raspberry Pi with the first SPI bus (SPI0)
 
-// 3 Channels are connected to the SPI:
-// 1. Pilot voltage
-// 2. Current sense
-// 3. AC Voltage
+// Which channel carries which signal is up to the channel registry, see
+// channels.rs. A second chip goes on the other chip select. Boards with
+// the ADC on SPI1, or a slower clock, give it in their SpiConfig.
+//
+// Every read takes &mut self: the Adc belongs to the acquisition thread and
+// is never shared. The values it computes are read through an
+// AcquisitionReader (see acquisition.rs).
+
+// Percentiles (0.0 to 1.0) used as the low and high peak of a window of
+// samples. Anything but 0.0 and 1.0 keeps single glitch samples from
+// setting the peaks of the whole window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeakPercentiles {
+    low: f32,
+    high: f32,
+}
+
+impl PeakPercentiles {
+    pub fn new(low: f32, high: f32) -> Option<Self> {
+        if (0.0..=1.0).contains(&low) && (0.0..=1.0).contains(&high) && low <= high {
+            Some(Self { low, high })
+        } else {
+            None
+        }
+    }
+
+    // Plain minimum and maximum.
+    pub fn min_max() -> Self {
+        Self { low: 0.0, high: 1.0 }
+    }
+}
+
+impl Default for PeakPercentiles {
+    // With 100 samples per window, up to two glitches on either side are
+    // ignored. The high level of a 5% duty cycle still covers five samples.
+    fn default() -> Self {
+        Self { low: 0.02, high: 0.98 }
+    }
+}
+
+// Percentile of sorted values, interpolated between neighbours.
+fn percentile(sorted: &[f32], p: f32) -> f32 {
+    let position = p * (sorted.len() - 1) as f32;
+    let below = position.floor() as usize;
+    let above = position.ceil() as usize;
+    sorted[below] + (sorted[above] - sorted[below]) * (position - below as f32)
+}
 
 // Define the struct:
 pub struct Adc {
-    mcp: Mcp3004
+    // By chip select.
+    chips: BTreeMap<u8, Mcp3004>,
+    registry: ChannelRegistry,
 }
 
 // Define the error type:
 #[derive(Debug)]
 pub enum AdcError {
-    SpiError(std::io::Error),
+    SpiError(SpiError),
     LibError(LibError),
+    // The registry has no channel for the signal.
+    NoChannel(Signal),
+    // Only SPI0 and SPI1 have two chip selects.
+    NoBus(u8),
+}
+
+impl From<SpiError> for AdcError {
+    fn from(error: SpiError) -> Self {
+        AdcError::SpiError(error)
+    }
 }
 
 impl From<LibError> for AdcError {
@@ -30,38 +91,136 @@ impl From<LibError> for AdcError {
 
 // Implement the Adc struct:
 impl Adc {
+    // The hat's channels.
     pub fn new() -> Result<Self, AdcError> {
-        let spi = Spi::new(Bus::Spi0, SlaveSelect::Ss0, 1_000_000, Mode::Mode0).unwrap();
-        let mcp3004 = Mcp3004::new(spi).unwrap();
+        Self::with_registry(ChannelRegistry::default())
+    }
+
+    pub fn with_registry(registry: ChannelRegistry) -> Result<Self, AdcError> {
+        Self::with_spi(registry, SpiConfig::default())
+    }
 
-        Ok(Self {
-            mcp: mcp3004,
-        })
+    pub fn with_spi(registry: ChannelRegistry, config: SpiConfig) -> Result<Self, AdcError> {
+        let bus = match config.bus {
+            0 => Bus::Spi0,
+            1 => Bus::Spi1,
+            bus => return Err(AdcError::NoBus(bus)),
+        };
+        let mut chips = BTreeMap::new();
+        for chip in registry.chips() {
+            let slave_select = if chip == 0 { SlaveSelect::Ss0 } else { SlaveSelect::Ss1 };
+            let spi = Spi::new(bus, slave_select, config.clock_hz, Mode::Mode0)?;
+            chips.insert(chip, Mcp3004::new(spi)?);
+        }
+
+        Ok(Self { chips, registry })
     }
 
     fn to_volts(reading: u16) -> f32 {
-        let voltage = (reading as f32) * 3.3 / 1024.0;
-        voltage
+        (reading as f32) * 3.3 / 1024.0
     }
 
     fn to_amps(reading: u16) -> f32 {
-        let voltage = (reading as f32) * 3.3 / 1024.0;
-        let amps = (voltage - 1.65) / 0.066;
-        amps
+        Self::mean_to_amps(reading as f32)
+    }
+
+    // The mean of oversampled readings keeps the fraction of a count.
+    fn mean_to_amps(reading: f32) -> f32 {
+        Conversion::CurrentSense.apply(reading)
+    }
+
+    fn entry(&self, signal: Signal) -> Result<ChannelEntry, AdcError> {
+        self.registry.get(signal).ok_or(AdcError::NoChannel(signal))
+    }
+
+    // The raw counts of one conversion.
+    fn read_counts(&mut self, signal: Signal, entry: ChannelEntry) -> Result<u16, AdcError> {
+        // The chips of all entries are opened up front.
+        let mcp = self.chips.get_mut(&entry.chip).ok_or(AdcError::NoChannel(signal))?;
+        let channel = Mcp3004Channel::try_from(entry.channel).map_err(LibError::from)?;
+        Ok(mcp.single_ended_read(channel)?.value())
+    }
+
+    // Returns the (low, high) peaks of a window of samples. NaN samples are
+    // ignored; a window without any valid sample gives NaN peaks.
+    pub fn peak_to_peak(samples: &[f32], percentiles: PeakPercentiles) -> (f32, f32) {
+        let mut sorted: Vec<f32> = samples.iter().cloned().filter(|v| !v.is_nan()).collect();
+        if sorted.is_empty() {
+            return (f32::NAN, f32::NAN);
+        }
+        sorted.sort_by(f32::total_cmp);
+        (percentile(&sorted, percentiles.low), percentile(&sorted, percentiles.high))
     }
 
+    // Samples the pilot feedback as fast as possible and returns the pilot
+    // voltages in the order they were read.
+    pub fn read_pilot_samples(&mut self, samples: usize) -> Result<Vec<f32>, AdcError> {
+        let entry = self.registry.pilot();
+        let mut voltages = Vec::with_capacity(samples);
+        for _ in 0..samples {
+            let counts = self.read_counts(Signal::PilotCp, entry)?;
+            voltages.push(entry.conversion.apply(counts as f32));
+        }
+        Ok(voltages)
+    }
+
+    // Single readings are not used by the state machine yet.
+    #[allow(dead_code)]
     pub fn read_pilot_voltage(&mut self) -> Result<f32, AdcError> {
-        let reading = self.mcp.single_ended_read(Channel(0))?;
-        let voltage = Self::to_volts(reading.value());
+        let reading = self.read_counts(Signal::PilotCp, self.registry.pilot())?;
+        let voltage = Self::to_volts(reading);
         Ok(voltage)
     }
 
+    #[allow(dead_code)]
     pub fn read_current_sense(&mut self) -> Result<f32, AdcError> {
-        let reading = self.mcp.single_ended_read(Channel(1))?;
-        let curr = Self::to_amps(reading.value());
+        let reading = self.read_counts(Signal::CurrentL1, self.entry(Signal::CurrentL1)?)?;
+        let curr = Self::to_amps(reading);
         Ok(curr)
     }
 
+    // Averages several conversions of the current sense. The noise of the
+    // sensor dithers the readings, so the mean resolves below one count.
+    pub fn read_current_oversampled(&mut self, conversions: usize) -> Result<f32, AdcError> {
+        let entry = self.entry(Signal::CurrentL1)?;
+        let mut sum = 0u32;
+        for _ in 0..conversions {
+            sum += self.read_counts(Signal::CurrentL1, entry)? as u32;
+        }
+        Ok(entry.conversion.apply(sum as f32 / conversions.max(1) as f32))
+    }
+
+    // One conversion of a signal, in its unit. None if the registry has no
+    // channel for it.
+    pub fn read_signal(&mut self, signal: Signal) -> Option<Result<f32, AdcError>> {
+        let entry = self.registry.get(signal)?;
+        Some(self.read_counts(signal, entry).map(|counts| entry.conversion.apply(counts as f32)))
+    }
+
+    // The chip of the pilot, which the scans run on.
+    fn pilot_chip(&mut self) -> Result<&mut Mcp3004, AdcError> {
+        let chip = self.registry.pilot().chip;
+        self.chips.get_mut(&chip).ok_or(AdcError::NoChannel(Signal::PilotCp))
+    }
+
+    // Reads several channels in the order and with the settling given by
+    // the scan. Returns the raw readings.
+    #[allow(dead_code)]
+    pub fn scan(&mut self, scan: &ChannelScan) -> Result<Vec<u16>, AdcError> {
+        Ok(scan.run(self.pilot_chip()?)?)
+    }
+
+    // Bench helper, see scan::compare_scans.
+    #[allow(dead_code)]
+    pub fn compare_scans(
+        &mut self,
+        baseline: &ChannelScan,
+        candidate: &ChannelScan,
+        rounds: usize,
+    ) -> Result<ScanComparison, AdcError> {
+        Ok(compare_scans(self.pilot_chip()?, baseline, candidate, rounds)?)
+    }
+
 }
 
 #[cfg(test)]
@@ -80,13 +239,45 @@ mod tests {
         let reading = 512;
         let amps = Adc::to_amps(reading);
         assert_eq!(amps, 0.0);
+        assert_eq!(Adc::mean_to_amps(512.0), 0.0);
+        assert!(Adc::mean_to_amps(512.5) > 0.0);
+    }
+
+    #[test]
+    fn test_to_pilot_volts() {
+        let pilot = Conversion::PilotFeedback;
+        assert_eq!(pilot.apply(184.0), -12.0);
+        assert_eq!(pilot.apply(932.0), 12.0);
+        assert_eq!(pilot.apply(558.0), 0.0);
+    }
+
+    #[test]
+    fn test_peak_to_peak_ignores_spikes() {
+        // A 10% duty cycle pilot at 6V with one spike to either side.
+        let mut samples: Vec<f32> = (0..100).map(|i| if i % 10 == 0 { 6.0 } else { -12.0 }).collect();
+        samples[35] = 12.0;
+        samples[70] = -20.0;
+
+        assert_eq!(Adc::peak_to_peak(&samples, PeakPercentiles::min_max()), (-20.0, 12.0));
+        assert_eq!(Adc::peak_to_peak(&samples, PeakPercentiles::default()), (-12.0, 6.0));
+    }
+
+    #[test]
+    fn test_peak_to_peak_edge_cases() {
+        let (low, high) = Adc::peak_to_peak(&[], PeakPercentiles::default());
+        assert!(low.is_nan() && high.is_nan());
+        assert_eq!(Adc::peak_to_peak(&[3.0], PeakPercentiles::default()), (3.0, 3.0));
+        assert_eq!(Adc::peak_to_peak(&[f32::NAN, 1.0, 2.0], PeakPercentiles::min_max()), (1.0, 2.0));
+        assert_eq!(Adc::peak_to_peak(&[0.0, 10.0], PeakPercentiles::new(0.5, 0.5).unwrap()), (5.0, 5.0));
+        assert_eq!(PeakPercentiles::new(0.9, 0.1), None);
+        assert_eq!(PeakPercentiles::new(-0.1, 0.9), None);
     }
 
     #[test]
     fn test_read_pilot_voltage() -> Result<(), AdcError> {
         let mut adc = Adc::new()?;
         let voltage = adc.read_pilot_voltage()?;
-        assert!(voltage >= 0.0 && voltage <= 3.3);
+        assert!((0.0..=3.3).contains(&voltage));
         Ok(())
     }
 
@@ -94,7 +285,7 @@ mod tests {
     fn test_read_current_sense() -> Result<(), AdcError> {
         let mut adc = Adc::new()?;
         let current = adc.read_current_sense()?;
-        assert!(current >= -50.0 && current <= 50.0);
+        assert!((-50.0..=50.0).contains(&current));
         Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::nuisance::{self, ClassifiedTrip, TripVerdict};
+use crate::store::{Store, StoreError};
+
+// Fault analytics over the faults kept in the store: how often the station
+// faults, how long it goes between GFI trips, and the temperature and
+// mains voltage the faults happened at. Faults that pile up in one band of
+// either point at the cause, like a GFI that trips on hot afternoons.
+//
+// The conditions are only known at the faults, so the bands count faults,
+// not faults per hour spent in the band.
+
+const DAY: u64 = 24 * 60 * 60;
+const WEEK: u64 = 7 * DAY;
+// The epoch was a Thursday; weeks start on Monday.
+const WEEK_OFFSET: u64 = 3 * DAY;
+const TEMPERATURE_BAND: f64 = 10.0;
+const VOLTAGE_BAND: f64 = 10.0;
+
+// The cause of a GFI trip.
+pub const GFI_TRIP: &str = "GFIInterrupted";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FaultRecord {
+    pub unix_time: u64,
+    // The state the fault latched.
+    pub fault: String,
+    // The machine input that led to it, if known.
+    pub cause: Option<String>,
+    pub temperature_c: Option<f64>,
+    pub mains_volts: Option<f64>,
+    // The residual current at the fault and the highest in the seconds
+    // before, for hardware with the GFI CT on the ADC. A current well
+    // below the trip threshold points at a nuisance trip.
+    pub residual_ma: Option<f64>,
+    pub residual_peak_ma: Option<f64>,
+    // The vehicle of the session, if identified.
+    pub vehicle: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct WeekCount {
+    // Monday 00:00 UTC.
+    pub week_start: u64,
+    pub faults: u64,
+}
+
+// Faults with a condition from `from` up to `to`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+pub struct BandCount {
+    pub from: f64,
+    pub to: f64,
+    pub faults: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct FaultReport {
+    pub faults: u64,
+    // Weeks without faults are left out.
+    pub faults_per_week: Vec<WeekCount>,
+    pub gfi_trips: u64,
+    // Needs two trips.
+    pub mean_time_between_gfi_trips_s: Option<u64>,
+    pub likely_nuisance_gfi_trips: u64,
+    // See nuisance.rs.
+    pub classified_gfi_trips: Vec<ClassifiedTrip>,
+    pub by_temperature_c: Vec<BandCount>,
+    pub by_mains_volts: Vec<BandCount>,
+}
+
+fn bands(values: impl Iterator<Item = f64>, width: f64) -> Vec<BandCount> {
+    let mut counts = BTreeMap::new();
+    for value in values {
+        *counts.entry((value / width).floor() as i64).or_insert(0) += 1;
+    }
+    counts
+        .into_iter()
+        .map(|(band, faults)| BandCount {
+            from: band as f64 * width,
+            to: (band + 1) as f64 * width,
+            faults,
+        })
+        .collect()
+}
+
+impl FaultReport {
+    pub fn new(faults: &[FaultRecord]) -> Self {
+        let mut weeks = BTreeMap::new();
+        for fault in faults {
+            let week_start = ((fault.unix_time + WEEK_OFFSET) / WEEK * WEEK).saturating_sub(WEEK_OFFSET);
+            *weeks.entry(week_start).or_insert(0) += 1;
+        }
+
+        let mut trips: Vec<u64> = faults
+            .iter()
+            .filter(|fault| fault.cause.as_deref() == Some(GFI_TRIP))
+            .map(|fault| fault.unix_time)
+            .collect();
+        trips.sort_unstable();
+        let mean_time_between_gfi_trips_s = match (trips.first(), trips.last()) {
+            (Some(first), Some(last)) if trips.len() > 1 => Some((last - first) / (trips.len() as u64 - 1)),
+            _ => None,
+        };
+
+        let classified_gfi_trips = nuisance::classify_trips(faults);
+
+        Self {
+            faults: faults.len() as u64,
+            faults_per_week: weeks
+                .into_iter()
+                .map(|(week_start, faults)| WeekCount { week_start, faults })
+                .collect(),
+            gfi_trips: trips.len() as u64,
+            mean_time_between_gfi_trips_s,
+            likely_nuisance_gfi_trips: classified_gfi_trips
+                .iter()
+                .filter(|trip| trip.verdict == TripVerdict::LikelyNuisance)
+                .count() as u64,
+            classified_gfi_trips,
+            by_temperature_c: bands(faults.iter().filter_map(|fault| fault.temperature_c), TEMPERATURE_BAND),
+            by_mains_volts: bands(faults.iter().filter_map(|fault| fault.mains_volts), VOLTAGE_BAND),
+        }
+    }
+
+    pub fn load(store_path: &Path) -> Result<Self, StoreError> {
+        Ok(Self::new(&Store::open(store_path)?.faults()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fault(unix_time: u64, cause: &str, temperature_c: Option<f64>) -> FaultRecord {
+        FaultRecord {
+            unix_time,
+            fault: "FailedStation".to_string(),
+            cause: Some(cause.to_string()),
+            temperature_c,
+            mains_volts: Some(231.0),
+            residual_ma: None,
+            residual_peak_ma: None,
+            vehicle: None,
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        // Monday 2024-01-01 00:00 UTC.
+        let monday = 1_704_067_200;
+        let faults = [
+            fault(monday + DAY, GFI_TRIP, Some(35.0)),
+            fault(monday + 2 * DAY, "PilotInError", None),
+            fault(monday + 6 * DAY, GFI_TRIP, Some(38.5)),
+            fault(monday + WEEK, GFI_TRIP, Some(-2.0)),
+        ];
+        let report = FaultReport::new(&faults);
+        assert_eq!(report.faults, 4);
+        assert_eq!(
+            report.faults_per_week,
+            vec![
+                WeekCount {
+                    week_start: monday,
+                    faults: 3
+                },
+                WeekCount {
+                    week_start: monday + WEEK,
+                    faults: 1
+                },
+            ]
+        );
+        assert_eq!(report.gfi_trips, 3);
+        assert_eq!(report.mean_time_between_gfi_trips_s, Some(3 * DAY));
+        // Nothing points at a nuisance.
+        assert_eq!(report.classified_gfi_trips.len(), 3);
+        assert_eq!(report.likely_nuisance_gfi_trips, 0);
+        let temperatures: Vec<_> = report.by_temperature_c.iter().map(|band| (band.from, band.faults)).collect();
+        assert_eq!(temperatures, vec![(-10.0, 1), (30.0, 2)]);
+        assert_eq!(report.by_mains_volts.len(), 1);
+        assert_eq!(report.by_mains_volts[0].faults, 4);
+
+        assert_eq!(FaultReport::new(&faults[..1]).mean_time_between_gfi_trips_s, None);
+    }
+}
@@ -0,0 +1,63 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Append-only log of security relevant events, one line per event:
+//   <unix time> <event>
+
+pub const DEFAULT_AUDIT_LOG_PATH: &str = "/var/log/juiced/audit.log";
+
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    // None disables the log.
+    path: Option<PathBuf>,
+}
+
+impl AuditLog {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: Some(path.to_path_buf()),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self { path: None }
+    }
+
+    pub fn record(&self, event: &str) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let unix_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{} {}", unix_time, event)?;
+        file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_appends() {
+        let path = std::env::temp_dir().join(format!("juicelib-audit-{}.log", std::process::id()));
+        let log = AuditLog::new(&path);
+        log.record("enclosure opened").unwrap();
+        log.record("tamper fault reset").unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        let events: Vec<&str> = text.lines().map(|line| line.split_once(' ').unwrap().1).collect();
+        assert_eq!(events, vec!["enclosure opened", "tamper fault reset"]);
+        fs::remove_file(&path).unwrap();
+
+        assert!(AuditLog::disabled().record("ignored").is_ok());
+    }
+}
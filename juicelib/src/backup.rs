@@ -0,0 +1,336 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::config_file::CORRUPT_SUFFIX;
+use crate::power_fail::DEFAULT_RECORD_PATH;
+use crate::provisioning::{DEFAULT_CONFIG_DIR, KEY_FILE_NAME};
+
+// Encrypted backups of the station's configuration and data, so a dead SD
+// card does not take the settings, lifetime counters and logs with it.
+//
+// A backup file is
+//   "JUICEDBK", format version (1 byte), salt (16 bytes), nonce (12 bytes),
+//   AES-256-GCM ciphertext of the JSON archive
+// with the key derived from a passphrase by Argon2id. The header is
+// authenticated along with the archive.
+
+pub const DEFAULT_DATA_DIR: &str = "/var/lib/juiced";
+
+// Version of the archive contents. Archives of older versions are migrated
+// on restore, newer ones are refused.
+pub const BACKUP_SCHEMA_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 8] = b"JUICEDBK";
+const FORMAT_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+
+// Migrations of the archive contents, the first one migrates version 1 to
+// version 2 and so on.
+const MIGRATIONS: &[fn(&mut Archive)] = &[];
+
+#[derive(Debug)]
+pub enum BackupError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    // Not a backup file, or one of an unknown format.
+    NotABackup,
+    // Wrong passphrase or a damaged file.
+    Decryption,
+    // Made by a newer juiced than this one.
+    NewerSchema { found: u32, supported: u32 },
+    // The archive names a file outside of the backed up directories.
+    InvalidFile(String),
+}
+
+impl From<io::Error> for BackupError {
+    fn from(error: io::Error) -> Self {
+        BackupError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for BackupError {
+    fn from(error: serde_json::Error) -> Self {
+        BackupError::Json(error)
+    }
+}
+
+// A directory whose files go into the backup, under a name that stays the
+// same if the directory moves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupSource {
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+impl BackupSource {
+    pub fn new(name: &str, dir: &Path) -> Self {
+        Self {
+            name: name.to_string(),
+            dir: dir.to_path_buf(),
+        }
+    }
+}
+
+// The configuration and the data directory.
+pub fn default_sources() -> Vec<BackupSource> {
+    vec![
+        BackupSource::new("config", Path::new(DEFAULT_CONFIG_DIR)),
+        BackupSource::new("data", Path::new(DEFAULT_DATA_DIR)),
+    ]
+}
+
+// The device key never leaves the station, and a power failure record is
+// only meaningful for the next boot of the same station.
+fn is_excluded(name: &str) -> bool {
+    let power_fail = Path::new(DEFAULT_RECORD_PATH).file_name().and_then(|name| name.to_str());
+    name == KEY_FILE_NAME || Some(name) == power_fail || name.ends_with(".tmp") || name.ends_with(CORRUPT_SUFFIX)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ArchiveFile {
+    source: String,
+    name: String,
+    // Base64.
+    contents: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Archive {
+    schema_version: u32,
+    unix_time: u64,
+    files: Vec<ArchiveFile>,
+}
+
+pub(crate) fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    File::open("/dev/urandom")?.read_exact(buf)
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<Aes256Gcm, BackupError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| BackupError::Decryption)?;
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+}
+
+fn encrypt(plain: &[u8], passphrase: &str) -> Result<Vec<u8>, BackupError> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.push(FORMAT_VERSION);
+    let mut salt_and_nonce = [0u8; SALT_LEN + NONCE_LEN];
+    random_bytes(&mut salt_and_nonce)?;
+    header.extend_from_slice(&salt_and_nonce);
+
+    let (salt, nonce) = salt_and_nonce.split_at(SALT_LEN);
+    let payload = Payload { msg: plain, aad: &header };
+    let ciphertext = cipher(passphrase, salt)?
+        .encrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| BackupError::Decryption)?;
+    header.extend_from_slice(&ciphertext);
+    Ok(header)
+}
+
+fn decrypt(data: &[u8], passphrase: &str) -> Result<Vec<u8>, BackupError> {
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC || data[MAGIC.len()] != FORMAT_VERSION {
+        return Err(BackupError::NotABackup);
+    }
+    let (header, ciphertext) = data.split_at(HEADER_LEN);
+    let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LEN];
+    let nonce = &header[MAGIC.len() + 1 + SALT_LEN..];
+    let payload = Payload {
+        msg: ciphertext,
+        aad: header,
+    };
+    cipher(passphrase, salt)?
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| BackupError::Decryption)
+}
+
+fn migrate(mut archive: Archive) -> Result<Archive, BackupError> {
+    if archive.schema_version > BACKUP_SCHEMA_VERSION {
+        return Err(BackupError::NewerSchema {
+            found: archive.schema_version,
+            supported: BACKUP_SCHEMA_VERSION,
+        });
+    }
+    if archive.schema_version == 0 {
+        return Err(BackupError::NotABackup);
+    }
+    while archive.schema_version < BACKUP_SCHEMA_VERSION {
+        MIGRATIONS[archive.schema_version as usize - 1](&mut archive);
+        archive.schema_version += 1;
+    }
+    Ok(archive)
+}
+
+// Writes an encrypted backup of the top level files of the sources and
+// returns the number of files in it. Missing directories are skipped.
+pub fn create_backup(sources: &[BackupSource], passphrase: &str, path: &Path) -> Result<usize, BackupError> {
+    let mut files = Vec::new();
+    for source in sources {
+        let Ok(entries) = fs::read_dir(&source.dir) else {
+            continue;
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| !is_excluded(name))
+            .collect();
+        names.sort();
+        for name in names {
+            let contents = fs::read(source.dir.join(&name))?;
+            files.push(ArchiveFile {
+                source: source.name.clone(),
+                name,
+                contents: BASE64.encode(contents),
+            });
+        }
+    }
+
+    let archive = Archive {
+        schema_version: BACKUP_SCHEMA_VERSION,
+        unix_time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        files,
+    };
+    let data = encrypt(&serde_json::to_vec(&archive)?, passphrase)?;
+    let mut file = File::create(path)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    Ok(archive.files.len())
+}
+
+// Restores a backup into the sources and returns the number of files
+// restored. Every file is checked before the first one is written. Each
+// file is replaced atomically; files that are not in the backup are left
+// alone.
+pub fn restore_backup(sources: &[BackupSource], passphrase: &str, path: &Path) -> Result<usize, BackupError> {
+    let archive: Archive = serde_json::from_slice(&decrypt(&fs::read(path)?, passphrase)?)?;
+    let archive = migrate(archive)?;
+
+    let mut restored = Vec::with_capacity(archive.files.len());
+    for file in &archive.files {
+        let invalid = || BackupError::InvalidFile(format!("{}/{}", file.source, file.name));
+        let source = sources.iter().find(|source| source.name == file.source).ok_or_else(invalid)?;
+        // Only plain file names, nothing that could leave the directory.
+        let is_plain = Path::new(&file.name).file_name().is_some_and(|name| name == file.name.as_str());
+        if !is_plain || is_excluded(&file.name) {
+            return Err(invalid());
+        }
+        let contents = BASE64.decode(&file.contents).map_err(|_| invalid())?;
+        restored.push((source.dir.join(&file.name), contents));
+    }
+
+    for (path, contents) in &restored {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+    }
+    Ok(restored.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("juicelib-backup-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let dir = temp_dir("roundtrip");
+        let config = dir.join("config");
+        let data = dir.join("data");
+        fs::create_dir_all(&config).unwrap();
+        fs::create_dir_all(&data).unwrap();
+        fs::write(config.join("site.json"), "{}").unwrap();
+        fs::write(config.join(KEY_FILE_NAME), [7u8; 32]).unwrap();
+        fs::write(data.join("counters.0"), [0u8, 1, 2, 255]).unwrap();
+        let sources = vec![BackupSource::new("config", &config), BackupSource::new("data", &data)];
+
+        let backup = dir.join("station.backup");
+        assert_eq!(create_backup(&sources, "correct horse", &backup).unwrap(), 2);
+        let raw = fs::read(&backup).unwrap();
+        assert!(raw.starts_with(MAGIC));
+        assert!(!raw.windows(9).any(|window| window == b"site.json"));
+
+        // Restore onto a fresh card.
+        fs::remove_dir_all(&config).unwrap();
+        fs::remove_dir_all(&data).unwrap();
+        assert!(matches!(
+            restore_backup(&sources, "wrong", &backup),
+            Err(BackupError::Decryption)
+        ));
+        assert_eq!(restore_backup(&sources, "correct horse", &backup).unwrap(), 2);
+        assert_eq!(fs::read_to_string(config.join("site.json")).unwrap(), "{}");
+        assert_eq!(fs::read(data.join("counters.0")).unwrap(), vec![0u8, 1, 2, 255]);
+        assert!(!config.join(KEY_FILE_NAME).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_schema_versions() {
+        let archive = Archive {
+            schema_version: BACKUP_SCHEMA_VERSION + 1,
+            unix_time: 0,
+            files: Vec::new(),
+        };
+        assert!(matches!(
+            migrate(archive.clone()),
+            Err(BackupError::NewerSchema { found, .. }) if found == BACKUP_SCHEMA_VERSION + 1
+        ));
+        let current = Archive {
+            schema_version: BACKUP_SCHEMA_VERSION,
+            ..archive
+        };
+        assert_eq!(migrate(current.clone()).unwrap(), current);
+    }
+
+    #[test]
+    fn test_rejects_foreign_files() {
+        let dir = temp_dir("foreign");
+        let sources = vec![BackupSource::new("config", &dir)];
+        let archive = Archive {
+            schema_version: BACKUP_SCHEMA_VERSION,
+            unix_time: 0,
+            files: vec![ArchiveFile {
+                source: "config".to_string(),
+                name: "../passwd".to_string(),
+                contents: String::new(),
+            }],
+        };
+        let backup = dir.join("evil.backup");
+        fs::write(&backup, encrypt(&serde_json::to_vec(&archive).unwrap(), "pass").unwrap()).unwrap();
+        assert!(matches!(
+            restore_backup(&sources, "pass", &backup),
+            Err(BackupError::InvalidFile(_))
+        ));
+
+        fs::write(&backup, b"not a backup").unwrap();
+        assert!(matches!(restore_backup(&sources, "pass", &backup), Err(BackupError::NotABackup)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::Receiver;
+use serde::Serialize;
+
+use crate::acquisition::AcquisitionHealth;
+use crate::clock::{system_clock, Clock};
+use crate::control_watchdog::SafeState;
+use crate::evse::{EVSEError, EVSEHardware, EVSEMachineInput, EVSEMachineState, EvseCommand, PilotReading};
+use crate::pilot::PilotSignal;
+use crate::readback::Discrepancy;
+
+// Flight recorder for faults. The machine keeps the last seconds of what it
+// saw and did in memory; when a fault latches the buffer is frozen and
+// written to a file, so the lead-up to the fault can be looked at later.
+// The entries and the file are stamped from the clock of the machine.
+
+pub const DEFAULT_BLACK_BOX_DIR: &str = "/var/lib/juiced/blackbox";
+
+// How far back the recorder reaches.
+pub const DEFAULT_BLACK_BOX_SPAN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum BlackBoxEntry {
+    // State machine inputs, including those derived from pilot readings.
+    Input(EVSEMachineInput),
+    // Raw sensor data behind the pilot inputs.
+    Pilot(PilotReading),
+    Command(EvseCommand),
+    State(EVSEMachineState),
+    // Hardware commands issued by the machine.
+    SetPilot(PilotSignal),
+    SetContactor(bool),
+    GfiSelfTest { passed: bool },
+    // The GFI CT output in mA, for hardware that has it on the ADC.
+    ResidualCurrent(f64),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct FrozenEntry {
+    // Milliseconds before the buffer was frozen.
+    age_ms: u64,
+    entry: BlackBoxEntry,
+}
+
+#[derive(Debug, Serialize)]
+struct FlightRecord {
+    fault: EVSEMachineState,
+    unix_time: u64,
+    // The state at the start of the recording, for the replay.
+    initial_state: Option<EVSEMachineState>,
+    entries: Vec<FrozenEntry>,
+}
+
+pub struct BlackBox {
+    span: Duration,
+    entries: VecDeque<(Instant, BlackBoxEntry)>,
+    // The last state that dropped out of the buffer.
+    initial_state: Option<EVSEMachineState>,
+    clock: Arc<dyn Clock>,
+}
+
+impl BlackBox {
+    pub fn new(span: Duration) -> Self {
+        Self {
+            span,
+            entries: VecDeque::new(),
+            initial_state: None,
+            clock: system_clock(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn record(&mut self, entry: BlackBoxEntry) {
+        self.record_at(self.clock.now(), entry);
+    }
+
+    fn record_at(&mut self, at: Instant, entry: BlackBoxEntry) {
+        while let Some(&(oldest, _)) = self.entries.front() {
+            if at.saturating_duration_since(oldest) <= self.span {
+                break;
+            }
+            if let Some((_, BlackBoxEntry::State(state))) = self.entries.pop_front() {
+                self.initial_state = Some(state);
+            }
+        }
+        self.entries.push_back((at, entry));
+    }
+
+    // The latest machine input still in the buffer.
+    pub fn last_input(&self) -> Option<EVSEMachineInput> {
+        self.entries.iter().rev().find_map(|(_, entry)| match entry {
+            BlackBoxEntry::Input(input) => Some(*input),
+            _ => None,
+        })
+    }
+
+    // The highest residual current recorded in the last `window`.
+    pub fn residual_peak(&self, window: Duration) -> Option<f64> {
+        self.residual_peak_at(self.clock.now(), window)
+    }
+
+    fn residual_peak_at(&self, at: Instant, window: Duration) -> Option<f64> {
+        self.entries
+            .iter()
+            .filter(|(time, _)| at.saturating_duration_since(*time) <= window)
+            .filter_map(|(_, entry)| match entry {
+                BlackBoxEntry::ResidualCurrent(milliamps) => Some(*milliamps),
+                _ => None,
+            })
+            .reduce(f64::max)
+    }
+
+    fn freeze(&self, at: Instant) -> Vec<FrozenEntry> {
+        self.entries
+            .iter()
+            .map(|(time, entry)| FrozenEntry {
+                age_ms: at.saturating_duration_since(*time).as_millis() as u64,
+                entry: entry.clone(),
+            })
+            .collect()
+    }
+
+    // Writes the buffer to a new file in `dir` and returns its path. The
+    // buffer is kept, so a later fault still sees the same lead-up.
+    pub fn persist(&self, dir: &Path, fault: EVSEMachineState) -> io::Result<PathBuf> {
+        let unix_time = self.clock.unix_now();
+        let record = FlightRecord {
+            fault,
+            unix_time,
+            initial_state: self.initial_state,
+            entries: self.freeze(self.clock.now()),
+        };
+
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("blackbox-{}-{:?}.json", unix_time, fault));
+        let mut file = File::create(&path)?;
+        serde_json::to_writer(&mut file, &record)?;
+        file.flush()?;
+        file.sync_all()?;
+        Ok(path)
+    }
+}
+
+impl Default for BlackBox {
+    fn default() -> Self {
+        Self::new(DEFAULT_BLACK_BOX_SPAN)
+    }
+}
+
+// Faults that hold the station until something outside the machine clears
+// them.
+pub fn is_latched_fault(state: EVSEMachineState) -> bool {
+    matches!(
+        state,
+        EVSEMachineState::ResetableError | EVSEMachineState::FailedStation | EVSEMachineState::TamperLockout
+    )
+}
+
+// Hardware wrapper putting every command the machine issues in the black
+// box.
+pub(crate) struct RecordingHardware<H> {
+    inner: H,
+    pub black_box: BlackBox,
+    // How long the last GFI self test took and whether it passed, until the
+    // metrics take it.
+    pub last_self_test: Option<(Duration, bool)>,
+}
+
+impl<H: EVSEHardware> RecordingHardware<H> {
+    pub fn new(inner: H, black_box: BlackBox) -> Self {
+        Self {
+            inner,
+            black_box,
+            last_self_test: None,
+        }
+    }
+}
+
+impl<H: EVSEHardware> EVSEHardware for RecordingHardware<H> {
+    fn set_pilot(&mut self, signal: PilotSignal) -> Result<(), EVSEError> {
+        self.black_box.record(BlackBoxEntry::SetPilot(signal));
+        self.inner.set_pilot(signal)
+    }
+
+    fn max_current(&self) -> f64 {
+        self.inner.max_current()
+    }
+
+    fn set_max_current(&mut self, ampere: f64) -> Result<(), EVSEError> {
+        self.inner.set_max_current(ampere)
+    }
+
+    fn set_contactor(&mut self, on: bool) -> Result<(), EVSEError> {
+        self.black_box.record(BlackBoxEntry::SetContactor(on));
+        self.inner.set_contactor(on)
+    }
+
+    fn run_gfi_self_test(&mut self) -> Result<(), EVSEError> {
+        let started = Instant::now();
+        let result = self.inner.run_gfi_self_test();
+        self.last_self_test = Some((started.elapsed(), result.is_ok()));
+        self.black_box.record(BlackBoxEntry::GfiSelfTest { passed: result.is_ok() });
+        result
+    }
+
+    fn pilot_channel(&self) -> Receiver<PilotReading> {
+        self.inner.pilot_channel()
+    }
+
+    fn fault_channel(&self) -> Receiver<EVSEMachineInput> {
+        self.inner.fault_channel()
+    }
+
+    fn acquisition_health(&self) -> Option<AcquisitionHealth> {
+        self.inner.acquisition_health()
+    }
+
+    fn temperature_c(&self) -> Option<f64> {
+        self.inner.temperature_c()
+    }
+
+    fn mains_volts(&self) -> Option<f64> {
+        self.inner.mains_volts()
+    }
+
+    fn mains_frequency_hz(&self) -> Option<f64> {
+        self.inner.mains_frequency_hz()
+    }
+
+    fn current_amps(&self) -> Option<f64> {
+        self.inner.current_amps()
+    }
+
+    fn residual_milliamps(&self) -> Option<f64> {
+        self.inner.residual_milliamps()
+    }
+
+    fn connector_temperatures_c(&self) -> Vec<f64> {
+        self.inner.connector_temperatures_c()
+    }
+
+    fn set_machine_state(&mut self, state: EVSEMachineState) {
+        self.inner.set_machine_state(state)
+    }
+
+    fn phase_currents(&self) -> Option<[f64; 3]> {
+        self.inner.phase_currents()
+    }
+
+    fn line_currents(&self) -> Option<[f64; 2]> {
+        self.inner.line_currents()
+    }
+
+    fn pilot_read(&mut self, reading: &PilotReading) {
+        self.inner.pilot_read(reading)
+    }
+
+    fn discrepancies(&mut self) -> Vec<Discrepancy> {
+        self.inner.discrepancies()
+    }
+
+    fn control_contact(&self) -> Option<bool> {
+        self.inner.control_contact()
+    }
+
+    fn set_alarm(&mut self, on: bool) -> Result<(), EVSEError> {
+        self.inner.set_alarm(on)
+    }
+
+    fn set_ventilation(&mut self, on: bool) -> Result<(), EVSEError> {
+        self.inner.set_ventilation(on)
+    }
+
+    fn reserved_safe_state(&self) -> Option<Box<dyn SafeState>> {
+        self.inner.reserved_safe_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolling_window() {
+        let mut black_box = BlackBox::new(Duration::from_secs(30));
+        let start = Instant::now();
+        black_box.record_at(start, BlackBoxEntry::State(EVSEMachineState::Standby));
+        black_box.record_at(start + Duration::from_secs(20), BlackBoxEntry::Input(EVSEMachineInput::PilotIs9V));
+        black_box.record_at(start + Duration::from_secs(40), BlackBoxEntry::SetContactor(false));
+
+        let frozen = black_box.freeze(start + Duration::from_secs(40));
+        let entries: Vec<BlackBoxEntry> = frozen.iter().map(|frozen| frozen.entry.clone()).collect();
+        assert_eq!(
+            entries,
+            vec![
+                BlackBoxEntry::Input(EVSEMachineInput::PilotIs9V),
+                BlackBoxEntry::SetContactor(false)
+            ]
+        );
+        assert_eq!(frozen[0].age_ms, 20_000);
+        assert_eq!(frozen[1].age_ms, 0);
+        assert_eq!(black_box.initial_state, Some(EVSEMachineState::Standby));
+    }
+
+    #[test]
+    fn test_residual_peak() {
+        let mut black_box = BlackBox::default();
+        let start = Instant::now();
+        assert_eq!(black_box.residual_peak_at(start, Duration::from_secs(5)), None);
+        black_box.record_at(start, BlackBoxEntry::ResidualCurrent(25.0));
+        black_box.record_at(start + Duration::from_secs(4), BlackBoxEntry::ResidualCurrent(3.5));
+        black_box.record_at(start + Duration::from_secs(6), BlackBoxEntry::ResidualCurrent(2.0));
+        let at = start + Duration::from_secs(6);
+        assert_eq!(black_box.residual_peak_at(at, Duration::from_secs(5)), Some(3.5));
+        assert_eq!(black_box.residual_peak_at(at, Duration::from_secs(10)), Some(25.0));
+    }
+
+    #[test]
+    fn test_persist() {
+        let dir = std::env::temp_dir().join(format!("juicelib-blackbox-{}", std::process::id()));
+        let mut black_box = BlackBox::default();
+        black_box.record(BlackBoxEntry::Input(EVSEMachineInput::GFIInterrupted));
+        black_box.record(BlackBoxEntry::SetPilot(PilotSignal::ErrorMinus12));
+
+        let path = black_box.persist(&dir, EVSEMachineState::FailedStation).unwrap();
+        let record: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(record["fault"], "FailedStation");
+        assert_eq!(record["entries"][0]["entry"]["Input"], "GFIInterrupted");
+        assert_eq!(record["entries"][1]["entry"]["SetPilot"], "ErrorMinus12");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+// Thermal model of the breaker feeding the station. Continuous loads may
+// only use 80% of the breaker rating (NEC 625.41, IEC 60364-7-722), but a
+// breaker takes a while to heat up, so a cool breaker can carry its full
+// rating for a short time. The model tracks the heating of the breaker as
+// a first order low pass of the squared current and derates the offer to
+// the continuous limit once the equivalent current reaches it.
+
+// Share of the rating that may be drawn continuously.
+pub const DEFAULT_CONTINUOUS_FRACTION: f64 = 0.8;
+
+// Thermal time constant of a typical miniature circuit breaker.
+pub const DEFAULT_TIME_CONSTANT: Duration = Duration::from_secs(30 * 60);
+
+// The full rating is offered again once the breaker has cooled to this
+// share of the continuous limit, so the offer does not flip on every
+// update.
+const RECOVERY_FRACTION: f64 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreakerConfig {
+    pub rating_amps: f64,
+    pub continuous_fraction: f64,
+    pub time_constant: Duration,
+}
+
+impl BreakerConfig {
+    pub fn new(rating_amps: f64) -> Self {
+        Self {
+            rating_amps,
+            continuous_fraction: DEFAULT_CONTINUOUS_FRACTION,
+            time_constant: DEFAULT_TIME_CONSTANT,
+        }
+    }
+
+    pub fn continuous_amps(&self) -> f64 {
+        self.rating_amps * self.continuous_fraction
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+pub struct BreakerStatus {
+    pub rating_amps: f64,
+    pub continuous_amps: f64,
+    // The steady current that would heat the breaker as much as it is now.
+    pub equivalent_amps: f64,
+    // The highest offer the breaker allows at the moment.
+    pub allowed_amps: f64,
+    pub derated: bool,
+}
+
+pub struct BreakerModel {
+    config: BreakerConfig,
+    // Squared equivalent current, in A².
+    heat: f64,
+    derated: bool,
+}
+
+impl BreakerModel {
+    // Starts with a cold breaker.
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            heat: 0.0,
+            derated: false,
+        }
+    }
+
+    // Accounts for `amps` having flowed for `elapsed`.
+    pub fn update(&mut self, elapsed: Duration, amps: f64) {
+        let tau = self.config.time_constant.as_secs_f64();
+        let weight = if tau > 0.0 {
+            1.0 - (-elapsed.as_secs_f64() / tau).exp()
+        } else {
+            1.0
+        };
+        // An unknown current is taken as the full rating.
+        let amps = if amps.is_nan() { self.config.rating_amps } else { amps };
+        self.heat += (amps * amps - self.heat) * weight;
+
+        let equivalent = self.equivalent_amps();
+        let continuous = self.config.continuous_amps();
+        if equivalent >= continuous {
+            self.derated = true;
+        } else if equivalent < continuous * RECOVERY_FRACTION {
+            self.derated = false;
+        }
+    }
+
+    pub fn equivalent_amps(&self) -> f64 {
+        self.heat.sqrt()
+    }
+
+    pub fn allowed_amps(&self) -> f64 {
+        if self.derated {
+            self.config.continuous_amps()
+        } else {
+            self.config.rating_amps
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        BreakerStatus {
+            rating_amps: self.config.rating_amps,
+            continuous_amps: self.config.continuous_amps(),
+            equivalent_amps: self.equivalent_amps(),
+            allowed_amps: self.allowed_amps(),
+            derated: self.derated,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_short_excursion_at_rating() {
+        let mut model = BreakerModel::new(BreakerConfig::new(40.0));
+        assert_eq!(model.allowed_amps(), 40.0);
+
+        // About a time constant at the full rating is fine.
+        for _ in 0..25 {
+            model.update(MINUTE, 40.0);
+        }
+        assert!(!model.status().derated);
+        assert_eq!(model.allowed_amps(), 40.0);
+    }
+
+    #[test]
+    fn test_sustained_load_is_derated() {
+        let mut model = BreakerModel::new(BreakerConfig::new(40.0));
+        let mut minutes = 0;
+        while model.allowed_amps() == 40.0 {
+            model.update(MINUTE, 40.0);
+            minutes += 1;
+        }
+        assert!((30..=32).contains(&minutes));
+        assert_eq!(model.status().continuous_amps, 32.0);
+        assert_eq!(model.allowed_amps(), 32.0);
+
+        // Drawing the continuous limit keeps it derated.
+        for _ in 0..120 {
+            model.update(MINUTE, 32.0);
+        }
+        assert!(model.status().derated);
+
+        // Cooling down lifts the derating.
+        for _ in 0..30 {
+            model.update(MINUTE, 0.0);
+        }
+        assert_eq!(model.allowed_amps(), 40.0);
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let mut model = BreakerModel::new(BreakerConfig::new(40.0));
+        model.update(Duration::from_secs(3600 * 10), 33.0);
+        assert!(model.status().derated);
+        // 31A heats less than the continuous limit, but not enough less.
+        model.update(Duration::from_secs(3600 * 10), 31.0);
+        assert!(model.status().derated);
+        model.update(Duration::from_secs(3600 * 10), 28.0);
+        assert!(!model.status().derated);
+    }
+}
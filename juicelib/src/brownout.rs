@@ -0,0 +1,206 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+// Gradual brownout of what the control loop does not need. On a Pi Zero
+// the HTTP and metrics consumers can take enough CPU to delay the machine.
+// The loop measures how long it takes to handle each event and how many
+// pilot readings are waiting behind it; while that stays over budget the
+// station sheds one subsystem after the other, and brings them back one by
+// one once it has been within budget for a while:
+//
+//   1. the sample streams publish only every REDUCED_RATE-th reading
+//   2. the guest web pages answer 503
+//   3. the sample streams, which feed the metrics, stop
+//
+// The control interfaces are never shed.
+
+// Of the pilot readings, the streams publish one in this many while
+// reduced.
+pub const REDUCED_RATE: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BrownoutLevel {
+    Normal,
+    ReducedTelemetry,
+    NoWebUi,
+    NoMetrics,
+}
+
+impl BrownoutLevel {
+    const ALL: [BrownoutLevel; 4] = [
+        BrownoutLevel::Normal,
+        BrownoutLevel::ReducedTelemetry,
+        BrownoutLevel::NoWebUi,
+        BrownoutLevel::NoMetrics,
+    ];
+
+    fn from_index(index: u8) -> Self {
+        Self::ALL[(index as usize).min(Self::ALL.len() - 1)]
+    }
+
+    fn raised(self) -> Self {
+        Self::from_index(self as u8 + 1)
+    }
+
+    fn lowered(self) -> Self {
+        Self::from_index((self as u8).saturating_sub(1))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyBudget {
+    // Longest the loop may take to handle an event.
+    pub max_latency: Duration,
+    // Pilot readings that may wait while the loop is busy.
+    pub max_backlog: usize,
+    // Events over budget in a row before the next subsystem is shed.
+    pub violations: u32,
+    // Time within budget before the last subsystem shed comes back.
+    pub restore_after: Duration,
+}
+
+impl Default for LatencyBudget {
+    fn default() -> Self {
+        Self {
+            max_latency: Duration::from_millis(50),
+            max_backlog: 2,
+            violations: 3,
+            restore_after: Duration::from_secs(30),
+        }
+    }
+}
+
+// The level, shared with the subsystems that shed.
+#[derive(Debug, Clone, Default)]
+pub struct Brownout {
+    level: Arc<AtomicU8>,
+}
+
+impl Brownout {
+    pub fn level(&self) -> BrownoutLevel {
+        BrownoutLevel::from_index(self.level.load(Ordering::Relaxed))
+    }
+
+    fn set_level(&self, level: BrownoutLevel) {
+        self.level.store(level as u8, Ordering::Relaxed);
+    }
+
+    pub fn web_ui_enabled(&self) -> bool {
+        self.level() < BrownoutLevel::NoWebUi
+    }
+
+    // Whether the streams publish the `count`-th reading.
+    pub fn publishes(&self, count: u64) -> bool {
+        match self.level() {
+            BrownoutLevel::Normal => true,
+            BrownoutLevel::ReducedTelemetry | BrownoutLevel::NoWebUi => count.is_multiple_of(REDUCED_RATE),
+            BrownoutLevel::NoMetrics => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BrownoutStatus {
+    pub level: BrownoutLevel,
+    // Of the last event.
+    pub latency_ms: f64,
+    pub backlog: usize,
+}
+
+pub struct ResourceMonitor {
+    budget: LatencyBudget,
+    brownout: Brownout,
+    violations: u32,
+    within_budget_since: Option<Instant>,
+    last: (Duration, usize),
+}
+
+impl ResourceMonitor {
+    pub fn new(budget: LatencyBudget, brownout: Brownout) -> Self {
+        Self {
+            budget,
+            brownout,
+            violations: 0,
+            within_budget_since: None,
+            last: (Duration::ZERO, 0),
+        }
+    }
+
+    // Feeds how long an event took and the pilot readings waiting after
+    // it. Returns the new level if it changed.
+    pub fn record(&mut self, latency: Duration, backlog: usize, now: Instant) -> Option<BrownoutLevel> {
+        self.last = (latency, backlog);
+        let level = self.brownout.level();
+        let next = if latency > self.budget.max_latency || backlog > self.budget.max_backlog {
+            self.within_budget_since = None;
+            self.violations += 1;
+            if self.violations < self.budget.violations {
+                return None;
+            }
+            self.violations = 0;
+            level.raised()
+        } else {
+            self.violations = 0;
+            let since = *self.within_budget_since.get_or_insert(now);
+            if now.duration_since(since) < self.budget.restore_after {
+                return None;
+            }
+            // The next one has to wait for the whole time again.
+            self.within_budget_since = Some(now);
+            level.lowered()
+        };
+        (next != level).then(|| {
+            self.brownout.set_level(next);
+            next
+        })
+    }
+
+    pub fn status(&self) -> BrownoutStatus {
+        BrownoutStatus {
+            level: self.brownout.level(),
+            latency_ms: self.last.0.as_secs_f64() * 1000.0,
+            backlog: self.last.1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sheds_and_restores() {
+        let brownout = Brownout::default();
+        let mut monitor = ResourceMonitor::new(LatencyBudget::default(), brownout.clone());
+        let start = Instant::now();
+        let slow = Duration::from_millis(80);
+        let fast = Duration::from_millis(2);
+
+        assert_eq!(monitor.record(slow, 0, start), None);
+        assert_eq!(monitor.record(slow, 0, start), None);
+        assert_eq!(monitor.record(slow, 0, start), Some(BrownoutLevel::ReducedTelemetry));
+        assert!(brownout.publishes(10) && !brownout.publishes(11));
+        // A backlog counts as well.
+        for _ in 0..6 {
+            monitor.record(fast, 5, start);
+        }
+        assert_eq!(brownout.level(), BrownoutLevel::NoMetrics);
+        assert!(!brownout.web_ui_enabled() && !brownout.publishes(10));
+        assert_eq!(monitor.record(slow, 0, start), None);
+
+        // One level back per period within budget.
+        assert_eq!(monitor.record(fast, 0, start), None);
+        let later = start + Duration::from_secs(30);
+        assert_eq!(monitor.record(fast, 0, later), Some(BrownoutLevel::NoWebUi));
+        assert_eq!(monitor.record(fast, 0, later + Duration::from_secs(10)), None);
+        assert_eq!(
+            monitor.record(fast, 0, later + Duration::from_secs(30)),
+            Some(BrownoutLevel::ReducedTelemetry)
+        );
+        assert_eq!(monitor.status().backlog, 0);
+    }
+}
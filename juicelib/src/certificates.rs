@@ -0,0 +1,304 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+use serde::Serialize;
+use x509_parser::pem::Pem;
+
+use crate::audit::AuditLog;
+
+// The client certificate of the station for OCPP security profile 3, TLS
+// with client certificates. The station makes its own key and a signing
+// request for it; the CSMS has the request signed and sends the
+// certificate back (OCPP SignCertificate and CertificateSigned). The key
+// never leaves the station.
+//
+// Rotation works the same way: a new request makes a new pending key and
+// the certificate in use stays until the signed one for the new key is
+// installed. The previous certificate and key are kept next to them.
+//
+// There is no OCPP client in juiced yet; the requests and certificates go
+// through the control socket meanwhile.
+
+pub const DEFAULT_CERTIFICATE_DIR: &str = "/var/lib/juiced/certs";
+
+const KEY_FILE: &str = "client.key";
+const CERTIFICATE_FILE: &str = "client.pem";
+const PENDING_KEY_FILE: &str = "client.key.pending";
+// Readable by juiced only.
+const KEY_MODE: u32 = 0o600;
+
+// Alarms start this long before the certificate expires.
+pub const EXPIRY_WARNING: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+#[derive(Debug)]
+pub enum CertificateError {
+    Io(io::Error),
+    Generation(rcgen::Error),
+    // Not a PEM X.509 certificate.
+    Invalid,
+    // No request is pending.
+    NoPendingKey,
+    // The certificate is for another key than the pending one.
+    KeyMismatch,
+    Expired,
+}
+
+impl From<io::Error> for CertificateError {
+    fn from(error: io::Error) -> Self {
+        CertificateError::Io(error)
+    }
+}
+
+impl From<rcgen::Error> for CertificateError {
+    fn from(error: rcgen::Error) -> Self {
+        CertificateError::Generation(error)
+    }
+}
+
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CertificateError::Io(error) => write!(f, "{}", error),
+            CertificateError::Generation(error) => write!(f, "{}", error),
+            CertificateError::Invalid => write!(f, "not a PEM certificate"),
+            CertificateError::NoPendingKey => write!(f, "no signing request is pending"),
+            CertificateError::KeyMismatch => write!(f, "the certificate is not for the pending key"),
+            CertificateError::Expired => write!(f, "the certificate has expired"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub serial: String,
+    pub not_before: u64,
+    pub not_after: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpiryStatus {
+    Valid,
+    ExpiresSoon { days: u64 },
+    Expired,
+}
+
+impl CertificateInfo {
+    fn from_pem(pem: &Pem) -> Result<(Self, Vec<u8>), CertificateError> {
+        let certificate = pem.parse_x509().map_err(|_| CertificateError::Invalid)?;
+        let validity = certificate.validity();
+        let info = Self {
+            subject: certificate.subject().to_string(),
+            issuer: certificate.issuer().to_string(),
+            serial: certificate.raw_serial_as_string(),
+            not_before: validity.not_before.timestamp().max(0) as u64,
+            not_after: validity.not_after.timestamp().max(0) as u64,
+        };
+        Ok((info, certificate.public_key().raw.to_vec()))
+    }
+
+    pub fn expiry(&self, unix_now: u64) -> ExpiryStatus {
+        if self.not_after <= unix_now {
+            ExpiryStatus::Expired
+        } else if self.not_after - unix_now <= EXPIRY_WARNING.as_secs() {
+            ExpiryStatus::ExpiresSoon {
+                days: (self.not_after - unix_now) / (24 * 60 * 60),
+            }
+        } else {
+            ExpiryStatus::Valid
+        }
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// The first certificate of a chain, the station's own.
+fn first_certificate(chain: &str) -> Result<Pem, CertificateError> {
+    Pem::iter_from_buffer(chain.as_bytes())
+        .next()
+        .and_then(Result::ok)
+        .filter(|pem| pem.label == "CERTIFICATE")
+        .ok_or(CertificateError::Invalid)
+}
+
+fn write_key(path: &Path, pem: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(KEY_MODE)
+        .open(path)?;
+    file.write_all(pem.as_bytes())?;
+    file.sync_all()
+}
+
+#[derive(Debug, Clone)]
+pub struct CertificateStore {
+    dir: PathBuf,
+}
+
+impl CertificateStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    // For the TLS client.
+    pub fn key_path(&self) -> PathBuf {
+        self.dir.join(KEY_FILE)
+    }
+
+    pub fn certificate_path(&self) -> PathBuf {
+        self.dir.join(CERTIFICATE_FILE)
+    }
+
+    // Makes a new key and returns the PEM signing request for it. OCPP
+    // wants the serial number of the station as the common name and the
+    // CPO as the organization. Replaces a request that is still pending.
+    pub fn request(&self, serial_number: &str, organization: &str) -> Result<String, CertificateError> {
+        fs::create_dir_all(&self.dir)?;
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::default();
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CommonName, serial_number);
+        name.push(DnType::OrganizationName, organization);
+        params.distinguished_name = name;
+        let request = params.serialize_request(&key)?.pem()?;
+        write_key(&self.dir.join(PENDING_KEY_FILE), &key.serialize_pem())?;
+        Ok(request)
+    }
+
+    // Installs the signed certificate (chain) for the pending key, as sent
+    // with CertificateSigned. The certificate in use is kept as previous.
+    pub fn install(&self, chain: &str) -> Result<CertificateInfo, CertificateError> {
+        let pending_path = self.dir.join(PENDING_KEY_FILE);
+        let pending = match fs::read_to_string(&pending_path) {
+            Ok(pem) => KeyPair::from_pem(&pem)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Err(CertificateError::NoPendingKey),
+            Err(error) => return Err(error.into()),
+        };
+        let (info, public_key) = CertificateInfo::from_pem(&first_certificate(chain)?)?;
+        if public_key != pending.public_key_der() {
+            return Err(CertificateError::KeyMismatch);
+        }
+        if info.expiry(unix_now()) == ExpiryStatus::Expired {
+            return Err(CertificateError::Expired);
+        }
+
+        for path in [self.key_path(), self.certificate_path()] {
+            if path.exists() {
+                let mut previous = path.clone().into_os_string();
+                previous.push(".previous");
+                fs::rename(&path, previous)?;
+            }
+        }
+        fs::write(self.certificate_path(), chain)?;
+        fs::rename(pending_path, self.key_path())?;
+        Ok(info)
+    }
+
+    // The certificate in use, if any.
+    pub fn current(&self) -> Result<Option<CertificateInfo>, CertificateError> {
+        match fs::read_to_string(self.certificate_path()) {
+            Ok(chain) => Ok(Some(CertificateInfo::from_pem(&first_certificate(&chain)?)?.0)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    // Checks the certificate twice a day and records in the audit log when
+    // it is about to expire or has.
+    pub fn watch_expiry(self, audit_log: AuditLog) {
+        thread::spawn(move || loop {
+            let event = match self.current() {
+                Ok(Some(info)) => match info.expiry(unix_now()) {
+                    ExpiryStatus::Valid => None,
+                    ExpiryStatus::ExpiresSoon { days } => {
+                        Some(format!("client certificate expires in {} days, request a new one", days))
+                    }
+                    ExpiryStatus::Expired => Some("client certificate has expired".to_string()),
+                },
+                Ok(None) => None,
+                Err(error) => Some(format!("client certificate unreadable: {}", error)),
+            };
+            if let Some(event) = event {
+                if let Err(error) = audit_log.record(&event) {
+                    eprintln!("Failed to record \"{}\" in the audit log: {}", event, error);
+                }
+            }
+            thread::sleep(EXPIRY_CHECK_INTERVAL);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Signs the pending key itself; the chain only has to carry its key.
+    fn sign_pending(store: &CertificateStore, not_after_year: i32) -> String {
+        let key = KeyPair::from_pem(&fs::read_to_string(store.dir.join(PENDING_KEY_FILE)).unwrap()).unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.not_before = rcgen::date_time_ymd(2024, 1, 1);
+        params.not_after = rcgen::date_time_ymd(not_after_year, 1, 1);
+        params.distinguished_name.push(DnType::CommonName, "JD-0001");
+        params.self_signed(&key).unwrap().pem()
+    }
+
+    #[test]
+    fn test_request_and_install() {
+        let dir = std::env::temp_dir().join(format!("juicelib-certs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = CertificateStore::new(&dir);
+        assert!(matches!(store.install("x"), Err(CertificateError::NoPendingKey)));
+        assert_eq!(store.current().unwrap(), None);
+
+        let request = store.request("JD-0001", "Example CPO").unwrap();
+        assert!(request.starts_with("-----BEGIN CERTIFICATE REQUEST-----"));
+        assert!(matches!(store.install("not a certificate"), Err(CertificateError::Invalid)));
+        let expired = sign_pending(&store, 2025);
+        assert!(matches!(store.install(&expired), Err(CertificateError::Expired)));
+
+        let signed = sign_pending(&store, 2090);
+        let info = store.install(&signed).unwrap();
+        assert_eq!(info.subject, "CN=JD-0001");
+        assert_eq!(store.current().unwrap(), Some(info.clone()));
+        assert!(store.key_path().exists());
+
+        // Rotation: the old certificate is not for the new pending key.
+        store.request("JD-0001", "Example CPO").unwrap();
+        assert!(matches!(store.install(&signed), Err(CertificateError::KeyMismatch)));
+        store.install(&sign_pending(&store, 2091)).unwrap();
+        assert!(dir.join("client.pem.previous").exists());
+        assert!(dir.join("client.key.previous").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expiry() {
+        let day = 24 * 60 * 60;
+        let info = CertificateInfo {
+            subject: "CN=JD-0001".to_string(),
+            issuer: "CN=CA".to_string(),
+            serial: "01".to_string(),
+            not_before: 0,
+            not_after: 100 * day,
+        };
+        assert_eq!(info.expiry(50 * day), ExpiryStatus::Valid);
+        assert_eq!(info.expiry(80 * day), ExpiryStatus::ExpiresSoon { days: 20 });
+        assert_eq!(info.expiry(100 * day), ExpiryStatus::Expired);
+    }
+}
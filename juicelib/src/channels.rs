@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::connector_temp::pt1000_celsius;
+
+// Which ADC input carries which signal. The hat has one MCP3004 with the
+// pilot feedback on channel 0 and the current sense on channel 1; boards
+// with more sensors, or a second ADC on the other chip select, describe
+// their inputs in the site settings instead:
+//
+//   "adc_channels": {
+//     "pilot_cp": { "chip": 0, "channel": 0, "conversion": { "type": "pilot_feedback" } },
+//     "mains_v": { "chip": 1, "channel": 2, "conversion": { "type": "linear", "gain": 0.36, "offset": 0.0 } }
+//   }
+//
+// A chip is a chip select of SPI0. Every signal but the pilot is optional.
+
+// Chip selects of SPI0.
+pub const CHIPS: u8 = 2;
+// Inputs of an MCP3004.
+pub const CHANNELS: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Signal {
+    PilotCp,
+    CurrentL1,
+    CurrentL2,
+    CurrentL3,
+    MainsV,
+    ProximityPp,
+    Temperature,
+    // The output of the GFI CT, in mA.
+    ResidualCurrent,
+    // The PT1000 sensors at the power contacts of the socket, see
+    // connector_temp.rs.
+    ConnectorTemp1,
+    ConnectorTemp2,
+}
+
+// From the counts of a conversion, 0..=1023 or the mean of several, to the
+// unit of the signal.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Conversion {
+    // The pilot feedback of the hat: -12 V reads as 184 and +12 V as 932,
+    // linear in between.
+    PilotFeedback,
+    // The Hall sensor of the hat: 1.65 V at 0 A, 66 mV/A.
+    CurrentSense,
+    Linear { gain: f64, offset: f64 },
+    // A PT1000 to ground under a reference resistor from 3.3 V, in °C.
+    Pt1000 { reference_ohms: f64 },
+}
+
+impl Conversion {
+    pub fn apply(&self, counts: f32) -> f32 {
+        match *self {
+            Conversion::PilotFeedback => (counts - 184.0) * 24.0 / (932.0 - 184.0) - 12.0,
+            Conversion::CurrentSense => {
+                let voltage = counts * 3.3 / 1024.0;
+                (voltage - 1.65) / 0.066
+            }
+            Conversion::Linear { gain, offset } => (counts as f64 * gain + offset) as f32,
+            Conversion::Pt1000 { reference_ohms } => {
+                let counts = f64::from(counts);
+                pt1000_celsius(reference_ohms * counts / (1024.0 - counts)) as f32
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ChannelEntry {
+    pub chip: u8,
+    pub channel: u8,
+    pub conversion: Conversion,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChannelError {
+    NoPilot,
+    InvalidChip(Signal, u8),
+    InvalidChannel(Signal, u8),
+    // Two signals on the same input.
+    Shared(Signal, Signal),
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelError::NoPilot => write!(f, "no ADC channel for the pilot"),
+            ChannelError::InvalidChip(signal, chip) => write!(f, "{:?} on chip {}, which does not exist", signal, chip),
+            ChannelError::InvalidChannel(signal, channel) => {
+                write!(f, "{:?} on channel {}, which does not exist", signal, channel)
+            }
+            ChannelError::Shared(first, second) => write!(f, "{:?} and {:?} on the same ADC channel", first, second),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "BTreeMap<Signal, ChannelEntry>", into = "BTreeMap<Signal, ChannelEntry>")]
+pub struct ChannelRegistry {
+    entries: BTreeMap<Signal, ChannelEntry>,
+}
+
+impl ChannelRegistry {
+    pub fn new(entries: BTreeMap<Signal, ChannelEntry>) -> Result<Self, ChannelError> {
+        if !entries.contains_key(&Signal::PilotCp) {
+            return Err(ChannelError::NoPilot);
+        }
+        let mut inputs: BTreeMap<(u8, u8), Signal> = BTreeMap::new();
+        for (&signal, entry) in &entries {
+            if entry.chip >= CHIPS {
+                return Err(ChannelError::InvalidChip(signal, entry.chip));
+            }
+            if entry.channel >= CHANNELS {
+                return Err(ChannelError::InvalidChannel(signal, entry.channel));
+            }
+            if let Some(&other) = inputs.get(&(entry.chip, entry.channel)) {
+                return Err(ChannelError::Shared(other, signal));
+            }
+            inputs.insert((entry.chip, entry.channel), signal);
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn get(&self, signal: Signal) -> Option<ChannelEntry> {
+        self.entries.get(&signal).copied()
+    }
+
+    // Always there.
+    pub fn pilot(&self) -> ChannelEntry {
+        self.entries[&Signal::PilotCp]
+    }
+
+    // The chips any signal is on.
+    pub fn chips(&self) -> Vec<u8> {
+        let mut chips: Vec<u8> = self.entries.values().map(|entry| entry.chip).collect();
+        chips.sort();
+        chips.dedup();
+        chips
+    }
+}
+
+impl Default for ChannelRegistry {
+    // The hat.
+    fn default() -> Self {
+        let entry = |channel, conversion| ChannelEntry {
+            chip: 0,
+            channel,
+            conversion,
+        };
+        Self {
+            entries: BTreeMap::from([
+                (Signal::PilotCp, entry(0, Conversion::PilotFeedback)),
+                (Signal::CurrentL1, entry(1, Conversion::CurrentSense)),
+            ]),
+        }
+    }
+}
+
+impl TryFrom<BTreeMap<Signal, ChannelEntry>> for ChannelRegistry {
+    type Error = ChannelError;
+
+    fn try_from(entries: BTreeMap<Signal, ChannelEntry>) -> Result<Self, ChannelError> {
+        Self::new(entries)
+    }
+}
+
+impl From<ChannelRegistry> for BTreeMap<Signal, ChannelEntry> {
+    fn from(registry: ChannelRegistry) -> Self {
+        registry.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_from_settings() {
+        let json = r#"{
+            "pilot_cp": { "chip": 0, "channel": 0, "conversion": { "type": "pilot_feedback" } },
+            "current_l1": { "chip": 0, "channel": 1, "conversion": { "type": "current_sense" } },
+            "mains_v": { "chip": 1, "channel": 0, "conversion": { "type": "linear", "gain": 0.5, "offset": -10.0 } }
+        }"#;
+        let registry: ChannelRegistry = serde_json::from_str(json).unwrap();
+        assert_eq!(registry.chips(), vec![0, 1]);
+        let mains = registry.get(Signal::MainsV).unwrap();
+        assert_eq!(mains.conversion.apply(500.0), 240.0);
+        assert_eq!(registry.get(Signal::Temperature), None);
+        let pt1000 = Conversion::Pt1000 { reference_ohms: 1000.0 };
+        assert!((pt1000.apply(512.0)).abs() < 0.01);
+        assert_eq!(
+            serde_json::from_str::<ChannelRegistry>(&serde_json::to_string(&registry).unwrap()).unwrap(),
+            registry
+        );
+    }
+
+    #[test]
+    fn test_invalid_registries() {
+        let entry = |chip, channel| ChannelEntry {
+            chip,
+            channel,
+            conversion: Conversion::PilotFeedback,
+        };
+        let registry = |entries: &[(Signal, ChannelEntry)]| ChannelRegistry::new(entries.iter().copied().collect());
+        assert_eq!(registry(&[(Signal::MainsV, entry(0, 0))]), Err(ChannelError::NoPilot));
+        assert_eq!(
+            registry(&[(Signal::PilotCp, entry(2, 0))]),
+            Err(ChannelError::InvalidChip(Signal::PilotCp, 2))
+        );
+        assert_eq!(
+            registry(&[(Signal::PilotCp, entry(0, 4))]),
+            Err(ChannelError::InvalidChannel(Signal::PilotCp, 4))
+        );
+        assert_eq!(
+            registry(&[(Signal::PilotCp, entry(0, 0)), (Signal::Temperature, entry(0, 0))]),
+            Err(ChannelError::Shared(Signal::PilotCp, Signal::Temperature))
+        );
+        assert!(serde_json::from_str::<ChannelRegistry>("{}").is_err());
+    }
+}
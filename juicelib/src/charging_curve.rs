@@ -0,0 +1,114 @@
+use serde::Serialize;
+
+// The charging curve of a session: the power drawn over time, at one point
+// a minute. Each point is the mean of the readings of its minute, so the
+// taper towards a full battery shows, as does a vehicle that never draws
+// what it is offered. The curve of the running session lives in memory and
+// goes into the store when the vehicle is unplugged.
+//
+// The power is reckoned from the current of L1 and the mains voltage, or
+// the offer and the nominal voltage without the sensors, times the phases
+// in use. Until the phases are detected, all three are assumed.
+
+pub const RESOLUTION_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CurvePoint {
+    // Minutes since the start of the charge.
+    pub minute: u32,
+    // The start of the minute.
+    pub unix_time: u64,
+    pub power_w: f64,
+    pub amps: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChargingCurve {
+    started_at: Option<u64>,
+    points: Vec<CurvePoint>,
+    // The readings of the last point so far.
+    readings: u32,
+}
+
+impl ChargingCurve {
+    pub fn add(&mut self, unix_time: u64, power_w: f64, amps: f64) {
+        let started_at = *self.started_at.get_or_insert(unix_time);
+        let minute = (unix_time.saturating_sub(started_at) / RESOLUTION_SECS) as u32;
+        match self.points.last_mut() {
+            Some(point) if point.minute == minute => {
+                self.readings += 1;
+                let n = self.readings as f64;
+                point.power_w += (power_w - point.power_w) / n;
+                point.amps += (amps - point.amps) / n;
+            }
+            _ => {
+                self.readings = 1;
+                self.points.push(CurvePoint {
+                    minute,
+                    unix_time: started_at + minute as u64 * RESOLUTION_SECS,
+                    power_w,
+                    amps,
+                });
+            }
+        }
+    }
+
+    // Minutes without readings, e.g. while the vehicle pauses, have no
+    // point.
+    pub fn points(&self) -> &[CurvePoint] {
+        &self.points
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+}
+
+pub fn curve_csv(points: &[CurvePoint]) -> String {
+    let mut csv = String::from("minute,unix_time,power_w,amps\n");
+    for point in points {
+        csv.push_str(&format!(
+            "{},{},{:.0},{:.2}\n",
+            point.minute, point.unix_time, point.power_w, point.amps
+        ));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_point_a_minute() {
+        let mut curve = ChargingCurve::default();
+        assert!(curve.is_empty());
+        let start = 1_700_000_000;
+        curve.add(start, 7000.0, 10.0);
+        curve.add(start + 30, 8000.0, 12.0);
+        curve.add(start + 59, 9000.0, 14.0);
+        // A pause leaves a gap.
+        curve.add(start + 185, 4000.0, 6.0);
+        assert_eq!(
+            curve.points(),
+            [
+                CurvePoint {
+                    minute: 0,
+                    unix_time: start,
+                    power_w: 8000.0,
+                    amps: 12.0
+                },
+                CurvePoint {
+                    minute: 3,
+                    unix_time: start + 180,
+                    power_w: 4000.0,
+                    amps: 6.0
+                },
+            ]
+        );
+        assert_eq!(
+            curve_csv(curve.points()),
+            "minute,unix_time,power_w,amps\n0,1700000000,8000,12.00\n3,1700000180,4000,6.00\n"
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+
+// Where the machine and its helpers take the time from. The machine loop,
+// the vehicle floors, the grace window and the error recovery, the breaker
+// model and the session accounting all read it through a Clock, so tests
+// can run them on a MockClock and move time forward instead of sleeping:
+//
+// let clock = MockClock::new();
+// let options = MachineOptions { clock: Some(Arc::new(clock.clone())), .. };
+// ...
+// clock.advance(Duration::from_secs(61));
+//
+// The machine only looks at the clock when an input comes in, so a test
+// sends one after advancing it. The hardware side (pilot sampling, read
+// back, the fault watch) keeps to the real time.
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn system_time(&self) -> SystemTime;
+
+    fn sleep(&self, duration: Duration);
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+
+    fn unix_now(&self) -> u64 {
+        self.system_time().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+    }
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        DateTime::from(self.system_time())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+pub fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+// Stands still until it is moved forward. Clones share the time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    started_at: Instant,
+    started_at_system: SystemTime,
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::at(SystemTime::now())
+    }
+
+    // Starting at the given wall clock time.
+    pub fn at(system_time: SystemTime) -> Self {
+        Self {
+            started_at: Instant::now(),
+            started_at_system: system_time,
+            offset: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.started_at + *self.offset.lock().unwrap()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.started_at_system + *self.offset.lock().unwrap()
+    }
+
+    // Moves the time on instead of waiting.
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::at(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let shared = clock.clone();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        shared.advance(Duration::from_secs(90));
+        clock.sleep(Duration::from_secs(10));
+        assert_eq!(clock.elapsed(start), Duration::from_secs(100));
+        assert_eq!(shared.unix_now(), 1_700_000_100);
+        assert_eq!(clock.utc_now().timestamp(), 1_700_000_100);
+    }
+}
@@ -0,0 +1,189 @@
+use std::fmt;
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+// Estimates when a charge is about to complete. Most vehicles draw close to
+// the offered current until the battery is nearly full and then taper the
+// current off. A current that stays below a fraction of the offer for a
+// while is taken as the start of that taper; how fast it keeps dropping
+// gives the time left until the vehicle is done.
+
+// The measured current has to stay below `fraction` of the offer for
+// `sustain` before the threshold counts as reached.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TaperThreshold {
+    pub fraction: f64,
+    pub sustain: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompletionConfig {
+    pub approaching_full: TaperThreshold,
+    pub full: TaperThreshold,
+}
+
+impl Default for CompletionConfig {
+    fn default() -> Self {
+        Self {
+            approaching_full: TaperThreshold {
+                fraction: 0.7,
+                sustain: Duration::from_secs(120),
+            },
+            full: TaperThreshold {
+                fraction: 0.1,
+                sustain: Duration::from_secs(60),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+pub enum CompletionEstimate {
+    // The vehicle draws (close to) what is offered.
+    Bulk,
+    // The current tapers off. The ETA is missing until the current has
+    // dropped measurably.
+    ApproachingFull { eta_secs: Option<u64> },
+    Full,
+}
+
+impl fmt::Display for CompletionEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompletionEstimate::Bulk => write!(f, "Charging"),
+            CompletionEstimate::ApproachingFull { eta_secs: None } => write!(f, "Approaching full"),
+            CompletionEstimate::ApproachingFull { eta_secs: Some(secs) } => {
+                write!(f, "Approaching full, about {} min left", secs.div_ceil(60))
+            }
+            CompletionEstimate::Full => write!(f, "Full"),
+        }
+    }
+}
+
+pub struct CompletionEstimator {
+    config: CompletionConfig,
+    // Session time and current when the current first dropped below the
+    // approaching full threshold.
+    taper_start: Option<(Duration, f64)>,
+    full_since: Option<Duration>,
+}
+
+impl CompletionEstimator {
+    pub fn new(config: CompletionConfig) -> Self {
+        Self {
+            config,
+            taper_start: None,
+            full_since: None,
+        }
+    }
+
+    // Feeds one current measurement. `elapsed` is the time since the start
+    // of the session and has to increase from call to call.
+    pub fn update(&mut self, elapsed: Duration, measured: f64, offered: f64) -> CompletionEstimate {
+        if offered <= 0.0 {
+            self.reset();
+            return CompletionEstimate::Bulk;
+        }
+        let ratio = measured / offered;
+
+        if ratio >= self.config.approaching_full.fraction {
+            self.reset();
+            return CompletionEstimate::Bulk;
+        }
+        let (taper_time, taper_current) = *self.taper_start.get_or_insert((elapsed, measured));
+
+        if ratio < self.config.full.fraction {
+            let full_since = *self.full_since.get_or_insert(elapsed);
+            if elapsed.saturating_sub(full_since) >= self.config.full.sustain {
+                return CompletionEstimate::Full;
+            }
+        } else {
+            self.full_since = None;
+        }
+
+        if elapsed.saturating_sub(taper_time) < self.config.approaching_full.sustain {
+            return CompletionEstimate::Bulk;
+        }
+
+        // Extrapolates the drop since the start of the taper down to the
+        // full threshold.
+        let taper_secs = elapsed.saturating_sub(taper_time).as_secs_f64();
+        let rate = (taper_current - measured) / taper_secs;
+        let eta_secs = if rate > 0.0 {
+            let remaining = (measured - self.config.full.fraction * offered).max(0.0);
+            Some((remaining / rate).round() as u64 + self.config.full.sustain.as_secs())
+        } else {
+            None
+        };
+        CompletionEstimate::ApproachingFull { eta_secs }
+    }
+
+    // Starts over, e.g. for a new session.
+    pub fn reset(&mut self) {
+        self.taper_start = None;
+        self.full_since = None;
+    }
+}
+
+impl Default for CompletionEstimator {
+    fn default() -> Self {
+        Self::new(CompletionConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_bulk_charging() {
+        let mut estimator = CompletionEstimator::default();
+        for t in 0..600 {
+            assert_eq!(estimator.update(secs(t), 31.5, 32.0), CompletionEstimate::Bulk);
+        }
+    }
+
+    #[test]
+    fn test_short_dip_is_ignored() {
+        let mut estimator = CompletionEstimator::default();
+        assert_eq!(estimator.update(secs(0), 10.0, 32.0), CompletionEstimate::Bulk);
+        assert_eq!(estimator.update(secs(60), 10.0, 32.0), CompletionEstimate::Bulk);
+        assert_eq!(estimator.update(secs(90), 30.0, 32.0), CompletionEstimate::Bulk);
+        assert_eq!(estimator.update(secs(200), 10.0, 32.0), CompletionEstimate::Bulk);
+    }
+
+    #[test]
+    fn test_taper_gives_eta_and_full() {
+        let mut estimator = CompletionEstimator::default();
+        // Drops by 0.01 A per second from 20 A.
+        let current = |t: u64| 20.0 - 0.01 * t as f64;
+        assert_eq!(estimator.update(secs(0), current(0), 32.0), CompletionEstimate::Bulk);
+
+        // 18.8 A at 120 s, 15.6 A to go down to 3.2 A at 0.01 A/s, plus the
+        // 60 s the full threshold has to be sustained.
+        assert_eq!(
+            estimator.update(secs(120), current(120), 32.0),
+            CompletionEstimate::ApproachingFull { eta_secs: Some(1620) }
+        );
+
+        let estimate = estimator.update(secs(1700), 3.0, 32.0);
+        assert!(matches!(estimate, CompletionEstimate::ApproachingFull { .. }));
+        assert_eq!(estimator.update(secs(1760), 3.0, 32.0), CompletionEstimate::Full);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(CompletionEstimate::Bulk.to_string(), "Charging");
+        assert_eq!(
+            CompletionEstimate::ApproachingFull { eta_secs: Some(61) }.to_string(),
+            "Approaching full, about 2 min left"
+        );
+        assert_eq!(CompletionEstimate::Full.to_string(), "Full");
+    }
+}
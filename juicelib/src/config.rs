@@ -0,0 +1,201 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::peripherals::{GpioPins, PeripheralsError};
+use crate::pilot::is_valid_max_current;
+use crate::power_quality::{Band, PowerQualityConfig};
+
+// What differs between the boards juiced runs on, read at startup from a
+// TOML file the installer writes by hand:
+//
+//   max_current = 16.0
+//   mains_frequency_hz = 50.0
+//   ventilation = true
+//
+//   [gpio]
+//   power = 5
+//   control_contact = 26
+//   ventilation = 19
+//
+//   [spi]
+//   bus = 1
+//
+// Anything left out keeps the EVSE Pi Hat's value, and a station without
+// the file runs as a hat. The site settings (see provisioning.rs) are what
+// the operator sets up for the site; the offer is the lower of the two
+// maxima. The JUICED_* environment variables override the file.
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/juiced/config.toml";
+
+// The highest GPIO of the Pi's header.
+const MAX_GPIO: u8 = 27;
+
+// Bus and clock of the ADC, chip 0 on chip select 0 and chip 1 on 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpiConfig {
+    pub bus: u8,
+    pub clock_hz: u32,
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        Self {
+            bus: 0,
+            clock_hz: 1_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // The most the board and its wiring carry.
+    pub max_current: f64,
+    pub mains_frequency_hz: f64,
+    // The installation is ventilated for vehicles that ask for it (J1772
+    // state D), by a fan on gpio.ventilation or by the building.
+    pub ventilation: bool,
+    pub gpio: GpioPins,
+    pub spi: SpiConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_current: 32.0,
+            mains_frequency_hz: 60.0,
+            ventilation: false,
+            gpio: GpioPins::default(),
+            spi: SpiConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Toml(toml::de::Error),
+    Invalid(&'static str),
+    Pins(PeripheralsError),
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(error: io::Error) -> Self {
+        ConfigError::Io(error)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(error: toml::de::Error) -> Self {
+        ConfigError::Toml(error)
+    }
+}
+
+impl From<PeripheralsError> for ConfigError {
+    fn from(error: PeripheralsError) -> Self {
+        ConfigError::Pins(error)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "{}", error),
+            ConfigError::Toml(error) => write!(f, "config does not parse: {}", error),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+            ConfigError::Pins(PeripheralsError::PinConflict(pin)) => {
+                write!(f, "invalid config: GPIO {} is given for two lines", pin)
+            }
+            ConfigError::Pins(error) => write!(f, "invalid config: {:?}", error),
+        }
+    }
+}
+
+impl Config {
+    // A missing file is the hat's config.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let config: Config = toml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // J1772 offers 6 A to 80 A.
+        if !is_valid_max_current(self.max_current) {
+            return Err(ConfigError::Invalid("max_current must be between 6 and 80 A"));
+        }
+        if !(45.0..=65.0).contains(&self.mains_frequency_hz) {
+            return Err(ConfigError::Invalid("mains_frequency_hz must be between 45 and 65 Hz"));
+        }
+        let pins = self.gpio;
+        let optional = [pins.power_good, pins.tamper, pins.alarm, pins.control_contact, pins.ventilation];
+        let fixed = [pins.power_watchdog, pins.power, pins.gfi_status, pins.relay_test, pins.gfi_test, pins.gfi_reset];
+        if fixed.into_iter().chain(optional.into_iter().flatten()).any(|pin| pin > MAX_GPIO) {
+            return Err(ConfigError::Invalid("GPIO numbers go up to 27"));
+        }
+        pins.validate()?;
+        if self.spi.bus > 1 {
+            return Err(ConfigError::Invalid("the ADC is on SPI bus 0 or 1"));
+        }
+        if self.spi.clock_hz == 0 || self.spi.clock_hz > 3_600_000 {
+            return Err(ConfigError::Invalid("the MCP3004/3008 clocks at up to 3.6 MHz"));
+        }
+        Ok(())
+    }
+
+    // The default power quality bands around the mains frequency.
+    pub fn power_quality(&self) -> PowerQualityConfig {
+        let hz = self.mains_frequency_hz;
+        PowerQualityConfig {
+            frequency: Band {
+                low: hz - 0.5,
+                high: hz + 0.5,
+            },
+            ..PowerQualityConfig::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_validate() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert_eq!(Config::load(Path::new("/nonexistent/config.toml")).unwrap(), Config::default());
+
+        let config = Config::parse(
+            "max_current = 16.0\nmains_frequency_hz = 50.0\nventilation = true\n[gpio]\npower = 5\nventilation = 19\n[spi]\nbus = 1\n",
+        )
+        .unwrap();
+        assert_eq!(config.max_current, 16.0);
+        assert_eq!((config.gpio.power, config.gpio.gfi_status), (5, 22));
+        assert!(config.ventilation);
+        assert_eq!(config.gpio.ventilation, Some(19));
+        assert_eq!(config.spi, SpiConfig { bus: 1, clock_hz: 1_000_000 });
+        assert_eq!(config.power_quality().frequency, Band { low: 49.5, high: 50.5 });
+
+        assert!(matches!(Config::parse("max_current = 100.0"), Err(ConfigError::Invalid(_))));
+        assert!(matches!(
+            Config::parse("[gpio]\ntamper = 17"),
+            Err(ConfigError::Pins(PeripheralsError::PinConflict(17)))
+        ));
+        assert!(matches!(Config::parse("[spi]\nbus = 2"), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::parse("max_curent = 16.0"), Err(ConfigError::Toml(_))));
+    }
+}
@@ -0,0 +1,165 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::provisioning::to_hex;
+
+// Config files that survive power losses and a failing SD card. A file is
+// written to a temporary file and renamed over the old one, so it is
+// either the old or the new version, never a mix. A checksum trailer
+// catches corruption of the file itself later on. Before it is replaced,
+// a file that still checks out is kept as the last known good copy; when
+// the file is corrupt on the next start, that copy is used and put back
+// instead of the station starting without its settings.
+//
+// Files without a checksum, written by hand or by an older version, are
+// taken as they are.
+
+const CHECKSUM_PREFIX: &str = "checksum=";
+pub const LAST_GOOD_SUFFIX: &str = ".last-good";
+pub const CORRUPT_SUFFIX: &str = ".corrupt";
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn checksum(body: &str) -> String {
+    to_hex(&Sha256::digest(body.as_bytes()))
+}
+
+fn encode(contents: &str) -> String {
+    let mut body = contents.to_string();
+    if !body.ends_with('\n') {
+        body.push('\n');
+    }
+    let checksum = checksum(&body);
+    format!("{}{}{}\n", body, CHECKSUM_PREFIX, checksum)
+}
+
+// The contents without the trailer. None if the checksum does not match.
+fn decode(text: &str) -> Option<&str> {
+    let trailer_at = match text.rfind(&format!("\n{}", CHECKSUM_PREFIX)) {
+        Some(at) => at + 1,
+        None if text.starts_with(CHECKSUM_PREFIX) => 0,
+        None => return Some(text),
+    };
+    let (body, trailer) = text.split_at(trailer_at);
+    (trailer[CHECKSUM_PREFIX.len()..].trim() == checksum(body)).then_some(body)
+}
+
+fn replace(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp = with_suffix(path, ".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(encode(contents).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Reads a file and checks its checksum. A corrupt file is an InvalidData
+// error.
+pub fn read(path: &Path) -> io::Result<String> {
+    let text = fs::read_to_string(path)?;
+    decode(&text)
+        .map(str::to_string)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} is corrupt", path.display())))
+}
+
+// Replaces the file, keeping the old one as the last known good copy if
+// it checks out.
+pub fn write(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    if let Ok(previous) = read(path) {
+        replace(&with_suffix(path, LAST_GOOD_SUFFIX), &previous)?;
+    }
+    replace(path, contents)
+}
+
+#[derive(Debug)]
+pub struct Loaded<T> {
+    pub value: T,
+    // Set when the last known good copy had to be used.
+    pub warning: Option<String>,
+}
+
+// Reads and parses a file. If it is corrupt or does not parse, the last
+// known good copy is used and put in its place; the corrupt file is kept
+// next to it for a look. A missing file is an error as before.
+pub fn load<T, E: From<io::Error>>(path: &Path, parse: impl Fn(&str) -> Result<T, E>) -> Result<Loaded<T>, E> {
+    let error = match read(path) {
+        Ok(text) => match parse(&text) {
+            Ok(value) => return Ok(Loaded { value, warning: None }),
+            Err(error) => error,
+        },
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Err(error.into()),
+        Err(error) => error.into(),
+    };
+
+    let last_good = with_suffix(path, LAST_GOOD_SUFFIX);
+    let Some((text, value)) = read(&last_good).ok().and_then(|text| parse(&text).ok().map(|value| (text, value)))
+    else {
+        return Err(error);
+    };
+    fs::rename(path, with_suffix(path, CORRUPT_SUFFIX))?;
+    replace(path, &text)?;
+    Ok(Loaded {
+        value,
+        warning: Some(format!("{} was corrupt, restored the last known good copy", path.display())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<u32, io::Error> {
+        text.trim().parse().map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+    }
+
+    #[test]
+    fn test_checksum_trailer() {
+        let text = encode("{\"a\": 1}");
+        assert!(text.starts_with("{\"a\": 1}\nchecksum="));
+        assert_eq!(decode(&text), Some("{\"a\": 1}\n"));
+        assert_eq!(decode(&text.replace('1', "2")), None);
+        // Without a trailer, as written by hand.
+        assert_eq!(decode("{}"), Some("{}"));
+    }
+
+    #[test]
+    fn test_falls_back_to_last_good() {
+        let dir = std::env::temp_dir().join(format!("juicelib-config-file-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("settings");
+        assert_eq!(load(&path, parse).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        write(&path, "1").unwrap();
+        write(&path, "2").unwrap();
+        let loaded = load(&path, parse).unwrap();
+        assert_eq!((loaded.value, loaded.warning), (2, None));
+
+        // A flipped bit.
+        fs::write(&path, fs::read_to_string(&path).unwrap().replace('2', "3")).unwrap();
+        let loaded = load(&path, parse).unwrap();
+        assert_eq!(loaded.value, 1);
+        assert!(loaded.warning.is_some());
+        assert!(with_suffix(&path, CORRUPT_SUFFIX).is_file());
+        assert_eq!(load(&path, parse).unwrap().warning, None);
+
+        // Checks out, but does not parse, and no good copy is left of it.
+        fs::write(&path, "x").unwrap();
+        fs::remove_file(with_suffix(&path, LAST_GOOD_SUFFIX)).unwrap();
+        assert_eq!(load(&path, parse).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
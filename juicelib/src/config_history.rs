@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use chrono::DateTime;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::certificates::unix_now;
+use crate::provisioning::{save_settings, to_hex, ProvisioningError, SiteSettings};
+use crate::store::{Store, StoreError};
+
+// Snapshots of the site settings as they were applied, for the first
+// support question: what changed before it broke. A start that applies
+// settings other than those of the last snapshot takes a new one, with the
+// SHA-256 of the settings and a full copy. Two snapshots are compared field
+// by field, and an older one can be written back as the site settings,
+// which applies it on the next start:
+//
+//   juiced config list
+//   juiced config diff <from id> <to id>
+//   juiced config rollback <id>
+
+#[derive(Debug)]
+pub enum ConfigHistoryError {
+    Store(StoreError),
+    Json(serde_json::Error),
+    Provisioning(ProvisioningError),
+    NoSuchSnapshot(i64),
+}
+
+impl From<StoreError> for ConfigHistoryError {
+    fn from(error: StoreError) -> Self {
+        ConfigHistoryError::Store(error)
+    }
+}
+
+impl From<serde_json::Error> for ConfigHistoryError {
+    fn from(error: serde_json::Error) -> Self {
+        ConfigHistoryError::Json(error)
+    }
+}
+
+impl From<ProvisioningError> for ConfigHistoryError {
+    fn from(error: ProvisioningError) -> Self {
+        ConfigHistoryError::Provisioning(error)
+    }
+}
+
+impl fmt::Display for ConfigHistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigHistoryError::Store(error) => write!(f, "{}", error),
+            ConfigHistoryError::Json(error) => write!(f, "snapshot does not parse: {}", error),
+            ConfigHistoryError::Provisioning(error) => write!(f, "settings not written: {:?}", error),
+            ConfigHistoryError::NoSuchSnapshot(id) => write!(f, "no snapshot #{}", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigSnapshot {
+    pub id: i64,
+    pub applied_at: u64,
+    pub hash: String,
+    // The settings as JSON.
+    pub settings: String,
+}
+
+impl fmt::Display for ConfigSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let applied_at = DateTime::from_timestamp(self.applied_at as i64, 0).unwrap_or_default();
+        write!(f, "#{} applied {}, sha256 {}", self.id, applied_at.to_rfc3339(), self.hash)
+    }
+}
+
+// A field that differs between two snapshots. A field missing from one of
+// them, e.g. one added by a later version, is None there.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SettingChange {
+    // Nested fields are joined with dots, e.g. "breaker_model.time_constant".
+    pub field: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+impl fmt::Display for SettingChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: &Option<Value>| value.as_ref().map_or("(unset)".to_string(), Value::to_string);
+        write!(f, "{}: {} -> {}", self.field, show(&self.before), show(&self.after))
+    }
+}
+
+// Takes a snapshot of the settings being applied, unless the last one has
+// the same. Returns the new snapshot.
+pub fn record_applied(store_path: &Path, settings: &SiteSettings) -> Result<Option<ConfigSnapshot>, ConfigHistoryError> {
+    let json = serde_json::to_string(settings)?;
+    let hash = to_hex(&Sha256::digest(json.as_bytes()));
+    let store = Store::open(store_path)?;
+    if store.config_snapshots()?.last().is_some_and(|last| last.hash == hash) {
+        return Ok(None);
+    }
+    let applied_at = unix_now();
+    let id = store.save_config_snapshot(applied_at, &hash, &json)?;
+    Ok(Some(ConfigSnapshot {
+        id,
+        applied_at,
+        hash,
+        settings: json,
+    }))
+}
+
+// Oldest first.
+pub fn snapshots(store_path: &Path) -> Result<Vec<ConfigSnapshot>, ConfigHistoryError> {
+    Ok(Store::open(store_path)?.config_snapshots()?)
+}
+
+fn snapshot(store_path: &Path, id: i64) -> Result<ConfigSnapshot, ConfigHistoryError> {
+    snapshots(store_path)?
+        .into_iter()
+        .find(|snapshot| snapshot.id == id)
+        .ok_or(ConfigHistoryError::NoSuchSnapshot(id))
+}
+
+fn flatten(prefix: &str, value: &Value, fields: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(object) if !object.is_empty() => {
+            for (key, value) in object {
+                let field = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten(&field, value, fields);
+            }
+        }
+        Value::Null => {}
+        _ => {
+            fields.insert(prefix.to_string(), value.clone());
+        }
+    }
+}
+
+// The fields that differ, in field order. A null field counts as unset.
+pub fn diff(before: &Value, after: &Value) -> Vec<SettingChange> {
+    let (mut before_fields, mut after_fields) = (BTreeMap::new(), BTreeMap::new());
+    flatten("", before, &mut before_fields);
+    flatten("", after, &mut after_fields);
+    let mut fields: Vec<&String> = before_fields.keys().chain(after_fields.keys()).collect();
+    fields.sort();
+    fields.dedup();
+    fields
+        .into_iter()
+        .filter(|field| before_fields.get(*field) != after_fields.get(*field))
+        .map(|field| SettingChange {
+            field: field.clone(),
+            before: before_fields.get(field).cloned(),
+            after: after_fields.get(field).cloned(),
+        })
+        .collect()
+}
+
+pub fn diff_snapshots(store_path: &Path, from: i64, to: i64) -> Result<Vec<SettingChange>, ConfigHistoryError> {
+    let before: Value = serde_json::from_str(&snapshot(store_path, from)?.settings)?;
+    let after: Value = serde_json::from_str(&snapshot(store_path, to)?.settings)?;
+    Ok(diff(&before, &after))
+}
+
+// Writes the settings of a snapshot back as the site settings. They are
+// validated first, and apply on the next start.
+pub fn roll_back(store_path: &Path, config_dir: &Path, id: i64) -> Result<SiteSettings, ConfigHistoryError> {
+    let settings: SiteSettings = serde_json::from_str(&snapshot(store_path, id)?.settings)?;
+    save_settings(config_dir, &settings)?;
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provisioning::load_settings;
+
+    #[test]
+    fn test_snapshot_diff_and_roll_back() {
+        let dir = std::env::temp_dir().join(format!("juicelib-config-history-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store_path = dir.join("juiced.db");
+        std::fs::create_dir_all(&dir).unwrap();
+        let settings: SiteSettings = serde_json::from_str(
+            r#"{"serial_number": "JD-0001", "site_name": "Garage", "breaker_amps": 40, "max_current": 32}"#,
+        )
+        .unwrap();
+
+        let first = record_applied(&store_path, &settings).unwrap().unwrap();
+        // A restart with the same settings.
+        assert_eq!(record_applied(&store_path, &settings).unwrap(), None);
+        let mut changed = settings.clone();
+        changed.max_current = 16.0;
+        changed.time_zone = Some("Europe/Berlin".to_string());
+        let second = record_applied(&store_path, &changed).unwrap().unwrap();
+        assert_ne!(first.hash, second.hash);
+        assert_eq!(snapshots(&store_path).unwrap(), vec![first.clone(), second.clone()]);
+
+        let changes = diff_snapshots(&store_path, first.id, second.id).unwrap();
+        assert_eq!(
+            changes.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            ["max_current: 32.0 -> 16.0", "time_zone: (unset) -> \"Europe/Berlin\""]
+        );
+        assert!(matches!(
+            diff_snapshots(&store_path, first.id, 99),
+            Err(ConfigHistoryError::NoSuchSnapshot(99))
+        ));
+
+        let config_dir = dir.join("config");
+        assert_eq!(roll_back(&store_path, &config_dir, first.id).unwrap(), settings);
+        assert_eq!(load_settings(&config_dir).unwrap(), settings);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
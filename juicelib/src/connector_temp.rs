@@ -0,0 +1,110 @@
+use std::ops::RangeInclusive;
+
+use serde::Serialize;
+
+// Temperature of the power contacts of the socket. Many Type 2 sockets have
+// PT1000 sensors next to the contacts: a contact that heats up is worn,
+// dirty or not fully mated, and it gets hotter the more current it
+// carries. Each sensor sits in a divider under a reference resistor from
+// 3.3 V, read on an ADC channel (see Conversion::Pt1000):
+//
+//   "connector_temp1": { "chip": 1, "channel": 0, "conversion": { "type": "pt1000", "reference_ohms": 1000.0 } }
+//
+// Above DERATE_ABOVE_C the offer goes down linearly from the hardware
+// maximum to 6 A at STOP_ABOVE_C, in whole amps. Above that nothing is
+// offered until the hottest contact is back below RESUME_BELOW_C. A sensor
+// that reads outside SENSOR_RANGE_C is open or shorted, and stops the
+// charge as well: the contacts cannot be watched without it.
+
+pub const DERATE_ABOVE_C: f64 = 70.0;
+pub const STOP_ABOVE_C: f64 = 85.0;
+pub const RESUME_BELOW_C: f64 = 65.0;
+const SENSOR_RANGE_C: RangeInclusive<f64> = -40.0..=200.0;
+
+const MIN_OFFER_AMPS: f64 = 6.0;
+
+// IEC 60751 coefficients, above 0 °C. Below, the error stays under 0.2 °C
+// down to -40 °C.
+const PT1000_R0: f64 = 1000.0;
+const PT1000_A: f64 = 3.9083e-3;
+const PT1000_B: f64 = -5.775e-7;
+
+pub fn pt1000_celsius(ohms: f64) -> f64 {
+    let discriminant = PT1000_A * PT1000_A - 4.0 * PT1000_B * (1.0 - ohms / PT1000_R0);
+    (-PT1000_A + discriminant.sqrt()) / (2.0 * PT1000_B)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectorThermalState {
+    Normal,
+    Derating,
+    Stopped,
+    SensorFault,
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectorThermal {
+    stopped: bool,
+}
+
+impl ConnectorThermal {
+    // The state for the readings of all the sensors, in °C.
+    pub fn update(&mut self, temperatures: &[f64]) -> ConnectorThermalState {
+        if temperatures.iter().any(|celsius| !SENSOR_RANGE_C.contains(celsius)) {
+            self.stopped = true;
+            return ConnectorThermalState::SensorFault;
+        }
+        let hottest = temperatures.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        self.stopped = hottest > STOP_ABOVE_C || (self.stopped && hottest >= RESUME_BELOW_C);
+        if self.stopped {
+            ConnectorThermalState::Stopped
+        } else if hottest > DERATE_ABOVE_C {
+            ConnectorThermalState::Derating
+        } else {
+            ConnectorThermalState::Normal
+        }
+    }
+
+    // The cap for the thermal limiter, None when the contacts are cool.
+    pub fn cap(&self, temperatures: &[f64], hardware_max: f64) -> Option<f64> {
+        if self.stopped {
+            return Some(0.0);
+        }
+        let hottest = temperatures.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        if hottest <= DERATE_ABOVE_C {
+            return None;
+        }
+        let share = (hottest - DERATE_ABOVE_C) / (STOP_ABOVE_C - DERATE_ABOVE_C);
+        Some((hardware_max - (hardware_max - MIN_OFFER_AMPS) * share).floor().max(MIN_OFFER_AMPS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derate_and_stop() {
+        assert!(pt1000_celsius(1000.0).abs() < 1e-9);
+        assert!((pt1000_celsius(1385.06) - 100.0).abs() < 0.01);
+        assert!((pt1000_celsius(1270.75) - 70.0).abs() < 0.01);
+
+        let mut thermal = ConnectorThermal::default();
+        assert_eq!(thermal.update(&[40.0, 45.0]), ConnectorThermalState::Normal);
+        assert_eq!(thermal.cap(&[40.0, 45.0], 32.0), None);
+        assert_eq!(thermal.update(&[40.0, 77.5]), ConnectorThermalState::Derating);
+        assert_eq!(thermal.cap(&[40.0, 77.5], 32.0), Some(19.0));
+
+        // Stopped until it cools down well below the stop.
+        assert_eq!(thermal.update(&[86.0]), ConnectorThermalState::Stopped);
+        assert_eq!(thermal.cap(&[86.0], 32.0), Some(0.0));
+        assert_eq!(thermal.update(&[68.0]), ConnectorThermalState::Stopped);
+        assert_eq!(thermal.update(&[64.0]), ConnectorThermalState::Normal);
+        assert_eq!(thermal.cap(&[64.0], 32.0), None);
+
+        // An open sensor.
+        assert_eq!(thermal.update(&[40.0, pt1000_celsius(f64::INFINITY)]), ConnectorThermalState::SensorFault);
+        assert_eq!(thermal.cap(&[40.0], 32.0), Some(0.0));
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+
+// Watchdog of the state machine loop. The loop pets it on every pass; if
+// the loop stalls, for instance on a deadlocked pins mutex, the pilot could
+// keep offering current with nobody watching. The watchdog then forces the
+// station safe on its own thread, through handles reserved for it that do
+// not go through the hardware the loop drives.
+
+// The loop passes several times a second, on every pilot reading. The
+// timeout leaves room for the GFI self test, which takes about a second.
+pub const CONTROL_WATCHDOG_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Brings the station to a safe state: no offer on the pilot, contactor
+// open.
+pub trait SafeState: Send + 'static {
+    fn force_safe(&mut self);
+}
+
+impl SafeState for Box<dyn SafeState> {
+    fn force_safe(&mut self) {
+        (**self).force_safe()
+    }
+}
+
+pub struct ControlWatchdog {
+    pet_tx: Sender<()>,
+    tripped: Arc<AtomicBool>,
+}
+
+impl ControlWatchdog {
+    // Runs until the watchdog is dropped or trips. It trips at most once.
+    pub fn start<S: SafeState>(timeout: Duration, mut safe_state: S) -> Self {
+        // A pet that is still pending is as good as a new one.
+        let (pet_tx, pet_rx) = bounded(1);
+        let tripped = Arc::new(AtomicBool::new(false));
+        let watchdog_tripped = tripped.clone();
+        thread::spawn(move || loop {
+            match pet_rx.recv_timeout(timeout) {
+                Ok(()) => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    watchdog_tripped.store(true, Ordering::SeqCst);
+                    safe_state.force_safe();
+                    return;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        });
+        Self { pet_tx, tripped }
+    }
+
+    // Returns false if the watchdog has tripped; the station was forced
+    // safe behind the loop's back and the loop has to catch up.
+    pub fn pet(&self) -> bool {
+        let _ = self.pet_tx.try_send(());
+        !self.has_tripped()
+    }
+
+    pub fn has_tripped(&self) -> bool {
+        self.tripped.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    struct CountingSafeState(Arc<AtomicUsize>);
+
+    impl SafeState for CountingSafeState {
+        fn force_safe(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_control_watchdog() {
+        let forced = Arc::new(AtomicUsize::new(0));
+        let watchdog = ControlWatchdog::start(Duration::from_millis(50), CountingSafeState(forced.clone()));
+        for _ in 0..10 {
+            thread::sleep(Duration::from_millis(10));
+            assert!(watchdog.pet());
+        }
+        assert_eq!(forced.load(Ordering::SeqCst), 0);
+
+        // The loop stalls.
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(forced.load(Ordering::SeqCst), 1);
+        assert!(!watchdog.pet());
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(forced.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_dropped_watchdog_does_not_trip() {
+        let forced = Arc::new(AtomicUsize::new(0));
+        drop(ControlWatchdog::start(Duration::from_millis(20), CountingSafeState(forced.clone())));
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(forced.load(Ordering::SeqCst), 0);
+    }
+}
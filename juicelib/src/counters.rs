@@ -0,0 +1,242 @@
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+// Lifetime counters of the station. They survive restarts and are stored
+// double-buffered: every save goes to the older of two slot files, each
+// carrying a sequence number and a checksum. A write that is torn by a power
+// loss therefore only ever destroys one slot and the previous values are
+// still available from the other one.
+//
+// The machine counts the energy while charging, a session at each unplug,
+// a contactor cycle each time it closes for a charge and the GFI trips. It
+// saves them at every change of state, along with the uptime.
+
+pub const DEFAULT_COUNTERS_DIR: &str = "/var/lib/juiced/counters";
+
+const SLOT_NAMES: [&str; 2] = ["counters.0", "counters.1"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, JsonSchema)]
+pub struct LifetimeCounters {
+    pub energy_wh: f64,
+    pub sessions: u64,
+    pub gfi_trips: u64,
+    pub contactor_cycles: u64,
+    pub uptime_secs: u64,
+}
+
+impl LifetimeCounters {
+    pub fn energy_kwh(&self) -> f64 {
+        self.energy_wh / 1000.0
+    }
+
+    pub fn add_energy_wh(&mut self, energy_wh: f64) {
+        // Counters only ever go up.
+        if energy_wh > 0.0 {
+            self.energy_wh += energy_wh;
+        }
+    }
+
+    pub fn add_uptime(&mut self, uptime: Duration) {
+        self.uptime_secs += uptime.as_secs();
+    }
+}
+
+#[derive(Debug)]
+pub enum CounterError {
+    Io(io::Error),
+    // Both slots exist but neither of them holds a valid record.
+    Corrupt,
+}
+
+impl From<io::Error> for CounterError {
+    fn from(error: io::Error) -> Self {
+        CounterError::Io(error)
+    }
+}
+
+pub struct CounterStore {
+    slots: [PathBuf; 2],
+    sequence: u64,
+}
+
+impl CounterStore {
+    // Opens the store in the given directory and returns the newest valid
+    // counters found in it. A directory without any slot files yields zeroed
+    // counters.
+    pub fn open(dir: &Path) -> Result<(Self, LifetimeCounters), CounterError> {
+        fs::create_dir_all(dir)?;
+        let slots = [dir.join(SLOT_NAMES[0]), dir.join(SLOT_NAMES[1])];
+
+        let mut newest: Option<(u64, LifetimeCounters)> = None;
+        let mut found_any = false;
+        for slot in &slots {
+            let text = match fs::read_to_string(slot) {
+                Ok(text) => text,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                // Unreadable contents count as a corrupt slot.
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    found_any = true;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            found_any = true;
+            if let Some((sequence, counters)) = decode(&text) {
+                if newest.is_none_or(|(s, _)| sequence > s) {
+                    newest = Some((sequence, counters));
+                }
+            }
+        }
+
+        match newest {
+            Some((sequence, counters)) => Ok((Self { slots, sequence }, counters)),
+            None if found_any => Err(CounterError::Corrupt),
+            None => Ok((Self { slots, sequence: 0 }, LifetimeCounters::default())),
+        }
+    }
+
+    pub fn save(&mut self, counters: &LifetimeCounters) -> Result<(), CounterError> {
+        let sequence = self.sequence + 1;
+        let slot = &self.slots[(sequence % 2) as usize];
+
+        let mut file = File::create(slot)?;
+        file.write_all(encode(sequence, counters).as_bytes())?;
+        file.sync_all()?;
+
+        self.sequence = sequence;
+        Ok(())
+    }
+}
+
+// FNV-1a, good enough to detect torn or garbled writes.
+fn checksum(data: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn encode(sequence: u64, counters: &LifetimeCounters) -> String {
+    let mut body = String::new();
+    // Writing into a String cannot fail.
+    let _ = writeln!(body, "sequence={}", sequence);
+    let _ = writeln!(body, "energy_wh={}", counters.energy_wh);
+    let _ = writeln!(body, "sessions={}", counters.sessions);
+    let _ = writeln!(body, "gfi_trips={}", counters.gfi_trips);
+    let _ = writeln!(body, "contactor_cycles={}", counters.contactor_cycles);
+    let _ = writeln!(body, "uptime_secs={}", counters.uptime_secs);
+    let _ = writeln!(body, "checksum={:016x}", checksum(&body));
+    body
+}
+
+fn decode(text: &str) -> Option<(u64, LifetimeCounters)> {
+    let checksum_at = text.rfind("checksum=")?;
+    let (body, trailer) = text.split_at(checksum_at);
+    let expected = u64::from_str_radix(trailer.trim_start_matches("checksum=").trim(), 16).ok()?;
+    if checksum(body) != expected {
+        return None;
+    }
+
+    let mut sequence = None;
+    let mut counters = LifetimeCounters::default();
+    for line in body.lines() {
+        let (key, value) = line.split_once('=')?;
+        match key {
+            "sequence" => sequence = Some(value.parse().ok()?),
+            "energy_wh" => counters.energy_wh = value.parse().ok()?,
+            "sessions" => counters.sessions = value.parse().ok()?,
+            "gfi_trips" => counters.gfi_trips = value.parse().ok()?,
+            "contactor_cycles" => counters.contactor_cycles = value.parse().ok()?,
+            "uptime_secs" => counters.uptime_secs = value.parse().ok()?,
+            _ => return None,
+        }
+    }
+    Some((sequence?, counters))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("juicelib-counters-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn sample() -> LifetimeCounters {
+        LifetimeCounters {
+            energy_wh: 12345.5,
+            sessions: 42,
+            gfi_trips: 1,
+            contactor_cycles: 84,
+            uptime_secs: 3600,
+        }
+    }
+
+    #[test]
+    fn test_empty_dir_starts_at_zero() -> Result<(), CounterError> {
+        let dir = test_dir("empty");
+        let (_, counters) = CounterStore::open(&dir)?;
+        assert_eq!(counters, LifetimeCounters::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_roundtrip() -> Result<(), CounterError> {
+        let dir = test_dir("roundtrip");
+        let (mut store, _) = CounterStore::open(&dir)?;
+        store.save(&sample())?;
+        let (_, counters) = CounterStore::open(&dir)?;
+        assert_eq!(counters, sample());
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_newest_slot_falls_back() -> Result<(), CounterError> {
+        let dir = test_dir("fallback");
+        let (mut store, _) = CounterStore::open(&dir)?;
+        store.save(&sample())?;
+        let mut newer = sample();
+        newer.sessions += 1;
+        store.save(&newer)?;
+
+        // The second save went to slot 0; tear it in half.
+        let text = fs::read_to_string(dir.join(SLOT_NAMES[0]))?;
+        fs::write(dir.join(SLOT_NAMES[0]), &text[..text.len() / 2])?;
+
+        let (mut store, counters) = CounterStore::open(&dir)?;
+        assert_eq!(counters, sample());
+
+        // Saving again must not overwrite the only valid slot.
+        store.save(&newer)?;
+        let (_, counters) = CounterStore::open(&dir)?;
+        assert_eq!(counters, newer);
+        Ok(())
+    }
+
+    #[test]
+    fn test_all_slots_corrupt() -> Result<(), CounterError> {
+        let dir = test_dir("corrupt");
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(SLOT_NAMES[0]), "sessions=1\nchecksum=0\n")?;
+        assert!(matches!(CounterStore::open(&dir), Err(CounterError::Corrupt)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_energy_never_decreases() {
+        let mut counters = LifetimeCounters::default();
+        counters.add_energy_wh(1500.0);
+        counters.add_energy_wh(-10.0);
+        assert_eq!(counters.energy_kwh(), 1.5);
+    }
+}
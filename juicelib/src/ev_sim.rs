@@ -0,0 +1,283 @@
+use std::thread;
+
+use crossbeam_channel::{select_biased, unbounded, Receiver, Sender};
+use serde::Serialize;
+
+use crate::acquisition::{AcquisitionMetrics, AcquisitionSchedule, OversamplingPolicy};
+use crate::adc::{Adc, PeakPercentiles};
+use crate::evse::{EVSEError, EVSEHardwareImpl, EVSEMachineInput, PilotClassifier, PilotReading, PilotSampling};
+use crate::hal::gpio::{Gpio, OutputPin};
+use crate::peripherals::PeripheralsError;
+use crate::pilot::duty_cycle_to_ampere;
+use crate::timing::STATE_DEBOUNCE;
+
+// Vehicle emulator. Turns the station around to test other EVSEs: the
+// pilot of the EVSE under test is wired to the pilot feedback of the hat
+// and two relays switch the vehicle side resistors onto it. Connecting
+// presents 2.74 kOhm behind a diode (state B); asking for a charge closes
+// S2, which adds 1.3 kOhm in parallel for 882 Ohm (state C). The emulator
+// measures the pilot and reports the level and the offered current.
+
+// GPIOs of the relays. Not used by the EVSE Pi Hat.
+pub const DEFAULT_CONNECT_PIN: u8 = 5;
+pub const DEFAULT_CHARGE_PIN: u8 = 6;
+
+// What the emulated vehicle presents on the pilot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum EvLoad {
+    Unplugged,
+    // 2.74 kOhm, state B.
+    Connected,
+    // 882 Ohm, state C.
+    RequestingCharge,
+}
+
+impl EvLoad {
+    // The pilot high level a compliant EVSE shows with this load.
+    fn expected_level(&self) -> EVSEMachineInput {
+        match self {
+            EvLoad::Unplugged => EVSEMachineInput::PilotIs12V,
+            EvLoad::Connected => EVSEMachineInput::PilotIs9V,
+            EvLoad::RequestingCharge => EVSEMachineInput::PilotIs6V,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvSimCommand {
+    Plug,
+    RequestCharge,
+    StopCharge,
+    Unplug,
+    // Unplugs and ends the emulation.
+    Stop,
+}
+
+// The load after a command. Asking for a charge plugs in first; stopping
+// the charge keeps the vehicle plugged in.
+pub fn next_load(load: EvLoad, command: EvSimCommand) -> EvLoad {
+    match (load, command) {
+        (_, EvSimCommand::Unplug | EvSimCommand::Stop) => EvLoad::Unplugged,
+        (_, EvSimCommand::RequestCharge) => EvLoad::RequestingCharge,
+        (EvLoad::Unplugged, EvSimCommand::Plug) => EvLoad::Connected,
+        (EvLoad::RequestingCharge, EvSimCommand::StopCharge) => EvLoad::Connected,
+        (load, _) => load,
+    }
+}
+
+// One measurement of the EVSE under test.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct EvSimReport {
+    pub load: EvLoad,
+    pub pilot: PilotReading,
+    pub level: EVSEMachineInput,
+    // The pilot shows the level that belongs to the load.
+    pub level_ok: bool,
+    // None while the EVSE offers nothing: steady pilot, 5% digital
+    // communication or an error.
+    pub offered_amps: Option<f64>,
+}
+
+impl EvSimReport {
+    pub fn new(load: EvLoad, pilot: PilotReading, classifier: &mut PilotClassifier) -> Self {
+        let level = classifier.get_pilot_state(pilot.high);
+        // A steady pilot has no duty cycle to read an offer from.
+        let offered_amps = if pilot.frequency > 0.0 {
+            duty_cycle_to_ampere(pilot.duty_cycle as f64)
+        } else {
+            None
+        };
+        Self {
+            load,
+            pilot,
+            level,
+            level_ok: level == load.expected_level(),
+            offered_amps,
+        }
+    }
+}
+
+// The relays and the pilot measurement of the emulator.
+pub trait EvSimHardware: Send + 'static {
+    fn set_load(&mut self, load: EvLoad) -> Result<(), EVSEError>;
+    fn pilot_channel(&self) -> Receiver<PilotReading>;
+}
+
+pub struct EvSimHardwareImpl {
+    connect: OutputPin,
+    charge: OutputPin,
+    pilot_rx: Receiver<PilotReading>,
+}
+
+impl EvSimHardwareImpl {
+    // Samples the pilot with the ADC of the hat, the same way the EVSE
+    // does.
+    pub fn new(connect_pin: u8, charge_pin: u8) -> Result<Self, EVSEError> {
+        let gpio = Gpio::new().map_err(PeripheralsError::from)?;
+        let connect = gpio.get(connect_pin).map_err(PeripheralsError::from)?.into_output_low();
+        let charge = gpio.get(charge_pin).map_err(PeripheralsError::from)?.into_output_low();
+        let sampler = Box::new(Adc::new()?);
+
+        let (pilot_tx, pilot_rx) = unbounded();
+        thread::spawn(move || {
+            EVSEHardwareImpl::sample_pilot(
+                sampler,
+                PeakPercentiles::default(),
+                PilotSampling::default(),
+                STATE_DEBOUNCE.default,
+                AcquisitionSchedule::new(OversamplingPolicy::NONE),
+                AcquisitionMetrics::default(),
+                pilot_tx,
+            )
+        });
+        Ok(Self {
+            connect,
+            charge,
+            pilot_rx,
+        })
+    }
+}
+
+impl EvSimHardware for EvSimHardwareImpl {
+    fn set_load(&mut self, load: EvLoad) -> Result<(), EVSEError> {
+        // S2 only ever closes with the vehicle connected.
+        match load {
+            EvLoad::Unplugged => {
+                self.charge.set_low();
+                self.connect.set_low();
+            }
+            EvLoad::Connected => {
+                self.charge.set_low();
+                self.connect.set_high();
+            }
+            EvLoad::RequestingCharge => {
+                self.connect.set_high();
+                self.charge.set_high();
+            }
+        }
+        Ok(())
+    }
+
+    fn pilot_channel(&self) -> Receiver<PilotReading> {
+        self.pilot_rx.clone()
+    }
+}
+
+// Runs the emulator until it is stopped or the command channel closes.
+// Reports every pilot reading. The vehicle is unplugged when it returns.
+pub fn run_ev_sim<H: EvSimHardware>(
+    mut hardware: H,
+    command_rx: Receiver<EvSimCommand>,
+    report_tx: Sender<EvSimReport>,
+) -> Result<(), EVSEError> {
+    let pilot_rx = hardware.pilot_channel();
+    let mut classifier = PilotClassifier::default();
+    let mut load = EvLoad::Unplugged;
+    hardware.set_load(load)?;
+
+    loop {
+        select_biased! {
+            recv(command_rx) -> command => {
+                let command = command.unwrap_or(EvSimCommand::Stop);
+                load = next_load(load, command);
+                hardware.set_load(load)?;
+                if command == EvSimCommand::Stop {
+                    return Ok(());
+                }
+            },
+            recv(pilot_rx) -> reading => match reading {
+                Ok(reading) => {
+                    if report_tx.send(EvSimReport::new(load, reading, &mut classifier)).is_err() {
+                        return hardware.set_load(EvLoad::Unplugged);
+                    }
+                }
+                Err(_) => return hardware.set_load(EvLoad::Unplugged),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_next_load() {
+        use EvLoad::*;
+        use EvSimCommand::*;
+        assert_eq!(next_load(Unplugged, Plug), Connected);
+        assert_eq!(next_load(Connected, RequestCharge), RequestingCharge);
+        assert_eq!(next_load(Unplugged, RequestCharge), RequestingCharge);
+        assert_eq!(next_load(RequestingCharge, StopCharge), Connected);
+        assert_eq!(next_load(Unplugged, StopCharge), Unplugged);
+        assert_eq!(next_load(RequestingCharge, Unplug), Unplugged);
+    }
+
+    fn reading(high: f32, duty_cycle: f32, frequency: f32) -> PilotReading {
+        PilotReading {
+            high,
+            low: -12.0,
+            duty_cycle,
+            frequency,
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let mut classifier = PilotClassifier::default();
+        let report = EvSimReport::new(EvLoad::RequestingCharge, reading(6.0, 0.5333, 1000.0), &mut classifier);
+        assert!(report.level_ok);
+        assert!((report.offered_amps.unwrap() - 32.0).abs() < 0.01);
+
+        // An EVSE that does not react to the load, and offers nothing.
+        let mut classifier = PilotClassifier::default();
+        let report = EvSimReport::new(EvLoad::Connected, reading(12.0, 1.0, 0.0), &mut classifier);
+        assert_eq!(report.level, EVSEMachineInput::PilotIs12V);
+        assert!(!report.level_ok);
+        assert_eq!(report.offered_amps, None);
+    }
+
+    struct FakeEvHardware {
+        loads: Arc<Mutex<Vec<EvLoad>>>,
+        pilot_rx: Receiver<PilotReading>,
+    }
+
+    impl EvSimHardware for FakeEvHardware {
+        fn set_load(&mut self, load: EvLoad) -> Result<(), EVSEError> {
+            self.loads.lock().unwrap().push(load);
+            Ok(())
+        }
+
+        fn pilot_channel(&self) -> Receiver<PilotReading> {
+            self.pilot_rx.clone()
+        }
+    }
+
+    #[test]
+    fn test_run_ev_sim() {
+        let loads = Arc::new(Mutex::new(Vec::new()));
+        let (pilot_tx, pilot_rx) = unbounded();
+        let (command_tx, command_rx) = unbounded();
+        let (report_tx, report_rx) = unbounded();
+        let hardware = FakeEvHardware {
+            loads: loads.clone(),
+            pilot_rx,
+        };
+        let sim = thread::spawn(move || run_ev_sim(hardware, command_rx, report_tx));
+
+        command_tx.send(EvSimCommand::Plug).unwrap();
+        pilot_tx.send(reading(9.0, 0.25, 1000.0)).unwrap();
+        let report = report_rx.recv().unwrap();
+        assert_eq!(report.load, EvLoad::Connected);
+        assert!(report.level_ok);
+        assert_eq!(report.offered_amps, Some(15.0));
+
+        command_tx.send(EvSimCommand::Stop).unwrap();
+        sim.join().unwrap().unwrap();
+        assert_eq!(
+            *loads.lock().unwrap(),
+            vec![EvLoad::Unplugged, EvLoad::Connected, EvLoad::Unplugged]
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use crossbeam_channel::{Sender, TrySendError};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::evse::{EVSEMachineInput, EVSEMachineState};
+
+// What the machine does, as it happens, for crates that build a UI, a log
+// or a network interface on it without patching the machine loop:
+//
+// let (event_tx, event_rx) = crossbeam_channel::unbounded();
+// let handle = start_machine_with_events(hardware, event_tx);
+// for event in event_rx { ... }
+//
+// The events go out in the order they happen; the channel ends when the
+// machine does. The machine never waits for the observer, so a bounded
+// channel that is full loses events, and an observer that goes away
+// stops getting them.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+pub enum EVSEEvent {
+    // None for the state the machine starts in.
+    StateEntered {
+        state: EVSEMachineState,
+        previous: Option<EVSEMachineState>,
+    },
+    // A fault state was entered, with the input that caused it if there
+    // was one.
+    FaultRaised {
+        state: EVSEMachineState,
+        cause: Option<EVSEMachineInput>,
+    },
+    // The GFI self test at start and before every charge.
+    SelfTest { passed: bool },
+    // What the pilot offers, 0 when it offers nothing.
+    OfferChanged { amps: f64 },
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EventSink {
+    sender: Option<Sender<EVSEEvent>>,
+}
+
+impl EventSink {
+    pub(crate) fn new(sender: Option<Sender<EVSEEvent>>) -> Self {
+        Self { sender }
+    }
+
+    pub(crate) fn emit(&mut self, event: EVSEEvent) {
+        if let Some(sender) = &self.sender {
+            if let Err(TrySendError::Disconnected(_)) = sender.try_send(event) {
+                self.sender = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::{bounded, unbounded};
+
+    #[test]
+    fn test_sink_drops_gone_observer() {
+        let (event_tx, event_rx) = unbounded();
+        let mut sink = EventSink::new(Some(event_tx));
+        sink.emit(EVSEEvent::SelfTest { passed: true });
+        assert_eq!(event_rx.try_recv(), Ok(EVSEEvent::SelfTest { passed: true }));
+        drop(event_rx);
+        sink.emit(EVSEEvent::OfferChanged { amps: 16.0 });
+        assert!(sink.sender.is_none());
+
+        // A full channel only loses the event.
+        let (event_tx, event_rx) = bounded(1);
+        let mut sink = EventSink::new(Some(event_tx));
+        sink.emit(EVSEEvent::OfferChanged { amps: 16.0 });
+        sink.emit(EVSEEvent::OfferChanged { amps: 10.0 });
+        assert!(sink.sender.is_some());
+        assert_eq!(event_rx.try_recv(), Ok(EVSEEvent::OfferChanged { amps: 16.0 }));
+    }
+}
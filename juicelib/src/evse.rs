@@ -0,0 +1,5485 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use chrono_tz::Tz;
+use crossbeam_channel::{at, bounded, never, select_biased, unbounded, Receiver, Sender};
+use futures_core::Stream;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use crate::acquisition::{
+    AcquisitionHealth, AcquisitionMetrics, AcquisitionReader, AcquisitionSchedule, OversamplingPolicy,
+};
+use crate::actuator::{ActuatorError, PilotActuator};
+use crate::adc::{Adc, AdcError};
+use crate::analytics::{FaultRecord, FaultReport};
+pub use crate::adc::PeakPercentiles;
+use crate::audit::{AuditLog, DEFAULT_AUDIT_LOG_PATH};
+use crate::blackbox::{is_latched_fault, BlackBox, BlackBoxEntry, RecordingHardware, DEFAULT_BLACK_BOX_DIR};
+use crate::breaker::{BreakerConfig, BreakerModel, BreakerStatus};
+use crate::brownout::{Brownout, BrownoutLevel, BrownoutStatus, LatencyBudget, ResourceMonitor};
+use crate::certificates::{CertificateStore, DEFAULT_CERTIFICATE_DIR};
+use crate::channels::{ChannelRegistry, Signal};
+use crate::charging_curve::{ChargingCurve, CurvePoint};
+use crate::clock::{system_clock, Clock};
+use crate::config::{Config, SpiConfig};
+use crate::counters::{CounterStore, LifetimeCounters, DEFAULT_COUNTERS_DIR};
+use crate::completion::{CompletionEstimate, CompletionEstimator};
+use crate::imbalance::{ImbalanceConfig, ImbalanceMonitor, ImbalanceStatus};
+use crate::connector_temp::{ConnectorThermal, ConnectorThermalState};
+use crate::config_history;
+use crate::control_watchdog::{ControlWatchdog, SafeState, CONTROL_WATCHDOG_TIMEOUT};
+use crate::events::{EVSEEvent, EventSink};
+use crate::fault_policy::AlarmPolicy;
+use crate::features::{Feature, FeatureFlags};
+use crate::guest::{self, GuestExposure, DEFAULT_GUEST_ADDRESS};
+use crate::gfi_retry::{GfiRetryPolicy, GfiRetryTracker};
+use crate::guest_token::{self, GuestSession, GuestToken, GuestTokenError, IssuedToken};
+use crate::hal::pwm::Error as PwmError;
+use crate::influx::{self, InfluxExporter, SensorSample};
+use crate::interlock::{InterlockedSampler, PilotInterlock};
+use crate::interruption::{self, Interruption, InterruptionCause, ResumeError, ResumeTokens, INTERRUPTION_BACKLOG};
+use crate::journal::{self, JournalSink, EVENT_BACKLOG};
+use crate::lab::{self, LabPattern, LabReport};
+use crate::machine_state::{ErrorCounters, PersistedState, DEFAULT_STATE_PATH};
+use crate::mains::measure_cycles;
+use crate::metrics::{MetricsReport, StateMetrics};
+use crate::limits::{Limiter, LimiterChain, LimitsStatus};
+use crate::nuisance::classify_trips;
+use crate::offer_pacing::OfferPacer;
+use crate::peripherals::{
+    BoardProfile, ContactorDrive, GfiDriver, GpioPeripherals, GpioPins, PeripheralsError, PowerWatchdog,
+    ReservedContactor,
+};
+use crate::peaks::{DailyPeak, PeakTracker};
+use crate::phases::{budget_amps, usable_power_w, PhaseDetectionConfig, PhaseDetector, PhaseStatus, NOMINAL_PHASE_VOLTS};
+use crate::pilot::{is_valid_max_current, Pilot, PilotDriver, PilotSignal, PwmAssignment, ReservedPilot};
+use crate::pilot_cable::{CableDiagnostics, CableHealth};
+use crate::power_fail::{request_os_shutdown, PowerFailRecord, DEFAULT_RECORD_PATH};
+use crate::power_quality::{PowerQualityConfig, PowerQualityLog, PowerQualityMonitor, DEFAULT_POWER_QUALITY_LOG_PATH};
+use crate::pricing::{self, AwattarProvider, HourlyPrice, PricePlanner, PricingSettings};
+use crate::provisioning::{load_settings_with_recovery, DEFAULT_CONFIG_DIR};
+use crate::readback::{Discrepancy, ReadbackMonitor};
+use crate::recovery::{ErrorRecovery, RecoveryTracker};
+use crate::rapi;
+use crate::rpc;
+use crate::session::SessionLog;
+use crate::session_id::SessionId;
+use crate::shadow::{PilotCandidate, ShadowPilot, ShadowReport};
+use crate::solar::{SolarController, SolarHysteresis};
+use crate::store::{Store, StoreError, DEFAULT_STORE_PATH};
+use crate::streams::{MachineStreams, MeterSample, PilotSample};
+use crate::telemetry::{TelemetrySample, TelemetryVerbosity};
+use crate::tenants::{self, Tenant, TenantError, TenantReportRow, TenantSession};
+use crate::theme::Theme;
+use crate::time::DEFAULT_TIME_ZONE;
+use crate::timing::{J1772Timing, TimingError};
+use crate::trim::OfferTrim;
+use crate::vehicle_floor::FloorTracker;
+
+// This file contains the EVSE logic that sits on top of the hardware
+// modules: the classification of the pilot feedback into the J1772 states
+// (A, B, C, D and error) and the state machine acting on it.
+
+// Inputs for the EVSE state machine. The pilot inputs are named after the
+// nominal high level of the pilot in each J1772 state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum EVSEMachineInput {
+    PilotIs12V,
+    PilotIs9V,
+    PilotIs6V,
+    PilotIs3V,
+    PilotInError,
+    GFIInterrupted,
+    SelfTestOk,
+    SelfTestFailed,
+    // The UPS hat reports that mains power is gone.
+    PowerLost,
+    // The vehicle paused charging for longer than allowed.
+    PauseTimedOut,
+    // The enclosure door/tamper switch opened.
+    EnclosureOpened,
+    // The vehicle was unplugged within the grace window of a resettable
+    // error.
+    UnpluggedInGrace,
+    // The negative half of the pilot is not at -12 V: the diode of the
+    // vehicle is missing or shorted.
+    DiodeCheckFailed,
+    // The line currents stayed apart beyond the stop threshold, see
+    // imbalance.rs.
+    LineImbalance,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema)]
+pub enum EVSEMachineState {
+    // No vehicle, the pilot is a steady +12V.
+    Standby,
+    // Vehicle connected (state B), the pilot offers current.
+    VehicleDetected,
+    // The vehicle asked for power, the GFI self test is running.
+    StartCharging,
+    Charging,
+    // The vehicle paused charging (back to state B), e.g. to wait for its
+    // own charging schedule. The offer stays up and the session open.
+    SuspendedEV,
+    StopCharging,
+    // The vehicle asked for power and needs ventilation (state D).
+    VentilationNeeded,
+    ResetableError,
+    FailedStation,
+    // The GFI tripped and the station waits out the cooldown before it
+    // tests the GFI again, see gfi_retry.rs.
+    GFITripped,
+    // Mains power is gone, the station runs from the UPS battery until the
+    // OS is shut down.
+    PowerFailure,
+    // The enclosure was opened while the vehicle was powered. Latched until
+    // an admin resets it.
+    TamperLockout,
+}
+
+// The highest and lowest pilot voltage seen in one sampling window, along
+// with the duty cycle (0.0 to 1.0) and frequency (Hz) measured from the
+// samples. Duty cycle and frequency are only meaningful while the pilot
+// oscillates; a steady pilot reads as 0 Hz.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PilotReading {
+    pub high: f32,
+    pub low: f32,
+    pub duty_cycle: f32,
+    pub frequency: f32,
+}
+
+impl PilotReading {
+    // Evaluates a window of equally spaced pilot samples that took `window`
+    // to acquire. An empty window gives an unusable (NaN) reading.
+    pub fn from_samples(samples: &[f32], window: Duration) -> Self {
+        Self::from_samples_with_peaks(samples, window, PeakPercentiles::default())
+    }
+
+    // Like from_samples, with the percentiles taken as high and low level.
+    pub fn from_samples_with_peaks(samples: &[f32], window: Duration, peaks: PeakPercentiles) -> Self {
+        if samples.is_empty() {
+            return Self {
+                high: f32::NAN,
+                low: f32::NAN,
+                duty_cycle: f32::NAN,
+                frequency: f32::NAN,
+            };
+        }
+
+        let (low, high) = Adc::peak_to_peak(samples, peaks);
+
+        // The pilot swings around 0V, so a rising edge is a crossing of 0V.
+        let rising_edges: Vec<usize> = (1..samples.len())
+            .filter(|&i| samples[i - 1] <= 0.0 && samples[i] > 0.0)
+            .collect();
+
+        // Only whole periods between the first and the last rising edge
+        // give an unbiased duty cycle.
+        let (duty_cycle, frequency) = match (rising_edges.first(), rising_edges.last()) {
+            (Some(&first), Some(&last)) if last > first => {
+                let positive = samples[first..last].iter().filter(|&&v| v > 0.0).count();
+                let sample_time = window.as_secs_f32() / samples.len() as f32;
+                let periods = (rising_edges.len() - 1) as f32;
+                let duty_cycle = positive as f32 / (last - first) as f32;
+                let frequency = if sample_time > 0.0 {
+                    periods / ((last - first) as f32 * sample_time)
+                } else {
+                    0.0
+                };
+                (duty_cycle, frequency)
+            }
+            _ => {
+                let positive = samples.iter().filter(|&&v| v > 0.0).count();
+                (positive as f32 / samples.len() as f32, 0.0)
+            }
+        };
+
+        Self {
+            high,
+            low,
+            duty_cycle,
+            frequency,
+        }
+    }
+
+    // Like from_samples_with_peaks, but the high and low level are taken
+    // from the plateaus of the pilot only. The samples are not synchronized
+    // to the PWM, so a sample next to a 0V crossing may have caught the
+    // pilot on its edge, including any overshoot; those are left out. A
+    // level without plateau samples, e.g. a very short high time, falls
+    // back to all samples. NaN samples are dropped first, they are neither
+    // high nor low.
+    pub fn from_plateaus(samples: &[f32], window: Duration, peaks: PeakPercentiles) -> Self {
+        let mut reading = Self::from_samples_with_peaks(samples, window, peaks);
+        let valid: Vec<f32> = samples.iter().copied().filter(|v| !v.is_nan()).collect();
+        let is_high = |v: f32| v > 0.0;
+        let plateau = |i: usize| {
+            let level = is_high(valid[i]);
+            let before = i == 0 || is_high(valid[i - 1]) == level;
+            let after = i + 1 == valid.len() || is_high(valid[i + 1]) == level;
+            before && after
+        };
+        let (high, low): (Vec<f32>, Vec<f32>) = (0..valid.len())
+            .filter(|&i| plateau(i))
+            .map(|i| valid[i])
+            .partition(|&v| is_high(v));
+
+        if !high.is_empty() {
+            reading.high = Adc::peak_to_peak(&high, peaks).1;
+        }
+        if !low.is_empty() {
+            reading.low = Adc::peak_to_peak(&low, peaks).0;
+        }
+        reading
+    }
+}
+
+// How the pilot levels are taken from a window of samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PilotSampling {
+    // From all samples.
+    Asynchronous,
+    // From the plateaus between the PWM edges only, see
+    // PilotReading::from_plateaus. The sampling itself is not synchronized
+    // to the PWM.
+    #[default]
+    PlateauFiltered,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum EvseCommand {
+    // Limits the current offered to the vehicle from the next offer on.
+    SetCurrentLimit(f64),
+    // Adjusts the offer during the session as well, e.g. for a load
+    // manager: the operator cap, paced as J1772 asks (see offer_pacing.rs).
+    AdjustCurrentOffer(f64),
+    // How long a vehicle may pause charging before the session ends.
+    SetMaxPause(Duration),
+    // Admin reset of a tamper lockout.
+    ResetTamper,
+    // Admin reset of a failed station. The GFI self test runs again, and
+    // the station is back in service if it passes.
+    ResetLockout,
+    // Overrides a feature toggle until the restart; None goes back to the
+    // settings.
+    SetFeature(Feature, Option<bool>),
+    // Sets the cap of a limiter in the limiter chain; None removes it.
+    // Like the current limit, it applies from the next offer on. The solar
+    // cap is the surplus and goes through the hysteresis, see solar.rs.
+    SetLimit(Limiter, Option<f64>),
+    // Power budget (W) of the load manager, split over the phases the
+    // vehicle charges on. Sets the load manager cap while it lasts; None
+    // removes both.
+    SetPowerBudget(Option<f64>),
+    // Identifies the vehicle of the session, e.g. by the VIN or an RFID
+    // card, so its minimum current can be learned.
+    IdentifyVehicle(String),
+    // The energy (Wh) the battery took in so far, as the vehicle reports it
+    // or the driver enters it. Goes to the running session, or to the last
+    // one after the unplug.
+    SetBatteryEnergy(f64),
+    // Changes the maximum current of the installation, the hardware cap of
+    // the limiter chain. Refused while the vehicle charges.
+    SetMaxCurrent(f64),
+    // Starts the session of a guest under the caps of a redeemed token.
+    StartGuestSession(GuestToken),
+    // Starts the session of an authorized tenant under the tenant's caps.
+    StartTenantSession(TenantSession),
+    // The hourly energy prices of a dynamic tariff, see pricing.rs.
+    SetPrices(Vec<HourlyPrice>),
+    // Plays a pilot test pattern to the vehicle. Only in lab mode and while
+    // the contactor is open.
+    RunLabPattern(LabPattern),
+    // The resume link of an interrupted session was used, see
+    // interruption.rs. A resettable error goes back to Standby and a
+    // vehicle asking for power is charged again; a failed station is reset
+    // as by ResetLockout.
+    ResumeCharging,
+    // Orderly stop, e.g. on SIGTERM: a charging vehicle is asked to stop
+    // drawing current before the contactor opens.
+    Shutdown,
+    Stop,
+}
+
+#[derive(Debug)]
+pub enum EVSEError {
+    Pwm(PwmError),
+    Adc(AdcError),
+    Peripherals(PeripheralsError),
+    Actuator(ActuatorError),
+    Timing(TimingError),
+    // The pilot cannot signal it: J1772 offers 6A to 80A.
+    InvalidMaxCurrent(f64),
+    // Not a current: negative or not a number.
+    InvalidOffer(f64),
+    // Refused while the vehicle charges.
+    Charging,
+    MachineStopped,
+}
+
+impl From<PwmError> for EVSEError {
+    fn from(error: PwmError) -> Self {
+        EVSEError::Pwm(error)
+    }
+}
+
+impl From<AdcError> for EVSEError {
+    fn from(error: AdcError) -> Self {
+        EVSEError::Adc(error)
+    }
+}
+
+impl From<PeripheralsError> for EVSEError {
+    fn from(error: PeripheralsError) -> Self {
+        EVSEError::Peripherals(error)
+    }
+}
+
+impl From<ActuatorError> for EVSEError {
+    fn from(error: ActuatorError) -> Self {
+        EVSEError::Actuator(error)
+    }
+}
+
+impl From<TimingError> for EVSEError {
+    fn from(error: TimingError) -> Self {
+        EVSEError::Timing(error)
+    }
+}
+
+// Nominal band edges (in volts) between the pilot states. The edges sit
+// half way between the nominal levels of neighbouring states.
+pub(crate) const EDGE_12V_9V: f32 = 10.5;
+pub(crate) const EDGE_9V_6V: f32 = 7.5;
+pub(crate) const EDGE_6V_3V: f32 = 4.5;
+pub(crate) const EDGE_3V_ERROR: f32 = 1.5;
+// The negative half of the pilot has to stay below this while a vehicle is
+// connected: half way between the -12 V a good diode leaves it at and the
+// -9 V a state B vehicle without one pulls it to.
+pub(crate) const DIODE_CHECK_MAX_LOW: f32 = -10.5;
+
+// Default hysteresis margin in volts. A state is only left once the pilot
+// is this far outside of its nominal band.
+pub const DEFAULT_PILOT_HYSTERESIS: f32 = 0.3;
+
+// Classifies the pilot high level. The classifier remembers the last state
+// it reported and widens that state's band by the hysteresis margin, so a
+// voltage sitting right at an edge does not flip between two states.
+pub struct PilotClassifier {
+    margin: f32,
+    last_state: Option<EVSEMachineInput>,
+}
+
+impl PilotClassifier {
+    pub fn new(margin: f32) -> Self {
+        Self {
+            margin: margin.max(0.0),
+            last_state: None,
+        }
+    }
+
+    // Returns the (low, high) edges of the nominal band of a state.
+    fn band(state: EVSEMachineInput) -> (f32, f32) {
+        match state {
+            EVSEMachineInput::PilotIs12V => (EDGE_12V_9V, f32::INFINITY),
+            EVSEMachineInput::PilotIs9V => (EDGE_9V_6V, EDGE_12V_9V),
+            EVSEMachineInput::PilotIs6V => (EDGE_6V_3V, EDGE_9V_6V),
+            EVSEMachineInput::PilotIs3V => (EDGE_3V_ERROR, EDGE_6V_3V),
+            // The classifier only ever stores pilot inputs, anything else
+            // is treated like PilotInError.
+            _ => (f32::NEG_INFINITY, EDGE_3V_ERROR),
+        }
+    }
+
+    // Classifies a voltage using the nominal bands only.
+    fn nominal_state(high: f32) -> EVSEMachineInput {
+        if high >= EDGE_12V_9V {
+            EVSEMachineInput::PilotIs12V
+        } else if high >= EDGE_9V_6V {
+            EVSEMachineInput::PilotIs9V
+        } else if high >= EDGE_6V_3V {
+            EVSEMachineInput::PilotIs6V
+        } else if high >= EDGE_3V_ERROR {
+            EVSEMachineInput::PilotIs3V
+        } else {
+            EVSEMachineInput::PilotInError
+        }
+    }
+
+    // Takes the highest pilot voltage seen in a sampling window and returns
+    // the corresponding state.
+    pub fn get_pilot_state(&mut self, high: f32) -> EVSEMachineInput {
+        if high.is_nan() {
+            self.last_state = Some(EVSEMachineInput::PilotInError);
+            return EVSEMachineInput::PilotInError;
+        }
+
+        if let Some(last_state) = self.last_state {
+            let (low_edge, high_edge) = Self::band(last_state);
+            if high >= low_edge - self.margin && high < high_edge + self.margin {
+                return last_state;
+            }
+        }
+
+        let state = Self::nominal_state(high);
+        self.last_state = Some(state);
+        state
+    }
+
+    // Classifies a whole reading: the state by the high level, then the
+    // J1772 diode check. The check needs the pilot to swing through 0 V,
+    // i.e. an offer; a steady pilot has no negative half to look at.
+    pub fn classify(&mut self, reading: &PilotReading) -> EVSEMachineInput {
+        let state = self.get_pilot_state(reading.high);
+        let vehicle = matches!(
+            state,
+            EVSEMachineInput::PilotIs9V | EVSEMachineInput::PilotIs6V | EVSEMachineInput::PilotIs3V
+        );
+        let oscillating = reading.high > 0.0 && reading.low < 0.0;
+        if vehicle && oscillating && reading.low > DIODE_CHECK_MAX_LOW {
+            EVSEMachineInput::DiodeCheckFailed
+        } else {
+            state
+        }
+    }
+
+    // Forgets the last state, e.g. after the pilot was switched off.
+    pub fn reset(&mut self) {
+        self.last_state = None;
+    }
+}
+
+impl Default for PilotClassifier {
+    fn default() -> Self {
+        Self::new(DEFAULT_PILOT_HYSTERESIS)
+    }
+}
+
+// The maximum current of hardware that is not told otherwise.
+pub const DEFAULT_MAX_CURRENT: f64 = 32.0;
+
+// The hardware the state machine drives. Pilot readings and faults are
+// delivered through channels so the machine can wait on both at once.
+pub trait EVSEHardware: Send + 'static {
+    // Offers above max_current() are limited to it.
+    fn set_pilot(&mut self, signal: PilotSignal) -> Result<(), EVSEError>;
+    fn set_contactor(&mut self, on: bool) -> Result<(), EVSEError>;
+    fn run_gfi_self_test(&mut self) -> Result<(), EVSEError>;
+    fn pilot_channel(&self) -> Receiver<PilotReading>;
+    fn fault_channel(&self) -> Receiver<EVSEMachineInput>;
+
+    // The highest offer the hardware makes.
+    fn max_current(&self) -> f64 {
+        DEFAULT_MAX_CURRENT
+    }
+
+    // Changes the highest offer, for hardware that limits the offers
+    // itself. The machine checks the current and that the vehicle does not
+    // charge.
+    fn set_max_current(&mut self, _ampere: f64) -> Result<(), EVSEError> {
+        Ok(())
+    }
+
+    // Health of the pilot acquisition, for hardware that measures it.
+    fn acquisition_health(&self) -> Option<AcquisitionHealth> {
+        None
+    }
+
+    // Enclosure temperature and mains RMS voltage, for hardware that
+    // measures them. Kept with the faults for the fault analytics.
+    fn temperature_c(&self) -> Option<f64> {
+        None
+    }
+
+    fn mains_volts(&self) -> Option<f64> {
+        None
+    }
+
+    // The mains frequency, for hardware that samples the mains waveform.
+    fn mains_frequency_hz(&self) -> Option<f64> {
+        None
+    }
+
+    // The last current sense reading, for hardware that has one.
+    fn current_amps(&self) -> Option<f64> {
+        None
+    }
+
+    // The last reading of the GFI CT, for hardware that has it on the ADC.
+    fn residual_milliamps(&self) -> Option<f64> {
+        None
+    }
+
+    // The temperatures of the power contacts of the socket, for sockets
+    // with sensors there (see connector_temp.rs).
+    fn connector_temperatures_c(&self) -> Vec<f64> {
+        Vec::new()
+    }
+
+    // Told on every change of state, for hardware that samples by state.
+    fn set_machine_state(&mut self, _state: EVSEMachineState) {}
+
+    // The RMS currents of the three lines, for hardware with a CT on each.
+    fn phase_currents(&self) -> Option<[f64; 3]> {
+        None
+    }
+
+    // The RMS currents of both lines of a split-phase installation, for
+    // hardware with a CT on each.
+    fn line_currents(&self) -> Option<[f64; 2]> {
+        None
+    }
+
+    // Told of every pilot reading the machine takes, for hardware that
+    // checks it against the pilot it drives.
+    fn pilot_read(&mut self, _reading: &PilotReading) {}
+
+    // The commanded outputs that read back otherwise since the last call,
+    // for hardware that reads them back (see readback.rs).
+    fn discrepancies(&mut self) -> Vec<Discrepancy> {
+        Vec::new()
+    }
+
+    // Whether the control contact of the grid operator is asserted, for
+    // hardware that has one.
+    fn control_contact(&self) -> Option<bool> {
+        None
+    }
+
+    // Drives the alarm output, for hardware that has one.
+    fn set_alarm(&mut self, _on: bool) -> Result<(), EVSEError> {
+        Ok(())
+    }
+
+    // Drives the ventilation relay, for hardware that has one.
+    fn set_ventilation(&mut self, _on: bool) -> Result<(), EVSEError> {
+        Ok(())
+    }
+
+    // Lets the plug go, for hardware with a plug lock.
+    fn release_plug_lock(&mut self) -> Result<(), EVSEError> {
+        Ok(())
+    }
+
+    // Handles the control watchdog uses to force the station safe if the
+    // machine loop stalls. They must not depend on anything the loop may
+    // be stuck on. Without them the loop runs unwatched.
+    fn reserved_safe_state(&self) -> Option<Box<dyn SafeState>> {
+        None
+    }
+}
+
+// Source of the pilot feedback samples. Implemented by the ADC on the hat;
+// other boards can bring their own.
+pub trait PilotSampler: Send + 'static {
+    // Samples the pilot as fast as possible and returns the voltages in the
+    // order they were read.
+    fn read_pilot_samples(&mut self, samples: usize) -> Result<Vec<f32>, EVSEError>;
+
+    // Averages the given number of conversions of the current sense, for
+    // samplers that have one.
+    fn read_current(&mut self, _conversions: usize) -> Option<Result<f32, EVSEError>> {
+        None
+    }
+
+    // One reading of another signal, for samplers that have it.
+    fn read_signal(&mut self, _signal: Signal) -> Option<Result<f32, EVSEError>> {
+        None
+    }
+
+    // Reads a signal back to back for `window`, with the time of every
+    // reading from the first.
+    fn read_waveform(&mut self, signal: Signal, window: Duration) -> Option<Result<Vec<(Duration, f32)>, EVSEError>> {
+        let start = Instant::now();
+        let mut samples = Vec::new();
+        while start.elapsed() < window {
+            match self.read_signal(signal)? {
+                Ok(volts) => samples.push((start.elapsed(), volts)),
+                Err(error) => return Some(Err(error)),
+            }
+        }
+        Some(Ok(samples))
+    }
+}
+
+impl PilotSampler for Adc {
+    fn read_pilot_samples(&mut self, samples: usize) -> Result<Vec<f32>, EVSEError> {
+        Ok(Adc::read_pilot_samples(self, samples)?)
+    }
+
+    fn read_current(&mut self, conversions: usize) -> Option<Result<f32, EVSEError>> {
+        match self.read_current_oversampled(conversions) {
+            // A board without a current sense.
+            Err(AdcError::NoChannel(_)) => None,
+            result => Some(result.map_err(EVSEError::from)),
+        }
+    }
+
+    fn read_signal(&mut self, signal: Signal) -> Option<Result<f32, EVSEError>> {
+        Some(Adc::read_signal(self, signal)?.map_err(EVSEError::from))
+    }
+}
+
+// How long a vehicle may pause charging unless set otherwise. Long enough
+// to wait for a cheap overnight tariff.
+pub const DEFAULT_MAX_PAUSE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Number of ADC conversions in one pilot sampling window.
+const PILOT_SAMPLES: usize = 100;
+// While the pilot rests at 12 V, the wait for the next window is spent
+// probing it with a few conversions every PROBE_INTERVAL, and cut short as
+// soon as it leaves 12 V. A vehicle plugged in is seen within a probe or
+// two instead of a whole interval.
+const PROBE_INTERVAL: Duration = Duration::from_millis(20);
+const PROBE_SAMPLES: usize = 4;
+// Read once after every pilot window, if the sampler has them.
+const AUXILIARY_SIGNALS: [Signal; 7] = [
+    Signal::CurrentL2,
+    Signal::CurrentL3,
+    Signal::ProximityPp,
+    Signal::Temperature,
+    Signal::ResidualCurrent,
+    Signal::ConnectorTemp1,
+    Signal::ConnectorTemp2,
+];
+// The mains voltage is sampled as a waveform for MAINS_WINDOW every
+// MAINS_INTERVAL, and its RMS and frequency measured over the whole cycles
+// in it, see mains.rs. Five cycles at 50 Hz.
+const MAINS_WINDOW: Duration = Duration::from_millis(100);
+const MAINS_INTERVAL: Duration = Duration::from_secs(1);
+const GFI_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct EVSEHardwareImpl {
+    // The pilot writes go through the actuator thread; the reserved handle
+    // reaches the PWM directly for the control watchdog.
+    pilot: PilotActuator,
+    reserved_pilot: ReservedPilot,
+    peripherals: GpioPeripherals,
+    pilot_rx: Receiver<PilotReading>,
+    fault_rx: Receiver<EVSEMachineInput>,
+    // The maximum current of the installation.
+    max_current: f64,
+    // The latest values of the acquisition thread, which owns the ADC.
+    acquisition: AcquisitionReader,
+    schedule: AcquisitionSchedule,
+    // Taken by the GFI self test.
+    interlock: PilotInterlock,
+    // Commands against the relay test line, the pilot readings and the GFI
+    // status. The fault thread reads the lines back.
+    readback: Arc<Mutex<ReadbackMonitor>>,
+    // The acquisition and fault threads, stopped and joined on drop.
+    threads: Vec<JoinHandle<()>>,
+    faults_stopped: Arc<AtomicBool>,
+}
+
+// Builds an EVSEHardwareImpl. Components that are not given are created
+// for the EVSE Pi Hat.
+#[derive(Default)]
+pub struct EVSEHardwareBuilder {
+    pwm: PwmAssignment,
+    pilot_driver: Option<PilotDriver>,
+    pilot: Option<Pilot>,
+    peripherals: Option<GpioPeripherals>,
+    sampler: Option<Box<dyn PilotSampler>>,
+    channels: Option<ChannelRegistry>,
+    contactor_drive: Option<ContactorDrive>,
+    gfi_driver: Option<GfiDriver>,
+    power_good_pin: Option<u8>,
+    tamper_pin: Option<u8>,
+    alarm_pin: Option<u8>,
+    control_contact_pin: Option<u8>,
+    ventilation_pin: Option<u8>,
+    gpio_pins: Option<GpioPins>,
+    spi: Option<SpiConfig>,
+    peaks: PeakPercentiles,
+    sampling: PilotSampling,
+    oversampling: OversamplingPolicy,
+    slew_rate: Option<f64>,
+    max_current: Option<f64>,
+    timing: J1772Timing,
+}
+
+impl EVSEHardwareBuilder {
+    // Only used for the components created by the builder: the pilot
+    // channel when no pilot is given, the watchdog channel when no
+    // peripherals are given.
+    pub fn pwm_assignment(mut self, pwm: PwmAssignment) -> Self {
+        self.pwm = pwm;
+        self
+    }
+
+    // The board's config file: its GPIO numbers and the SPI bus of its
+    // ADC.
+    pub fn config(self, config: &Config) -> Self {
+        self.gpio_pins(config.gpio).spi(config.spi).max_current(config.max_current)
+    }
+
+    // Only used when no peripherals are given. The pins set one by one
+    // take precedence.
+    pub fn gpio_pins(mut self, pins: GpioPins) -> Self {
+        self.gpio_pins = Some(pins);
+        self
+    }
+
+    // Only used when no sampler is given.
+    pub fn spi(mut self, spi: SpiConfig) -> Self {
+        self.spi = Some(spi);
+        self
+    }
+
+    // Used when no pilot is given instead of the pilot channel of the PWM
+    // assignment.
+    pub fn pilot_driver(mut self, driver: PilotDriver) -> Self {
+        self.pilot_driver = Some(driver);
+        self
+    }
+
+    pub fn pilot(mut self, pilot: Pilot) -> Self {
+        self.pilot = Some(pilot);
+        self
+    }
+
+    pub fn peripherals(mut self, peripherals: GpioPeripherals) -> Self {
+        self.peripherals = Some(peripherals);
+        self
+    }
+
+    pub fn pilot_sampler<S: PilotSampler>(mut self, sampler: S) -> Self {
+        self.sampler = Some(Box::new(sampler));
+        self
+    }
+
+    // Only used when no sampler is given: the ADC inputs of the signals,
+    // instead of the hat's.
+    pub fn channel_registry(mut self, channels: ChannelRegistry) -> Self {
+        self.channels = Some(channels);
+        self
+    }
+
+    pub fn contactor_drive(mut self, drive: ContactorDrive) -> Self {
+        self.contactor_drive = Some(drive);
+        self
+    }
+
+    // Only used when no peripherals are given. The EVSE Pi Hat latches.
+    pub fn gfi_driver(mut self, driver: GfiDriver) -> Self {
+        self.gfi_driver = Some(driver);
+        self
+    }
+
+    // GPIO of the power good output of a UPS hat.
+    pub fn power_good_pin(mut self, pin: u8) -> Self {
+        self.power_good_pin = Some(pin);
+        self
+    }
+
+    // How the high and low level of the pilot are taken from the samples.
+    pub fn peak_percentiles(mut self, peaks: PeakPercentiles) -> Self {
+        self.peaks = peaks;
+        self
+    }
+
+    // GPIO of the enclosure door/tamper switch.
+    pub fn tamper_pin(mut self, pin: u8) -> Self {
+        self.tamper_pin = Some(pin);
+        self
+    }
+
+    // GPIO of the alarm output, for a fault LED or an alarm panel.
+    pub fn alarm_pin(mut self, pin: u8) -> Self {
+        self.alarm_pin = Some(pin);
+        self
+    }
+
+    // GPIO of the potential-free control contact of the grid operator or
+    // an energy management system.
+    pub fn control_contact_pin(mut self, pin: u8) -> Self {
+        self.control_contact_pin = Some(pin);
+        self
+    }
+
+    // GPIO of the relay of the ventilation fan.
+    pub fn ventilation_pin(mut self, pin: u8) -> Self {
+        self.ventilation_pin = Some(pin);
+        self
+    }
+
+    // Highest rate in A/s at which the offer goes up.
+    pub fn pilot_slew_rate(mut self, amps_per_sec: f64) -> Self {
+        self.slew_rate = Some(amps_per_sec);
+        self
+    }
+
+    // The most the installation carries, DEFAULT_MAX_CURRENT if not given.
+    pub fn max_current(mut self, ampere: f64) -> Self {
+        self.max_current = Some(ampere);
+        self
+    }
+
+    // The J1772 timings; the state debounce is the pilot sampling interval.
+    pub fn timing(mut self, timing: J1772Timing) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    pub fn pilot_sampling(mut self, sampling: PilotSampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    // Conversions averaged into a current reading in each state.
+    pub fn current_oversampling(mut self, policy: OversamplingPolicy) -> Self {
+        self.oversampling = policy;
+        self
+    }
+
+    pub fn build(self) -> Result<EVSEHardwareImpl, EVSEError> {
+        self.timing.validate()?;
+        let max_current = self.max_current.unwrap_or(DEFAULT_MAX_CURRENT);
+        if !is_valid_max_current(max_current) {
+            return Err(EVSEError::InvalidMaxCurrent(max_current));
+        }
+        let pilot = match self.pilot {
+            Some(pilot) => pilot,
+            None => Pilot::with_driver(self.pilot_driver.unwrap_or(PilotDriver::HardwarePwm(self.pwm.pilot())))?,
+        };
+        let mut peripherals = match self.peripherals {
+            Some(peripherals) => {
+                if let Some(pin) = self.power_good_pin {
+                    peripherals.set_power_good_pin(pin)?;
+                }
+                if let Some(pin) = self.tamper_pin {
+                    peripherals.set_tamper_pin(pin)?;
+                }
+                if let Some(pin) = self.alarm_pin {
+                    peripherals.set_alarm_pin(pin)?;
+                }
+                if let Some(pin) = self.control_contact_pin {
+                    peripherals.set_control_contact_pin(pin)?;
+                }
+                if let Some(pin) = self.ventilation_pin {
+                    peripherals.set_ventilation_pin(pin)?;
+                }
+                peripherals
+            }
+            None => {
+                let defaults = BoardProfile::default();
+                let mut pins = self.gpio_pins.unwrap_or_default();
+                pins.power_good = self.power_good_pin.or(pins.power_good);
+                pins.tamper = self.tamper_pin.or(pins.tamper);
+                pins.alarm = self.alarm_pin.or(pins.alarm);
+                pins.control_contact = self.control_contact_pin.or(pins.control_contact);
+                pins.ventilation = self.ventilation_pin.or(pins.ventilation);
+                let profile = BoardProfile {
+                    power_watchdog: match self.pwm.watchdog() {
+                        Some(channel) => PowerWatchdog::HardwarePwm(channel),
+                        None => defaults.power_watchdog,
+                    },
+                    gfi: self.gfi_driver.unwrap_or(defaults.gfi),
+                    pins,
+                };
+                GpioPeripherals::with_profile(profile)?
+            }
+        };
+        if let Some(drive) = self.contactor_drive {
+            peripherals.set_contactor_drive(drive)?;
+        }
+        let sampler = match self.sampler {
+            Some(sampler) => sampler,
+            None => Box::new(Adc::with_spi(self.channels.unwrap_or_default(), self.spi.unwrap_or_default())?),
+        };
+
+        let interlock = PilotInterlock::default();
+        let sampler = Box::new(InterlockedSampler::new(sampler, interlock.clone()));
+
+        let (pilot_tx, pilot_rx) = unbounded();
+        let peaks = self.peaks;
+        let sampling = self.sampling;
+        let interval = self.timing.state_debounce;
+        let metrics = AcquisitionMetrics::default();
+        let acquisition = metrics.reader();
+        let schedule = AcquisitionSchedule::new(self.oversampling);
+        let acquisition_schedule = schedule.clone();
+        let sampling_thread = thread::spawn(move || {
+            EVSEHardwareImpl::sample_pilot(sampler, peaks, sampling, interval, acquisition_schedule, metrics, pilot_tx)
+        });
+
+        let (fault_tx, fault_rx) = unbounded();
+        let fault_peripherals = peripherals.clone();
+        let faults_stopped = Arc::new(AtomicBool::new(false));
+        let stopped = faults_stopped.clone();
+        let readback = Arc::new(Mutex::new(ReadbackMonitor::new()));
+        let fault_readback = readback.clone();
+        let fault_thread = thread::spawn(move || {
+            EVSEHardwareImpl::watch_faults(fault_peripherals, fault_tx, fault_readback, stopped)
+        });
+
+        let reserved_pilot = pilot.reserved_handle()?;
+        Ok(EVSEHardwareImpl {
+            pilot: PilotActuator::interlocked(pilot, self.slew_rate, interlock.clone()),
+            reserved_pilot,
+            peripherals,
+            pilot_rx,
+            fault_rx,
+            max_current,
+            acquisition,
+            schedule,
+            interlock,
+            readback,
+            threads: vec![sampling_thread, fault_thread],
+            faults_stopped,
+        })
+    }
+}
+
+impl EVSEHardwareImpl {
+    pub fn builder() -> EVSEHardwareBuilder {
+        EVSEHardwareBuilder::default()
+    }
+
+    pub fn new() -> Result<Self, EVSEError> {
+        Self::builder().build()
+    }
+
+    pub fn with_pwm_assignment(pwm: PwmAssignment) -> Result<Self, EVSEError> {
+        Self::builder().pwm_assignment(pwm).build()
+    }
+
+    pub fn with_max_current(ampere: f64) -> Result<Self, EVSEError> {
+        Self::builder().max_current(ampere).build()
+    }
+
+    // The latest pilot reading, currents and signals, for other threads.
+    pub fn acquisition(&self) -> AcquisitionReader {
+        self.acquisition.clone()
+    }
+
+    pub fn set_contactor_drive(&mut self, drive: ContactorDrive) -> Result<(), EVSEError> {
+        self.peripherals.set_contactor_drive(drive)?;
+        Ok(())
+    }
+
+    // Watches the power good output of a UPS hat on the given GPIO.
+    pub fn set_power_good_pin(&mut self, pin: u8) -> Result<(), EVSEError> {
+        self.peripherals.set_power_good_pin(pin)?;
+        Ok(())
+    }
+
+    // Runs until the machine drops the pilot channel or the schedule is
+    // stopped.
+    pub(crate) fn sample_pilot(
+        mut sampler: Box<dyn PilotSampler>,
+        peaks: PeakPercentiles,
+        sampling: PilotSampling,
+        interval: Duration,
+        schedule: AcquisitionSchedule,
+        metrics: AcquisitionMetrics,
+        pilot_tx: Sender<PilotReading>,
+    ) {
+        let mut mains_read_at: Option<Instant> = None;
+        while !schedule.is_stopped() {
+            let start = Instant::now();
+            let reading = match sampler.read_pilot_samples(PILOT_SAMPLES) {
+                Ok(samples) => {
+                    let end = Instant::now();
+                    metrics.record_window(start, end, samples.len());
+                    let window = end - start;
+                    match sampling {
+                        PilotSampling::Asynchronous => PilotReading::from_samples_with_peaks(&samples, window, peaks),
+                        PilotSampling::PlateauFiltered => PilotReading::from_plateaus(&samples, window, peaks),
+                    }
+                }
+                // A failed conversion is reported as an unusable reading.
+                Err(_) => {
+                    metrics.record_error(start);
+                    PilotReading::from_samples(&[], Duration::ZERO)
+                }
+            };
+            metrics.record_pilot(reading);
+            if pilot_tx.send(reading).is_err() {
+                return;
+            }
+            let conversions = schedule.current_conversions();
+            let amps = match conversions {
+                0 => None,
+                conversions => sampler.read_current(conversions).and_then(Result::ok),
+            };
+            if let Some(amps) = amps.filter(|_| schedule.reads_zero()) {
+                metrics.calibrate_zero(f64::from(amps));
+            }
+            metrics.record_current(amps.map(f64::from), conversions);
+            for signal in AUXILIARY_SIGNALS {
+                if let Some(value) = sampler.read_signal(signal) {
+                    metrics.record_signal(signal, value.ok().map(f64::from));
+                }
+            }
+            if mains_read_at.is_none_or(|at| at.elapsed() >= MAINS_INTERVAL) {
+                mains_read_at = Some(Instant::now());
+                if let Some(waveform) = sampler.read_waveform(Signal::MainsV, MAINS_WINDOW) {
+                    Self::record_mains(&metrics, waveform.ok());
+                }
+            }
+            // Steady at 12 V, no PWM: nothing plugged in.
+            let resting = reading.high >= EDGE_12V_9V && reading.low >= EDGE_12V_9V;
+            Self::wait_for_next_window(sampler.as_mut(), interval, resting);
+        }
+    }
+
+    // A mains input without a whole cycle in the window measures a level,
+    // not a waveform, and is averaged as such.
+    fn record_mains(metrics: &AcquisitionMetrics, waveform: Option<Vec<(Duration, f32)>>) {
+        let Some(waveform) = waveform.filter(|samples| !samples.is_empty()) else {
+            metrics.record_signal(Signal::MainsV, None);
+            metrics.record_mains_frequency(None);
+            return;
+        };
+        match measure_cycles(&waveform) {
+            Some(measured) => {
+                metrics.record_signal(Signal::MainsV, Some(measured.rms_volts));
+                metrics.record_mains_frequency(Some(measured.frequency_hz));
+            }
+            None => {
+                let mean = waveform.iter().map(|&(_, volts)| f64::from(volts)).sum::<f64>() / waveform.len() as f64;
+                metrics.record_signal(Signal::MainsV, Some(mean));
+                metrics.record_mains_frequency(None);
+            }
+        }
+    }
+
+    fn wait_for_next_window(sampler: &mut dyn PilotSampler, interval: Duration, resting: bool) {
+        if !resting {
+            thread::sleep(interval);
+            return;
+        }
+        let deadline = Instant::now() + interval;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return;
+            }
+            thread::sleep(PROBE_INTERVAL.min(left));
+            // A failed probe leaves it to the next window.
+            let left_12v = sampler
+                .read_pilot_samples(PROBE_SAMPLES)
+                .is_ok_and(|samples| !samples.is_empty() && samples.iter().all(|&volts| volts < EDGE_12V_9V));
+            if left_12v {
+                return;
+            }
+        }
+    }
+
+    // Reports a rising GFI status, except while the self test trips it on
+    // purpose, the loss of mains power and the enclosure being opened. Reads
+    // back the relay test line and the GFI status on the way.
+    fn watch_faults(
+        peripherals: GpioPeripherals,
+        fault_tx: Sender<EVSEMachineInput>,
+        readback: Arc<Mutex<ReadbackMonitor>>,
+        stopped: Arc<AtomicBool>,
+    ) {
+        let mut was_set = false;
+        let mut was_power_good = true;
+        let mut was_open = false;
+        while !stopped.load(Ordering::SeqCst) {
+            let gfi_set = peripherals.is_gfi_set();
+            {
+                let mut readback = readback.lock().unwrap();
+                readback.measure_contactor(peripherals.relay_test(), Instant::now());
+                readback.measure_gfi(gfi_set);
+            }
+            let is_set = gfi_set && !peripherals.is_self_test_active();
+            if is_set && !was_set && fault_tx.send(EVSEMachineInput::GFIInterrupted).is_err() {
+                return;
+            }
+            was_set = is_set;
+
+            let is_power_good = peripherals.is_power_good();
+            if !is_power_good && was_power_good && fault_tx.send(EVSEMachineInput::PowerLost).is_err() {
+                return;
+            }
+            was_power_good = is_power_good;
+
+            let is_open = peripherals.is_enclosure_open();
+            if is_open && !was_open && fault_tx.send(EVSEMachineInput::EnclosureOpened).is_err() {
+                return;
+            }
+            was_open = is_open;
+            thread::sleep(GFI_POLL_INTERVAL);
+        }
+    }
+}
+
+impl EVSEHardware for EVSEHardwareImpl {
+    fn set_pilot(&mut self, signal: PilotSignal) -> Result<(), EVSEError> {
+        let signal = match signal {
+            PilotSignal::OfferAmps(ampere) => PilotSignal::OfferAmps(ampere.min(self.max_current)),
+            signal => signal,
+        };
+        self.pilot.set_signal(signal)?;
+        self.readback.lock().unwrap().command_pilot(signal, Instant::now());
+        Ok(())
+    }
+
+    fn max_current(&self) -> f64 {
+        self.max_current
+    }
+
+    fn set_max_current(&mut self, ampere: f64) -> Result<(), EVSEError> {
+        self.max_current = ampere;
+        Ok(())
+    }
+
+    fn set_contactor(&mut self, on: bool) -> Result<(), EVSEError> {
+        self.readback.lock().unwrap().command_contactor(on, Instant::now());
+        if on {
+            self.schedule.set_contactor(true);
+        }
+        self.peripherals.set_power(on)?;
+        if !on {
+            self.schedule.set_contactor(false);
+        }
+        Ok(())
+    }
+
+    // Owns the pilot while it runs: no pilot writes, no pilot windows.
+    fn run_gfi_self_test(&mut self) -> Result<(), EVSEError> {
+        let _token = self.interlock.take();
+        self.readback.lock().unwrap().command_gfi_test(true);
+        let result = self.peripherals.run_gfi_self_test();
+        self.readback.lock().unwrap().command_gfi_test(false);
+        result?;
+        Ok(())
+    }
+
+    fn pilot_channel(&self) -> Receiver<PilotReading> {
+        self.pilot_rx.clone()
+    }
+
+    fn fault_channel(&self) -> Receiver<EVSEMachineInput> {
+        self.fault_rx.clone()
+    }
+
+    fn acquisition_health(&self) -> Option<AcquisitionHealth> {
+        Some(self.acquisition.health())
+    }
+
+    fn current_amps(&self) -> Option<f64> {
+        self.acquisition.current_amps()
+    }
+
+    fn temperature_c(&self) -> Option<f64> {
+        self.acquisition.signal(Signal::Temperature)
+    }
+
+    fn mains_volts(&self) -> Option<f64> {
+        self.acquisition.signal(Signal::MainsV)
+    }
+
+    fn mains_frequency_hz(&self) -> Option<f64> {
+        self.acquisition.mains_frequency_hz()
+    }
+
+    fn residual_milliamps(&self) -> Option<f64> {
+        self.acquisition.signal(Signal::ResidualCurrent)
+    }
+
+    fn connector_temperatures_c(&self) -> Vec<f64> {
+        [Signal::ConnectorTemp1, Signal::ConnectorTemp2]
+            .into_iter()
+            .filter_map(|signal| self.acquisition.signal(signal))
+            .collect()
+    }
+
+    // With a current sense on every line.
+    fn phase_currents(&self) -> Option<[f64; 3]> {
+        Some([
+            self.acquisition.current_amps()?,
+            self.acquisition.signal(Signal::CurrentL2)?,
+            self.acquisition.signal(Signal::CurrentL3)?,
+        ])
+    }
+
+    // Split-phase: a current sense on both lines and none on a third.
+    fn line_currents(&self) -> Option<[f64; 2]> {
+        if self.acquisition.signal(Signal::CurrentL3).is_some() {
+            return None;
+        }
+        Some([self.acquisition.current_amps()?, self.acquisition.signal(Signal::CurrentL2)?])
+    }
+
+    fn set_machine_state(&mut self, state: EVSEMachineState) {
+        self.schedule.set_state(state);
+    }
+
+    fn pilot_read(&mut self, reading: &PilotReading) {
+        self.readback.lock().unwrap().measure_pilot(reading, Instant::now());
+    }
+
+    fn discrepancies(&mut self) -> Vec<Discrepancy> {
+        self.readback.lock().unwrap().take_discrepancies()
+    }
+
+    fn control_contact(&self) -> Option<bool> {
+        self.peripherals.is_control_contact_asserted()
+    }
+
+    fn set_alarm(&mut self, on: bool) -> Result<(), EVSEError> {
+        self.peripherals.set_alarm(on);
+        Ok(())
+    }
+
+    fn set_ventilation(&mut self, on: bool) -> Result<(), EVSEError> {
+        self.peripherals.set_ventilation(on);
+        Ok(())
+    }
+
+    fn reserved_safe_state(&self) -> Option<Box<dyn SafeState>> {
+        Some(Box::new(ReservedSafeState {
+            pilot: self.reserved_pilot.clone(),
+            contactor: self.peripherals.reserved_contactor(),
+        }))
+    }
+}
+
+// The pilot and the contactor pin, reached without the locks the
+// machine loop goes through.
+struct ReservedSafeState {
+    pilot: ReservedPilot,
+    contactor: ReservedContactor,
+}
+
+impl SafeState for ReservedSafeState {
+    fn force_safe(&mut self) {
+        if let Err(error) = self.pilot.force_no_offer() {
+            eprintln!("Control watchdog failed to take the offer away: {:?}", error);
+        }
+        if let Err(error) = self.contactor.force_off() {
+            eprintln!("Control watchdog failed to open the contactor: {:?}", error);
+        }
+    }
+}
+
+// When the machine exits, or the hardware is dropped before it ran: no
+// pins stay energized and no thread keeps polling them.
+impl Drop for EVSEHardwareImpl {
+    fn drop(&mut self) {
+        make_safe(self);
+        self.schedule.stop();
+        self.faults_stopped.store(true, Ordering::SeqCst);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+// The state the machine moves to on an input, or None if the input does not
+// change the state.
+pub(crate) fn next_state(state: EVSEMachineState, input: EVSEMachineInput) -> Option<EVSEMachineState> {
+    use EVSEMachineInput::*;
+    use EVSEMachineState::*;
+
+    match (state, input) {
+        (FailedStation | PowerFailure, _) => None,
+        (_, GFIInterrupted) => Some(FailedStation),
+        (_, PowerLost) => Some(PowerFailure),
+        // Only an admin reset leaves the lockout.
+        (TamperLockout, _) => None,
+        // Opening the enclosure of an idle station is maintenance.
+        (Charging, EnclosureOpened) => Some(TamperLockout),
+
+        // Going from A straight to C or D is illegal.
+        (Standby, PilotIs9V) => Some(VehicleDetected),
+        (Standby, PilotIs6V | PilotIs3V | PilotInError) => Some(ResetableError),
+
+        (VehicleDetected, PilotIs12V) => Some(Standby),
+        (VehicleDetected, PilotIs6V) => Some(StartCharging),
+        (VehicleDetected, PilotIs3V) => Some(VentilationNeeded),
+        (VehicleDetected, PilotInError) => Some(ResetableError),
+
+        (StartCharging, SelfTestOk) => Some(Charging),
+        (StartCharging, SelfTestFailed) => Some(FailedStation),
+
+        (Charging, PilotIs12V) => Some(StopCharging),
+        (Charging, PilotIs9V) => Some(SuspendedEV),
+        (Charging, PilotIs3V) => Some(VentilationNeeded),
+        (Charging, PilotInError) => Some(ResetableError),
+
+        // The GFI self test runs again before the contactor closes.
+        (SuspendedEV, PilotIs6V) => Some(StartCharging),
+        (SuspendedEV, PilotIs3V) => Some(VentilationNeeded),
+        (SuspendedEV, PilotIs12V | PauseTimedOut) => Some(StopCharging),
+        (SuspendedEV, PilotInError) => Some(ResetableError),
+
+        (StopCharging, PilotIs12V) => Some(Standby),
+        (StopCharging, PilotIs9V) => Some(VehicleDetected),
+        (StopCharging, PilotInError) => Some(ResetableError),
+
+        (VentilationNeeded, PilotIs12V) => Some(Standby),
+        (VentilationNeeded, PilotIs9V) => Some(VehicleDetected),
+        (VentilationNeeded, PilotIs6V) => Some(StartCharging),
+        (VentilationNeeded, PilotInError) => Some(ResetableError),
+
+        (ResetableError, UnpluggedInGrace) => Some(Standby),
+
+        // The GFI self test after the cooldown of a trip.
+        (GFITripped, SelfTestOk) => Some(Standby),
+        (GFITripped, SelfTestFailed) => Some(FailedStation),
+
+        (
+            VehicleDetected | StartCharging | Charging | SuspendedEV | StopCharging | VentilationNeeded,
+            DiodeCheckFailed,
+        ) => Some(ResetableError),
+        (Charging, LineImbalance) => Some(ResetableError),
+
+        _ => None,
+    }
+}
+
+// Drives the hardware for a newly entered state. Some states immediately
+// produce the next input, which is returned.
+pub(crate) fn do_state_transition<H: EVSEHardware>(
+    evse: &mut H,
+    state: EVSEMachineState,
+    current_limit: f64,
+) -> Result<Option<EVSEMachineInput>, EVSEError> {
+    match state {
+        EVSEMachineState::Standby => {
+            evse.set_contactor(false)?;
+            evse.set_pilot(PilotSignal::SteadyPlus12)?;
+        }
+        EVSEMachineState::VehicleDetected => {
+            evse.set_pilot(PilotSignal::OfferAmps(current_limit))?;
+        }
+        EVSEMachineState::StartCharging => {
+            return match evse.run_gfi_self_test() {
+                Ok(()) => Ok(Some(EVSEMachineInput::SelfTestOk)),
+                Err(_) => Ok(Some(EVSEMachineInput::SelfTestFailed)),
+            };
+        }
+        EVSEMachineState::Charging => {
+            evse.set_contactor(true)?;
+        }
+        EVSEMachineState::SuspendedEV | EVSEMachineState::StopCharging => {
+            evse.set_contactor(false)?;
+        }
+        EVSEMachineState::VentilationNeeded => {
+            // Refused: the installation is not ventilated. The offer stays
+            // up, so a vehicle that can do without goes on in state C.
+            evse.set_contactor(false)?;
+        }
+        EVSEMachineState::ResetableError
+        | EVSEMachineState::FailedStation
+        | EVSEMachineState::GFITripped
+        | EVSEMachineState::PowerFailure
+        | EVSEMachineState::TamperLockout => {
+            make_safe(evse);
+        }
+    }
+    Ok(None)
+}
+
+// Opens the contactor and stops offering current. Errors are ignored, this
+// is already the last resort.
+fn make_safe<H: EVSEHardware>(evse: &mut H) {
+    let _ = evse.set_contactor(false);
+    let _ = evse.set_pilot(PilotSignal::ErrorMinus12);
+}
+
+// Takes the offer away and waits, up to the stop response time, for a
+// charging vehicle to leave state C, so the contactor does not open under
+// load.
+fn shut_down<H: EVSEHardware>(
+    evse: &mut H,
+    state: EVSEMachineState,
+    pilot_rx: &Receiver<PilotReading>,
+    classifier: &mut PilotClassifier,
+    stop_response: Duration,
+) {
+    if state == EVSEMachineState::Charging && evse.set_pilot(PilotSignal::SteadyPlus12).is_ok() {
+        let deadline = Instant::now() + stop_response;
+        while let Ok(reading) = pilot_rx.recv_deadline(deadline) {
+            if classifier.get_pilot_state(reading.high) != EVSEMachineInput::PilotIs6V {
+                break;
+            }
+        }
+    }
+    make_safe(evse);
+}
+
+fn vehicle_present(input: EVSEMachineInput) -> Option<bool> {
+    match input {
+        EVSEMachineInput::PilotIs12V => Some(false),
+        EVSEMachineInput::PilotIs9V | EVSEMachineInput::PilotIs6V | EVSEMachineInput::PilotIs3V => Some(true),
+        _ => None,
+    }
+}
+
+// How often the alarm output toggles while the station is locked out.
+const LOCKOUT_BLINK: Duration = Duration::from_millis(500);
+
+// How a lockout ended.
+enum Lockout {
+    Stopped(EVSEMachineState),
+    Reset,
+    // Reset from the resume link of the interrupted session.
+    Resumed,
+}
+
+// How often a locked out station raises the pilot to look for a vehicle.
+const LOCKOUT_PROBE: Duration = Duration::from_secs(1);
+
+// A failed station is locked out until an admin resets it or it is
+// stopped: the contactor stays open, the pilot is held at -12 V so no
+// vehicle draws current, and the alarm output blinks. Since no vehicle
+// shows at -12 V, the pilot is raised to +12 V, without an offer, once a
+// second for a single reading. That reading goes into the status,
+// and vehicles plugged in and unplugged go into the audit log. No input
+// takes the station out of the lockout.
+fn watch_latched<H: EVSEHardware>(
+    evse: &mut H,
+    status: &Mutex<MachineStatus>,
+    command_rx: &Receiver<EvseCommand>,
+    audit_log: &AuditLog,
+    control_watchdog: Option<&ControlWatchdog>,
+) -> Lockout {
+    let state = EVSEMachineState::FailedStation;
+    let pilot_rx = evse.pilot_channel();
+    let fault_rx = evse.fault_channel();
+    let blink = crossbeam_channel::tick(LOCKOUT_BLINK);
+    let probe = crossbeam_channel::tick(LOCKOUT_PROBE);
+    let mut alarm = true;
+    let mut probing = false;
+    // Whatever was there when the station failed.
+    let mut present = status.lock().unwrap().pilot.and_then(|(_, input)| vehicle_present(input));
+    let mut classifier = PilotClassifier::default();
+    if let Err(error) = evse.set_contactor(false).and_then(|_| evse.set_pilot(PilotSignal::ErrorMinus12)) {
+        eprintln!("Failed to lock out the station: {:?}", error);
+    }
+    loop {
+        if let Some(watchdog) = control_watchdog {
+            watchdog.pet();
+        }
+        // As get_new_state_input, with the blinking and probing in between.
+        let command = select_biased! {
+            recv(fault_rx) -> fault => match fault {
+                Ok(_) => continue,
+                Err(_) => EvseCommand::Stop,
+            },
+            recv(command_rx) -> command => command.unwrap_or(EvseCommand::Stop),
+            recv(pilot_rx) -> reading => match reading {
+                // Readings at -12 V tell nothing.
+                Ok(_) if !probing => continue,
+                Ok(reading) => {
+                    probing = false;
+                    if let Err(error) = evse.set_pilot(PilotSignal::ErrorMinus12) {
+                        eprintln!("Failed to lock out the station: {:?}", error);
+                    }
+                    let input = classifier.get_pilot_state(reading.high);
+                    status.lock().unwrap().pilot = Some((reading, input));
+                    let now_present = vehicle_present(input);
+                    if now_present.is_some() && now_present != present {
+                        // Without one before, the first reading only tells
+                        // what is there.
+                        if present.is_some() {
+                            let event = match now_present {
+                                Some(true) => "vehicle plugged in while the station is failed",
+                                _ => "vehicle unplugged while the station is failed",
+                            };
+                            record_event(audit_log, event);
+                        }
+                        present = now_present;
+                    }
+                    continue;
+                }
+                Err(_) => EvseCommand::Stop,
+            },
+            recv(probe) -> _ => {
+                if !probing {
+                    match evse.set_pilot(PilotSignal::SteadyPlus12) {
+                        Ok(()) => probing = true,
+                        Err(error) => eprintln!("Failed to set the pilot to look for vehicles: {:?}", error),
+                    }
+                }
+                continue;
+            },
+            recv(blink) -> _ => {
+                alarm = !alarm;
+                if let Err(error) = evse.set_alarm(alarm) {
+                    eprintln!("Failed to set the alarm output: {:?}", error);
+                }
+                continue;
+            },
+        };
+        match command {
+            EvseCommand::ResetLockout => {
+                record_event(audit_log, "lockout reset");
+                return Lockout::Reset;
+            }
+            EvseCommand::ResumeCharging => {
+                record_event(audit_log, "lockout reset from the resume link");
+                return Lockout::Resumed;
+            }
+            EvseCommand::Shutdown => {
+                record_event(audit_log, &format!("orderly shutdown in {:?}", state));
+                make_safe(evse);
+                return Lockout::Stopped(state);
+            }
+            EvseCommand::Stop => {
+                make_safe(evse);
+                return Lockout::Stopped(state);
+            }
+            _ => {}
+        }
+    }
+}
+
+enum MachineEvent {
+    Input(EVSEMachineInput),
+    Pilot(PilotReading, EVSEMachineInput),
+    Command(EvseCommand),
+    // No pilot reading came in time.
+    PilotTimedOut,
+}
+
+// How long the machine goes without a pilot reading before it takes the
+// pilot for failed. The ADC delivers several readings a second.
+pub const DEFAULT_PILOT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Blocks until the next input or command arrives, or until the pilot
+// deadline passes without a reading. Faults take precedence over everything
+// else. A closed channel means the hardware or the handle is gone, which
+// stops the machine.
+fn get_new_state_input(
+    pilot_rx: &Receiver<PilotReading>,
+    fault_rx: &Receiver<EVSEMachineInput>,
+    command_rx: &Receiver<EvseCommand>,
+    classifier: &mut PilotClassifier,
+    pilot_deadline: Option<Instant>,
+) -> MachineEvent {
+    let timeout = pilot_deadline.map_or_else(never, at);
+    select_biased! {
+        recv(fault_rx) -> fault => match fault {
+            Ok(input) => MachineEvent::Input(input),
+            Err(_) => MachineEvent::Command(EvseCommand::Stop),
+        },
+        recv(command_rx) -> command => match command {
+            Ok(command) => MachineEvent::Command(command),
+            Err(_) => MachineEvent::Command(EvseCommand::Stop),
+        },
+        recv(pilot_rx) -> reading => match reading {
+            Ok(reading) => MachineEvent::Pilot(reading, classifier.classify(&reading)),
+            Err(_) => MachineEvent::Command(EvseCommand::Stop),
+        },
+        recv(timeout) -> _ => MachineEvent::PilotTimedOut,
+    }
+}
+
+// Puts the sensor data and commands the machine receives in the black box.
+// The inputs are recorded once they are final.
+fn record_in_black_box(black_box: &mut BlackBox, event: MachineEvent) -> MachineEvent {
+    match &event {
+        MachineEvent::Pilot(reading, _) => black_box.record(BlackBoxEntry::Pilot(*reading)),
+        MachineEvent::Command(command) => black_box.record(BlackBoxEntry::Command(command.clone())),
+        MachineEvent::Input(_) | MachineEvent::PilotTimedOut => {}
+    }
+    event
+}
+
+// What the machine shares with its handle.
+#[derive(Debug, Clone)]
+struct MachineStatus {
+    state: EVSEMachineState,
+    // The last pilot reading and how it was classified.
+    pilot: Option<(PilotReading, EVSEMachineInput)>,
+    // The state the machine was in when mains power was lost.
+    interrupted: Option<EVSEMachineState>,
+    breaker: Option<BreakerStatus>,
+    acquisition: Option<AcquisitionHealth>,
+    // When the machine entered the current state.
+    state_since: Instant,
+    limits: LimiterChain,
+    features: FeatureFlags,
+    shadow_pilot: Option<ShadowReport<EVSEMachineInput>>,
+    // The last lab pattern run.
+    lab: Option<LabReport>,
+    guest: Option<GuestSession>,
+    tenant: Option<TenantSession>,
+    phases: PhaseStatus,
+    cable: CableHealth,
+    brownout: BrownoutStatus,
+    session_id: Option<SessionId>,
+    // The charging curve of the session so far.
+    curve: ChargingCurve,
+    sessions: SessionLog,
+    // Until when unplugging resets a resettable error.
+    grace_until: Option<Instant>,
+    control_contact: Option<bool>,
+    // The last readings of the connector sensors, in °C.
+    connector_temperatures_c: Vec<f64>,
+    metrics: StateMetrics,
+    // The resume link of the last interrupted session.
+    resume: ResumeTokens,
+    counters: ErrorCounters,
+    lifetime: LifetimeCounters,
+    // How close the vehicle of the session is to full, with a current
+    // sense.
+    completion: Option<CompletionEstimate>,
+    // The readings as of the last pilot reading, for the export.
+    sample: Option<SensorSample>,
+}
+
+// A vehicle is being charged, or about to be.
+fn is_charging(state: EVSEMachineState) -> bool {
+    matches!(state, EVSEMachineState::StartCharging | EVSEMachineState::Charging)
+}
+
+// A fault the machine records: the latched ones, and a GFI trip that waits
+// for its retry.
+fn is_recorded_fault(state: EVSEMachineState) -> bool {
+    is_latched_fault(state) || state == EVSEMachineState::GFITripped
+}
+
+// Publishes the limits and offers the vehicle what they allow, if that
+// changed. The loop calls it once the caps of an iteration are in, and the
+// commands that move a cap call it right away.
+fn apply_offer<H: EVSEHardware>(
+    evse: &mut H,
+    status: &Mutex<MachineStatus>,
+    state: EVSEMachineState,
+    limits: LimiterChain,
+    offered: &mut f64,
+    floors: &mut FloorTracker,
+) -> Result<(), EVSEError> {
+    status.lock().unwrap().limits = limits;
+    if is_offering(state) && limits.offer() != *offered {
+        *offered = limits.offer();
+        floors.offer_changed(*offered);
+        evse.set_pilot(PilotSignal::OfferAmps(*offered))?;
+    }
+    Ok(())
+}
+
+// The pilot offers current to the vehicle.
+fn is_offering(state: EVSEMachineState) -> bool {
+    matches!(
+        state,
+        EVSEMachineState::VehicleDetected
+            | EVSEMachineState::StartCharging
+            | EVSEMachineState::Charging
+            | EVSEMachineState::SuspendedEV
+    )
+}
+
+// How the machine is started.
+#[derive(Debug, Clone, Default)]
+pub struct MachineOptions {
+    // A vehicle that is already asking for power (state C) when the machine
+    // starts is charged again instead of being treated as an illegal A to C
+    // transition. Used after a power failure interrupted a charge.
+    pub resume: bool,
+    // Where tamper events and faults are recorded.
+    pub audit_log: AuditLog,
+    // Where the black box is written when a fault latches. None keeps it
+    // in memory only.
+    pub black_box_dir: Option<PathBuf>,
+    // Breaker thermal model, if enabled for the installation.
+    pub breaker: Option<BreakerConfig>,
+    pub features: FeatureFlags,
+    // The maximum current of the installation, from the site settings.
+    pub max_current: Option<f64>,
+    // Where the learned vehicle minimum currents are kept. None keeps them
+    // in memory only.
+    pub store_path: Option<PathBuf>,
+    // A candidate pilot classifier to run in shadow mode.
+    pub shadow_pilot: Option<PilotCandidate>,
+    pub timing: J1772Timing,
+    // Allows pilot test patterns, see lab.rs.
+    pub lab_mode: bool,
+    pub alarm_policy: AlarmPolicy,
+    // Where the OCPP client certificate is kept. None has no certificate.
+    pub certificate_dir: Option<PathBuf>,
+    // The site's time zone, where the months of the tenant quotas start.
+    // None is UTC.
+    pub time_zone: Option<Tz>,
+    // When the solar mode stops and starts the charge.
+    pub solar: SolarHysteresis,
+    // How long unplugging resets a resettable error. None waits for the
+    // operator.
+    pub grace_unplug: Option<Duration>,
+    // The dynamic tariff. None charges whatever the price.
+    pub pricing: Option<PricingSettings>,
+    // The current while the control contact is asserted. None stops the
+    // charge.
+    pub control_contact_amps: Option<f64>,
+    // Where the machine's events go, see events.rs.
+    pub events: Option<Sender<EVSEEvent>>,
+    // Goes back to Standby from a resettable error once the vehicle is
+    // unplugged, see recovery.rs. None waits for the operator or the grace
+    // window.
+    pub recovery: Option<ErrorRecovery>,
+    // Where the time comes from, see clock.rs. None is the system clock.
+    pub clock: Option<Arc<dyn Clock>>,
+    // The installation is ventilated: a vehicle asking for ventilation
+    // (state D) is charged, with the ventilation on. Otherwise it is
+    // refused.
+    pub ventilation: bool,
+    // Without a pilot reading for this long, the pilot counts as in error
+    // and the contactor is opened: a stalled ADC thread must not leave the
+    // vehicle energized. None waits for the readings forever.
+    pub pilot_timeout: Option<Duration>,
+    // Where the sessions that stop on a fault or the control contact are
+    // told, with a resume link, see interruption.rs.
+    pub interruptions: Option<Sender<Interruption>>,
+    // Where the state and the error counters are kept across restarts, see
+    // machine_state.rs. None starts afresh.
+    pub state_path: Option<PathBuf>,
+    // The cooldown and the retries after a GFI trip, with the AutoRetryGfi
+    // feature on.
+    pub gfi_retry: GfiRetryPolicy,
+    // Where the lifetime counters are kept, see counters.rs. None counts
+    // from zero.
+    pub counters_dir: Option<PathBuf>,
+    // When the difference of the line currents warns and stops the charge,
+    // for split-phase installations with a CT on both lines.
+    pub imbalance: ImbalanceConfig,
+    // The bands of the mains voltage and frequency, see power_quality.rs.
+    // None does not watch the mains.
+    pub power_quality: Option<PowerQualityConfig>,
+    // Where the excursions of the mains out of their bands are recorded.
+    pub power_quality_log: PowerQualityLog,
+}
+
+// Issues the resume link of the interrupted session and hands the
+// interruption to the webhook.
+fn notify_interruption(
+    interruptions: Option<&Sender<Interruption>>,
+    status: &Mutex<MachineStatus>,
+    cause: InterruptionCause,
+    session_id: Option<SessionId>,
+    clock: &dyn Clock,
+) {
+    let Some(interruptions) = interruptions else {
+        return;
+    };
+    let resume_token = match status.lock().unwrap().resume.issue(cause, clock.now()) {
+        Ok(token) => token,
+        Err(error) => {
+            eprintln!("Interruption not notified: {}", error);
+            return;
+        }
+    };
+    let interruption = Interruption {
+        cause,
+        session_id: session_id.map(|id| id.to_string()),
+        unix_time: clock.unix_now(),
+        resume_token,
+    };
+    if interruptions.try_send(interruption).is_err() {
+        eprintln!("Interruption not notified, the webhook is behind");
+    }
+}
+
+fn record_event(audit_log: &AuditLog, event: &str) {
+    if let Err(error) = audit_log.record(event) {
+        eprintln!("Failed to record \"{}\" in the audit log: {}", event, error);
+    }
+}
+
+// How far back the residual current is looked at when a fault latches.
+const RESIDUAL_LEAD_UP: Duration = Duration::from_secs(5);
+
+// Persists the black box for a fault that just latched and records the
+// fault, along with where the black box went, in the audit log. The store
+// keeps it for the fault analytics.
+fn record_fault<H: EVSEHardware>(
+    evse: &RecordingHardware<H>,
+    fault: EVSEMachineState,
+    audit_log: &AuditLog,
+    black_box_dir: Option<&Path>,
+    store_path: Option<&Path>,
+    vehicle: Option<&str>,
+    clock: &dyn Clock,
+) {
+    let event = match black_box_dir.map(|dir| evse.black_box.persist(dir, fault)) {
+        Some(Ok(path)) => format!("fault {:?}, black box {}", fault, path.display()),
+        Some(Err(error)) => format!("fault {:?}, black box not saved: {}", fault, error),
+        None => format!("fault {:?}", fault),
+    };
+    record_event(audit_log, &event);
+    if let Some(residual) = evse.residual_milliamps() {
+        let peak = evse.black_box.residual_peak(RESIDUAL_LEAD_UP).unwrap_or(residual);
+        record_event(
+            audit_log,
+            &format!(
+                "fault {:?}, residual current {:.1} mA, at most {:.1} mA in the {} s before",
+                fault,
+                residual,
+                peak,
+                RESIDUAL_LEAD_UP.as_secs()
+            ),
+        );
+    }
+
+    let Some(store_path) = store_path else {
+        return;
+    };
+    let record = FaultRecord {
+        unix_time: clock.unix_now(),
+        fault: format!("{:?}", fault),
+        cause: evse.black_box.last_input().map(|input| format!("{:?}", input)),
+        temperature_c: evse.temperature_c(),
+        mains_volts: evse.mains_volts(),
+        residual_ma: evse.residual_milliamps(),
+        residual_peak_ma: evse.black_box.residual_peak(RESIDUAL_LEAD_UP),
+        vehicle: vehicle.map(str::to_string),
+    };
+    if let Err(error) = Store::open(store_path).and_then(|store| store.record_fault(&record)) {
+        eprintln!("Fault {:?} not stored: {}", fault, error);
+    }
+}
+
+fn machine_loop<H: EVSEHardware>(
+    evse: H,
+    command_rx: Receiver<EvseCommand>,
+    status: Arc<Mutex<MachineStatus>>,
+    streams: MachineStreams,
+    brownout: Brownout,
+    options: MachineOptions,
+) -> EVSEMachineState {
+    let MachineOptions {
+        mut resume,
+        audit_log,
+        black_box_dir,
+        breaker,
+        features,
+        max_current,
+        store_path,
+        shadow_pilot,
+        timing,
+        lab_mode,
+        alarm_policy,
+        certificate_dir: _,
+        time_zone,
+        solar,
+        grace_unplug,
+        pricing,
+        control_contact_amps,
+        events,
+        recovery,
+        clock,
+        ventilation,
+        pilot_timeout,
+        interruptions,
+        state_path,
+        gfi_retry,
+        counters_dir,
+        imbalance,
+        power_quality,
+        power_quality_log,
+    } = options;
+    let clock = clock.unwrap_or_else(system_clock);
+    let timing = match timing.validate() {
+        Ok(()) => timing,
+        Err(error) => {
+            record_event(&audit_log, &format!("{}, using the default timings", error));
+            J1772Timing::default()
+        }
+    };
+    status.lock().unwrap().features = features;
+    let mut breaker = breaker.map(BreakerModel::new);
+    let mut limits = LimiterChain::new(evse.max_current());
+    limits.set(Limiter::ConfigMax, max_current);
+    limits.set(Limiter::Breaker, breaker.as_ref().map(BreakerModel::allowed_amps));
+    let mut floors = FloorTracker::new(store_path.clone()).with_clock(clock.clone());
+    let mut shadow_pilot = shadow_pilot.map(ShadowPilot::new);
+    let mut solar = SolarController::new(solar);
+    let mut pacer = OfferPacer::default();
+    let mut prices = pricing.map(PricePlanner::new);
+    // None without a control contact.
+    let mut control_contact: Option<bool> = None;
+    let mut breaker_updated_at = clock.now();
+    // What the vehicle was last offered.
+    let mut offered = 0.0;
+    let mut evse = RecordingHardware::new(evse, BlackBox::default().with_clock(clock.clone()));
+    let mut recorded_state = None;
+    let pilot_rx = evse.pilot_channel();
+    let fault_rx = evse.fault_channel();
+    let mut classifier = PilotClassifier::default();
+    // The wait is on the readings, not on the machine's clock.
+    let mut pilot_deadline = pilot_timeout.map(|timeout| Instant::now() + timeout);
+    // Until the next reading after a timeout.
+    let mut pilot_stalled = false;
+    let mut max_pause = DEFAULT_MAX_PAUSE;
+    let mut suspended_at = clock.now();
+    // Until when unplugging resets a resettable error.
+    let mut grace_until: Option<Instant> = None;
+    // While in a resettable error that an unplug resets.
+    let mut awaiting_unplug = false;
+    let persisted = state_path.as_deref().and_then(|path| match PersistedState::load(path) {
+        Ok(persisted) => persisted,
+        Err(error) => {
+            record_event(&audit_log, &format!("machine state not restored: {}", error));
+            None
+        }
+    });
+    let mut counters = persisted.as_ref().map(|persisted| persisted.counters.clone()).unwrap_or_default();
+    let mut recovery = RecoveryTracker::new(recovery).with_retries(counters.recovery_retries);
+    let (mut counter_store, mut lifetime) = match counters_dir.as_deref().map(CounterStore::open) {
+        Some(Ok((store, lifetime))) => (Some(store), lifetime),
+        Some(Err(error)) => {
+            record_event(&audit_log, &format!("lifetime counters not loaded: {:?}", error));
+            (None, LifetimeCounters::default())
+        }
+        None => (None, LifetimeCounters::default()),
+    };
+    // Up to when the uptime is counted.
+    let mut uptime_counted_at = clock.now();
+    let mut gfi_retry = GfiRetryTracker::new(gfi_retry);
+    // Until when a GFI trip is waited out.
+    let mut cooldown_until: Option<Instant> = None;
+    let mut connector_thermal = ConnectorThermal::default();
+    let mut connector_state = ConnectorThermalState::Normal;
+    // Whether the ventilation was switched on for the vehicle.
+    let mut ventilating = false;
+    let mut guest: Option<GuestSession> = None;
+    let mut guest_updated_at = clock.now();
+    let mut tenant: Option<TenantSession> = None;
+    let mut tenant_updated_at = clock.now();
+    // From plug-in to unplug.
+    let mut session_id: Option<SessionId> = None;
+    let mut session_updated_at = clock.now();
+    let mut completion = CompletionEstimator::default();
+    let mut imbalance = ImbalanceMonitor::new(imbalance);
+    let mut imbalance_warned = false;
+    let mut trim = OfferTrim::default();
+    let mut power_quality = power_quality.map(PowerQualityMonitor::new);
+    let mut phases = PhaseDetector::new(PhaseDetectionConfig::default());
+    let mut power_budget = None;
+    let started_at = clock.now();
+    let mut cable = CableDiagnostics::new(store_path.clone());
+    let mut peaks = PeakTracker::new(store_path.clone(), time_zone.unwrap_or(DEFAULT_TIME_ZONE));
+    let mut monitor = ResourceMonitor::new(LatencyBudget::default(), brownout.clone());
+    // When the event being handled came in, and the pilot readings so far.
+    let mut event_received_at: Option<Instant> = None;
+    let mut pilot_count: u64 = 0;
+    let mut events = EventSink::new(events);
+    // What the pilot offered as of the last event.
+    let mut announced_offer = 0.0;
+    status.lock().unwrap().cable = cable.health();
+
+    let self_test = evse.run_gfi_self_test();
+    events.emit(EVSEEvent::SelfTest {
+        passed: self_test.is_ok(),
+    });
+    let mut state = match self_test {
+        Ok(()) => EVSEMachineState::Standby,
+        Err(_) => EVSEMachineState::FailedStation,
+    };
+    // What latched the lockout the machine is in.
+    let mut lockout_cause = self_test.is_err().then_some(EVSEMachineInput::SelfTestFailed);
+    // A lockout from before the restart is not re-armed by the self test.
+    let restored_lockout = persisted.as_ref().and_then(PersistedState::restored_lockout);
+    if let Some(lockout) = restored_lockout {
+        record_event(&audit_log, &format!("{:?} before the restart, still locked out", lockout));
+        state = lockout;
+        lockout_cause = persisted.as_ref().and_then(|persisted| persisted.lockout_cause);
+    }
+    {
+        let mut status = status.lock().unwrap();
+        status.counters = counters.clone();
+        status.lifetime = lifetime;
+    }
+    let control_watchdog = evse
+        .reserved_safe_state()
+        .map(|safe_state| ControlWatchdog::start(CONTROL_WATCHDOG_TIMEOUT, safe_state));
+    let mut transition = do_state_transition(&mut evse, state, limits.offer());
+
+    loop {
+        let input = match transition {
+            Ok(Some(input)) => Some(input),
+            Ok(None) => None,
+            Err(_) => {
+                state = EVSEMachineState::FailedStation;
+                make_safe(&mut evse);
+                None
+            }
+        };
+        let stalled = control_watchdog.as_ref().is_some_and(|watchdog| !watchdog.pet());
+        if stalled && state != EVSEMachineState::FailedStation {
+            // The hardware was forced safe behind the machine's back; it
+            // cannot be trusted to be where the machine thinks it is.
+            record_event(&audit_log, "control loop stalled, station forced safe by the watchdog");
+            state = EVSEMachineState::FailedStation;
+            make_safe(&mut evse);
+        }
+        if let Some(received_at) = event_received_at.take() {
+            if let Some(level) = monitor.record(clock.elapsed(received_at), pilot_rx.len(), clock.now()) {
+                record_event(&audit_log, &format!("control loop latency, brownout level {:?}", level));
+            }
+        }
+        for discrepancy in evse.discrepancies() {
+            record_event(&audit_log, &format!("read back discrepancy: {}", discrepancy));
+        }
+        if session_id.is_none() && (state == EVSEMachineState::VehicleDetected || is_charging(state)) {
+            session_id = Some(SessionId::new());
+        }
+        {
+            let mut status = status.lock().unwrap();
+            status.state = state;
+            // Still the id of the session that ends when back in Standby.
+            status.session_id = session_id.filter(|_| state != EVSEMachineState::Standby);
+            status.acquisition = evse.acquisition_health();
+            status.limits = limits;
+            status.brownout = monitor.status();
+        }
+        if let Some((took, passed)) = evse.last_self_test.take() {
+            status.lock().unwrap().metrics.self_test(took, passed);
+        }
+        if recorded_state != Some(state) {
+            events.emit(EVSEEvent::StateEntered {
+                state,
+                previous: recorded_state,
+            });
+            {
+                let mut status = status.lock().unwrap();
+                status.state_since = clock.now();
+                status.metrics.state_entered(state, clock.now());
+            }
+            evse.black_box.record(BlackBoxEntry::State(state));
+            evse.set_machine_state(state);
+            if let Err(error) = evse.set_alarm(alarm_policy.alarm(state)) {
+                eprintln!("Failed to set the alarm output: {:?}", error);
+            }
+            // The lockout from before the restart is no new fault.
+            let restored = recorded_state.is_none() && restored_lockout.is_some();
+            if is_recorded_fault(state) && !restored {
+                if recorded_state.is_some() {
+                    lockout_cause = evse.black_box.last_input();
+                }
+                *counters.faults.entry(state).or_default() += 1;
+                if lockout_cause == Some(EVSEMachineInput::GFIInterrupted) {
+                    counters.gfi_trips += 1;
+                    lifetime.gfi_trips += 1;
+                }
+                events.emit(EVSEEvent::FaultRaised {
+                    state,
+                    cause: evse.black_box.last_input(),
+                });
+                record_fault(
+                    &evse,
+                    state,
+                    &audit_log,
+                    black_box_dir.as_deref(),
+                    store_path.as_deref(),
+                    floors.vehicle(),
+                    clock.as_ref(),
+                );
+            }
+            if matches!(state, EVSEMachineState::ResetableError | EVSEMachineState::FailedStation)
+                && recorded_state.is_some_and(is_offering)
+            {
+                let cause = InterruptionCause::Fault {
+                    state,
+                    input: evse.black_box.last_input(),
+                };
+                notify_interruption(interruptions.as_ref(), &status, cause, session_id, clock.as_ref());
+            }
+            if state == EVSEMachineState::VehicleDetected {
+                offered = limits.offer();
+            }
+            if ventilating && !is_charging(state) {
+                match evse.set_ventilation(false) {
+                    Ok(()) => ventilating = false,
+                    Err(error) => eprintln!("Failed to switch the ventilation off: {:?}", error),
+                }
+            }
+            if state == EVSEMachineState::VentilationNeeded {
+                record_event(&audit_log, "vehicle needs ventilation, charge refused");
+            }
+            // A pilot error is no hazard once the contactor is open. For the
+            // grace window, or until the unplug with the recovery on, the
+            // pilot rests at +12 V instead of -12 V so that an unplug shows,
+            // and the plug is let go.
+            cooldown_until = (state == EVSEMachineState::GFITripped).then(|| clock.now() + gfi_retry.cooldown());
+            grace_until = grace_unplug
+                .filter(|_| state == EVSEMachineState::ResetableError)
+                .map(|grace| clock.now() + grace);
+            awaiting_unplug =
+                state == EVSEMachineState::ResetableError && (grace_until.is_some() || recovery.is_enabled());
+            if awaiting_unplug {
+                recovery.error_entered();
+                let event = match grace_unplug {
+                    Some(grace) => format!("resettable error, unplug within {} s to retry", grace.as_secs()),
+                    None => "resettable error, unplug to retry".to_string(),
+                };
+                record_event(&audit_log, &event);
+                if let Err(error) = evse.set_pilot(PilotSignal::SteadyPlus12).and_then(|_| evse.release_plug_lock()) {
+                    eprintln!("Failed to open the grace window: {:?}", error);
+                }
+            }
+            if state == EVSEMachineState::Standby {
+                match recorded_state {
+                    Some(EVSEMachineState::ResetableError) => recovery.recovered(),
+                    _ => recovery.session_ended(),
+                }
+            }
+            status.lock().unwrap().grace_until = grace_until;
+            // Unplugging ends a guest session.
+            if let Some(session) = guest.take_if(|_| state == EVSEMachineState::Standby) {
+                record_event(
+                    &audit_log,
+                    &format!("guest session under token {} ended, {:.0} Wh", session.token.id, session.energy_wh),
+                );
+                if let Some(Err(error)) = store_path.as_deref().map(|path| session.record(path, session_id.as_ref())) {
+                    eprintln!("Guest session not stored: {}", error);
+                }
+                limits.set(Limiter::Guest, None);
+                status.lock().unwrap().guest = None;
+            }
+            if let Some(session) = tenant.take_if(|_| state == EVSEMachineState::Standby) {
+                record_event(
+                    &audit_log,
+                    &format!("session of tenant {} ended, {:.0} Wh", session.tenant.id, session.energy_wh),
+                );
+                if let Some(Err(error)) = store_path.as_deref().map(|path| session.record(path, session_id.as_ref())) {
+                    eprintln!("Tenant session not stored: {}", error);
+                }
+                limits.set(Limiter::Tenant, None);
+                status.lock().unwrap().tenant = None;
+            }
+            // The resume link is only good while the vehicle is plugged in.
+            if state == EVSEMachineState::Standby {
+                status.lock().unwrap().resume.clear();
+            }
+            if state == EVSEMachineState::Standby && recorded_state.is_some() {
+                if let Some(event) = cable.session_ended(clock.unix_now()) {
+                    record_event(&audit_log, &event);
+                }
+                status.lock().unwrap().cable = cable.health();
+            }
+            {
+                let sessions = &mut status.lock().unwrap().sessions;
+                if state == EVSEMachineState::Charging {
+                    sessions.charging_started(clock.unix_now(), session_id);
+                    session_updated_at = clock.now();
+                } else if recorded_state == Some(EVSEMachineState::Charging) {
+                    sessions.charging_stopped(clock.unix_now());
+                }
+                if state == EVSEMachineState::Standby {
+                    sessions.end(clock.unix_now());
+                }
+            }
+            if state == EVSEMachineState::Standby {
+                let curve = std::mem::take(&mut status.lock().unwrap().curve);
+                if let (Some(id), Some(path), false) = (session_id.as_ref(), store_path.as_deref(), curve.is_empty()) {
+                    let saved = Store::open(path).and_then(|mut store| store.save_charging_curve(id, curve.points()));
+                    if let Err(error) = saved {
+                        eprintln!("Charging curve not stored: {}", error);
+                    }
+                }
+                if session_id.is_some() {
+                    lifetime.sessions += 1;
+                }
+                session_id = None;
+                completion.reset();
+                status.lock().unwrap().completion = None;
+            }
+            floors.state_changed(state, offered);
+            limits.set_floor(floors.floor());
+            counters.recovery_retries = recovery.retries();
+            if state == EVSEMachineState::Charging {
+                lifetime.contactor_cycles += 1;
+            }
+            // Whole seconds; the rest counts with the next save.
+            let uptime = clock.elapsed(uptime_counted_at);
+            lifetime.add_uptime(uptime);
+            uptime_counted_at += Duration::from_secs(uptime.as_secs());
+            if let Some(Err(error)) = counter_store.as_mut().map(|store| store.save(&lifetime)) {
+                eprintln!("Lifetime counters not saved: {:?}", error);
+            }
+            {
+                let mut status = status.lock().unwrap();
+                status.counters = counters.clone();
+                status.lifetime = lifetime;
+            }
+            if let Some(path) = state_path.as_deref() {
+                let persisted = PersistedState {
+                    state,
+                    lockout_cause: lockout_cause.filter(|_| is_recorded_fault(state)),
+                    counters: counters.clone(),
+                    unix_time: clock.unix_now(),
+                };
+                if let Err(error) = persisted.save(path) {
+                    eprintln!("Machine state not saved: {}", error);
+                }
+            }
+            recorded_state = Some(state);
+        }
+        // Every change of the offer comes back here, whichever limiter
+        // made it.
+        let offer = if is_offering(state) { offered } else { 0.0 };
+        if offer != announced_offer {
+            announced_offer = offer;
+            events.emit(EVSEEvent::OfferChanged { amps: offer });
+        }
+        if state == EVSEMachineState::FailedStation {
+            match watch_latched(&mut evse, &status, &command_rx, &audit_log, control_watchdog.as_ref()) {
+                Lockout::Stopped(state) => return state,
+                lockout @ (Lockout::Reset | Lockout::Resumed) => {
+                    let self_test = evse.run_gfi_self_test();
+                    events.emit(EVSEEvent::SelfTest {
+                        passed: self_test.is_ok(),
+                    });
+                    if self_test.is_ok() {
+                        // The vehicle that kept failing gets its retries back.
+                        recovery.session_ended();
+                        resume = matches!(lockout, Lockout::Resumed);
+                        state = EVSEMachineState::Standby;
+                        transition = do_state_transition(&mut evse, state, limits.offer());
+                    } else {
+                        record_event(&audit_log, "GFI self test failed after the lockout reset");
+                        transition = Ok(None);
+                    }
+                    continue;
+                }
+            }
+        }
+        if state == EVSEMachineState::PowerFailure {
+            return state;
+        }
+
+        if let Some(model) = breaker.as_mut() {
+            // Without a current measurement the vehicle is taken to draw
+            // all it is offered.
+            let drawn = if state == EVSEMachineState::Charging { offered } else { 0.0 };
+            model.update(clock.elapsed(breaker_updated_at), drawn);
+            breaker_updated_at = clock.now();
+            // The offer follows the derating during the session.
+            limits.set(Limiter::Breaker, Some(model.allowed_amps()));
+            status.lock().unwrap().breaker = Some(model.status());
+        }
+
+        // What the vehicle draws, the offer where the current is not
+        // measured. The session log, the counters and the guest and tenant
+        // sessions all count it.
+        let measured = evse.current_amps();
+        let amps = measured.unwrap_or(offered);
+        let power_w = usable_power_w(amps, evse.mains_volts().unwrap_or(NOMINAL_PHASE_VOLTS), phases.phases());
+        if state == EVSEMachineState::Charging {
+            let elapsed = clock.elapsed(session_updated_at);
+            lifetime.add_energy_wh(power_w * elapsed.as_secs_f64() / 3600.0);
+            let mut status = status.lock().unwrap();
+            status.sessions.add_charging(amps, power_w, elapsed);
+            status.lifetime = lifetime;
+            // The estimate stays as it was through a pause of the vehicle.
+            if let Some(measured) = measured {
+                status.completion = Some(completion.update(clock.elapsed(started_at), measured, offered));
+            }
+        }
+
+        // The current that goes out on one line has to come back on the
+        // other.
+        match evse.line_currents().filter(|_| state == EVSEMachineState::Charging) {
+            Some([line1, line2]) => match imbalance.update(clock.elapsed(started_at), line1, line2) {
+                ImbalanceStatus::Balanced => imbalance_warned = false,
+                ImbalanceStatus::Warning { difference_amps } => {
+                    if !imbalance_warned {
+                        imbalance_warned = true;
+                        record_event(
+                            &audit_log,
+                            &format!("line currents {:.1} A apart, check the wiring", difference_amps),
+                        );
+                    }
+                }
+                ImbalanceStatus::Stop { difference_amps } => {
+                    record_event(
+                        &audit_log,
+                        &format!("line currents {:.1} A apart, charge stopped", difference_amps),
+                    );
+                    imbalance.reset();
+                    imbalance_warned = false;
+                    transition = Ok(Some(EVSEMachineInput::LineImbalance));
+                    continue;
+                }
+            },
+            None => {
+                imbalance.reset();
+                imbalance_warned = false;
+            }
+        }
+        session_updated_at = clock.now();
+
+        if let Some(session) = guest.as_mut() {
+            if state == EVSEMachineState::Charging {
+                session.add_charging(power_w, clock.elapsed(guest_updated_at));
+            }
+            guest_updated_at = clock.now();
+            status.lock().unwrap().guest = Some(session.clone());
+            // The offer is taken away once the energy of the token is used.
+            if session.energy_used_up() && limits.cap(Limiter::Guest) != Some(0.0) {
+                record_event(&audit_log, &format!("energy of guest token {} used up", session.token.id));
+                limits.set(Limiter::Guest, Some(0.0));
+            }
+        }
+
+        if let Some(session) = tenant.as_mut() {
+            if state == EVSEMachineState::Charging {
+                session.add_charging(power_w, clock.elapsed(tenant_updated_at));
+            }
+            tenant_updated_at = clock.now();
+            status.lock().unwrap().tenant = Some(session.clone());
+            // As for the guest tokens, once the quota is used.
+            if session.quota_used_up() && limits.cap(Limiter::Tenant) != Some(0.0) {
+                record_event(&audit_log, &format!("monthly energy of tenant {} used up", session.tenant.id));
+                limits.set(Limiter::Tenant, Some(0.0));
+            }
+        }
+
+        if state == EVSEMachineState::Standby {
+            phases.reset();
+        } else if let Some(line_amps) = evse.phase_currents().filter(|_| state == EVSEMachineState::Charging) {
+            phases.update(clock.elapsed(started_at), line_amps);
+        }
+        // The budget goes further once the vehicle is known to charge on
+        // fewer phases.
+        if let Some(budget) = power_budget {
+            let amps = budget_amps(budget, NOMINAL_PHASE_VOLTS, phases.phases());
+            limits.set(Limiter::LoadManager, Some(amps));
+        }
+        status.lock().unwrap().phases = PhaseStatus {
+            phases_in_use: phases.phases(),
+            usable_power_w: usable_power_w(limits.offer(), NOMINAL_PHASE_VOLTS, phases.phases()),
+            power_budget_w: power_budget,
+        };
+
+        // A raise of the offer that waited for the vehicle to settle.
+        if let Some(amps) = pacer.poll(clock.now()) {
+            limits.set(Limiter::Operator, Some(amps));
+        }
+
+        // The solar cap, once the hysteresis lets the charge stop or start.
+        if let Some(charging) = solar.update(floors.floor(), clock.now()) {
+            record_event(
+                &audit_log,
+                if charging {
+                    "solar surplus above the vehicle floor, charge started"
+                } else {
+                    "solar surplus below the vehicle floor, charge stopped"
+                },
+            );
+        }
+        limits.set(Limiter::Solar, solar.cap());
+
+        // The price cap, as the hours go by and the prices come in.
+        let price_cap = prices.as_ref().and_then(|prices| prices.cap(clock.unix_now()));
+        if prices.is_some() && limits.cap(Limiter::Price) != price_cap {
+            record_event(
+                &audit_log,
+                match price_cap {
+                    Some(_) => "energy price above the plan, charge paused",
+                    None => "energy price within the plan, charge resumed",
+                },
+            );
+            limits.set(Limiter::Price, price_cap);
+        }
+
+        // The control contact of the grid operator, e.g. for §14a EnWG.
+        let contact = evse.control_contact();
+        if contact != control_contact {
+            let cap = (contact == Some(true)).then(|| control_contact_amps.unwrap_or(0.0));
+            if let Some(amps) = cap {
+                record_event(&audit_log, &format!("control contact asserted, charge limited to {:.0} A", amps));
+                if amps == 0.0 && is_charging(state) {
+                    let cause = InterruptionCause::DemandResponse;
+                    notify_interruption(interruptions.as_ref(), &status, cause, session_id, clock.as_ref());
+                }
+            } else if control_contact == Some(true) {
+                record_event(&audit_log, "control contact released");
+            }
+            control_contact = contact;
+            limits.set(Limiter::ControlContact, cap);
+            status.lock().unwrap().control_contact = contact;
+        }
+
+        // The contacts of the socket, for sockets with sensors.
+        let temperatures = evse.connector_temperatures_c();
+        if !temperatures.is_empty() {
+            let thermal_state = connector_thermal.update(&temperatures);
+            if thermal_state != connector_state {
+                let hottest = temperatures.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                let event = match thermal_state {
+                    ConnectorThermalState::Normal => "connector temperature back to normal".to_string(),
+                    ConnectorThermalState::Derating => format!("connector at {:.0} °C, offer derated", hottest),
+                    ConnectorThermalState::Stopped => format!("connector at {:.0} °C, charge stopped", hottest),
+                    ConnectorThermalState::SensorFault => "connector temperature sensor fault, charge stopped".to_string(),
+                };
+                record_event(&audit_log, &event);
+                connector_state = thermal_state;
+            }
+            limits.set(Limiter::Thermal, connector_thermal.cap(&temperatures, limits.hardware_max()));
+            status.lock().unwrap().connector_temperatures_c = temperatures;
+        }
+
+        // The vehicle's draw held to what the other limits allow, see
+        // trim.rs.
+        let trimming =
+            state == EVSEMachineState::Charging && status.lock().unwrap().features.is_enabled(Feature::ClosedLoopTrim);
+        let trim_cap = match evse.current_amps().filter(|_| trimming) {
+            Some(measured) => {
+                let mut untrimmed = limits;
+                untrimmed.set(Limiter::Trim, None);
+                trim.update(untrimmed.offer(), measured)
+            }
+            None => {
+                trim.reset();
+                None
+            }
+        };
+        limits.set(Limiter::Trim, trim_cap);
+
+        // With the caps of all limiters in, the vehicle is offered what they
+        // allow together.
+        if let Err(error) = apply_offer(&mut evse, &status, state, limits, &mut offered, &mut floors) {
+            transition = Err(error);
+            continue;
+        }
+
+        // The GFI is tested again once the trip is waited out.
+        if cooldown_until.is_some_and(|until| clock.now() >= until) {
+            cooldown_until = None;
+            transition = match evse.run_gfi_self_test() {
+                Ok(()) => Ok(Some(EVSEMachineInput::SelfTestOk)),
+                Err(_) => Ok(Some(EVSEMachineInput::SelfTestFailed)),
+            };
+            continue;
+        }
+
+        // The grace window closed without an unplug.
+        if grace_until.is_some_and(|until| clock.now() >= until) {
+            grace_until = None;
+            awaiting_unplug = false;
+            status.lock().unwrap().grace_until = None;
+            record_event(&audit_log, "grace window over, the resettable error needs a reset");
+            make_safe(&mut evse);
+        }
+
+        if let Some(result @ (EVSEMachineInput::SelfTestOk | EVSEMachineInput::SelfTestFailed)) = input {
+            events.emit(EVSEEvent::SelfTest {
+                passed: result == EVSEMachineInput::SelfTestOk,
+            });
+        }
+        let input = match input {
+            Some(input) => input,
+            None => match record_in_black_box(&mut evse.black_box, {
+                let event = get_new_state_input(&pilot_rx, &fault_rx, &command_rx, &mut classifier, pilot_deadline);
+                event_received_at = Some(clock.now());
+                event
+            }) {
+                MachineEvent::Input(input) => input,
+                MachineEvent::PilotTimedOut => {
+                    pilot_deadline = pilot_timeout.map(|timeout| Instant::now() + timeout);
+                    if !pilot_stalled {
+                        pilot_stalled = true;
+                        record_event(
+                            &audit_log,
+                            &format!(
+                                "no pilot reading in {} ms, contactor opened",
+                                pilot_timeout.unwrap_or_default().as_millis()
+                            ),
+                        );
+                    }
+                    if let Err(error) = evse.set_contactor(false) {
+                        eprintln!("Failed to open the contactor: {:?}", error);
+                    }
+                    EVSEMachineInput::PilotInError
+                }
+                MachineEvent::Pilot(reading, input) => {
+                    pilot_deadline = pilot_timeout.map(|timeout| Instant::now() + timeout);
+                    pilot_stalled = false;
+                    evse.pilot_read(&reading);
+                    cable.observe(input, reading.high);
+                    if let Some(milliamps) = evse.residual_milliamps() {
+                        evse.black_box.record(BlackBoxEntry::ResidualCurrent(milliamps));
+                    }
+                    let mut shadow_report = None;
+                    if let Some(shadow) = shadow_pilot.as_mut() {
+                        if let Some(event) = shadow.observe(&reading, input) {
+                            record_event(&audit_log, &event);
+                        }
+                        shadow_report = Some(shadow.report().clone());
+                    }
+                    {
+                        let mut status = status.lock().unwrap();
+                        status.pilot = Some((reading, input));
+                        status.shadow_pilot = shadow_report;
+                        let charging = state == EVSEMachineState::Charging;
+                        let amps = evse.current_amps().unwrap_or(if charging { offered } else { 0.0 });
+                        let volts = evse.mains_volts().unwrap_or(NOMINAL_PHASE_VOLTS);
+                        let power_w = usable_power_w(amps, volts, phases.phases());
+                        if charging {
+                            status.curve.add(clock.unix_now(), power_w, amps);
+                        }
+                        status.sample = Some(SensorSample {
+                            state,
+                            amps,
+                            volts,
+                            power_w,
+                            energy_wh: lifetime.energy_wh,
+                            temperature_c: evse.temperature_c(),
+                            timestamp_ns: 0,
+                        });
+                    }
+                    if let (Some(monitor), Some(volts), Some(hz)) =
+                        (power_quality.as_mut(), evse.mains_volts(), evse.mains_frequency_hz())
+                    {
+                        for event in monitor.update(clock.system_time(), volts, hz) {
+                            if let Err(error) = power_quality_log.record(&event) {
+                                eprintln!("Failed to record a power quality event: {}", error);
+                            }
+                        }
+                    }
+                    let offering = if is_offering(state) { offered } else { 0.0 };
+                    peaks.observe(clock.utc_now(), offering, evse.current_amps());
+                    pilot_count += 1;
+                    if brownout.publishes(pilot_count) {
+                        let timestamp_ns = SensorSample::now_ns();
+                        streams.pilot.publish(&PilotSample {
+                            reading,
+                            classification: input,
+                            timestamp_ns,
+                        });
+                        streams.meter.publish(&MeterSample {
+                            state,
+                            amps: evse.current_amps().unwrap_or(if state == EVSEMachineState::Charging {
+                                offered
+                            } else {
+                                0.0
+                            }),
+                            mains_volts: evse.mains_volts(),
+                            temperature_c: evse.temperature_c(),
+                            session_id,
+                            timestamp_ns,
+                        });
+                    }
+                    input
+                }
+                MachineEvent::Command(EvseCommand::SetMaxCurrent(ampere)) => {
+                    if !is_valid_max_current(ampere) {
+                        transition = Ok(None);
+                        continue;
+                    }
+                    if is_charging(state) {
+                        record_event(&audit_log, &format!("maximum current {} A refused while charging", ampere));
+                        transition = Ok(None);
+                        continue;
+                    }
+                    match evse.set_max_current(ampere) {
+                        Ok(()) => {
+                            limits.set(Limiter::Hardware, Some(ampere));
+                            record_event(&audit_log, &format!("maximum current set to {} A", ampere));
+                        }
+                        Err(error) => eprintln!("Failed to set the maximum current: {:?}", error),
+                    }
+                    transition = apply_offer(&mut evse, &status, state, limits, &mut offered, &mut floors).map(|_| None);
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::SetCurrentLimit(ampere)) => {
+                    limits.set(Limiter::Operator, Some(ampere));
+                    transition = apply_offer(&mut evse, &status, state, limits, &mut offered, &mut floors).map(|_| None);
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::AdjustCurrentOffer(ampere)) => {
+                    // Without an offer to the vehicle, nothing to pace.
+                    if !is_offering(state) {
+                        pacer = OfferPacer::default();
+                    }
+                    transition = Ok(None);
+                    if let Some(amps) = pacer.request(ampere, clock.now()) {
+                        limits.set(Limiter::Operator, Some(amps));
+                        transition = apply_offer(&mut evse, &status, state, limits, &mut offered, &mut floors).map(|_| None);
+                    }
+                    continue;
+                }
+                // The surplus goes through the hysteresis, in solar mode
+                // only.
+                MachineEvent::Command(EvseCommand::SetLimit(Limiter::Solar, ampere)) => {
+                    if status.lock().unwrap().features.is_enabled(Feature::SolarMode) {
+                        solar.set_surplus(ampere);
+                    }
+                    transition = Ok(None);
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::SetLimit(limiter, ampere)) => {
+                    limits.set(limiter, ampere);
+                    transition = apply_offer(&mut evse, &status, state, limits, &mut offered, &mut floors).map(|_| None);
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::SetPrices(hourly)) => {
+                    if let Some(prices) = prices.as_mut() {
+                        prices.set_prices(hourly);
+                    }
+                    transition = Ok(None);
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::SetPowerBudget(watts)) => {
+                    power_budget = watts;
+                    if watts.is_none() {
+                        limits.set(Limiter::LoadManager, None);
+                    }
+                    transition = apply_offer(&mut evse, &status, state, limits, &mut offered, &mut floors).map(|_| None);
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::IdentifyVehicle(vehicle)) => {
+                    floors.identify(vehicle);
+                    limits.set_floor(floors.floor());
+                    transition = apply_offer(&mut evse, &status, state, limits, &mut offered, &mut floors).map(|_| None);
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::SetBatteryEnergy(battery_wh)) => {
+                    let sessions = &mut status.lock().unwrap().sessions;
+                    if let Some(session) = sessions.set_battery_energy(battery_wh) {
+                        if session.low_efficiency() && session.ended_at.is_some() {
+                            record_event(
+                                &audit_log,
+                                &format!(
+                                    "session efficiency {:.0}%, {:.0} Wh lost: check the on-board charger and the cable",
+                                    session.efficiency.unwrap_or_default() * 100.0,
+                                    session.losses_wh.unwrap_or_default()
+                                ),
+                            );
+                        }
+                    }
+                    transition = Ok(None);
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::SetMaxPause(pause)) => {
+                    max_pause = pause;
+                    transition = Ok(None);
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::SetFeature(feature, enabled)) => {
+                    let mut status = status.lock().unwrap();
+                    status.features.set_override(feature, enabled);
+                    // Out of solar mode the solar cap goes away with the
+                    // surplus.
+                    if !status.features.is_enabled(Feature::SolarMode) {
+                        solar.set_surplus(None);
+                    }
+                    transition = Ok(None);
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::ResetTamper) => {
+                    transition = if state == EVSEMachineState::TamperLockout {
+                        record_event(&audit_log, "tamper lockout reset");
+                        state = EVSEMachineState::Standby;
+                        do_state_transition(&mut evse, state, limits.offer())
+                    } else {
+                        Ok(None)
+                    };
+                    continue;
+                }
+                // Only a failed station is locked out, see watch_latched.
+                MachineEvent::Command(EvseCommand::ResetLockout) => {
+                    transition = Ok(None);
+                    continue;
+                }
+                // Only the errors need the resume link; after the control
+                // contact it is an acknowledgement.
+                MachineEvent::Command(EvseCommand::ResumeCharging) => {
+                    transition = if state == EVSEMachineState::ResetableError {
+                        record_event(&audit_log, "resettable error cleared from the resume link");
+                        resume = true;
+                        state = EVSEMachineState::Standby;
+                        do_state_transition(&mut evse, state, limits.offer())
+                    } else {
+                        Ok(None)
+                    };
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::StartGuestSession(token)) => {
+                    transition = Ok(None);
+                    if let Some(session) = &guest {
+                        record_event(
+                            &audit_log,
+                            &format!("guest token {} refused, token {} is in use", token.id, session.token.id),
+                        );
+                        continue;
+                    }
+                    if let Some(session) = &tenant {
+                        record_event(
+                            &audit_log,
+                            &format!("guest token {} refused, tenant {} is charging", token.id, session.tenant.id),
+                        );
+                        continue;
+                    }
+                    record_event(&audit_log, &format!("guest session under token {} ({})", token.id, token.label));
+                    limits.set(Limiter::Guest, token.max_current);
+                    guest = Some(GuestSession::new(token));
+                    guest_updated_at = clock.now();
+                    // A vehicle that is already plugged in gets the capped
+                    // offer now.
+                    transition = apply_offer(&mut evse, &status, state, limits, &mut offered, &mut floors).map(|_| None);
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::StartTenantSession(session)) => {
+                    transition = Ok(None);
+                    if guest.is_some() || tenant.is_some() {
+                        record_event(
+                            &audit_log,
+                            &format!("tenant {} refused, the station is in use", session.tenant.id),
+                        );
+                        continue;
+                    }
+                    record_event(
+                        &audit_log,
+                        &format!("session of tenant {} ({})", session.tenant.id, session.tenant.name),
+                    );
+                    limits.set(Limiter::Tenant, session.tenant.max_current);
+                    tenant = Some(session);
+                    tenant_updated_at = clock.now();
+                    transition = apply_offer(&mut evse, &status, state, limits, &mut offered, &mut floors).map(|_| None);
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::RunLabPattern(pattern)) => {
+                    let refused = if !lab_mode {
+                        Some("lab mode is off".to_string())
+                    } else if !matches!(state, EVSEMachineState::Standby | EVSEMachineState::VehicleDetected) {
+                        Some(format!("not in {:?}", state))
+                    } else {
+                        pattern.validate().err().map(|error| format!("{:?}", error))
+                    };
+                    if let Some(reason) = refused {
+                        record_event(&audit_log, &format!("lab pattern refused, {}", reason));
+                        transition = Ok(None);
+                        continue;
+                    }
+                    record_event(
+                        &audit_log,
+                        &format!("lab pattern of {} steps in {:?}", pattern.steps.len(), state),
+                    );
+                    transition = lab::run_pattern(&mut evse, &pattern, &pilot_rx, &fault_rx, &mut classifier)
+                        .and_then(|report| {
+                            let aborted_by = report.aborted_by;
+                            status.lock().unwrap().lab = Some(report);
+                            // Back to the pilot of the state.
+                            let input = do_state_transition(&mut evse, state, limits.offer())?;
+                            Ok(aborted_by.or(input))
+                        });
+                    continue;
+                }
+                MachineEvent::Command(EvseCommand::Shutdown) => {
+                    shut_down(&mut evse, state, &pilot_rx, &mut classifier, timing.stop_response);
+                    record_event(&audit_log, &format!("orderly shutdown in {:?}", state));
+                    return state;
+                }
+                MachineEvent::Command(EvseCommand::Stop) => {
+                    make_safe(&mut evse);
+                    return state;
+                }
+            },
+        };
+
+        // Pilot readings keep coming while the vehicle pauses, so the pause
+        // is checked whenever the vehicle reports it is still paused.
+        let input = if state == EVSEMachineState::SuspendedEV
+            && input == EVSEMachineInput::PilotIs9V
+            && clock.elapsed(suspended_at) >= max_pause
+        {
+            EVSEMachineInput::PauseTimedOut
+        } else if awaiting_unplug
+            && grace_until.is_none_or(|until| clock.now() < until)
+            && recovery.observe(input, clock.now())
+        {
+            EVSEMachineInput::UnpluggedInGrace
+        } else {
+            input
+        };
+        // With ventilation, state D is charged like state C once the
+        // ventilation runs. In Standby it is an illegal A to D either way.
+        let input = if ventilation && input == EVSEMachineInput::PilotIs3V && state != EVSEMachineState::Standby {
+            if !ventilating {
+                match evse.set_ventilation(true) {
+                    Ok(()) => {
+                        ventilating = true;
+                        record_event(&audit_log, "vehicle needs ventilation, ventilation on");
+                    }
+                    Err(error) => eprintln!("Failed to switch the ventilation on: {:?}", error),
+                }
+            }
+            if ventilating {
+                EVSEMachineInput::PilotIs6V
+            } else {
+                input
+            }
+        } else {
+            input
+        };
+
+        evse.black_box.record(BlackBoxEntry::Input(input));
+        if input == EVSEMachineInput::PowerLost {
+            status.lock().unwrap().interrupted = Some(state);
+        }
+        if input == EVSEMachineInput::EnclosureOpened {
+            let event = match state {
+                EVSEMachineState::Charging => "enclosure opened while charging, tamper lockout",
+                _ => "enclosure opened",
+            };
+            record_event(&audit_log, event);
+        }
+
+        // Only the first input after the start can resume a charge.
+        // The offer is made first, as if the vehicle had just been plugged
+        // in, then the 6V input is handled as usual.
+        if std::mem::take(&mut resume)
+            && state == EVSEMachineState::Standby
+            && input == EVSEMachineInput::PilotIs6V
+        {
+            state = EVSEMachineState::VehicleDetected;
+            let limit = limits.offer();
+            transition = do_state_transition(&mut evse, state, limit).map(|_| Some(input));
+            continue;
+        }
+
+        transition = match next_state(state, input) {
+            Some(next) => {
+                // The vehicle keeps failing: no more retries.
+                let next = if next == EVSEMachineState::ResetableError && recovery.is_exhausted() {
+                    record_event(
+                        &audit_log,
+                        &format!(
+                            "resettable error after {} recoveries in a row, station failed",
+                            recovery.retries()
+                        ),
+                    );
+                    EVSEMachineState::FailedStation
+                } else {
+                    next
+                };
+                // A GFI trip is retried after the cooldown while there are
+                // retries left, see gfi_retry.rs.
+                let next = if next == EVSEMachineState::FailedStation
+                    && input == EVSEMachineInput::GFIInterrupted
+                    && status.lock().unwrap().features.is_enabled(Feature::AutoRetryGfi)
+                {
+                    let history = store_path
+                        .as_deref()
+                        .and_then(|path| Store::open(path).and_then(|store| store.faults()).ok())
+                        .map(|faults| classify_trips(&faults));
+                    match gfi_retry.trip(history.as_deref(), clock.unix_now()) {
+                        Some(retry) => {
+                            record_event(
+                                &audit_log,
+                                &format!(
+                                    "GFI tripped, retry {} of {} after a {} s cooldown",
+                                    retry,
+                                    gfi_retry.max_retries(),
+                                    gfi_retry.cooldown().as_secs()
+                                ),
+                            );
+                            EVSEMachineState::GFITripped
+                        }
+                        None => {
+                            record_event(&audit_log, "GFI tripped with no retries left, station failed");
+                            next
+                        }
+                    }
+                } else {
+                    next
+                };
+                if next == EVSEMachineState::SuspendedEV {
+                    suspended_at = clock.now();
+                }
+                state = next;
+                do_state_transition(&mut evse, state, limits.offer())
+            }
+            None => Ok(None),
+        };
+    }
+}
+
+// Controls a running state machine. Cheap to clone, so control interfaces
+// on other threads can each have their own.
+#[derive(Clone)]
+pub struct EvseController {
+    command_tx: Sender<EvseCommand>,
+    status: Arc<Mutex<MachineStatus>>,
+    store_path: Option<PathBuf>,
+    lab_mode: bool,
+    streams: MachineStreams,
+    certificate_dir: Option<PathBuf>,
+    time_zone: Tz,
+    brownout: Brownout,
+    clock: Arc<dyn Clock>,
+}
+
+impl EvseController {
+    pub fn state(&self) -> EVSEMachineState {
+        self.status.lock().unwrap().state
+    }
+
+    // Snapshot for the periodic telemetry payloads.
+    pub fn telemetry(&self, verbosity: TelemetryVerbosity) -> TelemetrySample {
+        let status = self.status.lock().unwrap().clone();
+        let mut sample = TelemetrySample::new(status.state, status.pilot, verbosity);
+        if let Some(breaker) = status.breaker {
+            sample = sample.with_breaker(breaker);
+        }
+        if let Some(acquisition) = status.acquisition {
+            sample = sample.with_acquisition(acquisition);
+        }
+        if let Some(session_id) = status.session_id {
+            sample = sample.with_session(session_id);
+        }
+        if let Some(completion) = status.completion {
+            sample = sample.with_completion(completion);
+        }
+        if !status.connector_temperatures_c.is_empty() {
+            sample = sample.with_connector_temperatures(status.connector_temperatures_c);
+        }
+        if verbosity == TelemetryVerbosity::Diagnostic {
+            sample = sample.with_features(status.features.snapshot());
+        }
+        sample
+    }
+
+    // The id of the session the vehicle plugged in is in. None while no
+    // vehicle is.
+    pub fn session_id(&self) -> Option<SessionId> {
+        self.status.lock().unwrap().session_id
+    }
+
+    // How long unplugging still resets the resettable error.
+    pub fn grace_unplug_remaining(&self) -> Option<Duration> {
+        let grace_until = self.status.lock().unwrap().grace_until?;
+        grace_until.checked_duration_since(self.clock.now())
+    }
+
+    // Whether a vehicle is plugged in, as the last pilot reading tells.
+    // None while the pilot does not tell, e.g. at -12 V.
+    pub fn vehicle_present(&self) -> Option<bool> {
+        let (_, input) = self.status.lock().unwrap().pilot?;
+        vehicle_present(input)
+    }
+
+    // How long the machine has been in its current state.
+    pub fn time_in_state(&self) -> Duration {
+        self.clock.elapsed(self.status.lock().unwrap().state_since)
+    }
+
+    // Time in state, transitions, faults and self tests since the start,
+    // see metrics.rs.
+    pub fn metrics(&self) -> MetricsReport {
+        self.status.lock().unwrap().metrics.report(self.clock.now())
+    }
+
+    // The limit set with SetCurrentLimit, before any derating.
+    pub fn current_limit(&self) -> f64 {
+        let limits = self.status.lock().unwrap().limits;
+        let max_current = self.max_current();
+        limits.cap(Limiter::Operator).map_or(max_current, |limit| limit.min(max_current))
+    }
+
+    // The offer, the limiter that sets it and the caps of all limiters.
+    pub fn limits(&self) -> LimitsStatus {
+        self.status.lock().unwrap().limits.status()
+    }
+
+    pub fn features(&self) -> FeatureFlags {
+        self.status.lock().unwrap().features
+    }
+
+    // How the shadow pilot classifier compares, if one runs.
+    pub fn shadow_report(&self) -> Option<ShadowReport<EVSEMachineInput>> {
+        self.status.lock().unwrap().shadow_pilot.clone()
+    }
+
+    // The analytics of the stored faults. None without a store.
+    pub fn fault_report(&self) -> Option<Result<FaultReport, StoreError>> {
+        self.store_path.as_deref().map(FaultReport::load)
+    }
+
+    // Issues a guest token; see guest_token.rs.
+    pub fn issue_guest_token(
+        &self,
+        label: &str,
+        valid_for: Duration,
+        max_current: Option<f64>,
+        max_energy_wh: Option<f64>,
+    ) -> Result<IssuedToken, GuestTokenError> {
+        let store_path = self.store_path.as_deref().ok_or(GuestTokenError::Disabled)?;
+        guest_token::issue(store_path, label, valid_for, max_current, max_energy_wh)
+    }
+
+    // Redeems a guest token and starts a guest session under it.
+    pub fn redeem_guest_token(&self, secret: &str) -> Result<GuestToken, GuestTokenError> {
+        let store_path = self.store_path.as_deref().ok_or(GuestTokenError::Disabled)?;
+        let token = guest_token::redeem(store_path, secret)?;
+        if self.guest_session().is_some() || self.tenant_session().is_some() {
+            return Err(GuestTokenError::InUse);
+        }
+        self.send_command(EvseCommand::StartGuestSession(token.clone()))
+            .map_err(|_| GuestTokenError::MachineStopped)?;
+        Ok(token)
+    }
+
+    // Uses the resume link of an interrupted session. Refused while the
+    // control contact that stopped the charge is asserted.
+    pub fn resume_charging(&self, secret: &str) -> Result<InterruptionCause, ResumeError> {
+        let cause = {
+            let mut status = self.status.lock().unwrap();
+            let cause = status.resume.verify(secret, self.clock.now())?;
+            if cause == InterruptionCause::DemandResponse && status.control_contact == Some(true) {
+                return Err(ResumeError::ControlContact);
+            }
+            status.resume.clear();
+            cause
+        };
+        self.send_command(EvseCommand::ResumeCharging)
+            .map_err(|_| ResumeError::MachineStopped)?;
+        Ok(cause)
+    }
+
+    pub fn guest_session(&self) -> Option<GuestSession> {
+        self.status.lock().unwrap().guest.clone()
+    }
+
+    // Adds or updates a tenant; see tenants.rs.
+    pub fn save_tenant(&self, tenant: &Tenant) -> Result<(), TenantError> {
+        let store_path = self.store_path.as_deref().ok_or(TenantError::Disabled)?;
+        tenants::save_tenant(store_path, tenant)
+    }
+
+    pub fn tenants(&self) -> Result<Vec<Tenant>, TenantError> {
+        let store_path = self.store_path.as_deref().ok_or(TenantError::Disabled)?;
+        Ok(Store::open(store_path)?.tenants()?)
+    }
+
+    // An RFID UID or token the tenant authorizes with.
+    pub fn add_tenant_credential(&self, tenant_id: &str, credential: &str) -> Result<(), TenantError> {
+        let store_path = self.store_path.as_deref().ok_or(TenantError::Disabled)?;
+        tenants::add_credential(store_path, tenant_id, credential)
+    }
+
+    // Starts the session of the tenant with the credential.
+    pub fn authorize_tenant(&self, credential: &str) -> Result<Tenant, TenantError> {
+        let store_path = self.store_path.as_deref().ok_or(TenantError::Disabled)?;
+        let session = tenants::authorize(store_path, self.time_zone, credential)?;
+        if self.guest_session().is_some() || self.tenant_session().is_some() {
+            return Err(TenantError::InUse);
+        }
+        let tenant = session.tenant.clone();
+        self.send_command(EvseCommand::StartTenantSession(session))
+            .map_err(|_| TenantError::MachineStopped)?;
+        Ok(tenant)
+    }
+
+    pub fn tenant_session(&self) -> Option<TenantSession> {
+        self.status.lock().unwrap().tenant.clone()
+    }
+
+    // The tenants' energy of a month of the site's time zone.
+    pub fn tenant_report(&self, year: i32, month: u32) -> Result<Vec<TenantReportRow>, TenantError> {
+        let store_path = self.store_path.as_deref().ok_or(TenantError::Disabled)?;
+        tenants::monthly_report(store_path, self.time_zone, year, month)
+    }
+
+    // The charging curve of a session, by default the running one. None
+    // for an unknown session, or with no session running.
+    pub fn charging_curve(
+        &self,
+        session_id: Option<SessionId>,
+    ) -> Result<Option<(SessionId, Vec<CurvePoint>)>, StoreError> {
+        {
+            let status = self.status.lock().unwrap();
+            if let Some(running) = status.session_id.filter(|&running| session_id.unwrap_or(running) == running) {
+                return Ok(Some((running, status.curve.points().to_vec())));
+            }
+        }
+        let (Some(session_id), Some(store_path)) = (session_id, self.store_path.as_deref()) else {
+            return Ok(None);
+        };
+        let points = Store::open(store_path)?.charging_curve(&session_id)?;
+        Ok(Some(session_id).filter(|_| !points.is_empty()).map(|id| (id, points)))
+    }
+
+    // The peak currents of the last months, oldest first. None without a
+    // store.
+    pub fn daily_peaks(&self) -> Option<Result<Vec<DailyPeak>, StoreError>> {
+        self.store_path.as_deref().map(|path| Store::open(path)?.daily_peaks())
+    }
+
+    // The OCPP client certificate. None without a place to keep it.
+    pub fn certificates(&self) -> Option<CertificateStore> {
+        self.certificate_dir.as_ref().map(CertificateStore::new)
+    }
+
+    // The statistics of the running session and the last one.
+    pub fn session_log(&self) -> SessionLog {
+        self.status.lock().unwrap().sessions.clone()
+    }
+
+    // The phases the vehicle charges on and the power that allows.
+    pub fn phase_status(&self) -> PhaseStatus {
+        self.status.lock().unwrap().phases
+    }
+
+    pub fn cable_health(&self) -> CableHealth {
+        self.status.lock().unwrap().cable
+    }
+
+    // What is shed to keep the control loop within its latency budget.
+    pub fn brownout(&self) -> &Brownout {
+        &self.brownout
+    }
+
+    pub fn brownout_status(&self) -> BrownoutStatus {
+        self.status.lock().unwrap().brownout
+    }
+
+    // Every pilot reading from now on, for async consumers.
+    pub fn pilot_samples(&self) -> impl Stream<Item = PilotSample> + Send + Unpin {
+        self.streams.pilot.subscribe()
+    }
+
+    // A meter sample with every pilot reading from now on.
+    pub fn meter_samples(&self) -> impl Stream<Item = MeterSample> + Send + Unpin {
+        self.streams.meter.subscribe()
+    }
+
+    // Whether the site settings allow pilot test patterns.
+    pub fn lab_mode(&self) -> bool {
+        self.lab_mode
+    }
+
+    // The responses to the last lab pattern.
+    pub fn lab_report(&self) -> Option<LabReport> {
+        self.status.lock().unwrap().lab.clone()
+    }
+
+    // The highest offer the hardware makes.
+    pub fn max_current(&self) -> f64 {
+        self.status.lock().unwrap().limits.hardware_max()
+    }
+
+    // Adjusts the offer, during the charge too. A lower offer is made at
+    // once, a higher one once the vehicle had the time to follow the last
+    // change.
+    pub fn adjust_current_offer(&self, ampere: f64) -> Result<(), EVSEError> {
+        if ampere.is_nan() || ampere < 0.0 {
+            return Err(EVSEError::InvalidOffer(ampere));
+        }
+        self.send_command(EvseCommand::AdjustCurrentOffer(ampere))
+    }
+
+    // Changes the maximum current of the installation. Applies from the
+    // next offer on; refused while the vehicle charges.
+    pub fn set_max_current(&self, ampere: f64) -> Result<(), EVSEError> {
+        if !is_valid_max_current(ampere) {
+            return Err(EVSEError::InvalidMaxCurrent(ampere));
+        }
+        if is_charging(self.state()) {
+            return Err(EVSEError::Charging);
+        }
+        self.send_command(EvseCommand::SetMaxCurrent(ampere))
+    }
+
+    pub fn send_command(&self, command: EvseCommand) -> Result<(), EVSEError> {
+        self.command_tx.send(command).map_err(|_| EVSEError::MachineStopped)
+    }
+
+    // Asks the machine for an orderly stop: a charging vehicle is asked to
+    // stop first. Returns at once; the machine exits when done.
+    pub fn shut_down(&self) {
+        let _ = self.command_tx.send(EvseCommand::Shutdown);
+    }
+
+    // Asks the machine to stop. The machine opens the contactor and stops
+    // offering current before it exits.
+    pub fn stop(&self) {
+        // The machine may have stopped on its own already.
+        let _ = self.command_tx.send(EvseCommand::Stop);
+    }
+
+    // Whether the control contact of the grid operator is asserted. None
+    // without a contact.
+    pub fn control_contact(&self) -> Option<bool> {
+        self.status.lock().unwrap().control_contact
+    }
+
+    // The state the machine was in when mains power was lost, if it was.
+    pub fn interrupted_state(&self) -> Option<EVSEMachineState> {
+        self.status.lock().unwrap().interrupted
+    }
+
+    // The state and the readings as of the last pilot reading, stamped
+    // now. None before the first reading.
+    pub fn sensor_sample(&self) -> Option<SensorSample> {
+        let sample = self.status.lock().unwrap().sample?;
+        let timestamp_ns = self.clock.system_time().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+        Some(SensorSample { timestamp_ns, ..sample })
+    }
+
+    // Energy, sessions, GFI trips, contactor cycles and uptime over the
+    // life of the station, see counters.rs.
+    pub fn lifetime_counters(&self) -> LifetimeCounters {
+        self.status.lock().unwrap().lifetime
+    }
+
+    // The error counters, across restarts with the state file.
+    pub fn error_counters(&self) -> ErrorCounters {
+        self.status.lock().unwrap().counters.clone()
+    }
+}
+
+// How a machine ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineExit {
+    // Stopped or shut down on request, in the given state.
+    Stopped(EVSEMachineState),
+    // Mains power was lost. The contactor is open.
+    PowerFailure,
+    // The machine thread panicked. The hardware was dropped on the way out.
+    Panicked,
+}
+
+impl MachineExit {
+    // The exit code of juiced. A station stopped while out of service
+    // exits with a failure, so systemd shows it failed.
+    pub fn code(&self) -> i32 {
+        match self {
+            MachineExit::Stopped(EVSEMachineState::FailedStation) => 1,
+            MachineExit::Stopped(_) | MachineExit::PowerFailure => 0,
+            // Like an unwinding Rust process.
+            MachineExit::Panicked => 101,
+        }
+    }
+}
+
+impl From<thread::Result<EVSEMachineState>> for MachineExit {
+    fn from(result: thread::Result<EVSEMachineState>) -> Self {
+        match result {
+            Ok(EVSEMachineState::PowerFailure) => MachineExit::PowerFailure,
+            Ok(state) => MachineExit::Stopped(state),
+            Err(_) => MachineExit::Panicked,
+        }
+    }
+}
+
+// Handle to a running state machine.
+pub struct EvseHandle {
+    controller: EvseController,
+    thread: JoinHandle<EVSEMachineState>,
+}
+
+impl EvseHandle {
+    pub fn controller(&self) -> EvseController {
+        self.controller.clone()
+    }
+
+    pub fn state(&self) -> EVSEMachineState {
+        self.controller.state()
+    }
+
+    pub fn telemetry(&self, verbosity: TelemetryVerbosity) -> TelemetrySample {
+        self.controller.telemetry(verbosity)
+    }
+
+    pub fn send_command(&self, command: EvseCommand) -> Result<(), EVSEError> {
+        self.controller.send_command(command)
+    }
+
+    pub fn stop(&self) {
+        self.controller.stop()
+    }
+
+    pub fn interrupted_state(&self) -> Option<EVSEMachineState> {
+        self.controller.interrupted_state()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    // Waits for the machine to exit and returns the state it ended in.
+    pub fn join(self) -> thread::Result<EVSEMachineState> {
+        self.thread.join()
+    }
+
+    // Waits for the machine to exit. By then the hardware is dropped: the
+    // pilot no longer offers, the contactor is open and the threads of the
+    // hardware have ended.
+    pub fn wait(self) -> MachineExit {
+        self.join().into()
+    }
+
+    // Orderly stop: a charging vehicle is asked to stop first, then the
+    // contactor opens. Waits for the machine like wait.
+    pub fn shut_down(self) -> MachineExit {
+        self.controller.shut_down();
+        self.wait()
+    }
+}
+
+// Runs the state machine on its own thread and returns a handle to it.
+pub fn start_machine<H: EVSEHardware>(evse: H) -> EvseHandle {
+    start_machine_with(evse, MachineOptions::default())
+}
+
+// Like start_machine, but picks up a charge that a power failure
+// interrupted if the vehicle still asks for power.
+pub fn start_machine_resuming<H: EVSEHardware>(evse: H) -> EvseHandle {
+    start_machine_with(
+        evse,
+        MachineOptions {
+            resume: true,
+            ..MachineOptions::default()
+        },
+    )
+}
+
+// Like start_machine, and sends what the machine does to `event_tx`.
+pub fn start_machine_with_events<H: EVSEHardware>(evse: H, event_tx: Sender<EVSEEvent>) -> EvseHandle {
+    start_machine_with(
+        evse,
+        MachineOptions {
+            events: Some(event_tx),
+            ..MachineOptions::default()
+        },
+    )
+}
+
+pub fn start_machine_with<H: EVSEHardware>(evse: H, mut options: MachineOptions) -> EvseHandle {
+    let (command_tx, command_rx) = unbounded();
+    let clock = options.clock.get_or_insert_with(system_clock).clone();
+    let status = Arc::new(Mutex::new(MachineStatus {
+        state: EVSEMachineState::Standby,
+        pilot: None,
+        interrupted: None,
+        breaker: None,
+        acquisition: None,
+        state_since: clock.now(),
+        limits: LimiterChain::new(evse.max_current()),
+        features: FeatureFlags::default(),
+        shadow_pilot: None,
+        lab: None,
+        guest: None,
+        tenant: None,
+        phases: PhaseStatus {
+            phases_in_use: None,
+            usable_power_w: 0.0,
+            power_budget_w: None,
+        },
+        cable: CableHealth::default(),
+        brownout: BrownoutStatus {
+            level: BrownoutLevel::Normal,
+            latency_ms: 0.0,
+            backlog: 0,
+        },
+        session_id: None,
+        curve: ChargingCurve::default(),
+        sessions: SessionLog::default(),
+        grace_until: None,
+        control_contact: None,
+        connector_temperatures_c: Vec::new(),
+        metrics: StateMetrics::default(),
+        resume: ResumeTokens::default(),
+        counters: ErrorCounters::default(),
+        lifetime: LifetimeCounters::default(),
+        completion: None,
+        sample: None,
+    }));
+    let shared_status = status.clone();
+    let store_path = options.store_path.clone();
+    let lab_mode = options.lab_mode;
+    let certificate_dir = options.certificate_dir.clone();
+    let time_zone = options.time_zone.unwrap_or(DEFAULT_TIME_ZONE);
+    let streams = MachineStreams::default();
+    let machine_streams = streams.clone();
+    let brownout = Brownout::default();
+    let machine_brownout = brownout.clone();
+    let thread = thread::spawn(move || {
+        machine_loop(evse, command_rx, shared_status, machine_streams, machine_brownout, options)
+    });
+
+    EvseHandle {
+        controller: EvseController {
+            command_tx,
+            status,
+            store_path,
+            lab_mode,
+            streams,
+            certificate_dir,
+            time_zone,
+            brownout,
+            clock,
+        },
+        thread,
+    }
+}
+
+// Runs the state machine until it ends and returns how it ended, for the
+// exit code of the juiced binary.
+//
+// On a power failure the contactor is already open when the machine ends.
+// What is needed to resume is recorded, then the OS is shut down while the
+// UPS battery still lasts. The record is picked up on the next start.
+//
+// The offer never goes above the board's max_current, whatever the site
+// settings say.
+pub fn run_machine<H: EVSEHardware>(evse: H, config: &Config) -> MachineExit {
+    let record_path = Path::new(DEFAULT_RECORD_PATH);
+    let audit_log = AuditLog::new(Path::new(DEFAULT_AUDIT_LOG_PATH));
+    let settings = match load_settings_with_recovery(Path::new(DEFAULT_CONFIG_DIR)) {
+        Ok(loaded) => {
+            if let Some(warning) = &loaded.warning {
+                record_event(&audit_log, warning);
+            }
+            Some(loaded.value)
+        }
+        Err(error) => {
+            eprintln!("Site settings not loaded, using the defaults: {:?}", error);
+            None
+        }
+    };
+    if let Some(settings) = settings.as_ref() {
+        match config_history::record_applied(Path::new(DEFAULT_STORE_PATH), settings) {
+            Ok(Some(snapshot)) => record_event(
+                &audit_log,
+                &format!("site settings snapshot #{}, sha256 {}", snapshot.id, snapshot.hash),
+            ),
+            Ok(None) => {}
+            Err(error) => eprintln!("Site settings snapshot not taken: {}", error),
+        }
+    }
+    // The events in the system journal, for the alerting of the fleet.
+    let events = match JournalSink::connect() {
+        Ok(sink) => {
+            let (event_tx, event_rx) = bounded(EVENT_BACKLOG);
+            journal::watch_events(event_rx, sink);
+            Some(event_tx)
+        }
+        Err(error) => {
+            eprintln!("System journal unavailable: {}", error);
+            None
+        }
+    };
+    // The interrupted sessions, for the driver.
+    let interruptions = settings
+        .as_ref()
+        .and_then(|settings| settings.interruption_webhook.clone())
+        .map(|webhook| {
+            let (interruption_tx, interruption_rx) = bounded(INTERRUPTION_BACKLOG);
+            interruption::watch_interruptions(interruption_rx, webhook);
+            interruption_tx
+        });
+    let options = MachineOptions {
+        resume: PowerFailRecord::take(record_path).is_some_and(|record| record.was_charging),
+        audit_log,
+        black_box_dir: Some(PathBuf::from(DEFAULT_BLACK_BOX_DIR)),
+        breaker: settings.as_ref().and_then(|settings| settings.breaker_config()),
+        features: settings
+            .as_ref()
+            .map_or_else(FeatureFlags::default, |settings| FeatureFlags::from_settings(&settings.features)),
+        max_current: Some(
+            settings
+                .as_ref()
+                .map_or(config.max_current, |settings| settings.max_current.min(config.max_current)),
+        ),
+        store_path: Some(PathBuf::from(DEFAULT_STORE_PATH)),
+        shadow_pilot: settings.as_ref().and_then(|settings| settings.shadow_pilot_classifier),
+        timing: J1772Timing::default(),
+        lab_mode: settings.as_ref().is_some_and(|settings| settings.lab_mode),
+        alarm_policy: settings.as_ref().map(|settings| settings.alarm_policy).unwrap_or_default(),
+        certificate_dir: Some(PathBuf::from(DEFAULT_CERTIFICATE_DIR)),
+        time_zone: settings.as_ref().map(|settings| settings.time_zone()),
+        solar: settings.as_ref().map(|settings| settings.solar_hysteresis).unwrap_or_default(),
+        grace_unplug: settings
+            .as_ref()
+            .and_then(|settings| settings.grace_unplug_secs)
+            .map(Duration::from_secs),
+        pricing: settings.as_ref().and_then(|settings| settings.pricing.clone()),
+        control_contact_amps: settings.as_ref().and_then(|settings| settings.control_contact_amps),
+        events,
+        clock: None,
+        recovery: settings.as_ref().and_then(|settings| settings.error_recovery),
+        ventilation: config.ventilation,
+        pilot_timeout: Some(
+            settings
+                .as_ref()
+                .and_then(|settings| settings.pilot_timeout_ms)
+                .map_or(DEFAULT_PILOT_TIMEOUT, Duration::from_millis),
+        ),
+        interruptions,
+        state_path: settings
+            .as_ref()
+            .filter(|settings| settings.persist_state)
+            .map(|_| PathBuf::from(DEFAULT_STATE_PATH)),
+        gfi_retry: settings.as_ref().map(|settings| settings.gfi_retry).unwrap_or_default(),
+        counters_dir: Some(PathBuf::from(DEFAULT_COUNTERS_DIR)),
+        imbalance: settings.as_ref().map(|settings| settings.line_imbalance).unwrap_or_default(),
+        power_quality: Some(config.power_quality()),
+        power_quality_log: PowerQualityLog::new(Path::new(DEFAULT_POWER_QUALITY_LOG_PATH)),
+    };
+    CertificateStore::new(DEFAULT_CERTIFICATE_DIR).watch_expiry(options.audit_log.clone());
+    let handle = start_machine_with(evse, options);
+    let controller = handle.controller();
+    if let Some(config) = settings.as_ref().and_then(|settings| settings.influx.clone()) {
+        influx::watch_samples(controller.clone(), InfluxExporter::new(config));
+    }
+    if let Some(pricing) = settings.as_ref().and_then(|settings| settings.pricing.as_ref()) {
+        pricing::watch_prices(AwattarProvider::new(pricing), controller.clone());
+    }
+    if let Err(error) = rpc::serve(Path::new(rpc::DEFAULT_SOCKET_PATH), controller.clone()) {
+        eprintln!("Failed to open the control socket: {}", error);
+    }
+    if let Some(port) = settings.as_ref().and_then(|settings| settings.rapi_port.as_ref()) {
+        if let Err(error) = rapi::serve(port, controller.clone()) {
+            eprintln!("Failed to open the RAPI port {}: {}", port.display(), error);
+        }
+    }
+    let (exposure, theme) = settings.map_or((GuestExposure::Disabled, Theme::default()), |settings| {
+        (settings.guest_status, settings.theme)
+    });
+    if let Err(error) = guest::serve(DEFAULT_GUEST_ADDRESS, controller.clone(), exposure, theme) {
+        eprintln!("Failed to open the guest status endpoint: {}", error);
+    }
+    // SIGTERM from systemd, or SIGINT, ends the machine in order.
+    let terminated = Arc::new(AtomicBool::new(false));
+    match Signals::new([SIGTERM, SIGINT]) {
+        Ok(mut signals) => {
+            let terminated = terminated.clone();
+            let controller = controller.clone();
+            thread::spawn(move || {
+                if signals.forever().next().is_some() {
+                    terminated.store(true, Ordering::SeqCst);
+                    controller.shut_down();
+                }
+            });
+        }
+        Err(error) => eprintln!("Failed to install the signal handlers: {}", error),
+    }
+
+    let exit = handle.wait();
+    if terminated.load(Ordering::SeqCst) {
+        // Keeps the session for the next start, like a power failure does:
+        // a vehicle that was charging is charged again after a restart.
+        let record = PowerFailRecord::new(matches!(exit, MachineExit::Stopped(state) if is_charging(state)));
+        if let Err(error) = record.save(record_path) {
+            eprintln!("Failed to save the session state: {}", error);
+        }
+        return exit;
+    }
+    match exit {
+        MachineExit::PowerFailure => {
+            let interrupted = controller.interrupted_state();
+            let record = PowerFailRecord::new(interrupted.is_some_and(is_charging));
+            if let Err(error) = record.save(record_path) {
+                eprintln!("Failed to save the power failure record: {}", error);
+            }
+            if let Err(error) = request_os_shutdown() {
+                eprintln!("Failed to shut down: {}", error);
+            }
+        }
+        MachineExit::Stopped(EVSEMachineState::FailedStation) | MachineExit::Panicked => {
+            eprintln!("The station stopped out of service: {:?}", exit)
+        }
+        MachineExit::Stopped(_) => {}
+    }
+    exit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use EVSEMachineInput::*;
+    use crate::certificates::unix_now;
+    use crate::clock::MockClock;
+    use crate::lab::{LabStep, LabVector};
+    use crate::timing::STOP_RESPONSE;
+    use std::time::Instant;
+
+    // Hardware double recording the commands it receives.
+    struct FakeHardware {
+        pilot_rx: Receiver<PilotReading>,
+        fault_rx: Receiver<EVSEMachineInput>,
+        contactor: Arc<Mutex<bool>>,
+        pilot: Arc<Mutex<PilotSignal>>,
+        self_test_ok: bool,
+        control_contact: Arc<Mutex<Option<bool>>>,
+        alarm: Arc<Mutex<Vec<bool>>>,
+        connector_temperatures: Arc<Mutex<Vec<f64>>>,
+        ventilation: Arc<Mutex<bool>>,
+        current: Arc<Mutex<Option<f64>>>,
+        lines: Arc<Mutex<Option<[f64; 2]>>>,
+        mains: Arc<Mutex<Option<(f64, f64)>>>,
+    }
+
+    impl EVSEHardware for FakeHardware {
+        fn set_pilot(&mut self, signal: PilotSignal) -> Result<(), EVSEError> {
+            *self.pilot.lock().unwrap() = signal;
+            Ok(())
+        }
+
+        fn set_contactor(&mut self, on: bool) -> Result<(), EVSEError> {
+            *self.contactor.lock().unwrap() = on;
+            Ok(())
+        }
+
+        fn set_alarm(&mut self, on: bool) -> Result<(), EVSEError> {
+            self.alarm.lock().unwrap().push(on);
+            Ok(())
+        }
+
+        fn connector_temperatures_c(&self) -> Vec<f64> {
+            self.connector_temperatures.lock().unwrap().clone()
+        }
+
+        fn current_amps(&self) -> Option<f64> {
+            *self.current.lock().unwrap()
+        }
+
+        fn line_currents(&self) -> Option<[f64; 2]> {
+            *self.lines.lock().unwrap()
+        }
+
+        fn mains_volts(&self) -> Option<f64> {
+            self.mains.lock().unwrap().map(|(volts, _)| volts)
+        }
+
+        fn mains_frequency_hz(&self) -> Option<f64> {
+            self.mains.lock().unwrap().map(|(_, hz)| hz)
+        }
+
+        fn set_ventilation(&mut self, on: bool) -> Result<(), EVSEError> {
+            *self.ventilation.lock().unwrap() = on;
+            Ok(())
+        }
+
+        fn run_gfi_self_test(&mut self) -> Result<(), EVSEError> {
+            if self.self_test_ok {
+                Ok(())
+            } else {
+                Err(EVSEError::Peripherals(PeripheralsError::GfiSelfTestFailed("fake")))
+            }
+        }
+
+        fn pilot_channel(&self) -> Receiver<PilotReading> {
+            self.pilot_rx.clone()
+        }
+
+        fn fault_channel(&self) -> Receiver<EVSEMachineInput> {
+            self.fault_rx.clone()
+        }
+
+        fn control_contact(&self) -> Option<bool> {
+            *self.control_contact.lock().unwrap()
+        }
+    }
+
+    struct Harness {
+        pilot_tx: Sender<PilotReading>,
+        fault_tx: Sender<EVSEMachineInput>,
+        contactor: Arc<Mutex<bool>>,
+        pilot: Arc<Mutex<PilotSignal>>,
+        control_contact: Arc<Mutex<Option<bool>>>,
+        // Everything written to the alarm output.
+        alarm: Arc<Mutex<Vec<bool>>>,
+        connector_temperatures: Arc<Mutex<Vec<f64>>>,
+        ventilation: Arc<Mutex<bool>>,
+        // What the vehicle draws, for a current sense. None without one.
+        current: Arc<Mutex<Option<f64>>>,
+        // The currents of both lines, for a split-phase CT pair.
+        lines: Arc<Mutex<Option<[f64; 2]>>>,
+        // The mains RMS volts and frequency.
+        mains: Arc<Mutex<Option<(f64, f64)>>>,
+    }
+
+    fn fake_hardware(self_test_ok: bool) -> (FakeHardware, Harness) {
+        let (pilot_tx, pilot_rx) = unbounded();
+        let (fault_tx, fault_rx) = unbounded();
+        let contactor = Arc::new(Mutex::new(false));
+        let pilot = Arc::new(Mutex::new(PilotSignal::ErrorMinus12));
+        let control_contact = Arc::new(Mutex::new(None));
+        let alarm = Arc::new(Mutex::new(Vec::new()));
+        let connector_temperatures = Arc::new(Mutex::new(Vec::new()));
+        let ventilation = Arc::new(Mutex::new(false));
+        let current = Arc::new(Mutex::new(None));
+        let lines = Arc::new(Mutex::new(None));
+        let mains = Arc::new(Mutex::new(None));
+        let hardware = FakeHardware {
+            pilot_rx,
+            fault_rx,
+            contactor: contactor.clone(),
+            pilot: pilot.clone(),
+            self_test_ok,
+            control_contact: control_contact.clone(),
+            alarm: alarm.clone(),
+            connector_temperatures: connector_temperatures.clone(),
+            ventilation: ventilation.clone(),
+            current: current.clone(),
+            lines: lines.clone(),
+            mains: mains.clone(),
+        };
+        let harness = Harness {
+            pilot_tx,
+            fault_tx,
+            contactor,
+            pilot,
+            control_contact,
+            alarm,
+            connector_temperatures,
+            ventilation,
+            current,
+            lines,
+            mains,
+        };
+        (hardware, harness)
+    }
+
+    fn send_pilot(harness: &Harness, high: f32) {
+        let reading = PilotReading {
+            high,
+            low: -12.0,
+            duty_cycle: 0.5,
+            frequency: 1000.0,
+        };
+        harness.pilot_tx.send(reading).unwrap();
+    }
+
+    fn wait_for_state(handle: &EvseHandle, state: EVSEMachineState) {
+        let start = Instant::now();
+        while handle.state() != state {
+            assert!(start.elapsed() < Duration::from_secs(2), "stuck in {:?}", handle.state());
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn classify_all(classifier: &mut PilotClassifier, readings: &[f32]) -> Vec<EVSEMachineInput> {
+        readings.iter().map(|&v| classifier.get_pilot_state(v)).collect()
+    }
+
+    #[test]
+    fn test_nominal_levels() {
+        let mut classifier = PilotClassifier::default();
+        assert_eq!(classifier.get_pilot_state(12.0), PilotIs12V);
+        classifier.reset();
+        assert_eq!(classifier.get_pilot_state(9.0), PilotIs9V);
+        classifier.reset();
+        assert_eq!(classifier.get_pilot_state(6.0), PilotIs6V);
+        classifier.reset();
+        assert_eq!(classifier.get_pilot_state(3.0), PilotIs3V);
+        classifier.reset();
+        assert_eq!(classifier.get_pilot_state(0.0), PilotInError);
+        classifier.reset();
+        assert_eq!(classifier.get_pilot_state(-12.0), PilotInError);
+    }
+
+    #[test]
+    fn test_noise_at_edge_does_not_flip_state() {
+        let mut classifier = PilotClassifier::default();
+        let states = classify_all(&mut classifier, &[9.0, 7.6, 7.4, 7.6, 7.3, 7.55]);
+        assert!(states.iter().all(|&s| s == PilotIs9V));
+    }
+
+    #[test]
+    fn test_state_is_left_beyond_margin() {
+        let mut classifier = PilotClassifier::default();
+        assert_eq!(classifier.get_pilot_state(9.0), PilotIs9V);
+        assert_eq!(classifier.get_pilot_state(7.1), PilotIs6V);
+        // Going back up needs the 6V band plus the margin to be exceeded.
+        assert_eq!(classifier.get_pilot_state(7.6), PilotIs6V);
+        assert_eq!(classifier.get_pilot_state(7.7), PilotIs6V);
+        assert_eq!(classifier.get_pilot_state(7.9), PilotIs9V);
+    }
+
+    #[test]
+    fn test_large_jump_skips_bands() {
+        let mut classifier = PilotClassifier::default();
+        assert_eq!(classifier.get_pilot_state(12.0), PilotIs12V);
+        assert_eq!(classifier.get_pilot_state(6.0), PilotIs6V);
+        assert_eq!(classifier.get_pilot_state(-12.0), PilotInError);
+    }
+
+    #[test]
+    fn test_zero_margin_uses_nominal_edges() {
+        let mut classifier = PilotClassifier::new(0.0);
+        let states = classify_all(&mut classifier, &[7.6, 7.4, 7.6]);
+        assert_eq!(states, vec![PilotIs9V, PilotIs6V, PilotIs9V]);
+    }
+
+    #[test]
+    fn test_nan_is_an_error() {
+        let mut classifier = PilotClassifier::default();
+        assert_eq!(classifier.get_pilot_state(9.0), PilotIs9V);
+        assert_eq!(classifier.get_pilot_state(f32::NAN), PilotInError);
+    }
+
+    // Square wave between +high and -12V with the given period and duty
+    // cycle, in samples.
+    fn square_wave(high: f32, period: usize, duty_cycle: f32, len: usize) -> Vec<f32> {
+        let high_samples = (period as f32 * duty_cycle).round() as usize;
+        (0..len)
+            .map(|i| if (i + period - 1) % period < high_samples { high } else { -12.0 })
+            .collect()
+    }
+
+    #[test]
+    fn test_pilot_reading_from_oscillating_samples() {
+        // 40 samples per 1 ms period, 100 samples in 2.5 ms.
+        let samples = square_wave(9.0, 40, 0.25, 100);
+        let reading = PilotReading::from_samples(&samples, Duration::from_micros(2500));
+        assert_eq!(reading.high, 9.0);
+        assert_eq!(reading.low, -12.0);
+        assert!((reading.duty_cycle - 0.25).abs() < 0.01);
+        assert!((reading.frequency - 1000.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_pilot_reading_from_plateaus() {
+        // State C at a 10% duty cycle, each rising edge overshoots.
+        let mut samples = square_wave(6.0, 40, 0.1, 100);
+        for i in 1..samples.len() {
+            if samples[i - 1] < 0.0 && samples[i] > 0.0 {
+                samples[i] = 9.5;
+            }
+        }
+        let window = Duration::from_micros(2500);
+        let unsynchronized = PilotReading::from_samples(&samples, window);
+        assert_eq!(unsynchronized.high, 9.5);
+
+        let reading = PilotReading::from_plateaus(&samples, window, PeakPercentiles::default());
+        assert_eq!(reading.high, 6.0);
+        assert_eq!(reading.low, -12.0);
+        assert_eq!(reading.duty_cycle, unsynchronized.duty_cycle);
+
+        // A NaN does not end a plateau.
+        let with_nans: Vec<f32> = samples
+            .iter()
+            .flat_map(|&v| if v > 0.0 { vec![v, f32::NAN] } else { vec![v] })
+            .collect();
+        let reading = PilotReading::from_plateaus(&with_nans, window, PeakPercentiles::default());
+        assert_eq!(reading.high, 6.0);
+        assert_eq!(reading.low, -12.0);
+
+        let reading = PilotReading::from_plateaus(&[12.0; 100], window, PeakPercentiles::default());
+        assert_eq!(reading.high, 12.0);
+        assert!(PilotReading::from_plateaus(&[], window, PeakPercentiles::default()).high.is_nan());
+    }
+
+    #[test]
+    fn test_pilot_reading_from_steady_samples() {
+        let reading = PilotReading::from_samples(&[12.0; 100], Duration::from_micros(2500));
+        assert_eq!(reading.high, 12.0);
+        assert_eq!(reading.duty_cycle, 1.0);
+        assert_eq!(reading.frequency, 0.0);
+
+        let reading = PilotReading::from_samples(&[], Duration::ZERO);
+        assert!(reading.high.is_nan());
+    }
+
+    // Reads 12 V until the vehicle is plugged in, 9 V after.
+    struct PlugInSampler {
+        plugged_in_at: Instant,
+    }
+
+    impl PilotSampler for PlugInSampler {
+        fn read_pilot_samples(&mut self, samples: usize) -> Result<Vec<f32>, EVSEError> {
+            let volts = if Instant::now() < self.plugged_in_at { 12.0 } else { 9.0 };
+            Ok(vec![volts; samples])
+        }
+    }
+
+    #[test]
+    fn test_plug_in_detected_between_windows() {
+        let plugged_in_at = Instant::now() + Duration::from_millis(150);
+        let (pilot_tx, pilot_rx) = unbounded();
+        thread::spawn(move || {
+            EVSEHardwareImpl::sample_pilot(
+                Box::new(PlugInSampler { plugged_in_at }),
+                PeakPercentiles::default(),
+                PilotSampling::default(),
+                // The longest interval allowed.
+                Duration::from_millis(500),
+                AcquisitionSchedule::new(OversamplingPolicy::NONE),
+                AcquisitionMetrics::default(),
+                pilot_tx,
+            )
+        });
+        let detected_at = loop {
+            let reading = pilot_rx.recv().unwrap();
+            if reading.high < EDGE_12V_9V {
+                break Instant::now();
+            }
+        };
+        assert!(detected_at.duration_since(plugged_in_at) < Duration::from_millis(100));
+    }
+
+    // The mains at 230 V RMS and 50.2 Hz, and the pilot resting at 12 V.
+    struct MainsSampler {
+        started_at: Instant,
+    }
+
+    impl PilotSampler for MainsSampler {
+        fn read_pilot_samples(&mut self, samples: usize) -> Result<Vec<f32>, EVSEError> {
+            Ok(vec![12.0; samples])
+        }
+
+        fn read_signal(&mut self, signal: Signal) -> Option<Result<f32, EVSEError>> {
+            let t = self.started_at.elapsed().as_secs_f64();
+            let volts = 230.0 * 2f64.sqrt() * (2.0 * std::f64::consts::PI * 50.2 * t).sin();
+            (signal == Signal::MainsV).then_some(Ok(volts as f32))
+        }
+    }
+
+    #[test]
+    fn test_mains_measured_over_whole_cycles() {
+        let (pilot_tx, _pilot_rx) = unbounded();
+        let metrics = AcquisitionMetrics::default();
+        let reader = metrics.reader();
+        let schedule = AcquisitionSchedule::new(OversamplingPolicy::NONE);
+        let sampler = thread::spawn({
+            let schedule = schedule.clone();
+            move || {
+                EVSEHardwareImpl::sample_pilot(
+                    Box::new(MainsSampler { started_at: Instant::now() }),
+                    PeakPercentiles::default(),
+                    PilotSampling::default(),
+                    Duration::from_millis(50),
+                    schedule,
+                    metrics,
+                    pilot_tx,
+                )
+            }
+        });
+        let start = Instant::now();
+        while reader.mains_frequency_hz().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(2), "mains not measured");
+            thread::sleep(Duration::from_millis(5));
+        }
+        schedule.stop();
+        sampler.join().unwrap();
+        assert!((reader.mains_frequency_hz().unwrap() - 50.2).abs() < 0.1);
+        assert!((reader.signal(Signal::MainsV).unwrap() - 230.0).abs() < 2.0);
+    }
+
+    #[test]
+    fn test_telemetry_from_handle() {
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::VehicleDetected);
+        assert_eq!(handle.telemetry(TelemetryVerbosity::Normal).pilot, None);
+        let pilot = handle.telemetry(TelemetryVerbosity::Diagnostic).pilot.unwrap();
+        assert_eq!(pilot.classification, PilotIs9V);
+        assert_eq!(pilot.frequency, 1000.0);
+        let session_id = handle.controller().session_id();
+        assert!(session_id.is_some());
+        assert_eq!(handle.telemetry(TelemetryVerbosity::Normal).session_id, session_id);
+
+        send_pilot(&harness, 12.0);
+        wait_for_state(&handle, EVSEMachineState::Standby);
+        assert_eq!(handle.controller().session_id(), None);
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_next_state() {
+        use EVSEMachineState::*;
+        assert_eq!(next_state(Standby, PilotIs9V), Some(VehicleDetected));
+        assert_eq!(next_state(Standby, PilotIs6V), Some(ResetableError));
+        assert_eq!(next_state(VehicleDetected, PilotIs6V), Some(StartCharging));
+        assert_eq!(next_state(StartCharging, SelfTestOk), Some(Charging));
+        assert_eq!(next_state(Charging, PilotIs6V), None);
+        assert_eq!(next_state(Charging, PilotIs9V), Some(SuspendedEV));
+        assert_eq!(next_state(Charging, PilotIs12V), Some(StopCharging));
+        assert_eq!(next_state(SuspendedEV, PilotIs9V), None);
+        assert_eq!(next_state(SuspendedEV, PilotIs6V), Some(StartCharging));
+        assert_eq!(next_state(SuspendedEV, PauseTimedOut), Some(StopCharging));
+        assert_eq!(next_state(Charging, EnclosureOpened), Some(TamperLockout));
+        assert_eq!(next_state(Standby, EnclosureOpened), None);
+        assert_eq!(next_state(TamperLockout, PilotIs12V), None);
+        assert_eq!(next_state(Charging, GFIInterrupted), Some(FailedStation));
+        assert_eq!(next_state(FailedStation, PilotIs12V), None);
+        assert_eq!(next_state(Charging, PowerLost), Some(PowerFailure));
+        assert_eq!(next_state(FailedStation, PowerLost), None);
+        assert_eq!(next_state(Charging, DiodeCheckFailed), Some(ResetableError));
+        assert_eq!(next_state(VehicleDetected, DiodeCheckFailed), Some(ResetableError));
+        assert_eq!(next_state(Standby, DiodeCheckFailed), None);
+    }
+
+    #[test]
+    fn test_diode_check() {
+        let window = Duration::from_micros(2500);
+        let mut classifier = PilotClassifier::default();
+        let reading = PilotReading::from_samples(&square_wave(9.0, 40, 0.5, 100), window);
+        assert_eq!(classifier.classify(&reading), EVSEMachineInput::PilotIs9V);
+
+        // Without the diode, the vehicle loads both halves alike.
+        for (high, low) in [(9.0, -9.0), (6.0, -6.0), (9.0, -10.0)] {
+            let samples: Vec<f32> = square_wave(high, 40, 0.5, 100)
+                .into_iter()
+                .map(|v| if v < 0.0 { low } else { v })
+                .collect();
+            let reading = PilotReading::from_samples(&samples, window);
+            assert_eq!(classifier.classify(&reading), EVSEMachineInput::DiodeCheckFailed, "{} / {}", high, low);
+        }
+
+        // A steady pilot has no negative half to check.
+        let reading = PilotReading::from_samples(&[9.0; 100], window);
+        assert_eq!(classifier.classify(&reading), EVSEMachineInput::PilotIs9V);
+        let reading = PilotReading::from_samples(&[12.0; 100], window);
+        assert_eq!(classifier.classify(&reading), EVSEMachineInput::PilotIs12V);
+        let reading = PilotReading::from_samples(&[-12.0; 100], window);
+        assert_eq!(classifier.classify(&reading), EVSEMachineInput::PilotInError);
+    }
+
+    #[test]
+    fn test_missing_diode_is_resettable_error() {
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::VehicleDetected);
+        let reading = PilotReading {
+            high: 9.0,
+            low: -9.0,
+            duty_cycle: 0.5,
+            frequency: 1000.0,
+        };
+        harness.pilot_tx.send(reading).unwrap();
+        wait_for_state(&handle, EVSEMachineState::ResetableError);
+        assert!(!*harness.contactor.lock().unwrap());
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_charging_cycle_through_handle() {
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::VehicleDetected);
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(32.0));
+
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        assert!(*harness.contactor.lock().unwrap());
+
+        send_pilot(&harness, 12.0);
+        wait_for_state(&handle, EVSEMachineState::StopCharging);
+        assert!(!*harness.contactor.lock().unwrap());
+        let sessions = handle.controller().session_log();
+        let session = sessions.current().unwrap();
+        assert_eq!(session.charging_periods, 1);
+        assert!(session.stopped_at.is_some());
+        assert_eq!(session.peak_amps, 32.0);
+
+        handle.stop();
+        assert_eq!(handle.join().unwrap(), EVSEMachineState::StopCharging);
+    }
+
+    #[test]
+    fn test_orderly_shutdown_while_charging() {
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        handle.controller().shut_down();
+
+        // The offer goes first, the contactor stays closed until the vehicle
+        // stops.
+        let start = Instant::now();
+        while *harness.pilot.lock().unwrap() != PilotSignal::SteadyPlus12 {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(1));
+        }
+        send_pilot(&harness, 6.0);
+        thread::sleep(Duration::from_millis(20));
+        assert!(*harness.contactor.lock().unwrap());
+        send_pilot(&harness, 9.0);
+
+        assert_eq!(handle.join().unwrap(), EVSEMachineState::Charging);
+        assert!(!*harness.contactor.lock().unwrap());
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::ErrorMinus12);
+        assert!(start.elapsed() < STOP_RESPONSE.default);
+    }
+
+    #[test]
+    fn test_shutdown_respects_stop_response() {
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            timing: J1772Timing {
+                stop_response: Duration::from_millis(100),
+                ..J1772Timing::default()
+            },
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        let start = Instant::now();
+        handle.controller().shut_down();
+
+        // A vehicle that keeps asking for power is cut off once its time is
+        // up, not before.
+        while !handle.is_finished() {
+            if *harness.contactor.lock().unwrap() {
+                send_pilot(&harness, 6.0);
+            }
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(!*harness.contactor.lock().unwrap());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_lab_pattern() {
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            lab_mode: true,
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::VehicleDetected);
+
+        let vector = LabVector::OffFrequency {
+            duty_cycle: 0.25,
+            frequency: 1020.0,
+        };
+        let pattern = LabPattern {
+            steps: vec![LabStep { vector, hold_ms: 200 }],
+        };
+        handle.send_command(EvseCommand::RunLabPattern(pattern)).unwrap();
+        let start = Instant::now();
+        while *harness.pilot.lock().unwrap() != vector.signal() {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(1));
+        }
+        // The vehicle asks for power and gets none.
+        send_pilot(&harness, 6.0);
+        while !matches!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(_)) {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(1));
+        }
+        let report = handle.controller().lab_report().unwrap();
+        assert_eq!(report.aborted_by, None);
+        let responses: Vec<_> = report.responses.iter().map(|response| response.classification).collect();
+        assert_eq!(responses, vec![PilotIs6V]);
+        assert!(!*harness.contactor.lock().unwrap());
+        assert_eq!(handle.state(), EVSEMachineState::VehicleDetected);
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_vehicle_pause_keeps_session() {
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::SuspendedEV);
+        assert!(!*harness.contactor.lock().unwrap());
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(32.0));
+
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        assert!(*harness.contactor.lock().unwrap());
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_vehicle_pause_times_out() {
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+        handle.send_command(EvseCommand::SetMaxPause(Duration::from_millis(20))).unwrap();
+
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::SuspendedEV);
+        thread::sleep(Duration::from_millis(30));
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::StopCharging);
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_current_limit_command() {
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+
+        handle.send_command(EvseCommand::SetCurrentLimit(16.0)).unwrap();
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::VehicleDetected);
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(16.0));
+
+        // Lowered during the session, the offer follows at once.
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        let wait_for_pilot = |signal| {
+            let start = Instant::now();
+            while *harness.pilot.lock().unwrap() != signal {
+                assert!(start.elapsed() < Duration::from_secs(2), "pilot stuck");
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        handle.send_command(EvseCommand::SetCurrentLimit(12.0)).unwrap();
+        wait_for_pilot(PilotSignal::OfferAmps(12.0));
+        handle.send_command(EvseCommand::SetLimit(Limiter::DemandResponse, Some(8.0))).unwrap();
+        wait_for_pilot(PilotSignal::OfferAmps(8.0));
+        let limits = handle.controller().limits();
+        assert_eq!(limits.offer, 8.0);
+        assert_eq!(limits.binding, Limiter::DemandResponse);
+        assert_eq!(handle.state(), EVSEMachineState::Charging);
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_solar_mode_gates_surplus() {
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+        handle.send_command(EvseCommand::SetCurrentLimit(16.0)).unwrap();
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        let wait_for_pilot = |signal| {
+            let start = Instant::now();
+            while *harness.pilot.lock().unwrap() != signal {
+                assert!(start.elapsed() < Duration::from_secs(2), "pilot stuck");
+                send_pilot(&harness, 6.0);
+                thread::sleep(Duration::from_millis(5));
+            }
+        };
+
+        // Out of solar mode the surplus is ignored.
+        handle.send_command(EvseCommand::SetLimit(Limiter::Solar, Some(10.0))).unwrap();
+        for _ in 0..5 {
+            send_pilot(&harness, 6.0);
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(16.0));
+        assert!(handle.controller().limits().caps.iter().all(|cap| cap.limiter != Limiter::Solar));
+
+        handle.send_command(EvseCommand::SetFeature(Feature::SolarMode, Some(true))).unwrap();
+        handle.send_command(EvseCommand::SetLimit(Limiter::Solar, Some(10.0))).unwrap();
+        wait_for_pilot(PilotSignal::OfferAmps(10.0));
+        assert_eq!(handle.controller().limits().binding, Limiter::Solar);
+
+        // Turned off, the solar cap goes.
+        handle.send_command(EvseCommand::SetFeature(Feature::SolarMode, Some(false))).unwrap();
+        wait_for_pilot(PilotSignal::OfferAmps(16.0));
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_closed_loop_trim() {
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+        handle.send_command(EvseCommand::SetFeature(Feature::ClosedLoopTrim, Some(true))).unwrap();
+        handle.send_command(EvseCommand::SetCurrentLimit(16.0)).unwrap();
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+
+        // A vehicle that draws 1.5 A above the pilot.
+        let wait_for_pilot = |signal| {
+            let start = Instant::now();
+            loop {
+                let pilot = *harness.pilot.lock().unwrap();
+                if pilot == signal {
+                    break;
+                }
+                assert!(start.elapsed() < Duration::from_secs(2), "pilot stuck at {:?}", pilot);
+                if let PilotSignal::OfferAmps(amps) = pilot {
+                    *harness.current.lock().unwrap() = Some(amps + 1.5);
+                }
+                send_pilot(&harness, 6.0);
+                thread::sleep(Duration::from_millis(2));
+            }
+        };
+        wait_for_pilot(PilotSignal::OfferAmps(14.5));
+        assert_eq!(handle.controller().limits().binding, Limiter::Trim);
+
+        handle.send_command(EvseCommand::SetFeature(Feature::ClosedLoopTrim, Some(false))).unwrap();
+        wait_for_pilot(PilotSignal::OfferAmps(16.0));
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_max_current_command() {
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+        let controller = handle.controller();
+        assert_eq!(controller.max_current(), DEFAULT_MAX_CURRENT);
+        assert!(matches!(controller.set_max_current(5.0), Err(EVSEError::InvalidMaxCurrent(_))));
+        assert!(matches!(controller.set_max_current(100.0), Err(EVSEError::InvalidMaxCurrent(_))));
+
+        controller.set_max_current(40.0).unwrap();
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::VehicleDetected);
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(40.0));
+        assert_eq!(controller.max_current(), 40.0);
+
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        assert!(matches!(controller.set_max_current(16.0), Err(EVSEError::Charging)));
+        // Sent before the vehicle started to charge.
+        handle.send_command(EvseCommand::SetMaxCurrent(16.0)).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(controller.max_current(), 40.0);
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_completion_in_telemetry() {
+        let (hardware, harness) = fake_hardware(true);
+        let clock = MockClock::new();
+        let options = MachineOptions {
+            clock: Some(Arc::new(clock.clone())),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        let wait_for_completion = |wanted: fn(Option<CompletionEstimate>) -> bool| {
+            let start = Instant::now();
+            while !wanted(handle.telemetry(TelemetryVerbosity::Normal).completion) {
+                assert!(start.elapsed() < Duration::from_secs(2), "no completion estimate");
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        *harness.current.lock().unwrap() = Some(31.0);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        send_pilot(&harness, 6.0);
+        wait_for_completion(|completion| completion == Some(CompletionEstimate::Bulk));
+
+        // The current tapers off.
+        *harness.current.lock().unwrap() = Some(12.0);
+        send_pilot(&harness, 6.0);
+        thread::sleep(Duration::from_millis(20));
+        clock.advance(Duration::from_secs(150));
+        *harness.current.lock().unwrap() = Some(10.0);
+        send_pilot(&harness, 6.0);
+        wait_for_completion(|completion| matches!(completion, Some(CompletionEstimate::ApproachingFull { .. })));
+
+        // Gone with the session.
+        send_pilot(&harness, 12.0);
+        send_pilot(&harness, 12.0);
+        wait_for_state(&handle, EVSEMachineState::Standby);
+        wait_for_completion(|completion| completion.is_none());
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_line_imbalance_warns_then_stops() {
+        let path = std::env::temp_dir().join(format!("juicelib-imbalance-{}.log", std::process::id()));
+        let (hardware, harness) = fake_hardware(true);
+        let clock = MockClock::new();
+        let options = MachineOptions {
+            audit_log: AuditLog::new(&path),
+            clock: Some(Arc::new(clock.clone())),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+
+        let sustained = |lines: [f64; 2]| {
+            *harness.lines.lock().unwrap() = Some(lines);
+            send_pilot(&harness, 6.0);
+            thread::sleep(Duration::from_millis(20));
+            clock.advance(Duration::from_secs(3));
+            send_pilot(&harness, 6.0);
+            thread::sleep(Duration::from_millis(20));
+        };
+        sustained([16.0, 15.0]);
+        assert_eq!(handle.state(), EVSEMachineState::Charging);
+        sustained([16.0, 12.0]);
+        wait_for_state(&handle, EVSEMachineState::ResetableError);
+        assert!(!*harness.contactor.lock().unwrap());
+        handle.stop();
+        handle.join().unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(log.lines().any(|line| line.ends_with("line currents 1.0 A apart, check the wiring")));
+        assert!(log.lines().any(|line| line.ends_with("line currents 4.0 A apart, charge stopped")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mains_excursion_recorded() {
+        let path = std::env::temp_dir().join(format!("juicelib-power-quality-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (hardware, harness) = fake_hardware(true);
+        let clock = MockClock::new();
+        let options = MachineOptions {
+            clock: Some(Arc::new(clock.clone())),
+            power_quality: Some(PowerQualityConfig {
+                frequency: crate::power_quality::Band { low: 49.5, high: 50.5 },
+                ..PowerQualityConfig::default()
+            }),
+            power_quality_log: PowerQualityLog::new(&path),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+
+        let reading = |mains: (f64, f64)| {
+            *harness.mains.lock().unwrap() = Some(mains);
+            send_pilot(&harness, 12.0);
+            thread::sleep(Duration::from_millis(20));
+        };
+        reading((230.0, 50.0));
+        reading((230.0, 49.2));
+        clock.advance(Duration::from_secs(2));
+        reading((230.0, 49.0));
+        reading((230.0, 50.0));
+        handle.stop();
+        handle.join().unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let events: Vec<serde_json::Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["kind"], "UnderFrequency");
+        assert_eq!(events[0]["duration_ms"], 2000);
+        assert_eq!(events[0]["extreme"], 49.0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sensor_sample() {
+        let (hardware, harness) = fake_hardware(true);
+        let clock = MockClock::new();
+        let options = MachineOptions {
+            clock: Some(Arc::new(clock.clone())),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        assert_eq!(handle.controller().sensor_sample(), None);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        send_pilot(&harness, 6.0);
+        let start = Instant::now();
+        while handle.controller().sensor_sample().is_none_or(|sample| sample.state != EVSEMachineState::Charging) {
+            assert!(start.elapsed() < Duration::from_secs(2), "no sample while charging");
+            thread::sleep(Duration::from_millis(1));
+        }
+        let sample = handle.controller().sensor_sample().unwrap();
+        assert_eq!(sample.amps, 32.0);
+        assert_eq!(sample.timestamp_ns, clock.system_time().duration_since(UNIX_EPOCH).unwrap().as_nanos());
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_adjust_offer_while_charging() {
+        let (hardware, harness) = fake_hardware(true);
+        let clock = MockClock::new();
+        let options = MachineOptions {
+            clock: Some(Arc::new(clock.clone())),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        let controller = handle.controller();
+        assert!(matches!(controller.adjust_current_offer(-1.0), Err(EVSEError::InvalidOffer(_))));
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        let wait_for_offer = |amps: f64| {
+            let start = Instant::now();
+            while *harness.pilot.lock().unwrap() != PilotSignal::OfferAmps(amps) {
+                assert!(start.elapsed() < Duration::from_secs(2), "offer not adjusted to {}", amps);
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+
+        // Lower at once, without opening the contactor.
+        controller.adjust_current_offer(10.0).unwrap();
+        wait_for_offer(10.0);
+        assert!(*harness.contactor.lock().unwrap());
+
+        // Higher once the vehicle had the time to follow.
+        controller.adjust_current_offer(20.0).unwrap();
+        clock.advance(Duration::from_secs(1));
+        send_pilot(&harness, 6.0);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(10.0));
+        clock.advance(Duration::from_secs(4));
+        send_pilot(&harness, 6.0);
+        wait_for_offer(20.0);
+        assert_eq!(handle.state(), EVSEMachineState::Charging);
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_breaker_derates_offer() {
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            breaker: Some(BreakerConfig {
+                time_constant: Duration::from_millis(20),
+                ..BreakerConfig::new(20.0)
+            }),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::VehicleDetected);
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(20.0));
+
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        let start = Instant::now();
+        while *harness.pilot.lock().unwrap() != PilotSignal::OfferAmps(16.0) {
+            assert!(start.elapsed() < Duration::from_secs(2), "offer not derated");
+            send_pilot(&harness, 6.0);
+            thread::sleep(Duration::from_millis(5));
+        }
+        let breaker = handle.telemetry(TelemetryVerbosity::Normal).breaker.unwrap();
+        assert!(breaker.derated);
+        assert_eq!(breaker.allowed_amps, 16.0);
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_gfi_fault_latches() {
+        let path = std::env::temp_dir().join(format!("juicelib-latched-{}.log", std::process::id()));
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            audit_log: AuditLog::new(&path),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        harness.fault_tx.send(GFIInterrupted).unwrap();
+        wait_for_state(&handle, EVSEMachineState::FailedStation);
+        assert!(!*harness.contactor.lock().unwrap());
+
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::ErrorMinus12);
+
+        // Locked out: no vehicle gets the contactor closed, and the alarm
+        // blinks.
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        harness.fault_tx.send(SelfTestOk).unwrap();
+        let start = Instant::now();
+        while !harness.alarm.lock().unwrap().windows(3).any(|writes| writes == [true, false, true]) {
+            assert!(start.elapsed() < Duration::from_secs(3), "alarm not blinking");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!*harness.contactor.lock().unwrap());
+        assert_eq!(handle.state(), EVSEMachineState::FailedStation);
+
+        // The pilot is still probed for vehicles, with nothing offered.
+        let probe = |volts: f32, until: &dyn Fn() -> bool| {
+            let start = Instant::now();
+            while !until() {
+                assert!(start.elapsed() < Duration::from_secs(3), "pilot not probed");
+                assert!(!matches!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(_)));
+                send_pilot(&harness, volts);
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+        let logged = |event: &str| std::fs::read_to_string(&path).is_ok_and(|log| log.contains(event));
+        probe(12.0, &|| logged("unplugged while"));
+        assert_eq!(handle.controller().vehicle_present(), Some(false));
+        probe(9.0, &|| logged("plugged in while"));
+        assert_eq!(handle.controller().vehicle_present(), Some(true));
+        assert!(!*harness.contactor.lock().unwrap());
+        assert_eq!(handle.state(), EVSEMachineState::FailedStation);
+
+        // Back in service after the admin reset.
+        handle.send_command(EvseCommand::ResetLockout).unwrap();
+        wait_for_state(&handle, EVSEMachineState::Standby);
+        assert_eq!(harness.alarm.lock().unwrap().last(), Some(&false));
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+
+        handle.stop();
+        handle.join().unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        let events: Vec<&str> = log.lines().filter(|line| line.contains("while the station is failed")).collect();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].ends_with("vehicle unplugged while the station is failed"));
+        assert!(events[1].ends_with("vehicle plugged in while the station is failed"));
+        assert!(log.lines().any(|line| line.ends_with("lockout reset")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_gfi_lockout_survives_restart() {
+        let path = std::env::temp_dir().join(format!("juicelib-machine-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let options = || MachineOptions {
+            state_path: Some(path.clone()),
+            ..MachineOptions::default()
+        };
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine_with(hardware, options());
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        harness.fault_tx.send(GFIInterrupted).unwrap();
+        wait_for_state(&handle, EVSEMachineState::FailedStation);
+        handle.stop();
+        handle.join().unwrap();
+        let persisted = PersistedState::load(&path).unwrap().unwrap();
+        assert_eq!(persisted.lockout_cause, Some(GFIInterrupted));
+        assert_eq!(persisted.counters.gfi_trips, 1);
+
+        // The self test passes after the power cycle, but the station stays
+        // locked out until the reset.
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine_with(hardware, options());
+        wait_for_state(&handle, EVSEMachineState::FailedStation);
+        assert_eq!(handle.controller().error_counters().gfi_trips, 1);
+        send_pilot(&harness, 9.0);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(handle.state(), EVSEMachineState::FailedStation);
+        handle.send_command(EvseCommand::ResetLockout).unwrap();
+        wait_for_state(&handle, EVSEMachineState::Standby);
+        handle.stop();
+        handle.join().unwrap();
+
+        // Reset, so the next start is in service, with the counters kept.
+        let persisted = PersistedState::load(&path).unwrap().unwrap();
+        assert_eq!(persisted.state, EVSEMachineState::Standby);
+        assert_eq!(persisted.restored_lockout(), None);
+        assert_eq!(persisted.counters.gfi_trips, 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_gfi_trip_retried_after_cooldown() {
+        let (hardware, harness) = fake_hardware(true);
+        let mut features = FeatureFlags::default();
+        features.set_override(Feature::AutoRetryGfi, Some(true));
+        let options = MachineOptions {
+            features,
+            gfi_retry: GfiRetryPolicy {
+                cooldown_secs: 1,
+                max_retries_per_day: 1,
+            },
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        harness.fault_tx.send(GFIInterrupted).unwrap();
+        wait_for_state(&handle, EVSEMachineState::GFITripped);
+        assert!(!*harness.contactor.lock().unwrap());
+        // The self test passes after the cooldown, checked as the readings
+        // come in.
+        let start = Instant::now();
+        while handle.state() != EVSEMachineState::Standby {
+            assert!(start.elapsed() < Duration::from_secs(3), "stuck in {:?}", handle.state());
+            send_pilot(&harness, 9.0);
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        // The retry for the day is used up.
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        harness.fault_tx.send(GFIInterrupted).unwrap();
+        wait_for_state(&handle, EVSEMachineState::FailedStation);
+        assert_eq!(handle.controller().error_counters().gfi_trips, 2);
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_lifetime_counters_persisted() {
+        let dir = std::env::temp_dir().join(format!("juicelib-lifetime-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let options = || MachineOptions {
+            counters_dir: Some(dir.clone()),
+            ..MachineOptions::default()
+        };
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine_with(hardware, options());
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        thread::sleep(Duration::from_millis(20));
+        send_pilot(&harness, 6.0);
+        send_pilot(&harness, 12.0);
+        wait_for_state(&handle, EVSEMachineState::StopCharging);
+        send_pilot(&harness, 12.0);
+        wait_for_state(&handle, EVSEMachineState::Standby);
+        handle.stop();
+        handle.join().unwrap();
+
+        let (_, saved) = CounterStore::open(&dir).unwrap();
+        assert_eq!(saved.sessions, 1);
+        assert_eq!(saved.contactor_cycles, 1);
+        assert!(saved.energy_wh > 0.0);
+
+        // They go on where they left off.
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine_with(hardware, options());
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::VehicleDetected);
+        assert_eq!(handle.controller().lifetime_counters().sessions, 1);
+        handle.stop();
+        handle.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_guest_energy_from_measured_current() {
+        let dir = std::env::temp_dir().join(format!("juicelib-guest-energy-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        // Created before the machine opens it too.
+        Store::open(&dir.join("juiced.db")).unwrap();
+        let clock = MockClock::new();
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            clock: Some(Arc::new(clock.clone())),
+            store_path: Some(dir.join("juiced.db")),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        let controller = handle.controller();
+        let issued = controller
+            .issue_guest_token("Visitor", Duration::from_secs(3600), Some(16.0), Some(5000.0))
+            .unwrap();
+        controller.redeem_guest_token(&issued.secret).unwrap();
+
+        // The vehicle draws 6 A of the 16 A offered.
+        *harness.current.lock().unwrap() = Some(6.0);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        clock.advance(Duration::from_secs(15 * 60));
+        send_pilot(&harness, 6.0);
+        let start = Instant::now();
+        let session = loop {
+            let session = controller.guest_session().unwrap();
+            if session.energy_wh > 0.0 {
+                break session;
+            }
+            assert!(start.elapsed() < Duration::from_secs(2), "no guest energy");
+            thread::sleep(Duration::from_millis(1));
+        };
+        // 6 A at 230 V on three phases for 15 minutes is 1035 Wh.
+        assert!((session.energy_wh - 1035.0).abs() < 1e-6, "{}", session.energy_wh);
+        handle.stop();
+        handle.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tenant_energy_from_measured_current() {
+        let dir = std::env::temp_dir().join(format!("juicelib-tenant-energy-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Store::open(&dir.join("juiced.db")).unwrap();
+        let clock = MockClock::new();
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            clock: Some(Arc::new(clock.clone())),
+            store_path: Some(dir.join("juiced.db")),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        let controller = handle.controller();
+        let tenant = Tenant {
+            id: "1A".to_string(),
+            name: "Apartment 1A".to_string(),
+            max_current: Some(16.0),
+            monthly_energy_wh: Some(10_000.0),
+            price_per_kwh: None,
+        };
+        controller.save_tenant(&tenant).unwrap();
+        controller.add_tenant_credential("1A", "04A2B3C4").unwrap();
+        controller.authorize_tenant("04A2B3C4").unwrap();
+
+        // The vehicle draws 6 A of the 16 A offered.
+        *harness.current.lock().unwrap() = Some(6.0);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        clock.advance(Duration::from_secs(60 * 60));
+        send_pilot(&harness, 6.0);
+        let start = Instant::now();
+        let session = loop {
+            let session = controller.tenant_session().unwrap();
+            if session.energy_wh > 0.0 {
+                break session;
+            }
+            assert!(start.elapsed() < Duration::from_secs(2), "no tenant energy");
+            thread::sleep(Duration::from_millis(1));
+        };
+        // 6 A at 230 V on three phases for an hour is 4140 Wh, within the
+        // quota that the offer would have used up.
+        assert!((session.energy_wh - 4140.0).abs() < 1e-6, "{}", session.energy_wh);
+        assert!(!session.quota_used_up());
+        handle.stop();
+        handle.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fault_stamped_from_clock() {
+        let dir = std::env::temp_dir().join(format!("juicelib-fault-clock-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let clock = MockClock::at(UNIX_EPOCH + Duration::from_secs(1_792_137_600));
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            clock: Some(Arc::new(clock.clone())),
+            black_box_dir: Some(dir.join("blackbox")),
+            store_path: Some(dir.join("juiced.db")),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        clock.advance(Duration::from_secs(60));
+        harness.fault_tx.send(GFIInterrupted).unwrap();
+        wait_for_state(&handle, EVSEMachineState::FailedStation);
+        handle.stop();
+        handle.join().unwrap();
+
+        let faults = Store::open(&dir.join("juiced.db")).unwrap().faults().unwrap();
+        assert_eq!(faults[0].unix_time, 1_792_137_660);
+        assert!(dir.join("blackbox/blackbox-1792137660-FailedStation.json").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_shut_down_exit_status() {
+        let (hardware, _harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+        wait_for_state(&handle, EVSEMachineState::Standby);
+        let exit = handle.shut_down();
+        assert_eq!(exit, MachineExit::Stopped(EVSEMachineState::Standby));
+        assert_eq!(exit.code(), 0);
+
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+        harness.fault_tx.send(GFIInterrupted).unwrap();
+        wait_for_state(&handle, EVSEMachineState::FailedStation);
+        let exit = handle.shut_down();
+        assert_eq!(exit, MachineExit::Stopped(EVSEMachineState::FailedStation));
+        assert_eq!(exit.code(), 1);
+        assert!(!*harness.contactor.lock().unwrap());
+    }
+
+    #[test]
+    fn test_power_loss_while_charging() {
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        harness.fault_tx.send(PowerLost).unwrap();
+
+        while !handle.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(handle.interrupted_state(), Some(EVSEMachineState::Charging));
+        assert_eq!(handle.join().unwrap(), EVSEMachineState::PowerFailure);
+        assert!(!*harness.contactor.lock().unwrap());
+    }
+
+    #[test]
+    fn test_resume_after_power_failure() {
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine_resuming(hardware);
+
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(32.0));
+        assert!(*harness.contactor.lock().unwrap());
+
+        handle.stop();
+        handle.join().unwrap();
+
+        // Without resuming, A to C stays illegal.
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::ResetableError);
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_grace_unplug() {
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            grace_unplug: Some(Duration::from_secs(60)),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::VehicleDetected);
+        send_pilot(&harness, 0.0);
+        wait_for_state(&handle, EVSEMachineState::ResetableError);
+        assert!(!*harness.contactor.lock().unwrap());
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::SteadyPlus12);
+        assert!(handle.controller().grace_unplug_remaining().is_some());
+
+        // Still plugged in.
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 12.0);
+        wait_for_state(&handle, EVSEMachineState::Standby);
+        assert_eq!(handle.controller().grace_unplug_remaining(), None);
+        handle.stop();
+        handle.join().unwrap();
+
+        // Without a window, only a reset helps.
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::ResetableError);
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::ErrorMinus12);
+        send_pilot(&harness, 12.0);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(handle.state(), EVSEMachineState::ResetableError);
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_grace_window_on_mock_clock() {
+        let (hardware, harness) = fake_hardware(true);
+        let clock = MockClock::new();
+        let options = MachineOptions {
+            grace_unplug: Some(Duration::from_secs(60)),
+            clock: Some(Arc::new(clock.clone())),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        send_pilot(&harness, 0.0);
+        wait_for_state(&handle, EVSEMachineState::ResetableError);
+        let controller = handle.controller();
+        assert_eq!(controller.grace_unplug_remaining(), Some(Duration::from_secs(60)));
+        clock.advance(Duration::from_secs(45));
+        assert_eq!(controller.time_in_state(), Duration::from_secs(45));
+
+        // The window closes on the next reading after it is over.
+        clock.advance(Duration::from_secs(15));
+        send_pilot(&harness, 12.0);
+        let start = Instant::now();
+        while *harness.pilot.lock().unwrap() != PilotSignal::ErrorMinus12 {
+            assert!(start.elapsed() < Duration::from_secs(2), "window still open");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(controller.grace_unplug_remaining(), None);
+        assert_eq!(handle.state(), EVSEMachineState::ResetableError);
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            recovery: Some(ErrorRecovery {
+                debounce_ms: 50,
+                max_retries: 1,
+            }),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        let unplug = || {
+            let start = Instant::now();
+            while handle.state() != EVSEMachineState::Standby {
+                assert!(start.elapsed() < Duration::from_secs(2), "no recovery");
+                send_pilot(&harness, 12.0);
+                thread::sleep(Duration::from_millis(5));
+            }
+            start.elapsed()
+        };
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 0.0);
+        wait_for_state(&handle, EVSEMachineState::ResetableError);
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::SteadyPlus12);
+        // No time limit without a grace window.
+        thread::sleep(Duration::from_millis(100));
+        assert!(unplug() >= Duration::from_millis(50));
+
+        // The retry is used up.
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 0.0);
+        wait_for_state(&handle, EVSEMachineState::FailedStation);
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_price_pauses_charge() {
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            pricing: Some(PricingSettings {
+                address: "localhost:8080".to_string(),
+                path: pricing::DEFAULT_PRICES_PATH.to_string(),
+                price_cap_per_kwh: 0.30,
+                cheapest_hours: None,
+            }),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        send_pilot(&harness, 9.0);
+        wait_for_state(&handle, EVSEMachineState::VehicleDetected);
+        let offer = *harness.pilot.lock().unwrap();
+        assert!(matches!(offer, PilotSignal::OfferAmps(amps) if amps > 0.0));
+
+        let hour = unix_now() / 3600 * 3600;
+        let set_price = |price_per_kwh| {
+            let prices = vec![HourlyPrice {
+                unix_time: hour,
+                price_per_kwh,
+            }];
+            handle.send_command(EvseCommand::SetPrices(prices)).unwrap();
+            send_pilot(&harness, 9.0);
+        };
+        let wait_for_pilot = |signal| {
+            let start = Instant::now();
+            while *harness.pilot.lock().unwrap() != signal {
+                assert!(start.elapsed() < Duration::from_secs(2), "pilot stuck");
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        set_price(0.45);
+        wait_for_pilot(PilotSignal::OfferAmps(0.0));
+        assert_eq!(handle.controller().limits().binding, Limiter::Price);
+        set_price(0.12);
+        wait_for_pilot(offer);
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_ventilation() {
+        // Refused without ventilation, also when the vehicle goes from C
+        // to D while charging.
+        let (hardware, harness) = fake_hardware(true);
+        let handle = start_machine(hardware);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 3.0);
+        wait_for_state(&handle, EVSEMachineState::VentilationNeeded);
+        assert!(!*harness.contactor.lock().unwrap());
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        send_pilot(&harness, 3.0);
+        wait_for_state(&handle, EVSEMachineState::VentilationNeeded);
+        assert!(!*harness.contactor.lock().unwrap());
+        assert!(!*harness.ventilation.lock().unwrap());
+        handle.stop();
+        handle.join().unwrap();
+
+        // Charged with the ventilation on, which goes off with the session.
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            ventilation: true,
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 3.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        assert!(*harness.contactor.lock().unwrap());
+        assert!(*harness.ventilation.lock().unwrap());
+        send_pilot(&harness, 12.0);
+        wait_for_state(&handle, EVSEMachineState::StopCharging);
+        assert!(!*harness.ventilation.lock().unwrap());
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_pilot_timeout_opens_contactor() {
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            pilot_timeout: Some(Duration::from_millis(300)),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        assert!(*harness.contactor.lock().unwrap());
+        // The ADC stalls.
+        wait_for_state(&handle, EVSEMachineState::ResetableError);
+        assert!(!*harness.contactor.lock().unwrap());
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_resume_interrupted_session() {
+        let (hardware, harness) = fake_hardware(true);
+        let (interruption_tx, interruption_rx) = unbounded();
+        let options = MachineOptions {
+            interruptions: Some(interruption_tx),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        let controller = handle.controller();
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        send_pilot(&harness, 0.0);
+        wait_for_state(&handle, EVSEMachineState::ResetableError);
+
+        let interruption = interruption_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(
+            interruption.cause,
+            InterruptionCause::Fault {
+                state: EVSEMachineState::ResetableError,
+                input: Some(EVSEMachineInput::PilotInError),
+            }
+        );
+        assert_eq!(interruption.session_id, controller.session_id().map(|id| id.to_string()));
+        assert!(matches!(controller.resume_charging("ab12"), Err(ResumeError::Unknown)));
+
+        // The vehicle still asks for power and is charged again.
+        controller.resume_charging(&interruption.resume_token).unwrap();
+        wait_for_state(&handle, EVSEMachineState::Standby);
+        send_pilot(&harness, 6.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        // Good for one use.
+        assert!(matches!(
+            controller.resume_charging(&interruption.resume_token),
+            Err(ResumeError::Unknown)
+        ));
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_hot_connector_derates_offer() {
+        let (hardware, harness) = fake_hardware(true);
+        *harness.connector_temperatures.lock().unwrap() = vec![40.0, 42.0];
+        let handle = start_machine(hardware);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(32.0));
+
+        let wait_for_pilot = |signal| {
+            let start = Instant::now();
+            while *harness.pilot.lock().unwrap() != signal {
+                assert!(start.elapsed() < Duration::from_secs(2), "pilot stuck");
+                send_pilot(&harness, 6.0);
+                thread::sleep(Duration::from_millis(5));
+            }
+        };
+        *harness.connector_temperatures.lock().unwrap() = vec![40.0, 77.5];
+        wait_for_pilot(PilotSignal::OfferAmps(19.0));
+        assert_eq!(handle.controller().limits().binding, Limiter::Thermal);
+        let telemetry = handle.telemetry(TelemetryVerbosity::Normal);
+        assert_eq!(telemetry.connector_temperatures_c, Some(vec![40.0, 77.5]));
+
+        *harness.connector_temperatures.lock().unwrap() = vec![86.0, 40.0];
+        wait_for_pilot(PilotSignal::OfferAmps(0.0));
+        // Not before it has cooled down.
+        *harness.connector_temperatures.lock().unwrap() = vec![68.0, 40.0];
+        send_pilot(&harness, 6.0);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(0.0));
+        *harness.connector_temperatures.lock().unwrap() = vec![60.0, 40.0];
+        wait_for_pilot(PilotSignal::OfferAmps(32.0));
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_control_contact_limits_offer() {
+        let (hardware, harness) = fake_hardware(true);
+        *harness.control_contact.lock().unwrap() = Some(false);
+        let options = MachineOptions {
+            control_contact_amps: Some(6.0),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        assert_eq!(*harness.pilot.lock().unwrap(), PilotSignal::OfferAmps(32.0));
+        assert_eq!(handle.controller().control_contact(), Some(false));
+
+        let wait_for_pilot = |signal| {
+            let start = Instant::now();
+            while *harness.pilot.lock().unwrap() != signal {
+                assert!(start.elapsed() < Duration::from_secs(2), "pilot stuck");
+                send_pilot(&harness, 6.0);
+                thread::sleep(Duration::from_millis(5));
+            }
+        };
+        *harness.control_contact.lock().unwrap() = Some(true);
+        wait_for_pilot(PilotSignal::OfferAmps(6.0));
+        assert_eq!(handle.controller().limits().binding, Limiter::ControlContact);
+        assert_eq!(handle.controller().control_contact(), Some(true));
+        *harness.control_contact.lock().unwrap() = Some(false);
+        wait_for_pilot(PilotSignal::OfferAmps(32.0));
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_events_of_a_session() {
+        let (hardware, harness) = fake_hardware(true);
+        let (event_tx, event_rx) = unbounded();
+        let handle = start_machine_with_events(hardware, event_tx);
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        harness.fault_tx.send(GFIInterrupted).unwrap();
+        wait_for_state(&handle, EVSEMachineState::FailedStation);
+        handle.stop();
+        handle.join().unwrap();
+
+        let entered = |state, previous| EVSEEvent::StateEntered { state, previous };
+        let events: Vec<EVSEEvent> = event_rx.try_iter().collect();
+        assert_eq!(
+            events,
+            [
+                EVSEEvent::SelfTest { passed: true },
+                entered(EVSEMachineState::Standby, None),
+                entered(EVSEMachineState::VehicleDetected, Some(EVSEMachineState::Standby)),
+                EVSEEvent::OfferChanged { amps: 32.0 },
+                entered(EVSEMachineState::StartCharging, Some(EVSEMachineState::VehicleDetected)),
+                EVSEEvent::SelfTest { passed: true },
+                entered(EVSEMachineState::Charging, Some(EVSEMachineState::StartCharging)),
+                entered(EVSEMachineState::FailedStation, Some(EVSEMachineState::Charging)),
+                EVSEEvent::FaultRaised {
+                    state: EVSEMachineState::FailedStation,
+                    cause: Some(GFIInterrupted),
+                },
+                EVSEEvent::OfferChanged { amps: 0.0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tamper_lockout() {
+        let path = std::env::temp_dir().join(format!("juicelib-tamper-{}.log", std::process::id()));
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            audit_log: AuditLog::new(&path),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        harness.fault_tx.send(EnclosureOpened).unwrap();
+        wait_for_state(&handle, EVSEMachineState::TamperLockout);
+        assert!(!*harness.contactor.lock().unwrap());
+
+        // Closing the enclosure or unplugging does not clear the lockout.
+        send_pilot(&harness, 12.0);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(handle.state(), EVSEMachineState::TamperLockout);
+
+        handle.send_command(EvseCommand::ResetTamper).unwrap();
+        wait_for_state(&handle, EVSEMachineState::Standby);
+        handle.stop();
+        handle.join().unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(log.lines().next().unwrap().ends_with("enclosure opened while charging, tamper lockout"));
+        // The lockout is a fault; without a black box directory only the
+        // fault itself is logged.
+        assert!(log.lines().nth(1).unwrap().ends_with("fault TamperLockout"));
+        assert!(log.lines().nth(2).unwrap().ends_with("tamper lockout reset"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Hardware whose contactor call hangs, like on a deadlocked pins mutex.
+    struct StallingHardware {
+        inner: FakeHardware,
+        stall: Duration,
+        forced: Arc<Mutex<bool>>,
+    }
+
+    struct FakeSafeState {
+        contactor: Arc<Mutex<bool>>,
+        pilot: Arc<Mutex<PilotSignal>>,
+        forced: Arc<Mutex<bool>>,
+    }
+
+    impl SafeState for FakeSafeState {
+        fn force_safe(&mut self) {
+            *self.contactor.lock().unwrap() = false;
+            *self.pilot.lock().unwrap() = PilotSignal::SteadyPlus12;
+            *self.forced.lock().unwrap() = true;
+        }
+    }
+
+    impl EVSEHardware for StallingHardware {
+        fn set_pilot(&mut self, signal: PilotSignal) -> Result<(), EVSEError> {
+            self.inner.set_pilot(signal)
+        }
+
+        fn set_contactor(&mut self, on: bool) -> Result<(), EVSEError> {
+            if on {
+                thread::sleep(self.stall);
+            }
+            self.inner.set_contactor(on)
+        }
+
+        fn run_gfi_self_test(&mut self) -> Result<(), EVSEError> {
+            self.inner.run_gfi_self_test()
+        }
+
+        fn pilot_channel(&self) -> Receiver<PilotReading> {
+            self.inner.pilot_channel()
+        }
+
+        fn fault_channel(&self) -> Receiver<EVSEMachineInput> {
+            self.inner.fault_channel()
+        }
+
+        fn reserved_safe_state(&self) -> Option<Box<dyn SafeState>> {
+            Some(Box::new(FakeSafeState {
+                contactor: self.inner.contactor.clone(),
+                pilot: self.inner.pilot.clone(),
+                forced: self.forced.clone(),
+            }))
+        }
+    }
+
+    #[test]
+    fn test_control_watchdog_forces_safe_state() {
+        let path = std::env::temp_dir().join(format!("juicelib-stall-{}.log", std::process::id()));
+        let (inner, harness) = fake_hardware(true);
+        let forced = Arc::new(Mutex::new(false));
+        let hardware = StallingHardware {
+            inner,
+            stall: CONTROL_WATCHDOG_TIMEOUT + Duration::from_millis(500),
+            forced: forced.clone(),
+        };
+        let options = MachineOptions {
+            audit_log: AuditLog::new(&path),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        // The watchdog trips while the contactor call hangs, the machine
+        // gives up once it is back.
+        let start = Instant::now();
+        while handle.state() != EVSEMachineState::FailedStation {
+            assert!(start.elapsed() < Duration::from_secs(5), "stuck in {:?}", handle.state());
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.stop();
+        assert_eq!(handle.join().unwrap(), EVSEMachineState::FailedStation);
+        assert!(*forced.lock().unwrap());
+        assert!(!*harness.contactor.lock().unwrap());
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(log.lines().next().unwrap().ends_with("control loop stalled, station forced safe by the watchdog"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_black_box_on_fault() {
+        let name = format!("juicelib-fault-{}", std::process::id());
+        let log_path = std::env::temp_dir().join(format!("{}.log", name));
+        let dir = std::env::temp_dir().join(name);
+        let (hardware, harness) = fake_hardware(true);
+        let options = MachineOptions {
+            audit_log: AuditLog::new(&log_path),
+            black_box_dir: Some(dir.clone()),
+            ..MachineOptions::default()
+        };
+        let handle = start_machine_with(hardware, options);
+
+        send_pilot(&harness, 9.0);
+        send_pilot(&harness, 6.0);
+        wait_for_state(&handle, EVSEMachineState::Charging);
+        harness.fault_tx.send(GFIInterrupted).unwrap();
+        wait_for_state(&handle, EVSEMachineState::FailedStation);
+        handle.stop();
+        assert_eq!(handle.join().unwrap(), EVSEMachineState::FailedStation);
+
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        let (_, path) = log.trim_end().split_once(" black box ").unwrap();
+        assert!(log.contains("fault FailedStation,"));
+        let record: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let entries = record["entries"].as_array().unwrap();
+        assert!(entries.iter().any(|entry| entry["entry"]["SetPilot"] == "ErrorMinus12"));
+        assert!(entries.iter().any(|entry| entry["entry"]["Input"] == "GFIInterrupted"));
+        assert!(entries.iter().any(|entry| entry["entry"]["SetContactor"] == true));
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&log_path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_startup_self_test() {
+        let (hardware, _harness) = fake_hardware(false);
+        let handle = start_machine(hardware);
+        wait_for_state(&handle, EVSEMachineState::FailedStation);
+        // The self test fails again, so the reset does not take.
+        handle.send_command(EvseCommand::ResetLockout).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(handle.state(), EVSEMachineState::FailedStation);
+        handle.stop();
+        while !handle.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(matches!(
+            handle.send_command(EvseCommand::Stop),
+            Err(EVSEError::MachineStopped)
+        ));
+        assert_eq!(handle.join().unwrap(), EVSEMachineState::FailedStation);
+    }
+}
@@ -0,0 +1,68 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::blackbox::is_latched_fault;
+use crate::evse::EVSEMachineState;
+
+// What the station reports to the outside when something is wrong. The
+// alarm output is a GPIO for a fault LED or a relay into a building alarm
+// panel. It follows the machine state: it is asserted while a fault is
+// latched and, if the installation wants them, while a warning lasts.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmPolicy {
+    // Latched faults only.
+    #[default]
+    Faults,
+    FaultsAndWarnings,
+}
+
+impl FromStr for AlarmPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "faults" => Ok(AlarmPolicy::Faults),
+            "faults_and_warnings" => Ok(AlarmPolicy::FaultsAndWarnings),
+            _ => Err(format!("unknown alarm policy {}", name)),
+        }
+    }
+}
+
+// States that need attention without a latched fault: the station is
+// without mains, refuses a vehicle asking for ventilation or waits to
+// retry after a GFI trip.
+pub fn is_warning(state: EVSEMachineState) -> bool {
+    matches!(
+        state,
+        EVSEMachineState::PowerFailure | EVSEMachineState::VentilationNeeded | EVSEMachineState::GFITripped
+    )
+}
+
+impl AlarmPolicy {
+    pub fn alarm(&self, state: EVSEMachineState) -> bool {
+        match self {
+            AlarmPolicy::Faults => is_latched_fault(state),
+            AlarmPolicy::FaultsAndWarnings => is_latched_fault(state) || is_warning(state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alarm() {
+        let faults: AlarmPolicy = "faults".parse().unwrap();
+        let warnings: AlarmPolicy = "faults_and_warnings".parse().unwrap();
+        assert!(faults.alarm(EVSEMachineState::FailedStation));
+        assert!(warnings.alarm(EVSEMachineState::TamperLockout));
+        assert!(!faults.alarm(EVSEMachineState::PowerFailure));
+        assert!(warnings.alarm(EVSEMachineState::PowerFailure));
+        assert!(!warnings.alarm(EVSEMachineState::Charging));
+        assert!("siren".parse::<AlarmPolicy>().is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// Feature toggles for optional behaviors. Each feature has a safe default,
+// which the site settings can change for the installation and the control
+// API can override at runtime. Overrides are not stored: a restart goes
+// back to the settings.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    // Retry after a GFI trip instead of failing the station. Off: a trip
+    // needs a technician, as the GFI may have tripped for a reason.
+    AutoRetryGfi,
+    // Follow the solar surplus. Off: the vehicle gets the full offer and
+    // the surplus given is ignored.
+    SolarMode,
+    // Trim the offer from the measured current, see trim.rs. Off: the
+    // offer is what the limits allow, the vehicle's own regulation is
+    // trusted.
+    ClosedLoopTrim,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 3] = [Feature::AutoRetryGfi, Feature::SolarMode, Feature::ClosedLoopTrim];
+
+    pub fn safe_default(&self) -> bool {
+        match self {
+            Feature::AutoRetryGfi | Feature::SolarMode | Feature::ClosedLoopTrim => false,
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FeatureSource {
+    Default,
+    Settings,
+    Override,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct FeatureState {
+    pub feature: Feature,
+    pub enabled: bool,
+    pub source: FeatureSource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FeatureFlags {
+    settings: [Option<bool>; Feature::ALL.len()],
+    overrides: [Option<bool>; Feature::ALL.len()],
+}
+
+impl FeatureFlags {
+    pub fn from_settings(settings: &BTreeMap<Feature, bool>) -> Self {
+        let mut flags = Self::default();
+        for (feature, &enabled) in settings {
+            flags.settings[feature.index()] = Some(enabled);
+        }
+        flags
+    }
+
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.state(feature).enabled
+    }
+
+    // None removes the override.
+    pub fn set_override(&mut self, feature: Feature, enabled: Option<bool>) {
+        self.overrides[feature.index()] = enabled;
+    }
+
+    pub fn state(&self, feature: Feature) -> FeatureState {
+        let (enabled, source) = match (self.overrides[feature.index()], self.settings[feature.index()]) {
+            (Some(enabled), _) => (enabled, FeatureSource::Override),
+            (None, Some(enabled)) => (enabled, FeatureSource::Settings),
+            (None, None) => (feature.safe_default(), FeatureSource::Default),
+        };
+        FeatureState {
+            feature,
+            enabled,
+            source,
+        }
+    }
+
+    // All features, for the diagnostics.
+    pub fn snapshot(&self) -> Vec<FeatureState> {
+        Feature::ALL.iter().map(|&feature| self.state(feature)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_feature_flags() {
+        let settings: BTreeMap<Feature, bool> =
+            serde_json::from_str(r#"{"solar_mode": true, "closed_loop_trim": false}"#).unwrap();
+        let mut flags = FeatureFlags::from_settings(&settings);
+        assert!(!flags.is_enabled(Feature::AutoRetryGfi));
+        assert!(flags.is_enabled(Feature::SolarMode));
+
+        flags.set_override(Feature::SolarMode, Some(false));
+        flags.set_override(Feature::AutoRetryGfi, Some(true));
+        assert_eq!(
+            flags.snapshot(),
+            vec![
+                FeatureState {
+                    feature: Feature::AutoRetryGfi,
+                    enabled: true,
+                    source: FeatureSource::Override
+                },
+                FeatureState {
+                    feature: Feature::SolarMode,
+                    enabled: false,
+                    source: FeatureSource::Override
+                },
+                FeatureState {
+                    feature: Feature::ClosedLoopTrim,
+                    enabled: false,
+                    source: FeatureSource::Settings
+                },
+            ]
+        );
+
+        flags.set_override(Feature::SolarMode, None);
+        assert!(flags.is_enabled(Feature::SolarMode));
+    }
+
+    #[test]
+    fn test_safe_defaults() {
+        let flags = FeatureFlags::default();
+        for state in flags.snapshot() {
+            assert!(!state.enabled, "{:?} is on by default", state.feature);
+            assert_eq!(state.source, FeatureSource::Default);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::nuisance::{retry_allowance, ClassifiedTrip};
+
+// Automatic retry after a GFI trip, with the AutoRetryGfi feature on. The
+// station opens the contactor and waits out the cooldown in GFITripped,
+// then runs the GFI self test again: back to Standby if it passes, failed
+// if it does not. A station gets max_retries_per_day retries in any 24
+// hours; the next trip latches FailedStation as without the feature. A
+// likely genuine trip in the past week takes the retries away, see
+// nuisance.rs.
+//
+// The trips are counted from the faults in the store, so a restart does
+// not hand out new retries. Without a store, the trips since the start
+// are counted.
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GfiRetryPolicy {
+    pub cooldown_secs: u64,
+    pub max_retries_per_day: u32,
+}
+
+impl Default for GfiRetryPolicy {
+    fn default() -> Self {
+        Self {
+            cooldown_secs: 5 * 60,
+            max_retries_per_day: 3,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GfiRetryTracker {
+    policy: GfiRetryPolicy,
+    // The trips since the start, for the machine without a store.
+    trips: Vec<u64>,
+}
+
+impl GfiRetryTracker {
+    pub fn new(policy: GfiRetryPolicy) -> Self {
+        Self {
+            policy,
+            trips: Vec::new(),
+        }
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.policy.cooldown_secs)
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.policy.max_retries_per_day
+    }
+
+    // A GFI trip at now. Returns the number of the retry it gets, or None
+    // once they are used up. history is the earlier trips in the store.
+    pub fn trip(&mut self, history: Option<&[ClassifiedTrip]>, now: u64) -> Option<u32> {
+        let recent = |time: u64| now.saturating_sub(time) < DAY;
+        let (allowance, used) = match history {
+            Some(trips) => (
+                retry_allowance(trips, self.policy.max_retries_per_day, now),
+                trips.iter().filter(|trip| recent(trip.unix_time)).count(),
+            ),
+            None => (
+                self.policy.max_retries_per_day,
+                self.trips.iter().filter(|&&time| recent(time)).count(),
+            ),
+        };
+        self.trips.push(now);
+        let retry = used as u32 + 1;
+        (retry <= allowance).then_some(retry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nuisance::TripVerdict;
+
+    fn trip(unix_time: u64, verdict: TripVerdict) -> ClassifiedTrip {
+        ClassifiedTrip {
+            unix_time,
+            vehicle: None,
+            verdict,
+            clues: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_retries() {
+        let policy = GfiRetryPolicy {
+            cooldown_secs: 60,
+            max_retries_per_day: 2,
+        };
+        let t0 = 1_792_137_600;
+
+        let mut tracker = GfiRetryTracker::new(policy);
+        assert_eq!(tracker.cooldown(), Duration::from_secs(60));
+        assert_eq!(tracker.trip(None, t0), Some(1));
+        assert_eq!(tracker.trip(None, t0 + 600), Some(2));
+        assert_eq!(tracker.trip(None, t0 + 1200), None);
+        // A day on, the first two trips no longer count.
+        assert_eq!(tracker.trip(None, t0 + DAY + 600), Some(2));
+
+        // From the store.
+        let mut tracker = GfiRetryTracker::new(policy);
+        let nuisance = [trip(t0 - DAY, TripVerdict::LikelyNuisance), trip(t0, TripVerdict::LikelyNuisance)];
+        assert_eq!(tracker.trip(Some(&nuisance), t0 + 600), Some(2));
+        let genuine = [trip(t0, TripVerdict::LikelyGenuine)];
+        assert_eq!(tracker.trip(Some(&genuine), t0 + 2 * DAY), None);
+    }
+}
@@ -0,0 +1,324 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::evse::{EVSEMachineState, EvseController};
+use crate::guest_token::GuestTokenError;
+use crate::interruption::ResumeError;
+use crate::theme::{Message, Theme};
+
+// Public read-only status for shared parking. Anyone on the network may ask
+// whether the station is free; the answer is deliberately coarse: no
+// energy, no identity, no history. Served over plain HTTP:
+//   GET /        -> small HTML page
+//   GET /status  -> {"status": "available"}
+//
+// Guests with a token (see guest_token.rs) redeem it here; the QR code of
+// the token leads to the page:
+//   GET /redeem?token=...  -> page with a button to start the session
+//   POST /redeem?token=... -> redeems the token
+//
+// The resume link of an interrupted session (see interruption.rs) leads
+// here as well, to the same two steps:
+//   GET /resume?token=...  -> page with a button to resume the charge
+//   POST /resume?token=... -> uses the link
+//
+// The texts, logo and fault contact of the pages come from the theme of
+// the site settings, see theme.rs.
+
+pub const DEFAULT_GUEST_ADDRESS: &str = "0.0.0.0:8080";
+
+// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What the guest endpoint tells, set in the site settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GuestExposure {
+    // No guest endpoint.
+    #[default]
+    Disabled,
+    // Available, in use or fault.
+    Availability,
+    // Also tells a charging vehicle from one that is only plugged in.
+    ChargingState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GuestStatus {
+    Available,
+    InUse,
+    PluggedIn,
+    Charging,
+    Fault,
+    // A resettable error that unplugging clears.
+    UnplugToRetry,
+}
+
+impl GuestStatus {
+    pub fn new(state: EVSEMachineState, exposure: GuestExposure) -> Self {
+        use EVSEMachineState::*;
+
+        let charging_state = exposure == GuestExposure::ChargingState;
+        match state {
+            Standby => GuestStatus::Available,
+            StartCharging | Charging if charging_state => GuestStatus::Charging,
+            VehicleDetected | SuspendedEV | StopCharging | VentilationNeeded if charging_state => {
+                GuestStatus::PluggedIn
+            }
+            VehicleDetected | StartCharging | Charging | SuspendedEV | StopCharging | VentilationNeeded => {
+                GuestStatus::InUse
+            }
+            ResetableError | FailedStation | GFITripped | PowerFailure | TamperLockout => GuestStatus::Fault,
+        }
+    }
+
+    fn message(&self) -> Message {
+        match self {
+            GuestStatus::Available => Message::Available,
+            GuestStatus::InUse => Message::InUse,
+            GuestStatus::PluggedIn => Message::PluggedIn,
+            GuestStatus::Charging => Message::Charging,
+            GuestStatus::Fault => Message::Fault,
+            GuestStatus::UnplugToRetry => Message::UnplugToRetry,
+        }
+    }
+}
+
+// Binds the address and serves it on a background thread. Returns the
+// bound address. Does nothing if the endpoint is disabled.
+pub fn serve(
+    address: &str,
+    controller: EvseController,
+    exposure: GuestExposure,
+    theme: Theme,
+) -> io::Result<Option<SocketAddr>> {
+    if exposure == GuestExposure::Disabled {
+        return Ok(None);
+    }
+    let listener = TcpListener::bind(address)?;
+    let local_address = listener.local_addr()?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = respond(stream, &controller, exposure, &theme);
+        }
+    });
+    Ok(Some(local_address))
+}
+
+// Tokens are hex, which keeps them out of harm's way in the page.
+fn token_from_query(query: Option<&str>) -> Option<&str> {
+    let token = query?.split('&').find_map(|pair| pair.strip_prefix("token="))?;
+    (!token.is_empty() && token.chars().all(|c| c.is_ascii_hexdigit())).then_some(token)
+}
+
+fn redeem(controller: &EvseController, theme: &Theme, token: &str) -> (&'static str, String) {
+    match controller.redeem_guest_token(token) {
+        Ok(token) => (
+            "200 OK",
+            theme.page(Message::ChargingAuthorized, &format!("<p>{}</p>", token.id)),
+        ),
+        Err(error @ (GuestTokenError::Unknown | GuestTokenError::Expired | GuestTokenError::InUse)) => (
+            "403 Forbidden",
+            theme.page(Message::NotAuthorized, &format!("<p>{}</p>", error)),
+        ),
+        Err(error) => (
+            "503 Service Unavailable",
+            theme.page(Message::NotAvailable, &format!("<p>{}</p>", error)),
+        ),
+    }
+}
+
+fn resume(controller: &EvseController, theme: &Theme, token: &str) -> (&'static str, String) {
+    match controller.resume_charging(token) {
+        Ok(_) => ("200 OK", theme.page(Message::ChargingResumed, "")),
+        Err(error @ (ResumeError::Unknown | ResumeError::Expired | ResumeError::ControlContact)) => (
+            "403 Forbidden",
+            theme.page(Message::NotAuthorized, &format!("<p>{}</p>", error)),
+        ),
+        Err(error) => (
+            "503 Service Unavailable",
+            theme.page(Message::NotAvailable, &format!("<p>{}</p>", error)),
+        ),
+    }
+}
+
+fn respond(stream: TcpStream, controller: &EvseController, exposure: GuestExposure, theme: &Theme) -> io::Result<()> {
+    let status = match controller.grace_unplug_remaining() {
+        Some(_) => GuestStatus::UnplugToRetry,
+        None => GuestStatus::new(controller.state(), exposure),
+    };
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are not needed, but are read so closing the connection
+    // does not reset it.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next();
+    let target = parts.next();
+    let (path, query) = match target.and_then(|target| target.split_once('?')) {
+        Some((path, query)) => (Some(path), Some(query)),
+        None => (target, None),
+    };
+    let html = "text/html; charset=utf-8";
+    let (code, content_type, body) = match (method, path) {
+        // Shed while the control loop is short of CPU, see brownout.rs.
+        _ if !controller.brownout().web_ui_enabled() => {
+            ("503 Service Unavailable", "text/plain", "Busy".to_string())
+        }
+        (Some("GET"), Some("/status")) => (
+            "200 OK",
+            "application/json",
+            serde_json::json!({ "status": status }).to_string(),
+        ),
+        (Some("GET"), Some("/")) => (
+            "200 OK",
+            html,
+            theme.page(status.message(), &theme.status_body(status.message())),
+        ),
+        (Some("GET" | "POST"), Some("/redeem")) => match (method, token_from_query(query)) {
+            (_, None) => ("400 Bad Request", "text/plain", "No token".to_string()),
+            (Some("GET"), Some(token)) => {
+                let form = format!(
+                    "<form method=\"post\" action=\"/redeem?token={}\"><button>{}</button></form>",
+                    token,
+                    theme.text(Message::StartCharging)
+                );
+                ("200 OK", html, theme.page(Message::GuestCharging, &form))
+            }
+            (_, Some(token)) => {
+                let (code, body) = redeem(controller, theme, token);
+                (code, html, body)
+            }
+        },
+        (Some("GET" | "POST"), Some("/resume")) => match (method, token_from_query(query)) {
+            (_, None) => ("400 Bad Request", "text/plain", "No token".to_string()),
+            (Some("GET"), Some(token)) => {
+                let form = format!(
+                    "<form method=\"post\" action=\"/resume?token={}\"><button>{}</button></form>",
+                    token,
+                    theme.text(Message::ResumeCharging)
+                );
+                ("200 OK", html, theme.page(Message::ResumeCharging, &form))
+            }
+            (_, Some(token)) => {
+                let (code, body) = resume(controller, theme, token);
+                (code, html, body)
+            }
+        },
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "Method not allowed".to_string()),
+    };
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        code,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evse::{start_machine, EVSEError, EVSEHardware, EVSEMachineInput, PilotReading};
+    use crate::pilot::PilotSignal;
+    use crossbeam_channel::{unbounded, Receiver};
+    use std::io::Read;
+
+    #[test]
+    fn test_guest_status() {
+        use EVSEMachineState::*;
+        assert_eq!(GuestStatus::new(Standby, GuestExposure::Availability), GuestStatus::Available);
+        assert_eq!(GuestStatus::new(Charging, GuestExposure::Availability), GuestStatus::InUse);
+        assert_eq!(GuestStatus::new(SuspendedEV, GuestExposure::Availability), GuestStatus::InUse);
+        assert_eq!(GuestStatus::new(Charging, GuestExposure::ChargingState), GuestStatus::Charging);
+        assert_eq!(GuestStatus::new(SuspendedEV, GuestExposure::ChargingState), GuestStatus::PluggedIn);
+        assert_eq!(GuestStatus::new(TamperLockout, GuestExposure::ChargingState), GuestStatus::Fault);
+    }
+
+    struct IdleHardware {
+        pilot_rx: Receiver<PilotReading>,
+        fault_rx: Receiver<EVSEMachineInput>,
+    }
+
+    impl EVSEHardware for IdleHardware {
+        fn set_pilot(&mut self, _signal: PilotSignal) -> Result<(), EVSEError> {
+            Ok(())
+        }
+
+        fn set_contactor(&mut self, _on: bool) -> Result<(), EVSEError> {
+            Ok(())
+        }
+
+        fn run_gfi_self_test(&mut self) -> Result<(), EVSEError> {
+            Ok(())
+        }
+
+        fn pilot_channel(&self) -> Receiver<PilotReading> {
+            self.pilot_rx.clone()
+        }
+
+        fn fault_channel(&self) -> Receiver<EVSEMachineInput> {
+            self.fault_rx.clone()
+        }
+    }
+
+    fn get(address: SocketAddr, path: &str) -> String {
+        request(address, "GET", path)
+    }
+
+    fn request(address: SocketAddr, method: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: charger\r\n\r\n", method, path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve() {
+        let (_pilot_tx, pilot_rx) = unbounded();
+        let (_fault_tx, fault_rx) = unbounded();
+        let handle = start_machine(IdleHardware { pilot_rx, fault_rx });
+
+        assert_eq!(
+            serve("127.0.0.1:0", handle.controller(), GuestExposure::Disabled, Theme::default()).unwrap(),
+            None
+        );
+        let address = serve("127.0.0.1:0", handle.controller(), GuestExposure::Availability, Theme::default())
+            .unwrap()
+            .unwrap();
+
+        let response = get(address, "/status");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"status\":\"available\"}"));
+        assert!(get(address, "/").contains("<h1>Available</h1>"));
+        assert!(get(address, "/sessions").starts_with("HTTP/1.1 404"));
+
+        assert!(get(address, "/redeem?token=ab12").contains("action=\"/redeem?token=ab12\""));
+        assert!(get(address, "/redeem?token=<script>").starts_with("HTTP/1.1 400"));
+        // This machine keeps no store, so there are no tokens.
+        assert!(request(address, "POST", "/redeem?token=ab12").starts_with("HTTP/1.1 503"));
+        assert!(get(address, "/resume?token=ab12").contains("action=\"/resume?token=ab12\""));
+        // No session was interrupted.
+        assert!(request(address, "POST", "/resume?token=ab12").starts_with("HTTP/1.1 403"));
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::backup::random_bytes;
+use crate::provisioning::to_hex;
+use crate::session_id::SessionId;
+use crate::store::{Store, StoreError};
+
+// Guest charging. An admin issues a time-limited token on the control
+// socket and hands it to the guest, e.g. as a QR code of the redeem link
+// of the guest endpoint. The guest redeems it there or on the control
+// socket, and the session that follows runs under the caps of the token
+// and is recorded against it. A token can be redeemed until it expires.
+//
+// The store only keeps a hash of the token; the token itself is shown
+// once, when it is issued.
+//
+// The energy counts the same power as the session log: the measured
+// current, or without a meter the offer, at the mains voltage on the
+// phases in use.
+
+const TOKEN_BYTES: usize = 16;
+// Enough to tell tokens apart in the session records.
+const ID_LENGTH: usize = 12;
+pub const MAX_VALIDITY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GuestToken {
+    pub id: String,
+    pub label: String,
+    pub expires_at: u64,
+    pub max_current: Option<f64>,
+    pub max_energy_wh: Option<f64>,
+}
+
+// A newly issued token and its secret.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IssuedToken {
+    #[serde(flatten)]
+    pub token: GuestToken,
+    pub secret: String,
+    // Where the guest endpoint redeems it, for the QR code.
+    pub redeem_path: String,
+}
+
+// The session of a guest.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GuestSession {
+    pub token: GuestToken,
+    pub started_at: u64,
+    pub energy_wh: f64,
+}
+
+impl GuestSession {
+    pub fn new(token: GuestToken) -> Self {
+        Self {
+            token,
+            started_at: unix_now(),
+            energy_wh: 0.0,
+        }
+    }
+
+    pub fn add_charging(&mut self, power_w: f64, duration: Duration) {
+        self.energy_wh += power_w * duration.as_secs_f64() / 3600.0;
+    }
+
+    pub fn energy_used_up(&self) -> bool {
+        self.token.max_energy_wh.is_some_and(|max| self.energy_wh >= max)
+    }
+
+    // Records the finished session against the token.
+    pub fn record(&self, store_path: &Path, session_id: Option<&SessionId>) -> Result<(), StoreError> {
+        Store::open(store_path)?.record_session(
+            self.started_at,
+            unix_now(),
+            self.energy_wh,
+            Some(&self.token.id),
+            session_id,
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum GuestTokenError {
+    Store(StoreError),
+    Io(io::Error),
+    Unknown,
+    Expired,
+    InvalidValidity,
+    // Another guest session runs.
+    InUse,
+    // No store to keep the tokens in.
+    Disabled,
+    MachineStopped,
+}
+
+impl From<StoreError> for GuestTokenError {
+    fn from(error: StoreError) -> Self {
+        GuestTokenError::Store(error)
+    }
+}
+
+impl From<io::Error> for GuestTokenError {
+    fn from(error: io::Error) -> Self {
+        GuestTokenError::Io(error)
+    }
+}
+
+impl fmt::Display for GuestTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuestTokenError::Store(error) => write!(f, "{}", error),
+            GuestTokenError::Io(error) => write!(f, "no randomness for the token: {}", error),
+            GuestTokenError::Unknown => write!(f, "unknown token"),
+            GuestTokenError::Expired => write!(f, "the token has expired"),
+            GuestTokenError::InvalidValidity => write!(f, "a token is valid for up to 30 days"),
+            GuestTokenError::InUse => write!(f, "the station is in use by another guest"),
+            GuestTokenError::Disabled => write!(f, "guest charging is not available"),
+            GuestTokenError::MachineStopped => write!(f, "the station is stopped"),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub(crate) fn hash_secret(secret: &str) -> String {
+    to_hex(&Sha256::digest(secret.trim().as_bytes()))
+}
+
+pub fn issue(
+    store_path: &Path,
+    label: &str,
+    valid_for: Duration,
+    max_current: Option<f64>,
+    max_energy_wh: Option<f64>,
+) -> Result<IssuedToken, GuestTokenError> {
+    if valid_for.is_zero() || valid_for > MAX_VALIDITY {
+        return Err(GuestTokenError::InvalidValidity);
+    }
+    let mut bytes = [0u8; TOKEN_BYTES];
+    random_bytes(&mut bytes)?;
+    let secret = to_hex(&bytes);
+    let hash = hash_secret(&secret);
+    let token = GuestToken {
+        id: hash[..ID_LENGTH].to_string(),
+        label: label.to_string(),
+        expires_at: unix_now() + valid_for.as_secs(),
+        max_current,
+        max_energy_wh,
+    };
+    Store::open(store_path)?.save_guest_token(&hash, &token)?;
+    Ok(IssuedToken {
+        token,
+        redeem_path: format!("/redeem?token={}", secret),
+        secret,
+    })
+}
+
+pub fn redeem(store_path: &Path, secret: &str) -> Result<GuestToken, GuestTokenError> {
+    let token = Store::open(store_path)?
+        .guest_token(&hash_secret(secret))?
+        .ok_or(GuestTokenError::Unknown)?;
+    if token.expires_at <= unix_now() {
+        return Err(GuestTokenError::Expired);
+    }
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issue_and_redeem() {
+        let path = std::env::temp_dir().join(format!("juicelib-guest-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let issued = issue(&path, "Visitor", Duration::from_secs(3600), Some(16.0), Some(10_000.0)).unwrap();
+        assert_eq!(issued.secret.len(), 2 * TOKEN_BYTES);
+
+        assert_eq!(redeem(&path, &issued.secret).unwrap(), issued.token);
+        assert!(matches!(redeem(&path, "0123"), Err(GuestTokenError::Unknown)));
+        assert!(matches!(
+            issue(&path, "Visitor", Duration::ZERO, None, None),
+            Err(GuestTokenError::InvalidValidity)
+        ));
+
+        // Expired.
+        let hash = hash_secret("old");
+        let token = GuestToken {
+            expires_at: unix_now() - 1,
+            ..issued.token.clone()
+        };
+        Store::open(&path).unwrap().save_guest_token(&hash, &token).unwrap();
+        assert!(matches!(redeem(&path, "old"), Err(GuestTokenError::Expired)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_energy_cap() {
+        let token = GuestToken {
+            id: "0123456789ab".to_string(),
+            label: "Visitor".to_string(),
+            expires_at: unix_now() + 3600,
+            max_current: None,
+            max_energy_wh: Some(1000.0),
+        };
+        let mut session = GuestSession::new(token);
+        // 3840 W for 15 minutes is 960 Wh.
+        session.add_charging(3840.0, Duration::from_secs(15 * 60));
+        assert!((session.energy_wh - 960.0).abs() < 1e-6);
+        assert!(!session.energy_used_up());
+        session.add_charging(3840.0, Duration::from_secs(60));
+        assert!(session.energy_used_up());
+    }
+}
@@ -0,0 +1,316 @@
+// The Raspberry Pi peripherals the crate drives: GPIO, hardware PWM and
+// SPI. With the `hardware` feature, the default, they are rppal's. Without
+// it, mocks with the same narrow interface stand in, so the crate builds
+// and its tests run on any machine, e.g. x86 CI:
+//
+//   cargo test -p juicelib --no-default-features
+//
+// The mocks open like the real thing and behave like a quiet bench: GPIO
+// outputs drive a level table the inputs read back, which tests can set
+// with gpio::set_level; the PWM keeps what it is set to; the ADC on the
+// SPI bus reads mid-scale on every channel.
+
+#[cfg(feature = "hardware")]
+pub use rppal::{gpio, pwm, spi};
+
+#[cfg(not(feature = "hardware"))]
+pub mod gpio {
+    use std::fmt;
+    use std::sync::Mutex;
+
+    const PINS: usize = 54;
+
+    static LEVELS: Mutex<[bool; PINS]> = Mutex::new([false; PINS]);
+
+    #[derive(Debug)]
+    pub enum Error {
+        PinNotAvailable(u8),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::PinNotAvailable(pin) => write!(f, "Pin {} is not available", pin),
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    pub type Result<T> = std::result::Result<T, Error>;
+
+    // Sets the level an input pin reads.
+    pub fn set_level(pin: u8, high: bool) {
+        LEVELS.lock().unwrap()[pin as usize] = high;
+    }
+
+    fn level(pin: u8) -> bool {
+        LEVELS.lock().unwrap()[pin as usize]
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Gpio;
+
+    impl Gpio {
+        pub fn new() -> Result<Self> {
+            Ok(Gpio)
+        }
+
+        pub fn get(&self, pin: u8) -> Result<Pin> {
+            if pin as usize >= PINS {
+                return Err(Error::PinNotAvailable(pin));
+            }
+            Ok(Pin { pin })
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct Pin {
+        pin: u8,
+    }
+
+    impl Pin {
+        pub fn into_input(self) -> InputPin {
+            InputPin { pin: self.pin }
+        }
+
+        pub fn into_output_low(self) -> OutputPin {
+            set_level(self.pin, false);
+            OutputPin { pin: self.pin }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct InputPin {
+        pin: u8,
+    }
+
+    impl InputPin {
+        pub fn is_high(&self) -> bool {
+            level(self.pin)
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct OutputPin {
+        pin: u8,
+    }
+
+    impl OutputPin {
+        pub fn set_high(&mut self) {
+            set_level(self.pin, true);
+        }
+
+        pub fn set_low(&mut self) {
+            set_level(self.pin, false);
+        }
+
+        pub fn toggle(&mut self) {
+            set_level(self.pin, !level(self.pin));
+        }
+
+        pub fn is_set_high(&self) -> bool {
+            level(self.pin)
+        }
+
+        // Software PWM; the level reads high while it drives the pin at all.
+        pub fn set_pwm_frequency(&mut self, _frequency: f64, duty_cycle: f64) -> Result<()> {
+            set_level(self.pin, duty_cycle > 0.0);
+            Ok(())
+        }
+
+        pub fn clear_pwm(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(feature = "hardware"))]
+pub mod pwm {
+    use std::fmt;
+    use std::io;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    pub enum Error {
+        Io(io::Error),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::Io(error) => write!(f, "I/O error: {}", error),
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    pub type Result<T> = std::result::Result<T, Error>;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Channel {
+        Pwm0,
+        Pwm1,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Polarity {
+        Normal,
+        Inverse,
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    struct Settings {
+        period: Duration,
+        duty_cycle: f64,
+        enabled: bool,
+    }
+
+    #[derive(Debug)]
+    pub struct Pwm {
+        settings: Mutex<Settings>,
+    }
+
+    impl Pwm {
+        pub fn new(_channel: Channel) -> Result<Self> {
+            Ok(Self {
+                settings: Mutex::new(Settings {
+                    period: Duration::ZERO,
+                    duty_cycle: 0.0,
+                    enabled: false,
+                }),
+            })
+        }
+
+        pub fn with_frequency(
+            channel: Channel,
+            frequency: f64,
+            duty_cycle: f64,
+            _polarity: Polarity,
+            enabled: bool,
+        ) -> Result<Self> {
+            let pwm = Self::new(channel)?;
+            pwm.set_frequency(frequency, duty_cycle)?;
+            pwm.settings.lock().unwrap().enabled = enabled;
+            Ok(pwm)
+        }
+
+        pub fn set_period(&self, period: Duration) -> Result<()> {
+            self.settings.lock().unwrap().period = period;
+            Ok(())
+        }
+
+        pub fn set_duty_cycle(&self, duty_cycle: f64) -> Result<()> {
+            self.settings.lock().unwrap().duty_cycle = duty_cycle.clamp(0.0, 1.0);
+            Ok(())
+        }
+
+        pub fn duty_cycle(&self) -> Result<f64> {
+            Ok(self.settings.lock().unwrap().duty_cycle)
+        }
+
+        pub fn set_frequency(&self, frequency: f64, duty_cycle: f64) -> Result<()> {
+            let mut settings = self.settings.lock().unwrap();
+            settings.period = Duration::from_secs_f64(1.0 / frequency);
+            settings.duty_cycle = duty_cycle.clamp(0.0, 1.0);
+            Ok(())
+        }
+
+        pub fn enable(&self) -> Result<()> {
+            self.settings.lock().unwrap().enabled = true;
+            Ok(())
+        }
+
+        pub fn disable(&self) -> Result<()> {
+            self.settings.lock().unwrap().enabled = false;
+            Ok(())
+        }
+
+        pub fn is_enabled(&self) -> Result<bool> {
+            Ok(self.settings.lock().unwrap().enabled)
+        }
+    }
+}
+
+#[cfg(not(feature = "hardware"))]
+pub mod spi {
+    use std::fmt;
+    use std::io;
+
+    #[derive(Debug)]
+    pub enum Error {
+        Io(io::Error),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::Io(error) => write!(f, "I/O error: {}", error),
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    pub type Result<T> = std::result::Result<T, Error>;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Bus {
+        Spi0,
+        Spi1,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SlaveSelect {
+        Ss0,
+        Ss1,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Mode {
+        Mode0,
+        Mode1,
+        Mode2,
+        Mode3,
+    }
+
+    #[derive(Debug)]
+    pub struct Spi;
+
+    // 512 of 1023, in the last two bytes of the MCP300x answer.
+    const MID_SCALE: [u8; 3] = [0x00, 0x02, 0x00];
+
+    impl Spi {
+        pub fn new(_bus: Bus, _slave_select: SlaveSelect, _clock_speed: u32, _mode: Mode) -> Result<Self> {
+            Ok(Spi)
+        }
+
+        pub fn transfer(&mut self, read_buffer: &mut [u8], write_buffer: &[u8]) -> Result<usize> {
+            for (i, byte) in read_buffer.iter_mut().enumerate() {
+                *byte = MID_SCALE.get(i).copied().unwrap_or(0);
+            }
+            Ok(write_buffer.len())
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "hardware")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_gpio_reads_back() {
+        let gpio = gpio::Gpio::new().unwrap();
+        let mut output = gpio.get(40).unwrap().into_output_low();
+        let input = gpio.get(40).unwrap().into_input();
+        output.set_high();
+        assert!(input.is_high());
+        output.toggle();
+        assert!(!input.is_high());
+        gpio::set_level(40, true);
+        assert!(output.is_set_high());
+        assert!(gpio.get(54).is_err());
+    }
+}
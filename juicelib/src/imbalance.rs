@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+// Line current imbalance detection for split-phase installations with a CT
+// on both line conductors. Whatever goes out on one line has to come back
+// on the other; a difference means current finds another way back, e.g.
+// through a miswired neutral or a leakage path the GFI CT does not see.
+//
+// The machine feeds the monitor while charging. A warning goes to the
+// audit log; at the stop threshold the charge ends in a resettable error.
+// The thresholds are the line_imbalance of the site settings.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImbalanceConfig {
+    // Difference between the line currents (A RMS) that raises a warning.
+    pub warn_amps: f64,
+    // Difference that stops charging.
+    pub stop_amps: f64,
+    // How long a difference has to persist before it counts. Filters out
+    // the CTs settling at different speeds when the load changes.
+    pub sustain_secs: u64,
+}
+
+impl Default for ImbalanceConfig {
+    fn default() -> Self {
+        Self {
+            warn_amps: 0.5,
+            stop_amps: 2.0,
+            sustain_secs: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ImbalanceStatus {
+    Balanced,
+    Warning { difference_amps: f64 },
+    Stop { difference_amps: f64 },
+}
+
+pub struct ImbalanceMonitor {
+    config: ImbalanceConfig,
+    warn_since: Option<Duration>,
+    stop_since: Option<Duration>,
+}
+
+impl ImbalanceMonitor {
+    pub fn new(config: ImbalanceConfig) -> Self {
+        Self {
+            config,
+            warn_since: None,
+            stop_since: None,
+        }
+    }
+
+    // Feeds the RMS currents of both lines. `elapsed` is a monotonic time
+    // stamp, e.g. the time since the contactor closed.
+    pub fn update(&mut self, elapsed: Duration, line1_amps: f64, line2_amps: f64) -> ImbalanceStatus {
+        let difference_amps = (line1_amps - line2_amps).abs();
+
+        let sustained = |since: &mut Option<Duration>, above: bool, sustain: Duration| {
+            if !above {
+                *since = None;
+                return false;
+            }
+            elapsed.saturating_sub(*since.get_or_insert(elapsed)) >= sustain
+        };
+
+        // A NaN difference (failed reading) must not look balanced.
+        let above_stop = difference_amps.is_nan() || difference_amps >= self.config.stop_amps;
+        let above_warn = difference_amps.is_nan() || difference_amps >= self.config.warn_amps;
+        let sustain = Duration::from_secs(self.config.sustain_secs);
+        if sustained(&mut self.stop_since, above_stop, sustain) {
+            ImbalanceStatus::Stop { difference_amps }
+        } else if sustained(&mut self.warn_since, above_warn, sustain) {
+            ImbalanceStatus::Warning { difference_amps }
+        } else {
+            ImbalanceStatus::Balanced
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.warn_since = None;
+        self.stop_since = None;
+    }
+}
+
+impl Default for ImbalanceMonitor {
+    fn default() -> Self {
+        Self::new(ImbalanceConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_balanced_lines() {
+        let mut monitor = ImbalanceMonitor::default();
+        for t in 0..10 {
+            assert_eq!(monitor.update(secs(t), 31.8, 32.1), ImbalanceStatus::Balanced);
+        }
+    }
+
+    #[test]
+    fn test_warning_then_stop() {
+        let mut monitor = ImbalanceMonitor::default();
+        assert_eq!(monitor.update(secs(0), 32.0, 31.0), ImbalanceStatus::Balanced);
+        assert_eq!(
+            monitor.update(secs(2), 32.0, 31.0),
+            ImbalanceStatus::Warning { difference_amps: 1.0 }
+        );
+
+        // The stop threshold needs to be sustained on its own.
+        assert_eq!(
+            monitor.update(secs(3), 32.0, 29.0),
+            ImbalanceStatus::Warning { difference_amps: 3.0 }
+        );
+        assert_eq!(
+            monitor.update(secs(5), 32.0, 29.0),
+            ImbalanceStatus::Stop { difference_amps: 3.0 }
+        );
+    }
+
+    #[test]
+    fn test_transient_is_ignored() {
+        let mut monitor = ImbalanceMonitor::default();
+        assert_eq!(monitor.update(secs(0), 16.0, 10.0), ImbalanceStatus::Balanced);
+        assert_eq!(monitor.update(secs(1), 16.0, 16.0), ImbalanceStatus::Balanced);
+        assert_eq!(monitor.update(secs(2), 16.0, 10.0), ImbalanceStatus::Balanced);
+    }
+
+    #[test]
+    fn test_failed_reading_counts_as_imbalance() {
+        let mut monitor = ImbalanceMonitor::default();
+        monitor.update(secs(0), 16.0, f64::NAN);
+        assert!(matches!(monitor.update(secs(2), 16.0, f64::NAN), ImbalanceStatus::Stop { .. }));
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use serde::{Deserialize, Serialize};
+
+use crate::evse::{EVSEMachineState, EvseController};
+
+// Exports periodic sensor samples in the InfluxDB line protocol, which
+// InfluxDB and VictoriaMetrics both accept over HTTP. Samples are queued
+// and written in batches by a background thread. The queue is bounded: if
+// the endpoint is down long enough for it to fill up, new samples are
+// refused rather than piling up in memory.
+//
+// With influx in the site settings, juiced takes a sample of the machine
+// every sample_interval_secs and pushes it, see watch_samples.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InfluxConfig {
+    // Host and port of the endpoint, e.g. "metrics.local:8086".
+    pub address: String,
+    // Path and query of the write endpoint, e.g. "/write?db=juiced" for
+    // InfluxDB 1.x and VictoriaMetrics.
+    pub path: String,
+    pub measurement: String,
+    // Added to every line, e.g. site and connector id. In the order of the
+    // keys, as InfluxDB prefers.
+    pub tags: BTreeMap<String, String>,
+    pub batch_size: usize,
+    pub flush_interval_secs: u64,
+    pub queue_size: usize,
+    pub sample_interval_secs: u64,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        Self {
+            address: "localhost:8086".to_string(),
+            path: "/write?db=juiced".to_string(),
+            measurement: "evse".to_string(),
+            tags: BTreeMap::new(),
+            batch_size: 60,
+            flush_interval_secs: 10,
+            queue_size: 3600,
+            sample_interval_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorSample {
+    pub state: EVSEMachineState,
+    pub amps: f64,
+    pub volts: f64,
+    pub power_w: f64,
+    pub energy_wh: f64,
+    pub temperature_c: Option<f64>,
+    // Nanoseconds since the Unix epoch.
+    pub timestamp_ns: u128,
+}
+
+impl SensorSample {
+    // Time stamp of a sample taken now.
+    pub fn now_ns() -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0)
+    }
+}
+
+// Measurement names and tag keys/values escape commas and spaces, tag keys
+// and values also equal signs.
+fn escape(value: &str, equals: bool) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ',' || c == ' ' || (equals && c == '=') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub fn to_line(config: &InfluxConfig, sample: &SensorSample) -> String {
+    let mut line = escape(&config.measurement, false);
+    for (key, value) in &config.tags {
+        line.push_str(&format!(",{}={}", escape(key, true), escape(value, true)));
+    }
+    line.push_str(&format!(
+        " state=\"{:?}\",amps={},volts={},power={},energy={}",
+        sample.state, sample.amps, sample.volts, sample.power_w, sample.energy_wh
+    ));
+    if let Some(temperature) = sample.temperature_c {
+        line.push_str(&format!(",temperature={}", temperature));
+    }
+    line.push_str(&format!(" {}", sample.timestamp_ns));
+    line
+}
+
+// Where batches of lines go.
+pub trait LineSink: Send + 'static {
+    fn write_lines(&mut self, body: &str) -> io::Result<()>;
+}
+
+// Plain HTTP POST, one connection per batch.
+pub struct HttpSink {
+    address: String,
+    path: String,
+}
+
+impl HttpSink {
+    pub fn new(config: &InfluxConfig) -> Self {
+        Self {
+            address: config.address.clone(),
+            path: config.path.clone(),
+        }
+    }
+}
+
+impl LineSink for HttpSink {
+    fn write_lines(&mut self, body: &str) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.address,
+            body.len(),
+            body
+        )?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        // The write endpoints answer 204 No Content.
+        match status_line.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!("write failed: {}", status_line.trim()))),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExportError {
+    // The queue is full, the endpoint cannot keep up or is down.
+    QueueFull,
+    Stopped,
+}
+
+// Delay before a failed batch is retried.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+pub struct InfluxExporter {
+    config: InfluxConfig,
+    sample_tx: Option<Sender<String>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl InfluxExporter {
+    pub fn new(config: InfluxConfig) -> Self {
+        let sink = HttpSink::new(&config);
+        Self::with_sink(config, sink)
+    }
+
+    pub fn with_sink<S: LineSink>(config: InfluxConfig, sink: S) -> Self {
+        let (sample_tx, sample_rx) = bounded(config.queue_size);
+        let batch_size = config.batch_size.max(1);
+        let flush_interval = Duration::from_secs(config.flush_interval_secs);
+        let thread = thread::spawn(move || Self::export(sink, sample_rx, batch_size, flush_interval));
+        Self {
+            config,
+            sample_tx: Some(sample_tx),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn push(&self, sample: &SensorSample) -> Result<(), ExportError> {
+        let line = to_line(&self.config, sample);
+        match self.sample_tx.as_ref().map(|tx| tx.try_send(line)) {
+            Some(Ok(())) => Ok(()),
+            Some(Err(TrySendError::Full(_))) => Err(ExportError::QueueFull),
+            _ => Err(ExportError::Stopped),
+        }
+    }
+
+    // Writes a batch once it is full or the flush interval has passed.
+    fn export<S: LineSink>(mut sink: S, sample_rx: Receiver<String>, batch_size: usize, flush_interval: Duration) {
+        let mut batch: Vec<String> = Vec::with_capacity(batch_size);
+        let mut last_flush = Instant::now();
+        loop {
+            let timeout = flush_interval.saturating_sub(last_flush.elapsed());
+            let stopped = match sample_rx.recv_timeout(timeout) {
+                Ok(line) => {
+                    batch.push(line);
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+
+            let due = batch.len() >= batch_size || last_flush.elapsed() >= flush_interval || stopped;
+            if due && !batch.is_empty() {
+                match sink.write_lines(&batch.join("\n")) {
+                    Ok(()) => batch.clear(),
+                    // Keep the batch; the queue fills up meanwhile.
+                    Err(_) if !stopped => thread::sleep(RETRY_DELAY),
+                    Err(_) => {}
+                }
+            }
+            if due {
+                last_flush = Instant::now();
+            }
+            if stopped {
+                return;
+            }
+        }
+    }
+
+    // Flushes what is queued and stops the export thread.
+    pub fn shutdown(mut self) {
+        self.sample_tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Pushes a sample of the machine at every interval, from the first pilot
+// reading on. A full queue is reported once until it drains.
+pub fn watch_samples(controller: EvseController, exporter: InfluxExporter) {
+    let interval = Duration::from_secs(exporter.config.sample_interval_secs.max(1));
+    thread::spawn(move || {
+        let mut full = false;
+        loop {
+            thread::sleep(interval);
+            let Some(sample) = controller.sensor_sample() else {
+                continue;
+            };
+            match exporter.push(&sample) {
+                Ok(()) => full = false,
+                Err(ExportError::QueueFull) if !full => {
+                    full = true;
+                    eprintln!("InfluxDB export queue full, samples dropped");
+                }
+                Err(ExportError::QueueFull) => {}
+                Err(ExportError::Stopped) => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct MemorySink {
+        bodies: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl LineSink for MemorySink {
+        fn write_lines(&mut self, body: &str) -> io::Result<()> {
+            self.bodies.lock().unwrap().push(body.to_string());
+            Ok(())
+        }
+    }
+
+    fn sample(amps: f64) -> SensorSample {
+        SensorSample {
+            state: EVSEMachineState::Charging,
+            amps,
+            volts: 240.0,
+            power_w: amps * 240.0,
+            energy_wh: 1500.0,
+            temperature_c: None,
+            timestamp_ns: 1_700_000_000_000_000_000,
+        }
+    }
+
+    fn config() -> InfluxConfig {
+        InfluxConfig {
+            tags: BTreeMap::from([
+                ("site".to_string(), "Main St, Garage".to_string()),
+                ("connector".to_string(), "1".to_string()),
+            ]),
+            batch_size: 2,
+            flush_interval_secs: 60,
+            queue_size: 4,
+            ..InfluxConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_line_protocol() {
+        assert_eq!(
+            to_line(&config(), &sample(16.0)),
+            "evse,connector=1,site=Main\\ St\\,\\ Garage \
+             state=\"Charging\",amps=16,volts=240,power=3840,energy=1500 1700000000000000000"
+        );
+
+        let mut with_temperature = sample(16.0);
+        with_temperature.temperature_c = Some(41.5);
+        assert!(to_line(&InfluxConfig::default(), &with_temperature).contains(",temperature=41.5 "));
+    }
+
+    #[test]
+    fn test_batching_and_shutdown_flush() {
+        let sink = MemorySink::default();
+        let exporter = InfluxExporter::with_sink(config(), sink.clone());
+        for amps in [6.0, 8.0, 10.0] {
+            exporter.push(&sample(amps)).unwrap();
+        }
+        exporter.shutdown();
+
+        let bodies = sink.bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0].lines().count(), 2);
+        assert!(bodies[1].contains("amps=10"));
+    }
+
+    struct DownSink;
+
+    impl LineSink for DownSink {
+        fn write_lines(&mut self, _body: &str) -> io::Result<()> {
+            Err(io::Error::other("down"))
+        }
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let exporter = InfluxExporter::with_sink(config(), DownSink);
+        let results: Vec<_> = (0..10).map(|i| exporter.push(&sample(i as f64))).collect();
+        assert!(results.contains(&Err(ExportError::QueueFull)));
+    }
+}
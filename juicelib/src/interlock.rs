@@ -0,0 +1,167 @@
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+use crate::channels::Signal;
+use crate::evse::{EVSEError, PilotSampler};
+
+// Ownership of the pilot during the GFI self test. The test runs before the
+// machine starts and again in StartCharging, while the sampler thread keeps
+// reading the pilot and the actuator thread may still be ramping an offer.
+// The test takes a token for as long as it runs. Meanwhile the actuator
+// holds its pilot writes and the sampler holds its windows; a window the
+// test started in the middle of is thrown away and taken again, since the
+// test current disturbs the pilot feedback. Both carry on once the token
+// is dropped, which also happens if the test panics.
+//
+// The control watchdog reaches the pilot without going through either and
+// is never held.
+
+#[derive(Debug, Default)]
+struct Ownership {
+    taken: bool,
+    // Self tests so far, to tell a window that overlapped one.
+    tests: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PilotInterlock {
+    ownership: Arc<(Mutex<Ownership>, Condvar)>,
+}
+
+impl PilotInterlock {
+    // Takes the pilot until the token is dropped.
+    pub fn take(&self) -> SelfTestToken {
+        let mut ownership = self.ownership.0.lock().unwrap();
+        ownership.taken = true;
+        ownership.tests += 1;
+        SelfTestToken {
+            interlock: self.clone(),
+        }
+    }
+
+    pub fn is_taken(&self) -> bool {
+        self.ownership.0.lock().unwrap().taken
+    }
+
+    // Blocks while a self test owns the pilot. Returns the number of the
+    // last test, to check later that no other one started meanwhile.
+    pub fn wait_released(&self) -> u64 {
+        let (lock, released) = &*self.ownership;
+        let ownership = released
+            .wait_while(lock.lock().unwrap(), |ownership| ownership.taken)
+            .unwrap();
+        ownership.tests
+    }
+
+    // Whether the pilot stayed free since wait_released returned `tests`.
+    fn free_since(&self, tests: u64) -> bool {
+        let ownership = self.ownership.0.lock().unwrap();
+        !ownership.taken && ownership.tests == tests
+    }
+}
+
+pub struct SelfTestToken {
+    interlock: PilotInterlock,
+}
+
+impl Drop for SelfTestToken {
+    fn drop(&mut self) {
+        let (lock, released) = &*self.interlock.ownership;
+        lock.lock().unwrap_or_else(PoisonError::into_inner).taken = false;
+        released.notify_all();
+    }
+}
+
+// Sampler that only returns pilot windows no self test overlapped.
+pub(crate) struct InterlockedSampler {
+    inner: Box<dyn PilotSampler>,
+    interlock: PilotInterlock,
+}
+
+impl InterlockedSampler {
+    pub(crate) fn new(inner: Box<dyn PilotSampler>, interlock: PilotInterlock) -> Self {
+        Self { inner, interlock }
+    }
+}
+
+impl PilotSampler for InterlockedSampler {
+    fn read_pilot_samples(&mut self, samples: usize) -> Result<Vec<f32>, EVSEError> {
+        loop {
+            let tests = self.interlock.wait_released();
+            let result = self.inner.read_pilot_samples(samples);
+            if self.interlock.free_since(tests) {
+                return result;
+            }
+        }
+    }
+
+    fn read_current(&mut self, conversions: usize) -> Option<Result<f32, EVSEError>> {
+        self.inner.read_current(conversions)
+    }
+
+    fn read_signal(&mut self, signal: Signal) -> Option<Result<f32, EVSEError>> {
+        self.inner.read_signal(signal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    // Takes the pilot for a self test half way through its first window.
+    struct TestedSampler {
+        interlock: PilotInterlock,
+        windows: usize,
+        released: Arc<AtomicBool>,
+    }
+
+    impl PilotSampler for TestedSampler {
+        fn read_pilot_samples(&mut self, samples: usize) -> Result<Vec<f32>, EVSEError> {
+            self.windows += 1;
+            if self.windows == 1 {
+                let token = self.interlock.take();
+                let released = self.released.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(50));
+                    released.store(true, Ordering::SeqCst);
+                    drop(token);
+                });
+            }
+            Ok(vec![self.windows as f32; samples])
+        }
+    }
+
+    #[test]
+    fn test_window_during_self_test_is_taken_again() {
+        let interlock = PilotInterlock::default();
+        let released = Arc::new(AtomicBool::new(false));
+        let mut sampler = InterlockedSampler::new(
+            Box::new(TestedSampler {
+                interlock: interlock.clone(),
+                windows: 0,
+                released: released.clone(),
+            }),
+            interlock.clone(),
+        );
+        // The first window overlapped the test, the second waited for it.
+        assert_eq!(sampler.read_pilot_samples(2).unwrap(), vec![2.0, 2.0]);
+        assert!(released.load(Ordering::SeqCst));
+        assert!(!interlock.is_taken());
+    }
+
+    #[test]
+    fn test_token_released_on_panic() {
+        let interlock = PilotInterlock::default();
+        let tested = interlock.clone();
+        let result = thread::spawn(move || {
+            let _token = tested.take();
+            panic!("self test panicked");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(!interlock.is_taken());
+        interlock.wait_released();
+    }
+}
@@ -0,0 +1,251 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::Receiver;
+use serde::{Deserialize, Serialize};
+
+use crate::backup::random_bytes;
+use crate::evse::{EVSEMachineInput, EVSEMachineState};
+use crate::guest_token::hash_secret;
+use crate::provisioning::to_hex;
+
+// Tells the driver when a session stops without them: on a fault, or when
+// the grid operator asserts the control contact. The notification goes to
+// a webhook as a JSON POST:
+//
+//   {"cause": {"fault": {"state": "ResetableError", "input": "PilotInError"}},
+//    "session_id": "...", "unix_time": 1792137600,
+//    "resume_token": "9f2c...", "resume_url": "https://charger.example/resume?token=9f2c..."}
+//
+// The resume link is good for one use, for RESUME_VALIDITY, and until the
+// vehicle is unplugged. Using it, on the guest endpoint or with
+// resume_charging on the control socket, acknowledges the interruption:
+// a resettable error is cleared and a vehicle still asking for power is
+// charged again; a failed station is reset as by reset_lockout, so only if
+// the GFI self test passes. The control contact cannot be overridden; while
+// it is asserted the link is refused, and the charge resumes on its own
+// once it is released.
+//
+// The machine only keeps a hash of the token, and only of the last one.
+
+pub const RESUME_VALIDITY: Duration = Duration::from_secs(12 * 60 * 60);
+// Interruptions that wait for the webhook.
+pub const INTERRUPTION_BACKLOG: usize = 16;
+
+const TOKEN_BYTES: usize = 16;
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterruptionWebhook {
+    // host:port of the receiver.
+    pub address: String,
+    pub path: String,
+    // Where the guest endpoint is reached from outside, e.g.
+    // https://charger.example. Without it the notification only has the
+    // token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume_base_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterruptionCause {
+    Fault {
+        state: EVSEMachineState,
+        input: Option<EVSEMachineInput>,
+    },
+    // The control contact of the grid operator stopped the charge.
+    DemandResponse,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Interruption {
+    pub cause: InterruptionCause,
+    pub session_id: Option<String>,
+    pub unix_time: u64,
+    pub resume_token: String,
+}
+
+impl Interruption {
+    pub fn to_json(&self, webhook: &InterruptionWebhook) -> String {
+        let mut payload = serde_json::json!(self);
+        if let Some(base) = &webhook.resume_base_url {
+            payload["resume_url"] = format!("{}/resume?token={}", base.trim_end_matches('/'), self.resume_token).into();
+        }
+        payload.to_string()
+    }
+}
+
+#[derive(Debug)]
+pub enum ResumeError {
+    Io(io::Error),
+    Unknown,
+    Expired,
+    // The control contact is still asserted.
+    ControlContact,
+    MachineStopped,
+}
+
+impl fmt::Display for ResumeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResumeError::Io(error) => write!(f, "no randomness for the token: {}", error),
+            ResumeError::Unknown => write!(f, "unknown or used link"),
+            ResumeError::Expired => write!(f, "the link has expired"),
+            ResumeError::ControlContact => write!(f, "the grid operator still limits the charge"),
+            ResumeError::MachineStopped => write!(f, "the station is stopped"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PendingResume {
+    hash: String,
+    cause: InterruptionCause,
+    expires_at: Instant,
+}
+
+// The resume token of the last interruption.
+#[derive(Debug, Clone, Default)]
+pub struct ResumeTokens {
+    pending: Option<PendingResume>,
+}
+
+impl ResumeTokens {
+    // A new token for the interruption. It replaces the one before.
+    pub fn issue(&mut self, cause: InterruptionCause, now: Instant) -> Result<String, ResumeError> {
+        let mut bytes = [0u8; TOKEN_BYTES];
+        random_bytes(&mut bytes).map_err(ResumeError::Io)?;
+        let secret = to_hex(&bytes);
+        self.pending = Some(PendingResume {
+            hash: hash_secret(&secret),
+            cause,
+            expires_at: now + RESUME_VALIDITY,
+        });
+        Ok(secret)
+    }
+
+    // The interruption the token is for, without using it up.
+    pub fn verify(&self, secret: &str, now: Instant) -> Result<InterruptionCause, ResumeError> {
+        let pending = self
+            .pending
+            .as_ref()
+            .filter(|pending| pending.hash == hash_secret(secret))
+            .ok_or(ResumeError::Unknown)?;
+        if now >= pending.expires_at {
+            return Err(ResumeError::Expired);
+        }
+        Ok(pending.cause)
+    }
+
+    pub fn clear(&mut self) {
+        self.pending = None;
+    }
+}
+
+fn post(webhook: &InterruptionWebhook, body: &str) -> io::Result<()> {
+    let mut stream = TcpStream::connect(&webhook.address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        webhook.path,
+        webhook.address,
+        body.len(),
+        body
+    )?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("webhook failed: {}", status_line.trim()))),
+    }
+}
+
+// Posts the interruptions to the webhook until the machine ends the
+// channel. A failed post is tried again a few times; the driver would
+// rather hear late than not at all.
+pub fn watch_interruptions(interruptions: Receiver<Interruption>, webhook: InterruptionWebhook) {
+    thread::spawn(move || {
+        for interruption in interruptions {
+            let body = interruption.to_json(&webhook);
+            for attempt in 1..=ATTEMPTS {
+                match post(&webhook, &body) {
+                    Ok(()) => break,
+                    Err(error) if attempt == ATTEMPTS => {
+                        eprintln!("Interruption not posted to {}: {}", webhook.address, error)
+                    }
+                    Err(_) => thread::sleep(RETRY_DELAY),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::unbounded;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_resume_tokens() {
+        let mut tokens = ResumeTokens::default();
+        let t0 = Instant::now();
+        assert!(matches!(tokens.verify("ab12", t0), Err(ResumeError::Unknown)));
+
+        let first = tokens.issue(InterruptionCause::DemandResponse, t0).unwrap();
+        assert_eq!(first.len(), 2 * TOKEN_BYTES);
+        let cause = InterruptionCause::Fault {
+            state: EVSEMachineState::ResetableError,
+            input: Some(EVSEMachineInput::PilotInError),
+        };
+        let second = tokens.issue(cause, t0).unwrap();
+        // Only the last token is good.
+        assert!(matches!(tokens.verify(&first, t0), Err(ResumeError::Unknown)));
+        assert_eq!(tokens.verify(&second, t0).unwrap(), cause);
+        assert!(matches!(tokens.verify(&second, t0 + RESUME_VALIDITY), Err(ResumeError::Expired)));
+        tokens.clear();
+        assert!(matches!(tokens.verify(&second, t0), Err(ResumeError::Unknown)));
+    }
+
+    #[test]
+    fn test_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let webhook = InterruptionWebhook {
+            address: listener.local_addr().unwrap().to_string(),
+            path: "/hooks/charger".to_string(),
+            resume_base_url: Some("https://charger.example/".to_string()),
+        };
+        let (interruption_tx, interruption_rx) = unbounded();
+        watch_interruptions(interruption_rx, webhook);
+        interruption_tx
+            .send(Interruption {
+                cause: InterruptionCause::DemandResponse,
+                session_id: None,
+                unix_time: 1_792_137_600,
+                resume_token: "9f2c".to_string(),
+            })
+            .unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !String::from_utf8_lossy(&request).ends_with('}') {
+            let len = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..len]);
+        }
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /hooks/charger HTTP/1.1\r\n"), "{}", request);
+        let body: serde_json::Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["cause"], "demand_response");
+        assert_eq!(body["resume_url"], "https://charger.example/resume?token=9f2c");
+    }
+}
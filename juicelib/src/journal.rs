@@ -0,0 +1,282 @@
+use std::fs;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use crossbeam_channel::Receiver;
+
+use crate::events::EVSEEvent;
+use crate::evse::EVSEMachineState;
+
+// The machine's events in the system journal, as structured entries that
+// fleet hosts can alert on without parsing free text. With systemd they go
+// to journald over its native protocol, one field per line:
+//
+//   PRIORITY=3
+//   MESSAGE_ID=8f0c4bb1a25e4b6f9d3e51a7c2d04e61
+//   MESSAGE=Fault: FailedStation, caused by GFIInterrupted
+//   SYSLOG_IDENTIFIER=juiced
+//   JUICED_STATE=FailedStation
+//   JUICED_TIME=2026-10-16T08:30:00.000Z
+//
+// Without journald they go to the syslog socket as RFC 5424 lines, with the
+// message id as MSGID and the fields as structured data. Faults are err,
+// a failed self test warning, state changes info and offers debug. The
+// important events carry a fixed MESSAGE_ID.
+
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+// Events that wait for the sink. Beyond, they are lost rather than hold
+// up the machine.
+pub const EVENT_BACKLOG: usize = 256;
+
+const IDENTIFIER: &str = "juiced";
+// daemon
+const SYSLOG_FACILITY: u8 = 3;
+// Private enterprise number of the structured data, RFC 5424 section 7.2.2
+// reserves 32473 for examples and documentation.
+const SD_ID: &str = "juiced@32473";
+
+pub const MESSAGE_ID_FAULT: &str = "8f0c4bb1a25e4b6f9d3e51a7c2d04e61";
+pub const MESSAGE_ID_SELF_TEST_FAILED: &str = "3b9d6e2a7c514f08a6e1d94f0b7c25d3";
+pub const MESSAGE_ID_CHARGING_STARTED: &str = "d51a0e7f3c8b4a929e6f1b2c7d40a8e5";
+pub const MESSAGE_ID_CHARGING_STOPPED: &str = "6c2e94b0a7d34f1e8b5a03f9c1d27e64";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Err = 3,
+    Warning = 4,
+    Info = 6,
+    Debug = 7,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub priority: Priority,
+    pub message_id: Option<&'static str>,
+    pub message: String,
+    // Upper case names, without the JUICED_ prefix.
+    pub fields: Vec<(&'static str, String)>,
+}
+
+impl JournalEntry {
+    pub fn from_event(event: &EVSEEvent) -> Self {
+        let state_fields = |state: EVSEMachineState, other: Option<(&'static str, String)>| {
+            let mut fields = vec![("STATE", format!("{:?}", state))];
+            fields.extend(other);
+            fields
+        };
+        match *event {
+            EVSEEvent::StateEntered { state, previous } => Self {
+                priority: Priority::Info,
+                message_id: match state {
+                    EVSEMachineState::Charging => Some(MESSAGE_ID_CHARGING_STARTED),
+                    _ if previous == Some(EVSEMachineState::Charging) => Some(MESSAGE_ID_CHARGING_STOPPED),
+                    _ => None,
+                },
+                message: match previous {
+                    Some(previous) => format!("State {:?} -> {:?}", previous, state),
+                    None => format!("State {:?}", state),
+                },
+                fields: state_fields(state, previous.map(|previous| ("PREVIOUS_STATE", format!("{:?}", previous)))),
+            },
+            EVSEEvent::FaultRaised { state, cause } => Self {
+                priority: Priority::Err,
+                message_id: Some(MESSAGE_ID_FAULT),
+                message: match cause {
+                    Some(cause) => format!("Fault: {:?}, caused by {:?}", state, cause),
+                    None => format!("Fault: {:?}", state),
+                },
+                fields: state_fields(state, cause.map(|cause| ("CAUSE", format!("{:?}", cause)))),
+            },
+            EVSEEvent::SelfTest { passed } => Self {
+                priority: if passed { Priority::Info } else { Priority::Warning },
+                message_id: (!passed).then_some(MESSAGE_ID_SELF_TEST_FAILED),
+                message: format!("GFI self test {}", if passed { "passed" } else { "failed" }),
+                fields: vec![("SELF_TEST_PASSED", passed.to_string())],
+            },
+            EVSEEvent::OfferChanged { amps } => Self {
+                priority: Priority::Debug,
+                message_id: None,
+                message: format!("Offer {:.1} A", amps),
+                fields: vec![("OFFER_AMPS", format!("{:.1}", amps))],
+            },
+        }
+    }
+
+    // The native journal protocol. Values are kept to one line.
+    pub fn to_journal(&self, time: DateTime<Utc>) -> Vec<u8> {
+        let mut datagram = format!("PRIORITY={}\n", self.priority as u8);
+        if let Some(id) = self.message_id {
+            datagram += &format!("MESSAGE_ID={}\n", id);
+        }
+        datagram += &format!("MESSAGE={}\nSYSLOG_IDENTIFIER={}\n", one_line(&self.message), IDENTIFIER);
+        for (name, value) in &self.fields {
+            datagram += &format!("JUICED_{}={}\n", name, one_line(value));
+        }
+        datagram += &format!("JUICED_TIME={}\n", rfc3339(time));
+        datagram.into_bytes()
+    }
+
+    // An RFC 5424 syslog line.
+    pub fn to_syslog(&self, time: DateTime<Utc>, hostname: &str) -> Vec<u8> {
+        let params: String = self
+            .fields
+            .iter()
+            .map(|(name, value)| format!(" {}=\"{}\"", name.to_lowercase(), escape_param(value)))
+            .collect();
+        format!(
+            "<{}>1 {} {} {} {} {} [{}{}] {}",
+            SYSLOG_FACILITY * 8 + self.priority as u8,
+            rfc3339(time),
+            hostname,
+            IDENTIFIER,
+            process::id(),
+            self.message_id.unwrap_or("-"),
+            SD_ID,
+            params,
+            one_line(&self.message)
+        )
+        .into_bytes()
+    }
+}
+
+fn rfc3339(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn one_line(value: &str) -> String {
+    value.replace('\n', " ")
+}
+
+// The characters RFC 5424 section 6.3.3 asks to escape in a parameter.
+fn escape_param(value: &str) -> String {
+    one_line(value).replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Journal,
+    Syslog,
+}
+
+// Where the entries are written to.
+#[derive(Debug)]
+pub struct JournalSink {
+    socket: UnixDatagram,
+    path: PathBuf,
+    protocol: Protocol,
+    hostname: String,
+}
+
+impl JournalSink {
+    // journald if it runs, the syslog socket otherwise.
+    pub fn connect() -> io::Result<Self> {
+        Self::journal(Path::new(JOURNAL_SOCKET)).or_else(|_| Self::syslog(Path::new(SYSLOG_SOCKET)))
+    }
+
+    pub fn journal(path: &Path) -> io::Result<Self> {
+        Self::open(path, Protocol::Journal)
+    }
+
+    pub fn syslog(path: &Path) -> io::Result<Self> {
+        Self::open(path, Protocol::Syslog)
+    }
+
+    fn open(path: &Path, protocol: Protocol) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|name| name.trim().to_string())
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "-".to_string());
+        Ok(Self {
+            socket,
+            path: path.to_path_buf(),
+            protocol,
+            hostname,
+        })
+    }
+
+    pub fn write(&self, entry: &JournalEntry) -> io::Result<()> {
+        let now = Utc::now();
+        let datagram = match self.protocol {
+            Protocol::Journal => entry.to_journal(now),
+            Protocol::Syslog => entry.to_syslog(now, &self.hostname),
+        };
+        self.socket.send(&datagram).map(|_| ())
+    }
+}
+
+// Writes the events to the sink until the machine ends the channel.
+pub fn watch_events(events: Receiver<EVSEEvent>, sink: JournalSink) {
+    thread::spawn(move || {
+        for event in events {
+            if let Err(error) = sink.write(&JournalEntry::from_event(&event)) {
+                eprintln!("Event not written to {}: {}", sink.path.display(), error);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evse::EVSEMachineInput;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_journal_entry() {
+        let time = Utc.with_ymd_and_hms(2026, 10, 16, 8, 30, 0).unwrap();
+        let fault = JournalEntry::from_event(&EVSEEvent::FaultRaised {
+            state: EVSEMachineState::FailedStation,
+            cause: Some(EVSEMachineInput::GFIInterrupted),
+        });
+        assert_eq!(
+            String::from_utf8(fault.to_journal(time)).unwrap(),
+            "PRIORITY=3\nMESSAGE_ID=8f0c4bb1a25e4b6f9d3e51a7c2d04e61\n\
+             MESSAGE=Fault: FailedStation, caused by GFIInterrupted\nSYSLOG_IDENTIFIER=juiced\n\
+             JUICED_STATE=FailedStation\nJUICED_CAUSE=GFIInterrupted\nJUICED_TIME=2026-10-16T08:30:00.000Z\n"
+        );
+        let line = String::from_utf8(fault.to_syslog(time, "garage")).unwrap();
+        assert_eq!(
+            line,
+            format!(
+                "<27>1 2026-10-16T08:30:00.000Z garage juiced {} 8f0c4bb1a25e4b6f9d3e51a7c2d04e61 \
+                 [juiced@32473 state=\"FailedStation\" cause=\"GFIInterrupted\"] Fault: FailedStation, caused by GFIInterrupted",
+                process::id()
+            )
+        );
+
+        let stopped = JournalEntry::from_event(&EVSEEvent::StateEntered {
+            state: EVSEMachineState::StopCharging,
+            previous: Some(EVSEMachineState::Charging),
+        });
+        assert_eq!(stopped.priority, Priority::Info);
+        assert_eq!(stopped.message_id, Some(MESSAGE_ID_CHARGING_STOPPED));
+        let self_test = JournalEntry::from_event(&EVSEEvent::SelfTest { passed: false });
+        assert_eq!(self_test.priority, Priority::Warning);
+        assert_eq!(self_test.message_id, Some(MESSAGE_ID_SELF_TEST_FAILED));
+        assert_eq!(escape_param("a \"b\" [c]"), "a \\\"b\\\" [c\\]");
+    }
+
+    #[test]
+    fn test_sink() {
+        let path = std::env::temp_dir().join(format!("juicelib-journal-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let journald = UnixDatagram::bind(&path).unwrap();
+        let sink = JournalSink::journal(&path).unwrap();
+        sink.write(&JournalEntry::from_event(&EVSEEvent::OfferChanged { amps: 16.0 })).unwrap();
+        let mut buf = [0u8; 1024];
+        let len = journald.recv(&mut buf).unwrap();
+        let datagram = String::from_utf8_lossy(&buf[..len]);
+        assert!(datagram.starts_with("PRIORITY=7\nMESSAGE=Offer 16.0 A\n"), "{}", datagram);
+        fs::remove_file(&path).unwrap();
+
+        assert!(JournalSink::journal(Path::new("/nonexistent/journal.sock")).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{select_biased, Receiver};
+use serde::{Deserialize, Serialize};
+
+use crate::evse::{EVSEError, EVSEHardware, EVSEMachineInput, PilotClassifier, PilotReading};
+use crate::pilot::{PilotSignal, PILOT_FREQUENCY};
+
+// Lab mode for certification labs and interoperability work. A pattern of
+// pilot test vectors is played to the vehicle, including duty cycles and
+// frequencies J1772 does not allow, and the vehicle's responses on the
+// pilot are logged.
+//
+// It is only available when the site settings enable it, and a pattern
+// only runs while the contactor is open (Standby or VehicleDetected). The
+// responses are logged, not acted on: a vehicle asking for power during a
+// pattern gets none. A fault ends the pattern at once. Commands wait until
+// the pattern is over, hence the limit on its length.
+
+const MAX_STEPS: usize = 64;
+pub const MAX_PATTERN_LENGTH: Duration = Duration::from_secs(60);
+// Off-spec by up to 10%, which the pilot circuit still follows.
+const MIN_FREQUENCY: f64 = 0.9 * PILOT_FREQUENCY;
+const MAX_FREQUENCY: f64 = 1.1 * PILOT_FREQUENCY;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "vector")]
+pub enum LabVector {
+    // Duty cycle (0.0 to 1.0) at 1 kHz.
+    DutyCycle { duty_cycle: f64 },
+    // Duty cycle at another frequency.
+    OffFrequency { duty_cycle: f64, frequency: f64 },
+    SteadyPlus12,
+    ErrorMinus12,
+}
+
+impl LabVector {
+    pub fn signal(&self) -> PilotSignal {
+        match *self {
+            LabVector::DutyCycle { duty_cycle } => PilotSignal::TestVector {
+                duty_cycle,
+                frequency: PILOT_FREQUENCY,
+            },
+            LabVector::OffFrequency { duty_cycle, frequency } => PilotSignal::TestVector { duty_cycle, frequency },
+            LabVector::SteadyPlus12 => PilotSignal::SteadyPlus12,
+            LabVector::ErrorMinus12 => PilotSignal::ErrorMinus12,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LabStep {
+    #[serde(flatten)]
+    pub vector: LabVector,
+    pub hold_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabPattern {
+    pub steps: Vec<LabStep>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabPatternError {
+    Empty,
+    TooManySteps,
+    TooLong,
+    DutyCycleOutOfRange,
+    FrequencyOutOfRange,
+}
+
+impl LabPattern {
+    pub fn validate(&self) -> Result<(), LabPatternError> {
+        if self.steps.is_empty() {
+            return Err(LabPatternError::Empty);
+        }
+        if self.steps.len() > MAX_STEPS {
+            return Err(LabPatternError::TooManySteps);
+        }
+        if self.length() > MAX_PATTERN_LENGTH {
+            return Err(LabPatternError::TooLong);
+        }
+        for step in &self.steps {
+            let signal = step.vector.signal();
+            if !(0.0..=1.0).contains(&signal.duty_cycle()) {
+                return Err(LabPatternError::DutyCycleOutOfRange);
+            }
+            if !(MIN_FREQUENCY..=MAX_FREQUENCY).contains(&signal.frequency()) {
+                return Err(LabPatternError::FrequencyOutOfRange);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn length(&self) -> Duration {
+        Duration::from_millis(self.steps.iter().map(|step| step.hold_ms).sum())
+    }
+}
+
+// A pilot reading during a pattern, logged for the first reading of each
+// step and whenever the classification changes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LabResponse {
+    pub step: usize,
+    // Since the step started.
+    pub at_ms: u64,
+    pub reading: PilotReading,
+    pub classification: EVSEMachineInput,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LabReport {
+    pub pattern: LabPattern,
+    pub responses: Vec<LabResponse>,
+    // The fault that ended the pattern early.
+    pub aborted_by: Option<EVSEMachineInput>,
+}
+
+// Plays a validated pattern. The caller restores the pilot of its state
+// and handles the fault that ended the pattern, if any.
+pub(crate) fn run_pattern<H: EVSEHardware>(
+    evse: &mut H,
+    pattern: &LabPattern,
+    pilot_rx: &Receiver<PilotReading>,
+    fault_rx: &Receiver<EVSEMachineInput>,
+    classifier: &mut PilotClassifier,
+) -> Result<LabReport, EVSEError> {
+    let mut report = LabReport {
+        pattern: pattern.clone(),
+        responses: Vec::new(),
+        aborted_by: None,
+    };
+    for (index, step) in pattern.steps.iter().enumerate() {
+        evse.set_pilot(step.vector.signal())?;
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(step.hold_ms);
+        let mut last = None;
+        loop {
+            select_biased! {
+                recv(fault_rx) -> fault => {
+                    report.aborted_by = Some(fault.unwrap_or(EVSEMachineInput::PilotInError));
+                    return Ok(report);
+                },
+                recv(pilot_rx) -> reading => {
+                    let Ok(reading) = reading else {
+                        report.aborted_by = Some(EVSEMachineInput::PilotInError);
+                        return Ok(report);
+                    };
+                    let classification = classifier.get_pilot_state(reading.high);
+                    if last != Some(classification) {
+                        report.responses.push(LabResponse {
+                            step: index,
+                            at_ms: start.elapsed().as_millis() as u64,
+                            reading,
+                            classification,
+                        });
+                        last = Some(classification);
+                    }
+                },
+                recv(crossbeam_channel::at(deadline)) -> _ => break,
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_from_json() {
+        let pattern: LabPattern = serde_json::from_str(
+            r#"{"steps": [
+                {"vector": "duty_cycle", "duty_cycle": 0.25, "hold_ms": 2000},
+                {"vector": "off_frequency", "duty_cycle": 0.25, "frequency": 1020.0, "hold_ms": 2000},
+                {"vector": "error_minus12", "hold_ms": 500}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(pattern.validate(), Ok(()));
+        assert_eq!(pattern.length(), Duration::from_millis(4500));
+        assert_eq!(
+            pattern.steps[1].vector.signal(),
+            PilotSignal::TestVector {
+                duty_cycle: 0.25,
+                frequency: 1020.0
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let pattern = |vector, hold_ms| LabPattern {
+            steps: vec![LabStep { vector, hold_ms }],
+        };
+        assert_eq!(LabPattern { steps: vec![] }.validate(), Err(LabPatternError::Empty));
+        assert_eq!(
+            pattern(LabVector::SteadyPlus12, 61_000).validate(),
+            Err(LabPatternError::TooLong)
+        );
+        assert_eq!(
+            pattern(LabVector::DutyCycle { duty_cycle: 1.5 }, 100).validate(),
+            Err(LabPatternError::DutyCycleOutOfRange)
+        );
+        let far_off = LabVector::OffFrequency {
+            duty_cycle: 0.5,
+            frequency: 2000.0,
+        };
+        assert_eq!(pattern(far_off, 100).validate(), Err(LabPatternError::FrequencyOutOfRange));
+    }
+}
@@ -0,0 +1,193 @@
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+
+use crate::evse::{start_machine, EVSEError, EVSEHardware, EVSEMachineInput, EvseCommand, EvseHandle, PilotReading};
+use crate::pilot::PilotSignal;
+
+// End to end latencies of the control loop, from an event reaching the
+// machine to the hardware command it leads to, measured against simulated
+// hardware:
+//
+//   plug-in -> offer            a 9 V pilot reading to the PWM offer
+//   fault -> contactor open     a GFI trip while charging to the contactor
+//                               command
+//   limit change -> offer       an operator limit set while charging to
+//                               the lowered offer
+//
+// The pilot sampling window and the relay are not part of it; the tests
+// below hold the loop to the thresholds, the benches in benches/ track the
+// numbers:
+//
+//   cargo bench -p juicelib --bench control_loop
+
+pub const PLUG_IN_TO_OFFER_MAX: Duration = Duration::from_millis(20);
+pub const FAULT_TO_CONTACTOR_OPEN_MAX: Duration = Duration::from_millis(10);
+pub const LIMIT_CHANGE_TO_OFFER_MAX: Duration = Duration::from_millis(20);
+
+// Gives up on a command that does not come.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Issued {
+    Pilot(PilotSignal),
+    Contactor(bool),
+}
+
+// Hardware that passes its self tests and reports the commands it is given
+// as they are issued.
+struct SimulatedHardware {
+    pilot_rx: Receiver<PilotReading>,
+    fault_rx: Receiver<EVSEMachineInput>,
+    issued: Sender<(Instant, Issued)>,
+}
+
+impl EVSEHardware for SimulatedHardware {
+    fn set_pilot(&mut self, signal: PilotSignal) -> Result<(), EVSEError> {
+        let _ = self.issued.send((Instant::now(), Issued::Pilot(signal)));
+        Ok(())
+    }
+
+    fn set_contactor(&mut self, on: bool) -> Result<(), EVSEError> {
+        let _ = self.issued.send((Instant::now(), Issued::Contactor(on)));
+        Ok(())
+    }
+
+    fn run_gfi_self_test(&mut self) -> Result<(), EVSEError> {
+        Ok(())
+    }
+
+    fn pilot_channel(&self) -> Receiver<PilotReading> {
+        self.pilot_rx.clone()
+    }
+
+    fn fault_channel(&self) -> Receiver<EVSEMachineInput> {
+        self.fault_rx.clone()
+    }
+}
+
+struct Simulation {
+    handle: EvseHandle,
+    pilot_tx: Sender<PilotReading>,
+    fault_tx: Sender<EVSEMachineInput>,
+    issued: Receiver<(Instant, Issued)>,
+}
+
+impl Simulation {
+    fn start() -> Self {
+        let (pilot_tx, pilot_rx) = unbounded();
+        let (fault_tx, fault_rx) = unbounded();
+        let (issued_tx, issued) = unbounded();
+        let handle = start_machine(SimulatedHardware {
+            pilot_rx,
+            fault_rx,
+            issued: issued_tx,
+        });
+        Self {
+            handle,
+            pilot_tx,
+            fault_tx,
+            issued,
+        }
+    }
+
+    fn send_pilot(&self, high: f32) -> Instant {
+        let sent_at = Instant::now();
+        let _ = self.pilot_tx.send(PilotReading {
+            high,
+            low: -12.0,
+            duty_cycle: 0.5,
+            frequency: 1000.0,
+        });
+        sent_at
+    }
+
+    // When the machine issued the first command matching `wanted`.
+    fn issued(&self, wanted: impl Fn(Issued) -> bool) -> Instant {
+        loop {
+            match self.issued.recv_timeout(TIMEOUT) {
+                Ok((at, command)) if wanted(command) => return at,
+                Ok(_) => {}
+                Err(_) => panic!("the machine did not issue the command, in {:?}", self.handle.state()),
+            }
+        }
+    }
+
+    fn offer(&self) -> Instant {
+        self.issued(|command| matches!(command, Issued::Pilot(PilotSignal::OfferAmps(_))))
+    }
+
+    fn stop(self) {
+        self.handle.stop();
+        let _ = self.handle.join();
+    }
+}
+
+pub fn plug_in_to_offer() -> Duration {
+    let simulation = Simulation::start();
+    let plugged_in = simulation.send_pilot(9.0);
+    let latency = simulation.offer().duration_since(plugged_in);
+    simulation.stop();
+    latency
+}
+
+pub fn fault_to_contactor_open() -> Duration {
+    let simulation = Simulation::start();
+    simulation.send_pilot(9.0);
+    simulation.send_pilot(6.0);
+    simulation.issued(|command| command == Issued::Contactor(true));
+    let tripped = Instant::now();
+    let _ = simulation.fault_tx.send(EVSEMachineInput::GFIInterrupted);
+    let latency = simulation
+        .issued(|command| command == Issued::Contactor(false))
+        .duration_since(tripped);
+    simulation.stop();
+    latency
+}
+
+pub fn limit_change_to_offer() -> Duration {
+    let simulation = Simulation::start();
+    simulation.send_pilot(9.0);
+    simulation.send_pilot(6.0);
+    simulation.issued(|command| command == Issued::Contactor(true));
+    let changed = Instant::now();
+    let _ = simulation.handle.send_command(EvseCommand::SetCurrentLimit(10.0));
+    let latency = simulation
+        .issued(|command| command == Issued::Pilot(PilotSignal::OfferAmps(10.0)))
+        .duration_since(changed);
+    simulation.stop();
+    latency
+}
+
+// The median of `runs` measurements, which shrugs off the odd run the
+// scheduler gets in the way of.
+pub fn median(runs: usize, measure: impl Fn() -> Duration) -> Duration {
+    let mut latencies: Vec<Duration> = (0..runs).map(|_| measure()).collect();
+    latencies.sort();
+    latencies[latencies.len() / 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUNS: usize = 9;
+
+    #[test]
+    fn test_plug_in_to_offer() {
+        let latency = median(RUNS, plug_in_to_offer);
+        assert!(latency <= PLUG_IN_TO_OFFER_MAX, "{:?}", latency);
+    }
+
+    #[test]
+    fn test_fault_to_contactor_open() {
+        let latency = median(RUNS, fault_to_contactor_open);
+        assert!(latency <= FAULT_TO_CONTACTOR_OPEN_MAX, "{:?}", latency);
+    }
+
+    #[test]
+    fn test_limit_change_to_offer() {
+        let latency = median(RUNS, limit_change_to_offer);
+        assert!(latency <= LIMIT_CHANGE_TO_OFFER_MAX, "{:?}", latency);
+    }
+}
@@ -1,5 +1,78 @@
 
 pub mod pilot;
+pub mod evse;
+pub mod counters;
+pub mod peripherals;
+pub mod telemetry;
+pub mod scan;
+pub mod power_fail;
+pub mod completion;
+pub mod imbalance;
+pub mod provisioning;
+pub mod rpc;
+pub mod influx;
+pub mod audit;
+pub mod blackbox;
+pub mod breaker;
+pub mod power_quality;
+pub mod guest;
+pub mod backup;
+pub mod store;
+pub mod acquisition;
+pub mod time;
+pub mod control_watchdog;
+pub mod ev_sim;
+pub mod rapi;
+pub mod features;
+pub mod actuator;
+pub mod limits;
+pub mod vehicle_floor;
+pub mod shadow;
+pub mod timing;
+pub mod analytics;
+pub mod lab;
+pub mod guest_token;
+pub mod streams;
+pub mod fault_policy;
+pub mod phases;
+pub mod theme;
+pub mod certificates;
+pub mod pilot_wave;
+pub mod pilot_cable;
+pub mod tenants;
+pub mod brownout;
+pub mod config_file;
+pub mod session_id;
+pub mod hal;
+pub mod channels;
+pub mod replay;
+pub mod solar;
+pub mod interlock;
+pub mod charging_curve;
+pub mod peaks;
+pub mod latency;
+pub mod config_history;
+pub mod pricing;
+pub mod session;
+pub mod readback;
+pub mod config;
+pub mod events;
+pub mod schema;
+pub mod recovery;
+pub mod clock;
+pub mod mains;
+pub mod connector_temp;
+pub mod offer_pacing;
+pub mod journal;
+pub mod nuisance;
+pub mod load_test;
+pub mod metrics;
+pub mod interruption;
+pub mod machine_state;
+pub mod gfi_retry;
+pub mod trim;
+#[cfg(feature = "mock-hw")]
+pub mod mock_hw;
 
 
 // include the private adc module
@@ -8,3 +81,6 @@ mod adc;
 
 // include the private mcp module
 mod mcp;
+
+// Lock ordering for the peripherals.
+mod lock_order;
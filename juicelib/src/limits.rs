@@ -0,0 +1,244 @@
+use serde::{Deserialize, Serialize};
+
+use crate::vehicle_floor::MIN_FLOOR;
+
+// The chain of current limits. Every subsystem that wants to limit the
+// offer registers its cap under its own limiter; the vehicle is offered the
+// lowest cap, and the limiter that sets it is reported as binding so it is
+// clear why a vehicle gets less than it could.
+//
+// The load manager, the solar mode and the trim only ask for less current
+// to save some or to be precise; below the vehicle floor the vehicle would
+// stop charging rather than draw less, so their caps are raised to the
+// floor. A cap of 0 is meant to stop the charge and is not raised. The
+// other limits protect something and always hold.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Limiter {
+    // The highest offer the hardware makes. Always set.
+    Hardware,
+    // The current capacity coded by the proximity resistor of the cable.
+    CablePp,
+    // The maximum current of the installation.
+    ConfigMax,
+    // The limit set over the control interfaces.
+    Operator,
+    // The current cap of a guest token, and 0 once its energy is used up.
+    Guest,
+    // The current cap of a tenant, and 0 once the monthly energy is used.
+    Tenant,
+    // The breaker thermal model.
+    Breaker,
+    LoadManager,
+    Solar,
+    // 0 while the energy price pauses the charge, see pricing.rs.
+    Price,
+    DemandResponse,
+    Thermal,
+    // The reduced current, or 0, while the grid operator asserts the
+    // control contact (§14a EnWG).
+    ControlContact,
+    // The offer less what the vehicle draws above it, see trim.rs.
+    Trim,
+}
+
+impl Limiter {
+    pub const ALL: [Limiter; 14] = [
+        Limiter::Hardware,
+        Limiter::CablePp,
+        Limiter::ConfigMax,
+        Limiter::Operator,
+        Limiter::Guest,
+        Limiter::Tenant,
+        Limiter::Breaker,
+        Limiter::LoadManager,
+        Limiter::Solar,
+        Limiter::Price,
+        Limiter::DemandResponse,
+        Limiter::Thermal,
+        Limiter::ControlContact,
+        Limiter::Trim,
+    ];
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    fn yields_to_floor(&self) -> bool {
+        matches!(self, Limiter::LoadManager | Limiter::Solar | Limiter::Trim)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LimiterCap {
+    pub limiter: Limiter,
+    pub amps: f64,
+}
+
+// The offer and how it came about, for the control API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LimitsStatus {
+    pub offer: f64,
+    pub binding: Limiter,
+    // The minimum current of the vehicle.
+    pub vehicle_floor: f64,
+    // The caps that are set, in chain order.
+    pub caps: Vec<LimiterCap>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimiterChain {
+    caps: [Option<f64>; Limiter::ALL.len()],
+    floor: f64,
+}
+
+impl LimiterChain {
+    pub fn new(hardware_max: f64) -> Self {
+        let mut caps = [None; Limiter::ALL.len()];
+        caps[Limiter::Hardware.index()] = Some(hardware_max);
+        Self { caps, floor: MIN_FLOOR }
+    }
+
+    // None removes the cap. The hardware cap cannot be removed.
+    pub fn set(&mut self, limiter: Limiter, amps: Option<f64>) {
+        if limiter == Limiter::Hardware && amps.is_none() {
+            return;
+        }
+        self.caps[limiter.index()] = amps;
+    }
+
+    pub fn cap(&self, limiter: Limiter) -> Option<f64> {
+        self.caps[limiter.index()]
+    }
+
+    pub fn set_floor(&mut self, floor: f64) {
+        self.floor = floor;
+    }
+
+    pub fn hardware_max(&self) -> f64 {
+        self.cap(Limiter::Hardware).expect("the hardware cap is always set")
+    }
+
+    // The lowest cap and its limiter. Of equal caps, the one first in the
+    // chain binds.
+    fn lowest(&self) -> LimiterCap {
+        self.caps()
+            .map(|cap| {
+                if cap.limiter.yields_to_floor() && cap.amps > 0.0 {
+                    LimiterCap {
+                        amps: cap.amps.max(self.floor),
+                        ..cap
+                    }
+                } else {
+                    cap
+                }
+            })
+            .reduce(|lowest, cap| if cap.amps < lowest.amps { cap } else { lowest })
+            .expect("the hardware cap is always set")
+    }
+
+    fn caps(&self) -> impl Iterator<Item = LimiterCap> + '_ {
+        Limiter::ALL
+            .iter()
+            .filter_map(|&limiter| self.cap(limiter).map(|amps| LimiterCap { limiter, amps }))
+    }
+
+    // What the vehicle is offered.
+    pub fn offer(&self) -> f64 {
+        self.lowest().amps
+    }
+
+    pub fn binding(&self) -> Limiter {
+        self.lowest().limiter
+    }
+
+    pub fn status(&self) -> LimitsStatus {
+        let lowest = self.lowest();
+        LimitsStatus {
+            offer: lowest.amps,
+            binding: lowest.limiter,
+            vehicle_floor: self.floor,
+            caps: self.caps().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lowest_cap_binds() {
+        let mut chain = LimiterChain::new(32.0);
+        assert_eq!(chain.offer(), 32.0);
+        assert_eq!(chain.binding(), Limiter::Hardware);
+
+        chain.set(Limiter::Thermal, Some(10.0));
+        chain.set(Limiter::Breaker, Some(16.0));
+        chain.set(Limiter::CablePp, Some(20.0));
+        assert_eq!(chain.offer(), 10.0);
+        assert_eq!(chain.binding(), Limiter::Thermal);
+
+        chain.set(Limiter::Thermal, None);
+        assert_eq!(chain.binding(), Limiter::Breaker);
+
+        // A tie goes to the limiter first in the chain.
+        chain.set(Limiter::Thermal, Some(16.0));
+        assert_eq!(chain.binding(), Limiter::Breaker);
+
+        assert_eq!(
+            chain.status(),
+            LimitsStatus {
+                offer: 16.0,
+                binding: Limiter::Breaker,
+                vehicle_floor: MIN_FLOOR,
+                caps: vec![
+                    LimiterCap {
+                        limiter: Limiter::Hardware,
+                        amps: 32.0
+                    },
+                    LimiterCap {
+                        limiter: Limiter::CablePp,
+                        amps: 20.0
+                    },
+                    LimiterCap {
+                        limiter: Limiter::Breaker,
+                        amps: 16.0
+                    },
+                    LimiterCap {
+                        limiter: Limiter::Thermal,
+                        amps: 16.0
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_vehicle_floor() {
+        let mut chain = LimiterChain::new(32.0);
+        chain.set(Limiter::Solar, Some(4.0));
+        assert_eq!(chain.offer(), 6.0);
+        chain.set_floor(10.0);
+        assert_eq!(chain.offer(), 10.0);
+        assert_eq!(chain.binding(), Limiter::Solar);
+        chain.set(Limiter::Solar, Some(0.0));
+        assert_eq!(chain.offer(), 0.0);
+        chain.set(Limiter::Solar, Some(4.0));
+
+        // Protective limits are not raised.
+        chain.set(Limiter::Breaker, Some(8.0));
+        assert_eq!(chain.offer(), 8.0);
+        assert_eq!(chain.binding(), Limiter::Breaker);
+    }
+
+    #[test]
+    fn test_hardware_cap_stays() {
+        let mut chain = LimiterChain::new(32.0);
+        chain.set(Limiter::Hardware, None);
+        assert_eq!(chain.cap(Limiter::Hardware), Some(32.0));
+        chain.set(Limiter::Hardware, Some(24.0));
+        assert_eq!(chain.offer(), 24.0);
+    }
+}
@@ -0,0 +1,310 @@
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::Receiver;
+use serde::{Deserialize, Serialize};
+
+use crate::evse::{EVSEHardware, EVSEMachineInput, PilotClassifier, PilotReading};
+use crate::pilot::{PilotSignal, MIN_OFFER_AMPS};
+use crate::timing::STOP_RESPONSE;
+
+// Commissioning test of the rated current of the installation. With a test
+// load or a vehicle plugged in, the offer goes up from 6 A to the rated
+// current in steps; at each step, once the load has settled, the current
+// drawn and the mains voltage are measured. The test passes if the load
+// never draws more than the offer, draws close to the rated current at the
+// last step, and the voltage drops by no more than MAX_DROOP_PERCENT from
+// the voltage without load. A higher droop points at undersized or loose
+// wiring, which heats up under the continuous load of a charge.
+//
+// It needs the current sense and the mains voltage sense. A fault ends the
+// test at once, with the contactor open.
+
+// 5% for the feeder and the branch circuit together, NEC 210.19 and 215.2.
+pub const MAX_DROOP_PERCENT: f64 = 5.0;
+// What the load may draw above the offer, for the tolerance of the sense.
+const OVERCURRENT_MARGIN: f64 = 1.0;
+// Share of the rated current the load draws at the last step.
+const MIN_RATED_SHARE: f64 = 0.9;
+// How long the load has to ask for power once it is offered.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const READING_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadTestConfig {
+    pub rated_amps: f64,
+    pub step_amps: f64,
+    // How long each step is held before it is measured.
+    pub settle: Duration,
+}
+
+impl LoadTestConfig {
+    pub fn new(rated_amps: f64) -> Self {
+        Self {
+            rated_amps,
+            step_amps: 4.0,
+            settle: Duration::from_secs(15),
+        }
+    }
+
+    // From 6 A up to the rated current, which is always the last.
+    fn offers(&self) -> Vec<f64> {
+        let mut offers = Vec::new();
+        let mut amps = MIN_OFFER_AMPS;
+        while amps < self.rated_amps {
+            offers.push(amps);
+            amps += self.step_amps.max(1.0);
+        }
+        offers.push(self.rated_amps);
+        offers
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoadTestStep {
+    pub offered_amps: f64,
+    pub measured_amps: Option<f64>,
+    pub volts: Option<f64>,
+    // From the voltage without load.
+    pub droop_percent: Option<f64>,
+    pub passed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoadTestReport {
+    pub rated_amps: f64,
+    // Before the contactor closed.
+    pub no_load_volts: Option<f64>,
+    pub steps: Vec<LoadTestStep>,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl LoadTestReport {
+    fn failed(config: &LoadTestConfig, no_load_volts: Option<f64>, steps: Vec<LoadTestStep>, detail: &str) -> Self {
+        Self {
+            rated_amps: config.rated_amps,
+            no_load_volts,
+            steps,
+            passed: false,
+            detail: detail.to_string(),
+        }
+    }
+}
+
+fn measure(offered_amps: f64, measured_amps: Option<f64>, volts: Option<f64>, no_load_volts: f64) -> LoadTestStep {
+    let droop_percent = volts.map(|volts| (no_load_volts - volts) / no_load_volts * 100.0);
+    let passed = measured_amps.is_some_and(|amps| amps <= offered_amps + OVERCURRENT_MARGIN)
+        && droop_percent.is_some_and(|droop| droop <= MAX_DROOP_PERCENT);
+    LoadTestStep {
+        offered_amps,
+        measured_amps,
+        volts,
+        droop_percent,
+        passed,
+    }
+}
+
+// The classification of the next reading after the ones already queued.
+fn next_input(pilot_rx: &Receiver<PilotReading>, classifier: &mut PilotClassifier) -> Option<EVSEMachineInput> {
+    while pilot_rx.try_recv().is_ok() {}
+    pilot_rx
+        .recv_timeout(READING_TIMEOUT)
+        .ok()
+        .map(|reading| classifier.get_pilot_state(reading.high))
+}
+
+pub fn run_load_test<H: EVSEHardware>(evse: &mut H, config: LoadTestConfig) -> LoadTestReport {
+    let report = ramp(evse, &config);
+    // Without an offer the load stops drawing, then the contactor opens:
+    // once the load is back in state B, or after the stop response time.
+    let _ = evse.set_pilot(PilotSignal::SteadyPlus12);
+    let pilot_rx = evse.pilot_channel();
+    let mut classifier = PilotClassifier::default();
+    let stopped_by = Instant::now() + STOP_RESPONSE.default;
+    while Instant::now() < stopped_by && next_input(&pilot_rx, &mut classifier) != Some(EVSEMachineInput::PilotIs9V) {}
+    if let Err(error) = evse.set_contactor(false) {
+        eprintln!("Failed to open the contactor after the load test: {:?}", error);
+    }
+    report
+}
+
+fn ramp<H: EVSEHardware>(evse: &mut H, config: &LoadTestConfig) -> LoadTestReport {
+    let pilot_rx = evse.pilot_channel();
+    let fault_rx = evse.fault_channel();
+    let mut classifier = PilotClassifier::default();
+
+    if evse.set_pilot(PilotSignal::SteadyPlus12).is_err()
+        || next_input(&pilot_rx, &mut classifier) != Some(EVSEMachineInput::PilotIs9V)
+    {
+        return LoadTestReport::failed(config, None, Vec::new(), "no test load or vehicle plugged in");
+    }
+    let Some(no_load_volts) = evse.mains_volts() else {
+        return LoadTestReport::failed(config, None, Vec::new(), "no mains voltage sense");
+    };
+
+    let offers = config.offers();
+    if evse.set_pilot(PilotSignal::OfferAmps(offers[0])).is_err() {
+        return LoadTestReport::failed(config, Some(no_load_volts), Vec::new(), "pilot not set");
+    }
+    let asked_by = Instant::now() + REQUEST_TIMEOUT;
+    while next_input(&pilot_rx, &mut classifier) != Some(EVSEMachineInput::PilotIs6V) {
+        if Instant::now() >= asked_by {
+            return LoadTestReport::failed(config, Some(no_load_volts), Vec::new(), "the load did not ask for power");
+        }
+    }
+    if evse.set_contactor(true).is_err() {
+        return LoadTestReport::failed(config, Some(no_load_volts), Vec::new(), "contactor not closed");
+    }
+
+    let mut steps = Vec::new();
+    for &offer in &offers {
+        if evse.set_pilot(PilotSignal::OfferAmps(offer)).is_err() {
+            return LoadTestReport::failed(config, Some(no_load_volts), steps, "pilot not set");
+        }
+        if let Ok(fault) = fault_rx.recv_timeout(config.settle) {
+            let detail = format!("fault {:?} at {} A", fault, offer);
+            return LoadTestReport::failed(config, Some(no_load_volts), steps, &detail);
+        }
+        if next_input(&pilot_rx, &mut classifier) != Some(EVSEMachineInput::PilotIs6V) {
+            let detail = format!("the load stopped asking for power at {} A", offer);
+            return LoadTestReport::failed(config, Some(no_load_volts), steps, &detail);
+        }
+        steps.push(measure(offer, evse.current_amps(), evse.mains_volts(), no_load_volts));
+    }
+
+    let last = steps.last().and_then(|step| step.measured_amps);
+    let reached_rated = last.is_some_and(|amps| amps >= config.rated_amps * MIN_RATED_SHARE);
+    let passed = reached_rated && steps.iter().all(|step| step.passed);
+    let detail = match last {
+        _ if passed => "ok".to_string(),
+        None => "no current sense".to_string(),
+        Some(amps) if !reached_rated => format!("{:.1} A drawn at {} A rated", amps, config.rated_amps),
+        Some(_) => "overcurrent or voltage droop".to_string(),
+    };
+    LoadTestReport {
+        rated_amps: config.rated_amps,
+        no_load_volts: Some(no_load_volts),
+        steps,
+        passed,
+        detail,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evse::EVSEError;
+    use crossbeam_channel::{unbounded, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // A resistive test load: asks for power at once and draws the offer,
+    // with the given resistance of the wiring in front of it.
+    struct TestLoad {
+        pilot: Arc<Mutex<PilotSignal>>,
+        contactor: bool,
+        wiring_ohms: f64,
+        pilot_rx: Receiver<PilotReading>,
+        fault_rx: Receiver<EVSEMachineInput>,
+        _fault_tx: Sender<EVSEMachineInput>,
+    }
+
+    impl TestLoad {
+        fn new(wiring_ohms: f64) -> Self {
+            let pilot = Arc::new(Mutex::new(PilotSignal::ErrorMinus12));
+            let (pilot_tx, pilot_rx) = unbounded();
+            let (fault_tx, fault_rx) = unbounded();
+            let signal = pilot.clone();
+            thread::spawn(move || loop {
+                let high = match *signal.lock().unwrap() {
+                    PilotSignal::OfferAmps(_) => 6.0,
+                    _ => 9.0,
+                };
+                let reading = PilotReading {
+                    high,
+                    low: -12.0,
+                    duty_cycle: 0.5,
+                    frequency: 1000.0,
+                };
+                if pilot_tx.send(reading).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(5));
+            });
+            Self {
+                pilot,
+                contactor: false,
+                wiring_ohms,
+                pilot_rx,
+                fault_rx,
+                _fault_tx: fault_tx,
+            }
+        }
+    }
+
+    impl EVSEHardware for TestLoad {
+        fn set_pilot(&mut self, signal: PilotSignal) -> Result<(), EVSEError> {
+            *self.pilot.lock().unwrap() = signal;
+            Ok(())
+        }
+
+        fn set_contactor(&mut self, on: bool) -> Result<(), EVSEError> {
+            self.contactor = on;
+            Ok(())
+        }
+
+        fn run_gfi_self_test(&mut self) -> Result<(), EVSEError> {
+            Ok(())
+        }
+
+        fn pilot_channel(&self) -> Receiver<PilotReading> {
+            self.pilot_rx.clone()
+        }
+
+        fn fault_channel(&self) -> Receiver<EVSEMachineInput> {
+            self.fault_rx.clone()
+        }
+
+        fn current_amps(&self) -> Option<f64> {
+            match (*self.pilot.lock().unwrap(), self.contactor) {
+                (PilotSignal::OfferAmps(amps), true) => Some(amps),
+                _ => Some(0.0),
+            }
+        }
+
+        fn mains_volts(&self) -> Option<f64> {
+            self.current_amps().map(|amps| 240.0 - amps * self.wiring_ohms)
+        }
+    }
+
+    fn config() -> LoadTestConfig {
+        LoadTestConfig {
+            settle: Duration::from_millis(20),
+            ..LoadTestConfig::new(16.0)
+        }
+    }
+
+    #[test]
+    fn test_offers() {
+        assert_eq!(config().offers(), vec![6.0, 10.0, 14.0, 16.0]);
+        assert_eq!(LoadTestConfig::new(6.0).offers(), vec![6.0]);
+    }
+
+    #[test]
+    fn test_load_test() {
+        let mut load = TestLoad::new(0.1);
+        let report = run_load_test(&mut load, config());
+        assert!(report.passed, "{:?}", report);
+        assert_eq!(report.no_load_volts, Some(240.0));
+        assert_eq!(report.steps.len(), 4);
+        assert_eq!(report.steps[3].measured_amps, Some(16.0));
+        assert!(!load.contactor);
+
+        // Droops more than 5% from 14 A on.
+        let report = run_load_test(&mut TestLoad::new(0.9), config());
+        assert!(!report.passed);
+        assert!(report.steps[1].passed);
+        assert!(!report.steps[2].passed);
+        assert_eq!(report.detail, "overcurrent or voltage droop");
+    }
+}
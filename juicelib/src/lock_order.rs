@@ -0,0 +1,129 @@
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+// Mutex with a rank in a global lock order. A thread holding locks may only
+// take one of a higher rank than all it holds, which makes lock order
+// deadlocks impossible. Taking a lock out of order, or the same lock twice,
+// panics at once instead of deadlocking some day in the field.
+//
+// The locks guard hardware handles that hold no invariants of their own, so
+// a lock poisoned by a panicking thread is taken over rather than
+// propagating the panic: the contactor must still open.
+
+thread_local! {
+    // Ranks of the locks this thread holds, in the order they were taken.
+    static HELD: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+pub(crate) struct RankedMutex<T> {
+    rank: u8,
+    mutex: Mutex<T>,
+}
+
+impl<T> RankedMutex<T> {
+    pub(crate) fn new(rank: u8, value: T) -> Self {
+        Self {
+            rank,
+            mutex: Mutex::new(value),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> RankedGuard<'_, T> {
+        HELD.with(|held| {
+            if let Some(&highest) = held.borrow().iter().max() {
+                assert!(
+                    self.rank > highest,
+                    "lock order violation: rank {} taken while holding rank {}",
+                    self.rank,
+                    highest
+                );
+            }
+        });
+        let guard = self.mutex.lock().unwrap_or_else(PoisonError::into_inner);
+        HELD.with(|held| held.borrow_mut().push(self.rank));
+        RankedGuard { rank: self.rank, guard }
+    }
+}
+
+pub(crate) struct RankedGuard<'a, T> {
+    rank: u8,
+    guard: MutexGuard<'a, T>,
+}
+
+impl<T> Deref for RankedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for RankedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for RankedGuard<'_, T> {
+    fn drop(&mut self) {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(position) = held.iter().rposition(|&rank| rank == self.rank) {
+                held.remove(position);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_locks_in_order() {
+        let low = RankedMutex::new(1, 0);
+        let high = RankedMutex::new(2, 0);
+        {
+            let mut a = low.lock();
+            let mut b = high.lock();
+            *a += 1;
+            *b += 1;
+        }
+        // Released locks no longer count.
+        let b = high.lock();
+        drop(b);
+        assert_eq!(*low.lock(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order violation: rank 1 taken while holding rank 2")]
+    fn test_out_of_order_panics() {
+        let low = RankedMutex::new(1, ());
+        let high = RankedMutex::new(2, ());
+        let _high = high.lock();
+        let _low = low.lock();
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order violation")]
+    fn test_relock_panics() {
+        let mutex = RankedMutex::new(1, ());
+        let _guard = mutex.lock();
+        let _again = mutex.lock();
+    }
+
+    #[test]
+    fn test_poisoned_lock_is_taken_over() {
+        let mutex = Arc::new(RankedMutex::new(1, 5));
+        let poisoner = mutex.clone();
+        let _ = thread::spawn(move || {
+            let _guard = poisoner.lock();
+            panic!("while holding the lock");
+        })
+        .join();
+        assert_eq!(*mutex.lock(), 5);
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::evse::{EVSEMachineInput, EVSEMachineState};
+
+// The state of the machine across restarts. The machine writes the state
+// it is in, what latched a lockout and its error counters to a small file
+// whenever the state changes. After a power cycle a station that had failed
+// for a GFI fault, or was in a tamper lockout, comes back in that lockout
+// instead of re-arming because its self test happens to pass; the lockout
+// still needs the admin reset. One that waited out a GFI trip waits the
+// whole cooldown again. The counters go on where they left off, so
+// a vehicle that keeps failing the station does not get its retries back
+// from a power cycle either.
+//
+// The file is JSON, written to a temporary file first and renamed. A file
+// that cannot be read is reported and the machine starts afresh.
+
+pub const DEFAULT_STATE_PATH: &str = "/var/lib/juiced/machine-state.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ErrorCounters {
+    // The latched faults by the state.
+    pub faults: BTreeMap<EVSEMachineState, u64>,
+    pub gfi_trips: u64,
+    // Resettable errors recovered in a row, see recovery.rs.
+    pub recovery_retries: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistedState {
+    pub state: EVSEMachineState,
+    // What latched the lockout the machine is in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lockout_cause: Option<EVSEMachineInput>,
+    #[serde(default)]
+    pub counters: ErrorCounters,
+    pub unix_time: u64,
+}
+
+// A GFI that tripped, or failed its self test.
+fn is_gfi_cause(cause: Option<EVSEMachineInput>) -> bool {
+    matches!(
+        cause,
+        Some(EVSEMachineInput::GFIInterrupted | EVSEMachineInput::SelfTestFailed)
+    )
+}
+
+impl PersistedState {
+    // None without a file.
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(serde_json::to_string(self)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // The lockout the station comes back in after the restart, if any.
+    pub fn restored_lockout(&self) -> Option<EVSEMachineState> {
+        match self.state {
+            EVSEMachineState::FailedStation if is_gfi_cause(self.lockout_cause) => Some(self.state),
+            EVSEMachineState::GFITripped | EVSEMachineState::TamperLockout => Some(self.state),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("juicelib-machine-state-{}", std::process::id()));
+        let path = dir.join("machine-state.json");
+        assert_eq!(PersistedState::load(&path)?, None);
+
+        let state = PersistedState {
+            state: EVSEMachineState::FailedStation,
+            lockout_cause: Some(EVSEMachineInput::GFIInterrupted),
+            counters: ErrorCounters {
+                faults: BTreeMap::from([(EVSEMachineState::FailedStation, 2)]),
+                gfi_trips: 1,
+                recovery_retries: 0,
+            },
+            unix_time: 1_792_137_600,
+        };
+        state.save(&path)?;
+        assert_eq!(PersistedState::load(&path)?, Some(state));
+
+        fs::write(&path, "{\"state\": \"Sleeping\"}")?;
+        assert_eq!(PersistedState::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn test_restored_lockout() {
+        let persisted = |state, lockout_cause| PersistedState {
+            state,
+            lockout_cause,
+            counters: ErrorCounters::default(),
+            unix_time: 0,
+        };
+        use EVSEMachineInput::*;
+        use EVSEMachineState::*;
+        assert_eq!(
+            persisted(FailedStation, Some(GFIInterrupted)).restored_lockout(),
+            Some(FailedStation)
+        );
+        assert_eq!(
+            persisted(FailedStation, Some(SelfTestFailed)).restored_lockout(),
+            Some(FailedStation)
+        );
+        // Failed for another reason, the self test decides.
+        assert_eq!(persisted(FailedStation, Some(PilotInError)).restored_lockout(), None);
+        assert_eq!(persisted(TamperLockout, None).restored_lockout(), Some(TamperLockout));
+        assert_eq!(persisted(GFITripped, Some(GFIInterrupted)).restored_lockout(), Some(GFITripped));
+        assert_eq!(persisted(Charging, None).restored_lockout(), None);
+    }
+}
@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+// RMS voltage, peak and frequency of a sampled mains waveform, over whole
+// cycles. A window of fixed length holds a fractional cycle unless the
+// mains runs at exactly the nominal frequency: at 50.2 Hz, 100 ms are
+// 5.02 cycles, and the partial cycle makes the RMS beat with the phase the
+// window starts at. Here the window is cut at the first and the last
+// rising zero crossing of the samples, so it holds an integer number of
+// cycles of the measured frequency, whatever it is.
+//
+// The zero crossings are interpolated between the samples. A crossing
+// only counts once the waveform has been below -ZERO_CROSSING_HYSTERESIS
+// of its peak since the last one, so noise around zero does not add
+// cycles.
+//
+// The samples are of the instantaneous voltage, with the DC offset of the
+// input stage removed. The acquisition thread samples the mains input once
+// a second, see EVSEHardwareImpl::sample_pilot.
+
+const ZERO_CROSSING_HYSTERESIS: f32 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MainsMeasurement {
+    pub frequency_hz: f64,
+    pub rms_volts: f64,
+    pub peak_volts: f64,
+    // How many whole cycles the measurement covers.
+    pub cycles: u32,
+}
+
+// The rising zero crossings, in seconds from the first sample.
+fn rising_zero_crossings(samples: &[(Duration, f32)]) -> Vec<f64> {
+    let peak = samples.iter().map(|(_, volts)| volts.abs()).fold(0.0, f32::max);
+    let arm_below = -peak * ZERO_CROSSING_HYSTERESIS;
+    let mut armed = false;
+    let mut crossings = Vec::new();
+    for pair in samples.windows(2) {
+        let ((t0, v0), (t1, v1)) = (pair[0], pair[1]);
+        if v0 < arm_below {
+            armed = true;
+        }
+        if armed && v0 < 0.0 && v1 >= 0.0 {
+            let (t0, t1) = (t0.as_secs_f64(), t1.as_secs_f64());
+            crossings.push(t0 + f64::from(-v0) / f64::from(v1 - v0) * (t1 - t0));
+            armed = false;
+        }
+    }
+    crossings
+}
+
+// None with less than one whole cycle in the samples, which are in the
+// order they were taken.
+pub fn measure_cycles(samples: &[(Duration, f32)]) -> Option<MainsMeasurement> {
+    let crossings = rising_zero_crossings(samples);
+    let (&first, &last) = (crossings.first()?, crossings.last()?);
+    let cycles = crossings.len() as u32 - 1;
+    if cycles == 0 {
+        return None;
+    }
+
+    // The samples within the cycles, starting and ending at the crossings.
+    let mut points = vec![(first, 0.0)];
+    points.extend(
+        samples
+            .iter()
+            .map(|(at, volts)| (at.as_secs_f64(), f64::from(*volts)))
+            .filter(|(at, _)| *at > first && *at < last),
+    );
+    points.push((last, 0.0));
+    let squares: f64 = points
+        .windows(2)
+        .map(|pair| (pair[1].0 - pair[0].0) * (pair[0].1.powi(2) + pair[1].1.powi(2)) / 2.0)
+        .sum();
+    let span = last - first;
+    Some(MainsMeasurement {
+        frequency_hz: f64::from(cycles) / span,
+        rms_volts: (squares / span).sqrt(),
+        peak_volts: points.iter().map(|(_, volts)| volts.abs()).fold(0.0, f64::max),
+        cycles,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    // 230 V RMS at `hz`, sampled at 5 kHz for `window`, starting at
+    // `phase` radians.
+    fn sine(hz: f64, phase: f64, window: Duration) -> Vec<(Duration, f32)> {
+        let count = (window.as_secs_f64() * 5000.0) as u32;
+        (0..count)
+            .map(|i| {
+                let t = f64::from(i) / 5000.0;
+                let volts = 230.0 * 2f64.sqrt() * (2.0 * PI * hz * t + phase).sin();
+                (Duration::from_secs_f64(t), volts as f32)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_locked_to_whole_cycles() {
+        // 2.26 cycles: the RMS over the whole window beats by several volts
+        // with where it starts.
+        let window = Duration::from_millis(45);
+        let mut worst_naive: f64 = 0.0;
+        for phase in [0.0, 1.0, 2.0, 3.0, 4.0, 5.0] {
+            let samples = sine(50.2, phase, window);
+            let measured = measure_cycles(&samples).unwrap();
+            assert!(measured.cycles >= 1, "{:?}", measured);
+            assert!((measured.frequency_hz - 50.2).abs() < 0.05, "{:?}", measured);
+            assert!((measured.rms_volts - 230.0).abs() < 0.2, "{:?}", measured);
+            assert!((measured.peak_volts - 325.3).abs() < 0.5, "{:?}", measured);
+
+            let squares: f64 = samples.iter().map(|(_, volts)| f64::from(*volts).powi(2)).sum();
+            worst_naive = worst_naive.max(((squares / samples.len() as f64).sqrt() - 230.0).abs());
+        }
+        assert!(worst_naive > 3.0, "{}", worst_naive);
+
+        // Noise around zero adds no cycles.
+        let noisy: Vec<(Duration, f32)> = sine(50.0, 0.0, Duration::from_millis(100))
+            .into_iter()
+            .enumerate()
+            .map(|(i, (at, volts))| (at, volts + if i % 2 == 0 { 3.0 } else { -3.0 }))
+            .collect();
+        assert_eq!(measure_cycles(&noisy).unwrap().cycles, 3);
+        assert_eq!(measure_cycles(&sine(50.0, 0.0, Duration::from_millis(15))), None);
+    }
+}
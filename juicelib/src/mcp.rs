@@ -0,0 +1,85 @@
+use mcp3xxx_eh::channel::{decode, Input, InvalidChannel};
+pub use mcp3xxx_eh::channel::Mcp3004Channel;
+
+use crate::hal::spi::{Error as SpiError, Spi};
+
+use crate::scan::ChannelReader;
+
+// Minimal driver for the MCP3004 ADC on the EVSE Pi Hat, on the SPI of the
+// hal. The channels, the command and the decoding of the result come from
+// mcp3xxx-eh; a read is one three byte transfer.
+
+#[derive(Debug)]
+pub enum LibError {
+    Spi(SpiError),
+    InvalidChannel(u8),
+}
+
+impl From<SpiError> for LibError {
+    fn from(error: SpiError) -> Self {
+        LibError::Spi(error)
+    }
+}
+
+impl From<InvalidChannel> for LibError {
+    fn from(InvalidChannel(channel): InvalidChannel) -> Self {
+        LibError::InvalidChannel(channel)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reading(u16);
+
+impl Reading {
+    // Raw conversion result, 0..=1023.
+    pub fn value(&self) -> u16 {
+        self.0
+    }
+}
+
+pub struct Mcp3004 {
+    spi: Spi,
+}
+
+impl Mcp3004 {
+    pub fn new(spi: Spi) -> Result<Self, LibError> {
+        Ok(Self { spi })
+    }
+
+    pub fn single_ended_read(&mut self, channel: Mcp3004Channel) -> Result<Reading, LibError> {
+        let tx_buf = channel.command();
+        let mut rx_buf = [0u8; 3];
+        self.spi.transfer(&mut rx_buf, &tx_buf)?;
+        Ok(Reading(decode(&rx_buf)))
+    }
+}
+
+impl ChannelReader for Mcp3004 {
+    type Error = LibError;
+
+    // Scan steps name channels by number, so they are checked here.
+    fn read_channel(&mut self, channel: u8) -> Result<u16, LibError> {
+        Ok(self.single_ended_read(Mcp3004Channel::try_from(channel)?)?.value())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        assert_eq!(Mcp3004Channel::Ch0.command(), [0x01, 0x80, 0x00]);
+        assert_eq!(Mcp3004Channel::Ch2.command(), [0x01, 0xa0, 0x00]);
+        assert_eq!(Mcp3004Channel::try_from(3).unwrap(), Mcp3004Channel::Ch3);
+        let error = Mcp3004Channel::try_from(4).map_err(LibError::from);
+        assert!(matches!(error, Err(LibError::InvalidChannel(4))));
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Reading(decode(&[0xff, 0xfb, 0xff])).value(), 1023);
+        assert_eq!(Reading(decode(&[0x00, 0x02, 0x00])).value(), 512);
+        assert_eq!(Reading(decode(&[0x00, 0x00, 0xb8])).value(), 184);
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::blackbox::is_latched_fault;
+use crate::evse::EVSEMachineState;
+
+// Reliability metrics of the machine since it started: how long it spent in
+// each state, how often it went from one state to another, the faults it
+// latched and how long the GFI self tests took. A station that spends more
+// and more time in StartCharging, or goes from Charging to ResetableError
+// every other session, shows it here long before anyone complains. They
+// are handed out by get_metrics on the control socket and start over with
+// the machine.
+
+#[derive(Debug, Clone, Default)]
+pub struct StateMetrics {
+    time_in_state: BTreeMap<EVSEMachineState, Duration>,
+    transitions: BTreeMap<(EVSEMachineState, EVSEMachineState), u64>,
+    faults: BTreeMap<EVSEMachineState, u64>,
+    self_tests: Vec<(Duration, bool)>,
+    // The state the machine is in, and since when.
+    current: Option<(EVSEMachineState, Instant)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct TransitionCount {
+    pub from: EVSEMachineState,
+    pub to: EVSEMachineState,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, JsonSchema)]
+pub struct SelfTestMetrics {
+    pub runs: u64,
+    pub failed: u64,
+    pub mean_ms: Option<u64>,
+    pub max_ms: Option<u64>,
+    pub last_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct MetricsReport {
+    // Including the time in the current state so far.
+    pub time_in_state_ms: BTreeMap<EVSEMachineState, u64>,
+    pub transitions: Vec<TransitionCount>,
+    // By the fault state latched.
+    pub faults: BTreeMap<EVSEMachineState, u64>,
+    pub self_tests: SelfTestMetrics,
+}
+
+impl StateMetrics {
+    pub fn state_entered(&mut self, state: EVSEMachineState, now: Instant) {
+        if let Some((previous, since)) = self.current {
+            if previous == state {
+                return;
+            }
+            *self.time_in_state.entry(previous).or_default() += now.saturating_duration_since(since);
+            *self.transitions.entry((previous, state)).or_default() += 1;
+        }
+        if is_latched_fault(state) {
+            *self.faults.entry(state).or_default() += 1;
+        }
+        self.current = Some((state, now));
+    }
+
+    pub fn self_test(&mut self, took: Duration, passed: bool) {
+        self.self_tests.push((took, passed));
+    }
+
+    pub fn report(&self, now: Instant) -> MetricsReport {
+        let mut time_in_state = self.time_in_state.clone();
+        if let Some((state, since)) = self.current {
+            *time_in_state.entry(state).or_default() += now.saturating_duration_since(since);
+        }
+        let durations = || self.self_tests.iter().map(|(took, _)| took.as_millis() as u64);
+        let runs = self.self_tests.len() as u64;
+        MetricsReport {
+            time_in_state_ms: time_in_state
+                .into_iter()
+                .map(|(state, time)| (state, time.as_millis() as u64))
+                .collect(),
+            transitions: self
+                .transitions
+                .iter()
+                .map(|(&(from, to), &count)| TransitionCount { from, to, count })
+                .collect(),
+            faults: self.faults.clone(),
+            self_tests: SelfTestMetrics {
+                runs,
+                failed: self.self_tests.iter().filter(|(_, passed)| !passed).count() as u64,
+                mean_ms: (runs > 0).then(|| durations().sum::<u64>() / runs),
+                max_ms: durations().max(),
+                last_ms: self.self_tests.last().map(|(took, _)| took.as_millis() as u64),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use EVSEMachineState::*;
+
+    #[test]
+    fn test_metrics() {
+        let mut metrics = StateMetrics::default();
+        let t0 = Instant::now();
+        let secs = Duration::from_secs;
+
+        metrics.self_test(Duration::from_millis(900), true);
+        metrics.state_entered(Standby, t0);
+        metrics.state_entered(VehicleDetected, t0 + secs(10));
+        metrics.self_test(Duration::from_millis(1100), false);
+        metrics.state_entered(FailedStation, t0 + secs(12));
+        // Entering the state it is in changes nothing.
+        metrics.state_entered(FailedStation, t0 + secs(13));
+
+        let report = metrics.report(t0 + secs(20));
+        assert_eq!(
+            report.time_in_state_ms,
+            BTreeMap::from([(Standby, 10_000), (VehicleDetected, 2000), (FailedStation, 8000)])
+        );
+        assert_eq!(
+            report.transitions,
+            vec![
+                TransitionCount {
+                    from: Standby,
+                    to: VehicleDetected,
+                    count: 1
+                },
+                TransitionCount {
+                    from: VehicleDetected,
+                    to: FailedStation,
+                    count: 1
+                },
+            ]
+        );
+        assert_eq!(report.faults, BTreeMap::from([(FailedStation, 1)]));
+        assert_eq!(
+            report.self_tests,
+            SelfTestMetrics {
+                runs: 2,
+                failed: 1,
+                mean_ms: Some(1000),
+                max_ms: Some(1100),
+                last_ms: Some(1100),
+            }
+        );
+        assert_eq!(StateMetrics::default().report(t0).self_tests, SelfTestMetrics::default());
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+
+use crate::evse::{EVSEError, EVSEHardware, EVSEMachineInput, PilotReading};
+use crate::peripherals::PeripheralsError;
+use crate::pilot::PilotSignal;
+
+// Hardware backend for running the state machine off the station, in CI
+// for instance. It takes the pilot readings, GFI trips and other faults
+// from scripts, passes or fails the GFI self tests as it is told and
+// records what the machine drives:
+//
+//   cargo test -p juicelib --features mock-hw
+//
+// let hardware = MockEVSEHardware::new();
+// let probe = hardware.probe();
+// let handle = start_machine(hardware);
+// let script = MockScript::new().pilot(9.0).pilot(6.0).until_contactor(true).gfi_trip();
+// probe.run(script).join().unwrap()?;
+//
+// Readings are passed on as they are, without a sampling window or the
+// PWM: the pilot reads what the script says, whatever the machine drives.
+
+// How long a script waits for the machine to switch the contactor.
+pub const CONTACTOR_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockStep {
+    // A pilot reading of the given high level, -12 V low at 1 kHz.
+    Pilot(f32),
+    Fault(EVSEMachineInput),
+    // The current the sense reads from now on.
+    Current(Option<f64>),
+    Wait(Duration),
+    // Waits until the contactor is closed, or open, for up to
+    // CONTACTOR_TIMEOUT.
+    UntilContactor(bool),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MockScript {
+    steps: Vec<MockStep>,
+}
+
+impl MockScript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn step(mut self, step: MockStep) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    pub fn pilot(self, high: f32) -> Self {
+        self.step(MockStep::Pilot(high))
+    }
+
+    pub fn fault(self, input: EVSEMachineInput) -> Self {
+        self.step(MockStep::Fault(input))
+    }
+
+    pub fn gfi_trip(self) -> Self {
+        self.fault(EVSEMachineInput::GFIInterrupted)
+    }
+
+    pub fn current(self, amps: Option<f64>) -> Self {
+        self.step(MockStep::Current(amps))
+    }
+
+    pub fn wait(self, duration: Duration) -> Self {
+        self.step(MockStep::Wait(duration))
+    }
+
+    pub fn until_contactor(self, closed: bool) -> Self {
+        self.step(MockStep::UntilContactor(closed))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MockCommand {
+    Pilot(PilotSignal),
+    Contactor(bool),
+    GfiSelfTest { passed: bool },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MockError {
+    // The step of the script, counted from 0, waited in vain.
+    Timeout(usize),
+    // The machine dropped the hardware.
+    MachineGone(usize),
+}
+
+#[derive(Debug)]
+struct MockState {
+    pilot: PilotSignal,
+    contactor: bool,
+    current: Option<f64>,
+    // The outcomes of the next GFI self tests; they pass once these run out.
+    self_tests: VecDeque<bool>,
+    commands: Vec<MockCommand>,
+}
+
+pub struct MockEVSEHardware {
+    state: Arc<Mutex<MockState>>,
+    pilot_tx: Sender<PilotReading>,
+    pilot_rx: Receiver<PilotReading>,
+    fault_tx: Sender<EVSEMachineInput>,
+    fault_rx: Receiver<EVSEMachineInput>,
+}
+
+impl Default for MockEVSEHardware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockEVSEHardware {
+    pub fn new() -> Self {
+        let (pilot_tx, pilot_rx) = unbounded();
+        let (fault_tx, fault_rx) = unbounded();
+        Self {
+            state: Arc::new(Mutex::new(MockState {
+                pilot: PilotSignal::ErrorMinus12,
+                contactor: false,
+                current: None,
+                self_tests: VecDeque::new(),
+                commands: Vec::new(),
+            })),
+            pilot_tx,
+            pilot_rx,
+            fault_tx,
+            fault_rx,
+        }
+    }
+
+    // The outcomes of the next GFI self tests, the one at the start first.
+    pub fn with_self_tests(self, passed: impl IntoIterator<Item = bool>) -> Self {
+        self.state.lock().unwrap().self_tests.extend(passed);
+        self
+    }
+
+    // Drives the hardware and watches it once the machine owns it.
+    pub fn probe(&self) -> MockProbe {
+        MockProbe {
+            state: self.state.clone(),
+            pilot_tx: self.pilot_tx.clone(),
+            fault_tx: self.fault_tx.clone(),
+        }
+    }
+}
+
+impl EVSEHardware for MockEVSEHardware {
+    fn set_pilot(&mut self, signal: PilotSignal) -> Result<(), EVSEError> {
+        let mut state = self.state.lock().unwrap();
+        state.pilot = signal;
+        state.commands.push(MockCommand::Pilot(signal));
+        Ok(())
+    }
+
+    fn set_contactor(&mut self, on: bool) -> Result<(), EVSEError> {
+        let mut state = self.state.lock().unwrap();
+        state.contactor = on;
+        state.commands.push(MockCommand::Contactor(on));
+        Ok(())
+    }
+
+    fn run_gfi_self_test(&mut self) -> Result<(), EVSEError> {
+        let mut state = self.state.lock().unwrap();
+        let passed = state.self_tests.pop_front().unwrap_or(true);
+        state.commands.push(MockCommand::GfiSelfTest { passed });
+        match passed {
+            true => Ok(()),
+            false => Err(EVSEError::Peripherals(PeripheralsError::GfiSelfTestFailed("mock"))),
+        }
+    }
+
+    fn pilot_channel(&self) -> Receiver<PilotReading> {
+        self.pilot_rx.clone()
+    }
+
+    fn fault_channel(&self) -> Receiver<EVSEMachineInput> {
+        self.fault_rx.clone()
+    }
+
+    fn current_amps(&self) -> Option<f64> {
+        self.state.lock().unwrap().current
+    }
+}
+
+#[derive(Clone)]
+pub struct MockProbe {
+    state: Arc<Mutex<MockState>>,
+    pilot_tx: Sender<PilotReading>,
+    fault_tx: Sender<EVSEMachineInput>,
+}
+
+impl MockProbe {
+    pub fn pilot(&self) -> PilotSignal {
+        self.state.lock().unwrap().pilot
+    }
+
+    pub fn contactor(&self) -> bool {
+        self.state.lock().unwrap().contactor
+    }
+
+    // What the machine drove so far, oldest first.
+    pub fn commands(&self) -> Vec<MockCommand> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    // Runs the script on a thread of its own.
+    pub fn run(&self, script: MockScript) -> JoinHandle<Result<(), MockError>> {
+        let probe = self.clone();
+        thread::spawn(move || probe.run_here(&script))
+    }
+
+    // Runs the script on this thread.
+    pub fn run_here(&self, script: &MockScript) -> Result<(), MockError> {
+        for (index, step) in script.steps.iter().enumerate() {
+            match step {
+                MockStep::Pilot(high) => {
+                    let reading = PilotReading {
+                        high: *high,
+                        low: -12.0,
+                        duty_cycle: 0.5,
+                        frequency: 1000.0,
+                    };
+                    self.pilot_tx.send(reading).map_err(|_| MockError::MachineGone(index))?;
+                }
+                MockStep::Fault(input) => self.fault_tx.send(*input).map_err(|_| MockError::MachineGone(index))?,
+                MockStep::Current(amps) => self.state.lock().unwrap().current = *amps,
+                MockStep::Wait(duration) => thread::sleep(*duration),
+                MockStep::UntilContactor(closed) => {
+                    let start = Instant::now();
+                    while self.contactor() != *closed {
+                        if start.elapsed() >= CONTACTOR_TIMEOUT {
+                            return Err(MockError::Timeout(index));
+                        }
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evse::{start_machine, EVSEMachineState};
+
+    #[test]
+    fn test_scripted_session() {
+        let hardware = MockEVSEHardware::new();
+        let probe = hardware.probe();
+        let handle = start_machine(hardware);
+
+        let script = MockScript::new()
+            .pilot(9.0)
+            .pilot(6.0)
+            .until_contactor(true)
+            .current(Some(15.8))
+            .gfi_trip()
+            .until_contactor(false);
+        probe.run(script).join().unwrap().unwrap();
+        assert_eq!(handle.state(), EVSEMachineState::FailedStation);
+        let commands = probe.commands();
+        assert_eq!(commands[0], MockCommand::GfiSelfTest { passed: true });
+        assert!(commands
+            .iter()
+            .any(|command| matches!(command, MockCommand::Pilot(PilotSignal::OfferAmps(_)))));
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_failed_self_test() {
+        let hardware = MockEVSEHardware::new().with_self_tests([false]);
+        let probe = hardware.probe();
+        let handle = start_machine(hardware);
+
+        // No vehicle gets the contactor closed.
+        let script = MockScript::new().pilot(9.0).pilot(6.0).until_contactor(true);
+        assert_eq!(probe.run_here(&script), Err(MockError::Timeout(2)));
+        assert_eq!(handle.state(), EVSEMachineState::FailedStation);
+        handle.stop();
+        handle.join().unwrap();
+        assert_eq!(probe.run_here(&MockScript::new().pilot(12.0)), Err(MockError::MachineGone(0)));
+    }
+}
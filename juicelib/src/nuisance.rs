@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, TimeZone, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::analytics::{FaultRecord, GFI_TRIP};
+use crate::power_quality::PowerQualityConfig;
+
+// Tells nuisance GFI trips from genuine ones, from the faults in the store.
+// A GFI that trips with little residual current, on a sag or a surge of the
+// mains, or in the thunderstorm months was likely not tripped by a leak.
+// One that trips well above its threshold, or again and again with the same
+// vehicle, likely was.
+//
+// The heuristics err on the side of a genuine fault: a clue of a genuine
+// fault wins over any clues of a nuisance, and a mains event or the season
+// alone is not enough. The automatic retry after a trip only gets its
+// retries while no genuine trip is recent, see retry_allowance.
+
+// The trip threshold of a CCID20 GFI, UL 2231.
+pub const GFI_TRIP_MA: f64 = 20.0;
+// Below this share of the threshold the GFI had little reason to trip.
+const LOW_RESIDUAL_SHARE: f64 = 0.5;
+// Trips with one vehicle that point at the vehicle.
+const VEHICLE_REPEATS: usize = 2;
+// Thunderstorm season, northern hemisphere.
+const STORM_MONTHS: [u32; 5] = [5, 6, 7, 8, 9];
+// How long a genuine trip holds back the automatic retry.
+const RETRY_MEMORY: u64 = 7 * 24 * 60 * 60;
+// What makes a trip a likely nuisance; a low residual current is enough,
+// a mains event needs the season as well.
+const NUISANCE_SCORE: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TripVerdict {
+    LikelyNuisance,
+    LikelyGenuine,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TripClue {
+    // The residual current stayed well below the threshold.
+    LowResidual,
+    // The residual current reached the threshold.
+    HighResidual,
+    // The mains was out of its band at the trip.
+    MainsEvent,
+    StormSeason,
+    // The vehicle of the trip tripped the GFI before or after.
+    RepeatVehicle,
+}
+
+impl TripClue {
+    fn is_genuine(&self) -> bool {
+        matches!(self, TripClue::HighResidual | TripClue::RepeatVehicle)
+    }
+
+    fn nuisance_score(&self) -> u32 {
+        match self {
+            TripClue::LowResidual => 2,
+            TripClue::MainsEvent | TripClue::StormSeason => 1,
+            TripClue::HighResidual | TripClue::RepeatVehicle => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct ClassifiedTrip {
+    pub unix_time: u64,
+    pub vehicle: Option<String>,
+    pub verdict: TripVerdict,
+    pub clues: Vec<TripClue>,
+}
+
+// The GFI trips among the faults, oldest first.
+pub fn classify_trips(faults: &[FaultRecord]) -> Vec<ClassifiedTrip> {
+    let mut trips: Vec<&FaultRecord> = faults
+        .iter()
+        .filter(|fault| fault.cause.as_deref() == Some(GFI_TRIP))
+        .collect();
+    trips.sort_by_key(|fault| fault.unix_time);
+
+    let mut by_vehicle = BTreeMap::new();
+    for vehicle in trips.iter().filter_map(|fault| fault.vehicle.as_deref()) {
+        *by_vehicle.entry(vehicle).or_insert(0) += 1;
+    }
+    let mains = PowerQualityConfig::default().voltage;
+
+    trips
+        .into_iter()
+        .map(|fault| {
+            let mut clues = Vec::new();
+            match fault.residual_peak_ma.or(fault.residual_ma) {
+                Some(residual) if residual >= GFI_TRIP_MA => clues.push(TripClue::HighResidual),
+                Some(residual) if residual < GFI_TRIP_MA * LOW_RESIDUAL_SHARE => clues.push(TripClue::LowResidual),
+                _ => {}
+            }
+            if fault.mains_volts.is_some_and(|volts| volts < mains.low || volts > mains.high) {
+                clues.push(TripClue::MainsEvent);
+            }
+            let month = Utc.timestamp_opt(fault.unix_time as i64, 0).single().map(|time| time.month());
+            if month.is_some_and(|month| STORM_MONTHS.contains(&month)) {
+                clues.push(TripClue::StormSeason);
+            }
+            if fault
+                .vehicle
+                .as_deref()
+                .is_some_and(|vehicle| by_vehicle[vehicle] >= VEHICLE_REPEATS)
+            {
+                clues.push(TripClue::RepeatVehicle);
+            }
+
+            let nuisance = !clues.iter().any(TripClue::is_genuine)
+                && clues.iter().map(TripClue::nuisance_score).sum::<u32>() >= NUISANCE_SCORE;
+            ClassifiedTrip {
+                unix_time: fault.unix_time,
+                vehicle: fault.vehicle.clone(),
+                verdict: if nuisance {
+                    TripVerdict::LikelyNuisance
+                } else {
+                    TripVerdict::LikelyGenuine
+                },
+                clues,
+            }
+        })
+        .collect()
+}
+
+// The automatic retries the history leaves out of max_retries: none after a
+// likely genuine trip in the last RETRY_MEMORY, all of them otherwise.
+pub fn retry_allowance(trips: &[ClassifiedTrip], max_retries: u32, now: u64) -> u32 {
+    let genuine_recently = trips
+        .iter()
+        .any(|trip| trip.verdict == TripVerdict::LikelyGenuine && now.saturating_sub(trip.unix_time) < RETRY_MEMORY);
+    if genuine_recently {
+        0
+    } else {
+        max_retries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-15 and 2024-07-15, 12:00 UTC.
+    const WINTER: u64 = 1_705_320_000;
+    const SUMMER: u64 = 1_721_044_800;
+
+    fn trip(unix_time: u64, residual_ma: Option<f64>, mains_volts: f64, vehicle: Option<&str>) -> FaultRecord {
+        FaultRecord {
+            unix_time,
+            fault: "FailedStation".to_string(),
+            cause: Some(GFI_TRIP.to_string()),
+            temperature_c: None,
+            mains_volts: Some(mains_volts),
+            residual_ma,
+            residual_peak_ma: residual_ma,
+            vehicle: vehicle.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let mut pilot_fault = trip(WINTER, None, 240.0, None);
+        pilot_fault.cause = Some("PilotInError".to_string());
+        let faults = [
+            pilot_fault,
+            trip(WINTER + 10, Some(4.0), 240.0, None),
+            // A sag alone is not enough, with the season it is.
+            trip(WINTER + 20, None, 190.0, None),
+            trip(SUMMER, None, 190.0, None),
+            // Over the threshold, and the trips of a vehicle that trips again.
+            trip(SUMMER + 10, Some(25.0), 240.0, None),
+            trip(SUMMER + 20, Some(3.0), 240.0, Some("leaky")),
+            trip(SUMMER + 30, Some(3.0), 240.0, Some("leaky")),
+        ];
+        let trips = classify_trips(&faults);
+        let verdicts: Vec<TripVerdict> = trips.iter().map(|trip| trip.verdict).collect();
+        use TripVerdict::*;
+        assert_eq!(
+            verdicts,
+            vec![LikelyNuisance, LikelyGenuine, LikelyNuisance, LikelyGenuine, LikelyGenuine, LikelyGenuine]
+        );
+        assert_eq!(trips[2].clues, vec![TripClue::MainsEvent, TripClue::StormSeason]);
+        assert_eq!(
+            trips[5].clues,
+            vec![TripClue::LowResidual, TripClue::StormSeason, TripClue::RepeatVehicle]
+        );
+    }
+
+    #[test]
+    fn test_retry_allowance() {
+        let trips = classify_trips(&[trip(WINTER, Some(4.0), 240.0, None)]);
+        assert_eq!(retry_allowance(&trips, 3, WINTER + 60), 3);
+        assert_eq!(retry_allowance(&[], 3, WINTER), 3);
+
+        let trips = classify_trips(&[trip(WINTER, Some(30.0), 240.0, None)]);
+        assert_eq!(retry_allowance(&trips, 3, WINTER + 60), 0);
+        assert_eq!(retry_allowance(&trips, 3, WINTER + RETRY_MEMORY), 3);
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::{Duration, Instant};
+
+use crate::timing::OFFER_RESPONSE;
+
+// Paces the adjustments of the offer during a session, from a load manager
+// for instance. J1772 gives the vehicle OFFER_RESPONSE to follow a lower
+// offer, so a higher offer is made no sooner than that after the last
+// change: an adjustment every second would otherwise have the vehicle chase
+// offers it never gets to settle on. Raises in between are debounced, the
+// last one is made once the time has passed; the pilot actuator then ramps
+// up to it at its slew rate. A lower offer protects the supply and is made
+// at once.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OfferPacer {
+    spacing: Duration,
+    // The adjustment in effect.
+    applied: Option<f64>,
+    // A raise waiting for its turn.
+    pending: Option<f64>,
+    changed_at: Option<Instant>,
+}
+
+impl Default for OfferPacer {
+    fn default() -> Self {
+        Self::new(OFFER_RESPONSE.default)
+    }
+}
+
+impl OfferPacer {
+    pub fn new(spacing: Duration) -> Self {
+        Self {
+            spacing,
+            applied: None,
+            pending: None,
+            changed_at: None,
+        }
+    }
+
+    // Asks for an adjustment. Returns it if it is made now.
+    pub fn request(&mut self, amps: f64, now: Instant) -> Option<f64> {
+        let lower = self.applied.is_none_or(|applied| amps <= applied);
+        let settled = self.changed_at.is_none_or(|at| now.saturating_duration_since(at) >= self.spacing);
+        if lower || settled {
+            self.pending = None;
+            self.apply(amps, now)
+        } else {
+            self.pending = Some(amps);
+            None
+        }
+    }
+
+    // The raise that was held back, once its turn has come.
+    pub fn poll(&mut self, now: Instant) -> Option<f64> {
+        let amps = self.pending?;
+        if self.changed_at.is_some_and(|at| now.saturating_duration_since(at) < self.spacing) {
+            return None;
+        }
+        self.pending = None;
+        self.apply(amps, now)
+    }
+
+    pub fn pending(&self) -> Option<f64> {
+        self.pending
+    }
+
+    fn apply(&mut self, amps: f64, now: Instant) -> Option<f64> {
+        self.applied = Some(amps);
+        self.changed_at = Some(now);
+        Some(amps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pacing() {
+        let mut pacer = OfferPacer::new(Duration::from_secs(5));
+        let t0 = Instant::now();
+        let secs = Duration::from_secs;
+
+        assert_eq!(pacer.request(16.0, t0), Some(16.0));
+        // Lower at once.
+        assert_eq!(pacer.request(10.0, t0 + secs(1)), Some(10.0));
+        // Raises wait, the last one wins.
+        assert_eq!(pacer.request(20.0, t0 + secs(2)), None);
+        assert_eq!(pacer.request(24.0, t0 + secs(3)), None);
+        assert_eq!(pacer.pending(), Some(24.0));
+        assert_eq!(pacer.poll(t0 + secs(5)), None);
+        assert_eq!(pacer.poll(t0 + secs(6)), Some(24.0));
+        assert_eq!(pacer.poll(t0 + secs(7)), None);
+
+        // A lower offer drops the raise that waits.
+        assert_eq!(pacer.request(32.0, t0 + secs(8)), None);
+        assert_eq!(pacer.request(12.0, t0 + secs(9)), Some(12.0));
+        assert_eq!(pacer.poll(t0 + secs(20)), None);
+        assert_eq!(pacer.request(32.0, t0 + secs(20)), Some(32.0));
+    }
+}
@@ -0,0 +1,142 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Months, NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+
+use crate::store::Store;
+
+// The highest current offered and measured each day, for electrical audits
+// and demand charge negotiations: what the breaker and the supply actually
+// had to carry, as opposed to what the station is rated for. Days are days
+// of the site's time zone and RETENTION_MONTHS of them are kept.
+//
+// The station has one connector, so the site peak is that of the
+// connector. The measured current is that of L1, and missing without a
+// current sensor.
+
+pub const RETENTION_MONTHS: u32 = 13;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DailyPeak {
+    // The local date, e.g. "2024-05-01".
+    pub day: String,
+    pub offered_amps: f64,
+    pub measured_amps: Option<f64>,
+}
+
+impl DailyPeak {
+    fn new(day: NaiveDate) -> Self {
+        Self {
+            day: day.to_string(),
+            offered_amps: 0.0,
+            measured_amps: None,
+        }
+    }
+
+    // Whether the readings raised the peak.
+    fn update(&mut self, offered: f64, measured: Option<f64>) -> bool {
+        let mut raised = false;
+        if offered > self.offered_amps {
+            self.offered_amps = offered;
+            raised = true;
+        }
+        if let Some(measured) = measured.filter(|&amps| self.measured_amps.is_none_or(|peak| amps > peak)) {
+            self.measured_amps = Some(measured);
+            raised = true;
+        }
+        raised
+    }
+}
+
+// The oldest day kept on `today`.
+pub fn retained_since(today: NaiveDate) -> NaiveDate {
+    today.checked_sub_months(Months::new(RETENTION_MONTHS)).unwrap_or(NaiveDate::MIN)
+}
+
+// Follows the currents and keeps the peak of the day in the store, where it
+// survives a restart.
+pub struct PeakTracker {
+    tz: Tz,
+    // None keeps the peaks in memory only.
+    store_path: Option<PathBuf>,
+    today: Option<(NaiveDate, DailyPeak)>,
+}
+
+impl PeakTracker {
+    pub fn new(store_path: Option<PathBuf>, tz: Tz) -> Self {
+        Self {
+            tz,
+            store_path,
+            today: None,
+        }
+    }
+
+    // Returns the peak of the day when the readings raised it.
+    pub fn observe(&mut self, now: DateTime<Utc>, offered: f64, measured: Option<f64>) -> Option<&DailyPeak> {
+        let day = now.with_timezone(&self.tz).date_naive();
+        if self.today.as_ref().is_none_or(|(today, _)| *today != day) {
+            self.today = Some((day, self.load(day)));
+        }
+        let (_, peak) = self.today.as_mut()?;
+        if !peak.update(offered, measured) {
+            return None;
+        }
+        if let Some(path) = self.store_path.as_deref() {
+            let since = retained_since(day).to_string();
+            let saved = Store::open(path).and_then(|store| store.save_daily_peak(peak, &since));
+            if let Err(error) = saved {
+                eprintln!("Daily peak not stored: {}", error);
+            }
+        }
+        Some(peak)
+    }
+
+    // The peak stored earlier in the day, e.g. before a restart.
+    fn load(&self, day: NaiveDate) -> DailyPeak {
+        let stored = self
+            .store_path
+            .as_deref()
+            .map(|path| Store::open(path)?.daily_peak(&day.to_string()));
+        match stored {
+            Some(Ok(Some(peak))) => peak,
+            Some(Err(error)) => {
+                eprintln!("Daily peak not loaded: {}", error);
+                DailyPeak::new(day)
+            }
+            _ => DailyPeak::new(day),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use chrono_tz::Europe::Berlin;
+
+    #[test]
+    fn test_peak_of_the_local_day() {
+        let mut peaks = PeakTracker::new(None, Berlin);
+        // 23:30 in Berlin.
+        let evening = Utc.with_ymd_and_hms(2024, 5, 1, 21, 30, 0).unwrap();
+        assert_eq!(peaks.observe(evening, 16.0, None).unwrap().offered_amps, 16.0);
+        assert_eq!(peaks.observe(evening, 10.0, Some(9.5)).unwrap().measured_amps, Some(9.5));
+        assert_eq!(peaks.observe(evening, 12.0, Some(9.0)), None);
+
+        // 00:30 the next day.
+        let peak = peaks.observe(evening + chrono::Duration::hours(1), 6.0, Some(5.8)).unwrap();
+        assert_eq!(
+            *peak,
+            DailyPeak {
+                day: "2024-05-02".to_string(),
+                offered_amps: 6.0,
+                measured_amps: Some(5.8),
+            }
+        );
+        assert_eq!(
+            retained_since(NaiveDate::from_ymd_opt(2024, 5, 2).unwrap()),
+            NaiveDate::from_ymd_opt(2023, 4, 2).unwrap()
+        );
+    }
+}
@@ -0,0 +1,703 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::hal::gpio::{Error as GpioError, Gpio, InputPin, OutputPin};
+use crate::hal::pwm::{Channel, Error as PwmError, Polarity, Pwm};
+use crate::lock_order::RankedMutex;
+
+// This file wraps the GPIO pins of the EVSE Pi Hat. The numbers are GPIO
+// numbers, not pin numbers on the connector (see docs/evse-spec.md). Other
+// boards give theirs in the GpioPins of their profile.
+const POWER_WATCHDOG_PIN: u8 = 4;
+const POWER_PIN: u8 = 17;
+const GFI_STATUS_PIN: u8 = 22;
+const RELAY_TEST_PIN: u8 = 23;
+const GFI_TEST_PIN: u8 = 24;
+const GFI_RESET_PIN: u8 = 27;
+
+// Frequency and half period of the power watchdog signal.
+const WATCHDOG_FREQUENCY: f64 = 1000.0;
+const WATCHDOG_HALF_PERIOD: Duration = Duration::from_micros(500);
+// The relay test line must follow the power pin within this time.
+const RELAY_SETTLE_TIME: Duration = Duration::from_millis(100);
+// Half period of the 60 Hz signal on the GFI test line.
+const GFI_TEST_HALF_PERIOD: Duration = Duration::from_micros(8333);
+const GFI_TEST_CYCLES: usize = 10;
+// How long the GFI reset line is held high.
+const GFI_RESET_PULSE: Duration = Duration::from_millis(200);
+// How often the status of a momentary GFI is polled. Shorter pulses may be
+// missed.
+const GFI_PULSE_POLL_INTERVAL: Duration = Duration::from_micros(250);
+
+// The pins are locked in groups, each on its own, so that nobody waits on
+// a group they do not use: the power watchdog toggles its pin every 500us,
+// the fault thread polls the GFI every 10ms. No lock is held across a
+// sleep. Nothing nests the locks today; if something has to, the ranks
+// give the order (see lock_order.rs).
+const POWER_RANK: u8 = 1;
+const POWER_WATCHDOG_RANK: u8 = 2;
+const GFI_RANK: u8 = 3;
+const RELAY_TEST_RANK: u8 = 4;
+const MONITOR_RANK: u8 = 5;
+const ALARM_RANK: u8 = 6;
+const VENTILATION_RANK: u8 = 7;
+
+// How the contactor coil is driven while the power is on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContactorDrive {
+    Full,
+    // Coil economizer: full drive for `pull_in`, then PWM on the power pin
+    // with `hold_duty_cycle` (0.0 to 1.0) to cut coil heating.
+    Economized {
+        pull_in: Duration,
+        hold_frequency: f64,
+        hold_duty_cycle: f64,
+    },
+}
+
+impl ContactorDrive {
+    fn validate(&self) -> Result<(), PeripheralsError> {
+        match *self {
+            ContactorDrive::Full => Ok(()),
+            ContactorDrive::Economized {
+                pull_in,
+                hold_frequency,
+                hold_duty_cycle,
+            } => {
+                // The relay test has to confirm the pull-in before the
+                // drive is reduced.
+                let valid = pull_in >= RELAY_SETTLE_TIME
+                    && hold_frequency > 0.0
+                    && hold_duty_cycle > 0.0
+                    && hold_duty_cycle <= 1.0;
+                if !valid {
+                    return Err(PeripheralsError::InvalidContactorDrive);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// How the power watchdog signal is generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerWatchdog {
+    // GPIO 4 toggled by a thread.
+    Software,
+    // A hardware PWM channel the watchdog line is wired to.
+    HardwarePwm(Channel),
+}
+
+// How the GFI board reports a fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GfiDriver {
+    // The status line stays high until the reset line is pulsed (EVSE Pi
+    // Hat).
+    Latching,
+    // The board resets itself and only pulses the status line. The pulse
+    // is latched in software until the GFI is reset; the board has no
+    // reset line.
+    Momentary,
+}
+
+impl FromStr for GfiDriver {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "latching" => Ok(GfiDriver::Latching),
+            "momentary" => Ok(GfiDriver::Momentary),
+            _ => Err(format!("unknown GFI driver {}", name)),
+        }
+    }
+}
+
+// The GPIO numbers of the lines. The defaults are the EVSE Pi Hat's; the
+// optional inputs and the alarm output are not fitted there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct GpioPins {
+    // Only claimed for the software power watchdog.
+    pub power_watchdog: u8,
+    pub power: u8,
+    pub gfi_status: u8,
+    pub relay_test: u8,
+    pub gfi_test: u8,
+    // Only claimed for a latching GFI.
+    pub gfi_reset: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power_good: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tamper: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alarm: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_contact: Option<u8>,
+    // The relay of the ventilation fan.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ventilation: Option<u8>,
+}
+
+impl Default for GpioPins {
+    fn default() -> Self {
+        Self {
+            power_watchdog: POWER_WATCHDOG_PIN,
+            power: POWER_PIN,
+            gfi_status: GFI_STATUS_PIN,
+            relay_test: RELAY_TEST_PIN,
+            gfi_test: GFI_TEST_PIN,
+            gfi_reset: GFI_RESET_PIN,
+            power_good: None,
+            tamper: None,
+            alarm: None,
+            control_contact: None,
+            ventilation: None,
+        }
+    }
+}
+
+impl GpioPins {
+    // No GPIO may serve two lines.
+    pub fn validate(&self) -> Result<(), PeripheralsError> {
+        let fixed = [
+            self.power_watchdog,
+            self.power,
+            self.gfi_status,
+            self.relay_test,
+            self.gfi_test,
+            self.gfi_reset,
+        ];
+        let optional = [self.power_good, self.tamper, self.alarm, self.control_contact, self.ventilation];
+        let mut pins: Vec<u8> = fixed.into_iter().chain(optional.into_iter().flatten()).collect();
+        pins.sort_unstable();
+        match pins.windows(2).find(|pair| pair[0] == pair[1]) {
+            Some(pair) => Err(PeripheralsError::PinConflict(pair[0])),
+            None => Ok(()),
+        }
+    }
+}
+
+// What differs between the boards juiced drives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoardProfile {
+    pub power_watchdog: PowerWatchdog,
+    pub gfi: GfiDriver,
+    pub pins: GpioPins,
+}
+
+impl Default for BoardProfile {
+    // The EVSE Pi Hat.
+    fn default() -> Self {
+        Self {
+            power_watchdog: PowerWatchdog::Software,
+            gfi: GfiDriver::Latching,
+            pins: GpioPins::default(),
+        }
+    }
+}
+
+struct GfiPins {
+    status: InputPin,
+    test: OutputPin,
+    // Not claimed for a momentary GFI.
+    reset: Option<OutputPin>,
+}
+
+// Optional inputs watched by the fault thread.
+#[derive(Default)]
+struct MonitorPins {
+    // "Power good" line of an optional UPS hat, high while mains is present.
+    power_good: Option<InputPin>,
+    // Enclosure door/tamper switch, high while the enclosure is open.
+    tamper: Option<InputPin>,
+    // Potential-free control contact of the grid operator or an energy
+    // management system, high while asserted.
+    control_contact: Option<InputPin>,
+}
+
+#[derive(Debug)]
+pub enum PeripheralsError {
+    Gpio(GpioError),
+    Pwm(PwmError),
+    RelayTestFailed,
+    // The contactor dropped out after switching to the hold drive.
+    ContactorHoldFailed,
+    InvalidContactorDrive,
+    GfiSelfTestFailed(&'static str),
+    // The GFI must not be reset while the vehicle power is on.
+    GfiResetUnderPower,
+    // The GPIO is given for two lines.
+    PinConflict(u8),
+}
+
+impl From<GpioError> for PeripheralsError {
+    fn from(error: GpioError) -> Self {
+        PeripheralsError::Gpio(error)
+    }
+}
+
+impl From<PwmError> for PeripheralsError {
+    fn from(error: PwmError) -> Self {
+        PeripheralsError::Pwm(error)
+    }
+}
+
+// Handle to the GPIO peripherals. It is cheap to clone; all clones share
+// the same pins.
+#[derive(Clone)]
+pub struct GpioPeripherals {
+    // Also reached by the control watchdog through ReservedContactor.
+    power: Arc<RankedMutex<OutputPin>>,
+    // Not claimed when the watchdog runs on a hardware PWM channel.
+    power_watchdog: Arc<RankedMutex<Option<OutputPin>>>,
+    gfi: Arc<RankedMutex<GfiPins>>,
+    relay_test: Arc<RankedMutex<InputPin>>,
+    monitors: Arc<RankedMutex<MonitorPins>>,
+    // High while the alarm is asserted, if an alarm output is configured.
+    alarm: Arc<RankedMutex<Option<OutputPin>>>,
+    // High while the ventilation runs, if a ventilation relay is
+    // configured.
+    ventilation: Arc<RankedMutex<Option<OutputPin>>>,
+    watchdog_pwm: Option<Arc<Pwm>>,
+    contactor_drive: ContactorDrive,
+    power_on: Arc<AtomicBool>,
+    self_test_active: Arc<AtomicBool>,
+    gfi_driver: GfiDriver,
+    // A pulse of a momentary GFI seen since the last reset.
+    gfi_pulse_seen: Arc<AtomicBool>,
+}
+
+// Handle to the contactor, reserved for the control watchdog. It only ever
+// opens the contactor.
+#[derive(Clone)]
+pub struct ReservedContactor {
+    power: Arc<RankedMutex<OutputPin>>,
+    power_on: Arc<AtomicBool>,
+    watchdog_pwm: Option<Arc<Pwm>>,
+}
+
+impl ReservedContactor {
+    pub fn force_off(&self) -> Result<(), PeripheralsError> {
+        {
+            let mut power = self.power.lock();
+            // Stops the hold PWM, if any.
+            power.clear_pwm()?;
+            power.set_low();
+        }
+        self.power_on.store(false, Ordering::SeqCst);
+        if let Some(pwm) = &self.watchdog_pwm {
+            pwm.disable()?;
+        }
+        Ok(())
+    }
+}
+
+impl GpioPeripherals {
+    pub fn new() -> Result<Self, PeripheralsError> {
+        Self::with_profile(BoardProfile::default())
+    }
+
+    pub fn with_power_watchdog(watchdog: PowerWatchdog) -> Result<Self, PeripheralsError> {
+        Self::with_profile(BoardProfile {
+            power_watchdog: watchdog,
+            ..BoardProfile::default()
+        })
+    }
+
+    pub fn with_profile(profile: BoardProfile) -> Result<Self, PeripheralsError> {
+        let pins = profile.pins;
+        pins.validate()?;
+        let gpio = Gpio::new()?;
+        let watchdog = profile.power_watchdog;
+        let (power_watchdog, watchdog_pwm) = match watchdog {
+            PowerWatchdog::Software => (Some(gpio.get(pins.power_watchdog)?.into_output_low()), None),
+            PowerWatchdog::HardwarePwm(channel) => {
+                let pwm = Pwm::with_frequency(channel, WATCHDOG_FREQUENCY, 0.5, Polarity::Normal, false)?;
+                (None, Some(Arc::new(pwm)))
+            }
+        };
+        let gfi = GfiPins {
+            status: gpio.get(pins.gfi_status)?.into_input(),
+            test: gpio.get(pins.gfi_test)?.into_output_low(),
+            reset: match profile.gfi {
+                GfiDriver::Latching => Some(gpio.get(pins.gfi_reset)?.into_output_low()),
+                GfiDriver::Momentary => None,
+            },
+        };
+
+        let peripherals = Self {
+            power: Arc::new(RankedMutex::new(POWER_RANK, gpio.get(pins.power)?.into_output_low())),
+            power_watchdog: Arc::new(RankedMutex::new(POWER_WATCHDOG_RANK, power_watchdog)),
+            gfi: Arc::new(RankedMutex::new(GFI_RANK, gfi)),
+            relay_test: Arc::new(RankedMutex::new(RELAY_TEST_RANK, gpio.get(pins.relay_test)?.into_input())),
+            monitors: Arc::new(RankedMutex::new(MONITOR_RANK, MonitorPins::default())),
+            alarm: Arc::new(RankedMutex::new(ALARM_RANK, None)),
+            ventilation: Arc::new(RankedMutex::new(VENTILATION_RANK, None)),
+            watchdog_pwm,
+            contactor_drive: ContactorDrive::Full,
+            power_on: Arc::new(AtomicBool::new(false)),
+            self_test_active: Arc::new(AtomicBool::new(false)),
+            gfi_driver: profile.gfi,
+            gfi_pulse_seen: Arc::new(AtomicBool::new(false)),
+        };
+        if watchdog == PowerWatchdog::Software {
+            peripherals.start_power_watchdog();
+        }
+        if profile.gfi == GfiDriver::Momentary {
+            peripherals.start_gfi_pulse_watch();
+        }
+        if let Some(pin) = pins.power_good {
+            peripherals.set_power_good_pin(pin)?;
+        }
+        if let Some(pin) = pins.tamper {
+            peripherals.set_tamper_pin(pin)?;
+        }
+        if let Some(pin) = pins.alarm {
+            peripherals.set_alarm_pin(pin)?;
+        }
+        if let Some(pin) = pins.control_contact {
+            peripherals.set_control_contact_pin(pin)?;
+        }
+        if let Some(pin) = pins.ventilation {
+            peripherals.set_ventilation_pin(pin)?;
+        }
+
+        Ok(peripherals)
+    }
+
+    // The power watchdog pin has to toggle whenever the relay is powered,
+    // otherwise the hat raises a synthetic GFI event.
+    fn start_power_watchdog(&self) {
+        let power_watchdog = self.power_watchdog.clone();
+        let power_on = self.power_on.clone();
+        thread::spawn(move || loop {
+            if power_on.load(Ordering::SeqCst) {
+                if let Some(pin) = power_watchdog.lock().as_mut() {
+                    pin.toggle();
+                }
+                thread::sleep(WATCHDOG_HALF_PERIOD);
+            } else {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+    }
+
+    // Latches the status pulses of a momentary GFI. They are too short for
+    // the fault thread to catch.
+    fn start_gfi_pulse_watch(&self) {
+        let gfi = self.gfi.clone();
+        let pulse_seen = self.gfi_pulse_seen.clone();
+        thread::spawn(move || loop {
+            if gfi.lock().status.is_high() {
+                pulse_seen.store(true, Ordering::SeqCst);
+            }
+            thread::sleep(GFI_PULSE_POLL_INTERVAL);
+        });
+    }
+
+    // Applies to clones made after the call.
+    pub fn set_contactor_drive(&mut self, drive: ContactorDrive) -> Result<(), PeripheralsError> {
+        drive.validate()?;
+        self.contactor_drive = drive;
+        Ok(())
+    }
+
+    // Switches the vehicle power and verifies the relay through the relay
+    // test line.
+    pub fn set_power(&self, on: bool) -> Result<(), PeripheralsError> {
+        if on {
+            // Start the watchdog before the relay closes.
+            if let Some(pwm) = &self.watchdog_pwm {
+                pwm.enable()?;
+            }
+            self.power_on.store(true, Ordering::SeqCst);
+            self.power.lock().set_high();
+        } else {
+            self.reserved_contactor().force_off()?;
+        }
+
+        thread::sleep(RELAY_SETTLE_TIME);
+        if self.relay_test() != on {
+            return Err(PeripheralsError::RelayTestFailed);
+        }
+
+        if let (true, ContactorDrive::Economized { pull_in, hold_frequency, hold_duty_cycle }) =
+            (on, self.contactor_drive)
+        {
+            self.hold_contactor(pull_in, hold_frequency, hold_duty_cycle)?;
+        }
+        Ok(())
+    }
+
+    fn hold_contactor(
+        &self,
+        pull_in: Duration,
+        hold_frequency: f64,
+        hold_duty_cycle: f64,
+    ) -> Result<(), PeripheralsError> {
+        thread::sleep(pull_in.saturating_sub(RELAY_SETTLE_TIME));
+        self.power.lock().set_pwm_frequency(hold_frequency, hold_duty_cycle)?;
+
+        // Make sure the reduced drive still holds the contactor closed.
+        thread::sleep(RELAY_SETTLE_TIME);
+        if !self.relay_test() {
+            self.set_power(false)?;
+            return Err(PeripheralsError::ContactorHoldFailed);
+        }
+        Ok(())
+    }
+
+    pub fn reserved_contactor(&self) -> ReservedContactor {
+        ReservedContactor {
+            power: self.power.clone(),
+            power_on: self.power_on.clone(),
+            watchdog_pwm: self.watchdog_pwm.clone(),
+        }
+    }
+
+    // Enables monitoring of the mains through the power good output of a UPS
+    // hat on the given GPIO. Shared by all clones.
+    pub fn set_power_good_pin(&self, pin: u8) -> Result<(), PeripheralsError> {
+        let input = Gpio::new()?.get(pin)?.into_input();
+        self.monitors.lock().power_good = Some(input);
+        Ok(())
+    }
+
+    // Always true when no power good input is configured.
+    pub fn is_power_good(&self) -> bool {
+        self.monitors
+            .lock()
+            .power_good
+            .as_ref()
+            .is_none_or(|pin| pin.is_high())
+    }
+
+    // Enables the enclosure tamper switch on the given GPIO. Shared by all
+    // clones.
+    pub fn set_tamper_pin(&self, pin: u8) -> Result<(), PeripheralsError> {
+        let input = Gpio::new()?.get(pin)?.into_input();
+        self.monitors.lock().tamper = Some(input);
+        Ok(())
+    }
+
+    // Enables the control contact on the given GPIO. Shared by all clones.
+    pub fn set_control_contact_pin(&self, pin: u8) -> Result<(), PeripheralsError> {
+        let input = Gpio::new()?.get(pin)?.into_input();
+        self.monitors.lock().control_contact = Some(input);
+        Ok(())
+    }
+
+    // None when no control contact is configured.
+    pub fn is_control_contact_asserted(&self) -> Option<bool> {
+        self.monitors.lock().control_contact.as_ref().map(InputPin::is_high)
+    }
+
+    // Enables the alarm output on the given GPIO, low until the alarm is
+    // asserted. Shared by all clones.
+    pub fn set_alarm_pin(&self, pin: u8) -> Result<(), PeripheralsError> {
+        let output = Gpio::new()?.get(pin)?.into_output_low();
+        *self.alarm.lock() = Some(output);
+        Ok(())
+    }
+
+    // Does nothing when no alarm output is configured.
+    pub fn set_alarm(&self, on: bool) {
+        if let Some(pin) = self.alarm.lock().as_mut() {
+            if on {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+    }
+
+    // Enables the ventilation relay on the given GPIO, off until the
+    // ventilation is asked for. Shared by all clones.
+    pub fn set_ventilation_pin(&self, pin: u8) -> Result<(), PeripheralsError> {
+        let output = Gpio::new()?.get(pin)?.into_output_low();
+        *self.ventilation.lock() = Some(output);
+        Ok(())
+    }
+
+    // Does nothing when no ventilation relay is configured.
+    pub fn set_ventilation(&self, on: bool) {
+        if let Some(pin) = self.ventilation.lock().as_mut() {
+            if on {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+    }
+
+    // Always false when no tamper switch is configured.
+    pub fn is_enclosure_open(&self) -> bool {
+        self.monitors
+            .lock()
+            .tamper
+            .as_ref()
+            .is_some_and(|pin| pin.is_high())
+    }
+
+    pub fn is_power_on(&self) -> bool {
+        self.power_on.load(Ordering::SeqCst)
+    }
+
+    pub fn is_gfi_set(&self) -> bool {
+        let status = self.gfi.lock().status.is_high();
+        match self.gfi_driver {
+            GfiDriver::Latching => status,
+            GfiDriver::Momentary => status || self.gfi_pulse_seen.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn relay_test(&self) -> bool {
+        self.relay_test.lock().is_high()
+    }
+
+    // True while the GFI self test is running. GFI events seen during that
+    // time are caused by the test itself.
+    pub fn is_self_test_active(&self) -> bool {
+        self.self_test_active.load(Ordering::SeqCst)
+    }
+
+    // Refused while the contactor is commanded on or the relay test line
+    // reads it closed.
+    pub fn gfi_reset(&self) -> Result<(), PeripheralsError> {
+        if self.is_power_on() || self.relay_test() {
+            return Err(PeripheralsError::GfiResetUnderPower);
+        }
+        match self.gfi_driver {
+            GfiDriver::Latching => {
+                if let Some(reset) = self.gfi.lock().reset.as_mut() {
+                    reset.set_high();
+                }
+                thread::sleep(GFI_RESET_PULSE);
+                if let Some(reset) = self.gfi.lock().reset.as_mut() {
+                    reset.set_low();
+                }
+            }
+            // The board has reset itself; a status still high latches the
+            // pulse again.
+            GfiDriver::Momentary => self.gfi_pulse_seen.store(false, Ordering::SeqCst),
+        }
+        Ok(())
+    }
+
+    // Returns true if the GFI stays clear for the whole duration.
+    fn gfi_stays_clear(&self, duration: Duration) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            if self.is_gfi_set() {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    // GFI self test as described in docs/evse-spec.md. It has to run
+    // immediately before every attempt to turn the power on. A momentary
+    // GFI passes on the latched pulse and has to reset itself.
+    pub fn run_gfi_self_test(&self) -> Result<(), PeripheralsError> {
+        self.self_test_active.store(true, Ordering::SeqCst);
+        let result = self.gfi_self_test_sequence();
+        self.self_test_active.store(false, Ordering::SeqCst);
+        result
+    }
+
+    fn gfi_self_test_sequence(&self) -> Result<(), PeripheralsError> {
+        if self.is_gfi_set() {
+            self.gfi_reset()?;
+        }
+        if !self.gfi_stays_clear(Duration::from_millis(50)) {
+            return Err(PeripheralsError::GfiSelfTestFailed("GFI does not clear"));
+        }
+
+        for _ in 0..GFI_TEST_CYCLES * 2 {
+            self.gfi.lock().test.toggle();
+            thread::sleep(GFI_TEST_HALF_PERIOD);
+        }
+        self.gfi.lock().test.set_low();
+
+        if !self.is_gfi_set() {
+            return Err(PeripheralsError::GfiSelfTestFailed("GFI not set by test"));
+        }
+
+        thread::sleep(Duration::from_millis(100));
+        if self.gfi_driver == GfiDriver::Momentary && self.gfi.lock().status.is_high() {
+            return Err(PeripheralsError::GfiSelfTestFailed("GFI does not reset itself"));
+        }
+        self.gfi_reset()?;
+        if self.is_gfi_set() {
+            return Err(PeripheralsError::GfiSelfTestFailed("GFI does not clear after test"));
+        }
+        if !self.gfi_stays_clear(Duration::from_millis(100)) {
+            return Err(PeripheralsError::GfiSelfTestFailed("GFI does not stay clear after test"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contactor_drive_validation() {
+        assert!(ContactorDrive::Full.validate().is_ok());
+
+        let drive = |pull_in_ms, hold_frequency, hold_duty_cycle| ContactorDrive::Economized {
+            pull_in: Duration::from_millis(pull_in_ms),
+            hold_frequency,
+            hold_duty_cycle,
+        };
+        assert!(drive(200, 1000.0, 0.4).validate().is_ok());
+        assert!(drive(200, 1000.0, 1.0).validate().is_ok());
+        // Too short to verify the pull-in first.
+        assert!(drive(50, 1000.0, 0.4).validate().is_err());
+        assert!(drive(200, 0.0, 0.4).validate().is_err());
+        assert!(drive(200, 1000.0, 0.0).validate().is_err());
+        assert!(drive(200, 1000.0, 1.5).validate().is_err());
+        assert!(drive(200, f64::NAN, 0.4).validate().is_err());
+    }
+
+    #[test]
+    fn test_gfi_driver_names() {
+        assert_eq!("latching".parse(), Ok(GfiDriver::Latching));
+        assert_eq!("momentary".parse(), Ok(GfiDriver::Momentary));
+        assert!("Momentary".parse::<GfiDriver>().is_err());
+    }
+
+    // On the mock GPIO, the relay test line reads whatever the test sets.
+    #[cfg(not(feature = "hardware"))]
+    #[test]
+    fn test_gfi_reset_refused_under_power() {
+        use crate::hal::gpio::set_level;
+
+        let peripherals = GpioPeripherals::new().unwrap();
+        assert!(peripherals.gfi_reset().is_ok());
+
+        // Measured on: a welded contactor.
+        set_level(RELAY_TEST_PIN, true);
+        assert!(matches!(peripherals.gfi_reset(), Err(PeripheralsError::GfiResetUnderPower)));
+
+        // Commanded on, whatever the relay test line reads.
+        peripherals.set_power(true).unwrap();
+        set_level(RELAY_TEST_PIN, false);
+        assert!(matches!(peripherals.gfi_reset(), Err(PeripheralsError::GfiResetUnderPower)));
+        // Nor does the self test reset a tripped GFI.
+        set_level(GFI_STATUS_PIN, true);
+        assert!(matches!(peripherals.run_gfi_self_test(), Err(PeripheralsError::GfiResetUnderPower)));
+        set_level(GFI_STATUS_PIN, false);
+
+        peripherals.set_power(false).unwrap();
+        assert!(peripherals.gfi_reset().is_ok());
+    }
+}
@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+// Detection of the phases a vehicle charges on, from the currents of the
+// three lines. Many vehicles charge on one phase only, so a power budget
+// divided by three phases leaves them with a third of what they could
+// draw. With the phases known, the load manager's power budget is split
+// over the phases actually in use. Until they are known, all three are
+// assumed, which keeps the power within the budget whatever the vehicle
+// does.
+
+// Phase voltage the power is reckoned at. The measured mains voltage
+// would move the offer with every reading.
+pub const NOMINAL_PHASE_VOLTS: f64 = 230.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhaseDetectionConfig {
+    // Current (A RMS) above which a line counts as in use. Well above
+    // what the CTs read on an idle line.
+    pub in_use_amps: f64,
+    // How long the lines have to agree before the phases count. The
+    // onboard charger may bring up its phases one after the other.
+    pub sustain: Duration,
+}
+
+impl Default for PhaseDetectionConfig {
+    fn default() -> Self {
+        Self {
+            in_use_amps: 1.0,
+            sustain: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PhaseStatus {
+    // None until detected.
+    pub phases_in_use: Option<u8>,
+    // The power the offer allows on the phases in use, or on all three
+    // before they are known.
+    pub usable_power_w: f64,
+    // The budget of the load manager, if one is set.
+    pub power_budget_w: Option<f64>,
+}
+
+pub struct PhaseDetector {
+    config: PhaseDetectionConfig,
+    phases: Option<u8>,
+    // Phases seen in use and since when.
+    candidate: Option<(u8, Duration)>,
+}
+
+impl PhaseDetector {
+    pub fn new(config: PhaseDetectionConfig) -> Self {
+        Self {
+            config,
+            phases: None,
+            candidate: None,
+        }
+    }
+
+    // Feeds the RMS currents of the three lines. `elapsed` is a monotonic
+    // time stamp. Returns the phases in use once detected.
+    pub fn update(&mut self, elapsed: Duration, line_amps: [f64; 3]) -> Option<u8> {
+        let in_use = line_amps.iter().filter(|&&amps| amps >= self.config.in_use_amps).count() as u8;
+        // No current says nothing about the phases, e.g. a paused vehicle.
+        if in_use == 0 {
+            self.candidate = None;
+            return self.phases;
+        }
+        match self.candidate {
+            Some((phases, since)) if phases == in_use => {
+                if elapsed.saturating_sub(since) >= self.config.sustain {
+                    self.phases = Some(phases);
+                }
+            }
+            _ => self.candidate = Some((in_use, elapsed)),
+        }
+        self.phases
+    }
+
+    pub fn phases(&self) -> Option<u8> {
+        self.phases
+    }
+
+    // Forgets the phases, e.g. when the vehicle is unplugged.
+    pub fn reset(&mut self) {
+        self.phases = None;
+        self.candidate = None;
+    }
+}
+
+// Assumes all three phases until they are known.
+fn phases_or_worst_case(phases: Option<u8>) -> f64 {
+    phases.unwrap_or(3) as f64
+}
+
+pub fn usable_power_w(offer_amps: f64, phase_volts: f64, phases: Option<u8>) -> f64 {
+    offer_amps * phase_volts * phases_or_worst_case(phases)
+}
+
+// The current per phase that keeps the vehicle within a power budget.
+pub fn budget_amps(budget_w: f64, phase_volts: f64, phases: Option<u8>) -> f64 {
+    budget_w / (phase_volts * phases_or_worst_case(phases))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_single_phase() {
+        let mut detector = PhaseDetector::new(PhaseDetectionConfig::default());
+        let s = Duration::from_secs;
+        assert_eq!(detector.update(s(0), [15.8, 0.1, 0.2]), None);
+        assert_eq!(detector.update(s(3), [0.0, 0.0, 0.0]), None);
+        assert_eq!(detector.update(s(4), [15.9, 0.1, 0.2]), None);
+        assert_eq!(detector.update(s(9), [15.9, 0.1, 0.2]), Some(1));
+
+        // A second phase coming up has to last too.
+        assert_eq!(detector.update(s(10), [15.9, 15.7, 0.2]), Some(1));
+        assert_eq!(detector.update(s(15), [15.9, 15.7, 0.2]), Some(2));
+        detector.reset();
+        assert_eq!(detector.phases(), None);
+    }
+
+    #[test]
+    fn test_budget() {
+        // 11 kW on three phases is 16 A; on one phase 16 A is only 3.7 kW.
+        assert!((budget_amps(11_040.0, 230.0, None) - 16.0).abs() < 1e-9);
+        assert!((budget_amps(3_680.0, 230.0, Some(1)) - 16.0).abs() < 1e-9);
+        assert!((usable_power_w(16.0, 230.0, Some(1)) - 3_680.0).abs() < 1e-9);
+        assert!((usable_power_w(16.0, 230.0, None) - 11_040.0).abs() < 1e-9);
+    }
+}
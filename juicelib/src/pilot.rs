@@ -1,41 +1,274 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use rppal::pwm::{Pwm, Error as PwmError, Channel};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hal::pwm::{Pwm, Error as PwmError, Channel};
+use crate::pilot_wave::{ReservedWave, WavePilot, DEFAULT_PIGPIO_ADDRESS};
+
+// Converts a current offer into the J1772 pilot duty cycle (0.0 to 1.0).
+// 6A to 51A map linearly to 10% to 85% (amps / 0.6), 51A to 80A to 85% to
+// 96% (amps / 2.5 + 64). Offers outside of that range are clamped.
+pub fn ampere_to_duty_cycle(ampere: f64) -> f64 {
+    let ampere = ampere.clamp(MIN_OFFER_AMPS, MAX_OFFER_AMPS);
+    let percent = if ampere <= 51.0 {
+        ampere / 0.6
+    } else {
+        ampere / 2.5 + 64.0
+    };
+    percent / 100.0
+}
+
+// The offer signalled by a duty cycle, the inverse of ampere_to_duty_cycle.
+// 8% to 10% count as 6A. None for a duty cycle that is not an offer: 5%
+// digital communication, steady levels and anything out of range.
+pub fn duty_cycle_to_ampere(duty_cycle: f64) -> Option<f64> {
+    let percent = duty_cycle * 100.0;
+    if !(8.0..=97.0).contains(&percent) {
+        return None;
+    }
+    let ampere = if percent <= 85.0 {
+        percent * 0.6
+    } else {
+        (percent - 64.0) * 2.5
+    };
+    Some(ampere.clamp(MIN_OFFER_AMPS, MAX_OFFER_AMPS))
+}
+
+// The lowest offer J1772 can signal.
+pub const MIN_OFFER_AMPS: f64 = 6.0;
+// The highest offer the duty cycle formula covers.
+const MAX_OFFER_AMPS: f64 = 80.0;
+
+// Whether an installation may have this maximum current: the pilot has to
+// be able to signal it.
+pub fn is_valid_max_current(ampere: f64) -> bool {
+    (MIN_OFFER_AMPS..=MAX_OFFER_AMPS).contains(&ampere)
+}
+pub const PILOT_FREQUENCY: f64 = 1000.0;
+
+// What the pilot signals to the vehicle.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PilotSignal {
+    // 1 kHz with the duty cycle for the offer. Offers below 6A cannot be
+    // signalled and are sent as SteadyPlus12 (no offer) - never as a 0%
+    // duty cycle, which the vehicle sees as ErrorMinus12.
+    OfferAmps(f64),
+    // No offer; a connected vehicle stays in state B.
+    SteadyPlus12,
+    // Error/fault state, the vehicle must not draw power.
+    ErrorMinus12,
+    // 5% duty cycle: the offer is negotiated over digital communication.
+    DigitalComm5Pct,
+    // Any duty cycle at any frequency. Only for the lab mode.
+    TestVector { duty_cycle: f64, frequency: f64 },
+}
+
+impl PilotSignal {
+    pub fn duty_cycle(&self) -> f64 {
+        match *self {
+            PilotSignal::OfferAmps(ampere) if ampere >= MIN_OFFER_AMPS => ampere_to_duty_cycle(ampere),
+            PilotSignal::OfferAmps(_) | PilotSignal::SteadyPlus12 => 1.0,
+            PilotSignal::ErrorMinus12 => 0.0,
+            PilotSignal::DigitalComm5Pct => 0.05,
+            PilotSignal::TestVector { duty_cycle, .. } => duty_cycle,
+        }
+    }
+
+    pub fn frequency(&self) -> f64 {
+        match *self {
+            PilotSignal::TestVector { frequency, .. } => frequency,
+            _ => PILOT_FREQUENCY,
+        }
+    }
+}
+
+// Assignment of the two hardware PWM channels. The pilot always needs one
+// of them. Boards that route the power watchdog line to the other PWM
+// channel can have the watchdog generated in hardware as well; without a
+// watchdog channel it is toggled in software.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PwmAssignment {
+    pilot: Channel,
+    watchdog: Option<Channel>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PwmAssignmentError {
+    ChannelClaimedTwice(Channel),
+}
+
+impl PwmAssignment {
+    pub fn new(pilot: Channel, watchdog: Option<Channel>) -> Result<Self, PwmAssignmentError> {
+        if watchdog == Some(pilot) {
+            return Err(PwmAssignmentError::ChannelClaimedTwice(pilot));
+        }
+        Ok(Self { pilot, watchdog })
+    }
+
+    pub fn pilot(&self) -> Channel {
+        self.pilot
+    }
+
+    pub fn watchdog(&self) -> Option<Channel> {
+        self.watchdog
+    }
+}
+
+impl Default for PwmAssignment {
+    // The EVSE Pi Hat wiring: pilot on PWM0 (GPIO 18), software watchdog.
+    fn default() -> Self {
+        Self {
+            pilot: Channel::Pwm0,
+            watchdog: None,
+        }
+    }
+}
+
+// What generates the pilot. The hardware PWM is the default; on Pis whose
+// PWM is claimed by the audio, pigpio's DMA-timed waves stand in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PilotDriver {
+    HardwarePwm(Channel),
+    PigpioWave { address: String, gpio: u8 },
+}
+
+impl FromStr for PilotDriver {
+    type Err = String;
+
+    // "pwm0", "pwm1" or "pigpio:<gpio>".
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.split_once(':') {
+            None if name == "pwm0" => Ok(PilotDriver::HardwarePwm(Channel::Pwm0)),
+            None if name == "pwm1" => Ok(PilotDriver::HardwarePwm(Channel::Pwm1)),
+            Some(("pigpio", gpio)) => match gpio.parse() {
+                Ok(gpio) if gpio < 32 => Ok(PilotDriver::PigpioWave {
+                    address: DEFAULT_PIGPIO_ADDRESS.to_string(),
+                    gpio,
+                }),
+                _ => Err(format!("invalid pilot GPIO {}", gpio)),
+            },
+            _ => Err(format!("unknown pilot driver {}", name)),
+        }
+    }
+}
+
+fn wave_error(error: std::io::Error) -> PwmError {
+    PwmError::Io(error)
+}
+
+enum Generator {
+    Pwm(Arc<Pwm>),
+    Wave { pilot: WavePilot, address: String },
+}
 
 pub struct Pilot {
-    pwm: Pwm,
+    generator: Generator,
+    // Only test vectors change it.
+    frequency: f64,
+}
+
+// Second handle to the pilot, reserved for the control watchdog. It only
+// ever takes the offer away.
+#[derive(Clone)]
+pub struct ReservedPilot {
+    generator: ReservedGenerator,
+}
+
+#[derive(Clone)]
+enum ReservedGenerator {
+    Pwm(Arc<Pwm>),
+    // Its own connection to pigpiod; the lock is only shared by clones.
+    Wave(Arc<Mutex<ReservedWave>>),
+}
+
+impl ReservedPilot {
+    pub fn force_no_offer(&self) -> Result<(), PwmError> {
+        match &self.generator {
+            ReservedGenerator::Pwm(pwm) => pwm.set_duty_cycle(PilotSignal::SteadyPlus12.duty_cycle()),
+            ReservedGenerator::Wave(wave) => {
+                let mut wave = wave.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                wave.force_no_offer().map_err(wave_error)
+            }
+        }
+    }
 }
 
 impl Pilot {
     pub fn new() -> Result<Self, PwmError> {
-        let pwm = Pwm::new(Channel::Pwm0)?;
+        Self::with_channel(Channel::Pwm0)
+    }
+
+    pub fn with_channel(channel: Channel) -> Result<Self, PwmError> {
+        let pwm = Pwm::new(channel)?;
         pwm.set_period(Duration::from_millis(1))?;
         pwm.enable()?;
 
         Ok(Self {
-            pwm,
+            generator: Generator::Pwm(Arc::new(pwm)),
+            frequency: PILOT_FREQUENCY,
         })
     }
 
-    pub fn set_to_waiting_for_vehicle(&mut self) -> Result<(), PwmError> {
-        // Setting the dc to 1.0 will cause the pilot to go to +12V constant
-        // which is the waiting for vehicle state.
-        self.pwm.set_duty_cycle(1.01 as f64)?;
+    // Fails if pigpio's timing does not meet the pilot tolerance.
+    pub fn with_driver(driver: PilotDriver) -> Result<Self, PwmError> {
+        match driver {
+            PilotDriver::HardwarePwm(channel) => Self::with_channel(channel),
+            PilotDriver::PigpioWave { address, gpio } => {
+                let (pilot, report) = WavePilot::start(&address, gpio).map_err(wave_error)?;
+                eprintln!(
+                    "pigpio pilot on GPIO {}: {:.2} Hz, {:.1} us jitter",
+                    gpio, report.frequency, report.max_jitter_us
+                );
+                Ok(Self {
+                    generator: Generator::Wave { pilot, address },
+                    frequency: PILOT_FREQUENCY,
+                })
+            }
+        }
+    }
+
+    pub fn reserved_handle(&self) -> Result<ReservedPilot, PwmError> {
+        let generator = match &self.generator {
+            Generator::Pwm(pwm) => ReservedGenerator::Pwm(pwm.clone()),
+            Generator::Wave { pilot, address } => {
+                ReservedGenerator::Wave(Arc::new(Mutex::new(pilot.reserved_handle(address).map_err(wave_error)?)))
+            }
+        };
+        Ok(ReservedPilot { generator })
+    }
+
+    pub fn set_signal(&mut self, signal: PilotSignal) -> Result<(), PwmError> {
+        match &mut self.generator {
+            Generator::Pwm(pwm) if signal.frequency() != self.frequency => {
+                pwm.set_frequency(signal.frequency(), signal.duty_cycle())?;
+            }
+            Generator::Pwm(pwm) => pwm.set_duty_cycle(signal.duty_cycle())?,
+            Generator::Wave { pilot, .. } => {
+                pilot.set(signal.frequency(), signal.duty_cycle()).map_err(wave_error)?
+            }
+        }
+        self.frequency = signal.frequency();
 
         Ok(())
     }
 
+    pub fn set_to_waiting_for_vehicle(&mut self) -> Result<(), PwmError> {
+        self.set_signal(PilotSignal::SteadyPlus12)
+    }
+
     pub fn set_duty_cycle(&mut self, duty_cycle: f64) -> Result<(), PwmError> {
-        self.pwm.set_duty_cycle(duty_cycle)?;
+        match &mut self.generator {
+            Generator::Pwm(pwm) => pwm.set_duty_cycle(duty_cycle)?,
+            Generator::Wave { pilot, .. } => pilot.set(self.frequency, duty_cycle).map_err(wave_error)?,
+        }
 
         Ok(())
     }
 
     pub fn set_to_error(&mut self) -> Result<(), PwmError> {
-        // Setting the dc to 0 will cause the pilot to go to -12V which is
-        // the error state.
-        self.pwm.set_duty_cycle(0 as f64)?;
-
-        Ok(())
+        self.set_signal(PilotSignal::ErrorMinus12)
     }
 }
 
@@ -43,21 +276,97 @@ impl Pilot {
 mod tests {
     use super::*;
 
+    fn pwm(pilot: &Pilot) -> &Pwm {
+        match &pilot.generator {
+            Generator::Pwm(pwm) => pwm,
+            Generator::Wave { .. } => panic!("not a hardware PWM pilot"),
+        }
+    }
+
+    #[test]
+    fn test_ampere_to_duty_cycle() {
+        assert!((ampere_to_duty_cycle(6.0) - 0.1).abs() < 1e-9);
+        assert!((ampere_to_duty_cycle(32.0) - 0.5333).abs() < 1e-4);
+        assert!((ampere_to_duty_cycle(51.0) - 0.85).abs() < 1e-9);
+        assert!((ampere_to_duty_cycle(80.0) - 0.96).abs() < 1e-9);
+        assert_eq!(ampere_to_duty_cycle(0.0), ampere_to_duty_cycle(6.0));
+        assert_eq!(ampere_to_duty_cycle(100.0), ampere_to_duty_cycle(80.0));
+    }
+
+    #[test]
+    fn test_duty_cycle_to_ampere() {
+        for ampere in [6.0, 16.0, 32.0, 51.0, 60.0, 80.0] {
+            let offered = duty_cycle_to_ampere(ampere_to_duty_cycle(ampere)).unwrap();
+            assert!((offered - ampere).abs() < 1e-9, "{} A read back as {} A", ampere, offered);
+        }
+        assert_eq!(duty_cycle_to_ampere(0.09), Some(6.0));
+        assert_eq!(duty_cycle_to_ampere(0.05), None);
+        assert_eq!(duty_cycle_to_ampere(1.0), None);
+        assert_eq!(duty_cycle_to_ampere(f64::NAN), None);
+    }
+
+    #[test]
+    fn test_pilot_signal_duty_cycle() {
+        assert_eq!(PilotSignal::OfferAmps(6.0).duty_cycle(), ampere_to_duty_cycle(6.0));
+        // Offering nothing must not look like the error state.
+        assert_eq!(PilotSignal::OfferAmps(0.0).duty_cycle(), 1.0);
+        assert_eq!(PilotSignal::OfferAmps(5.9).duty_cycle(), 1.0);
+        assert_eq!(PilotSignal::OfferAmps(f64::NAN).duty_cycle(), 1.0);
+        assert_eq!(PilotSignal::SteadyPlus12.duty_cycle(), 1.0);
+        assert_eq!(PilotSignal::ErrorMinus12.duty_cycle(), 0.0);
+        assert_eq!(PilotSignal::DigitalComm5Pct.duty_cycle(), 0.05);
+    }
+
     #[test]
+    fn test_pwm_assignment() {
+        let assignment = PwmAssignment::new(Channel::Pwm1, Some(Channel::Pwm0)).unwrap();
+        assert_eq!(assignment.pilot(), Channel::Pwm1);
+        assert_eq!(assignment.watchdog(), Some(Channel::Pwm0));
+        assert!(PwmAssignment::new(Channel::Pwm1, None).is_ok());
+        assert_eq!(PwmAssignment::default().pilot(), Channel::Pwm0);
+    }
+
+    #[test]
+    fn test_pwm_channel_claimed_twice() {
+        assert_eq!(
+            PwmAssignment::new(Channel::Pwm0, Some(Channel::Pwm0)),
+            Err(PwmAssignmentError::ChannelClaimedTwice(Channel::Pwm0))
+        );
+        assert_eq!(
+            PwmAssignment::new(Channel::Pwm1, Some(Channel::Pwm1)),
+            Err(PwmAssignmentError::ChannelClaimedTwice(Channel::Pwm1))
+        );
+    }
+
+    #[test]
+    fn test_pilot_driver() {
+        assert_eq!("pwm1".parse(), Ok(PilotDriver::HardwarePwm(Channel::Pwm1)));
+        assert_eq!(
+            "pigpio:12".parse(),
+            Ok(PilotDriver::PigpioWave {
+                address: DEFAULT_PIGPIO_ADDRESS.to_string(),
+                gpio: 12
+            })
+        );
+        assert!("pigpio:40".parse::<PilotDriver>().is_err());
+        assert!("pwm2".parse::<PilotDriver>().is_err());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "hardware"), ignore = "needs the PWM output on a scope")]
     fn test_set_to_waiting_for_vehicle() -> Result<(), PwmError> {
         let mut pilot = Pilot::new()?;
         pilot.set_to_waiting_for_vehicle()?;
-        assert_eq!(pilot.pwm.duty_cycle().unwrap(), 1.0);
+        assert_eq!(pwm(&pilot).duty_cycle().unwrap(), 1.0);
         // TODO: Measure the PWM using an oscilloscope
         panic!("TODO: Measure the PWM using an oscilloscope");
-        Ok(())
     }
 
     #[test]
     fn test_set_duty_cycle() -> Result<(), PwmError> {
         let mut pilot = Pilot::new()?;
         pilot.set_duty_cycle(0.5)?;
-        assert_eq!(pilot.pwm.duty_cycle().unwrap(), 0.5);
+        assert_eq!(pwm(&pilot).duty_cycle().unwrap(), 0.5);
         Ok(())
     }
 
@@ -65,7 +374,7 @@ mod tests {
     fn test_set_to_error() -> Result<(), PwmError> {
         let mut pilot = Pilot::new()?;
         pilot.set_to_error()?;
-        assert_eq!(pilot.pwm.duty_cycle().unwrap(), 0.0);
+        assert_eq!(pwm(&pilot).duty_cycle().unwrap(), 0.0);
         Ok(())
     }
 }
\ No newline at end of file
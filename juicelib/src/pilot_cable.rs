@@ -0,0 +1,228 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::evse::{EVSEMachineInput, EDGE_6V_3V, EDGE_9V_6V};
+use crate::store::Store;
+
+// Pilot cable diagnostics. A long cable or worn, corroded contacts add
+// resistance to the pilot circuit, and the vehicle's levels read low: 9 V
+// as 8.2 V and so on, until the classifier puts them into the next state
+// and sessions break. The resistance in series with the vehicle is
+// estimated from the levels of states B and C, whose vehicle resistances
+// J1772 fixes, with the state A level as the source voltage. Each session
+// gives one estimate. The first sessions are the baseline; a warning goes
+// into the audit log when the recent sessions have drifted away from it or
+// the levels come close to the edges of their bands.
+
+// The pilot source resistor and the diode in the vehicle.
+const SOURCE_OHMS: f64 = 1000.0;
+const DIODE_VOLTS: f64 = 0.7;
+// 2.74 kOhm in state B, 1.3 kOhm in parallel in state C.
+const STATE_B_OHMS: f64 = 2740.0;
+const STATE_C_OHMS: f64 = 882.0;
+const NOMINAL_SOURCE_VOLTS: f64 = 12.0;
+
+// Readings a session needs for an estimate.
+const MIN_SESSION_READINGS: usize = 10;
+const BASELINE_SESSIONS: usize = 10;
+const RECENT_SESSIONS: usize = 5;
+// Vehicles differ by about 100 Ohm, their resistors have a 3% tolerance.
+pub const DRIFT_WARNING_OHMS: f64 = 150.0;
+// Above the lower edge of the band of state B and C.
+pub const MARGIN_WARNING_VOLTS: f64 = 0.5;
+
+// The resistance in series with the vehicle that makes the level `high`.
+// None for levels no resistance explains.
+pub fn series_ohms(source_volts: f64, vehicle_ohms: f64, high: f64) -> Option<f64> {
+    if high <= DIODE_VOLTS || high >= source_volts {
+        return None;
+    }
+    let ohms = (source_volts - DIODE_VOLTS) * vehicle_ohms / (high - DIODE_VOLTS) - SOURCE_OHMS - vehicle_ohms;
+    Some(ohms.max(0.0))
+}
+
+// The level a vehicle makes with the resistance in series.
+pub fn expected_high(source_volts: f64, vehicle_ohms: f64, series_ohms: f64) -> f64 {
+    DIODE_VOLTS + (source_volts - DIODE_VOLTS) * vehicle_ohms / (SOURCE_OHMS + series_ohms + vehicle_ohms)
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct CableHealth {
+    // Sessions with an estimate.
+    pub sessions: usize,
+    // None until there are enough sessions.
+    pub baseline_ohms: Option<f64>,
+    pub recent_ohms: Option<f64>,
+    // How far the levels of state B and C are expected above the lower
+    // edges of their bands, the smaller of the two.
+    pub margin_volts: Option<f64>,
+    pub worn: bool,
+}
+
+pub struct CableDiagnostics {
+    // One per session, oldest first.
+    estimates: Vec<f64>,
+    // Where the estimates are kept. None keeps them in memory only.
+    store_path: Option<PathBuf>,
+    // The last state A level.
+    source_volts: f64,
+    session: Vec<f64>,
+}
+
+impl CableDiagnostics {
+    pub fn new(store_path: Option<PathBuf>) -> Self {
+        let estimates = match store_path.as_deref().map(|path| Store::open(path)?.cable_estimates()) {
+            Some(Ok(estimates)) => estimates,
+            Some(Err(error)) => {
+                eprintln!("Pilot cable estimates not loaded: {}", error);
+                Vec::new()
+            }
+            None => Vec::new(),
+        };
+        Self {
+            estimates,
+            store_path,
+            source_volts: NOMINAL_SOURCE_VOLTS,
+            session: Vec::new(),
+        }
+    }
+
+    // Feeds a classified pilot reading.
+    pub fn observe(&mut self, input: EVSEMachineInput, high: f32) {
+        let high = high as f64;
+        if !high.is_finite() {
+            return;
+        }
+        let vehicle_ohms = match input {
+            EVSEMachineInput::PilotIs12V => {
+                self.source_volts = high;
+                return;
+            }
+            EVSEMachineInput::PilotIs9V => STATE_B_OHMS,
+            EVSEMachineInput::PilotIs6V => STATE_C_OHMS,
+            _ => return,
+        };
+        if let Some(ohms) = series_ohms(self.source_volts, vehicle_ohms, high) {
+            self.session.push(ohms);
+        }
+    }
+
+    // Takes the estimate of the session that ended. Returns the warning
+    // for the audit log if the cable looks worn.
+    pub fn session_ended(&mut self, unix_time: u64) -> Option<String> {
+        let mut session = std::mem::take(&mut self.session);
+        if session.len() < MIN_SESSION_READINGS {
+            return None;
+        }
+        // The median, so the readings around the transitions do not count.
+        session.sort_by(f64::total_cmp);
+        let estimate = session[session.len() / 2];
+        self.estimates.push(estimate);
+        if let Some(Err(error)) = self
+            .store_path
+            .as_deref()
+            .map(|path| Store::open(path)?.record_cable_estimate(unix_time, estimate))
+        {
+            eprintln!("Pilot cable estimate not stored: {}", error);
+        }
+
+        let health = self.health();
+        health.worn.then(|| {
+            format!(
+                "pilot circuit resistance up to {:.0} Ohm from {:.0} Ohm, {:.1} V margin left, check the cable and \
+                 connector",
+                health.recent_ohms.unwrap_or(estimate),
+                health.baseline_ohms.unwrap_or(0.0),
+                health.margin_volts.unwrap_or(0.0)
+            )
+        })
+    }
+
+    pub fn health(&self) -> CableHealth {
+        let baseline_ohms = (self.estimates.len() >= BASELINE_SESSIONS)
+            .then(|| mean(&self.estimates[..BASELINE_SESSIONS]))
+            .flatten();
+        let recent_ohms = mean(&self.estimates[self.estimates.len().saturating_sub(RECENT_SESSIONS)..]);
+        let margin_volts = recent_ohms.map(|ohms| {
+            let margin_b = expected_high(self.source_volts, STATE_B_OHMS, ohms) - EDGE_9V_6V as f64;
+            let margin_c = expected_high(self.source_volts, STATE_C_OHMS, ohms) - EDGE_6V_3V as f64;
+            margin_b.min(margin_c)
+        });
+        let drifted = matches!((baseline_ohms, recent_ohms), (Some(baseline), Some(recent))
+            if recent - baseline > DRIFT_WARNING_OHMS);
+        CableHealth {
+            sessions: self.estimates.len(),
+            baseline_ohms,
+            recent_ohms,
+            margin_volts,
+            worn: drifted || margin_volts.is_some_and(|margin| margin < MARGIN_WARNING_VOLTS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(diagnostics: &mut CableDiagnostics, series: f64) -> Option<String> {
+        diagnostics.observe(EVSEMachineInput::PilotIs12V, 12.0);
+        for _ in 0..MIN_SESSION_READINGS {
+            diagnostics.observe(
+                EVSEMachineInput::PilotIs9V,
+                expected_high(12.0, STATE_B_OHMS, series) as f32,
+            );
+            diagnostics.observe(
+                EVSEMachineInput::PilotIs6V,
+                expected_high(12.0, STATE_C_OHMS, series) as f32,
+            );
+        }
+        diagnostics.session_ended(0)
+    }
+
+    #[test]
+    fn test_series_ohms() {
+        // The nominal levels need no resistance.
+        assert!(series_ohms(12.0, STATE_B_OHMS, 8.98).unwrap() < 5.0);
+        assert!((series_ohms(12.0, STATE_B_OHMS, 8.2).unwrap() - 388.0).abs() < 1.0);
+        assert_eq!(series_ohms(12.0, STATE_B_OHMS, 12.0), None);
+        let ohms = series_ohms(11.8, STATE_C_OHMS, 5.5).unwrap();
+        assert!((expected_high(11.8, STATE_C_OHMS, ohms) - 5.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_drift_warns() {
+        let mut diagnostics = CableDiagnostics::new(None);
+        for _ in 0..BASELINE_SESSIONS {
+            assert_eq!(session(&mut diagnostics, 20.0), None);
+        }
+        let health = diagnostics.health();
+        assert!((health.baseline_ohms.unwrap() - 20.0).abs() < 1.0);
+        assert!(!health.worn);
+
+        // A short session says nothing.
+        diagnostics.observe(EVSEMachineInput::PilotIs9V, 6.0);
+        assert_eq!(diagnostics.session_ended(0), None);
+        assert_eq!(diagnostics.health().sessions, BASELINE_SESSIONS);
+
+        for _ in 0..RECENT_SESSIONS {
+            session(&mut diagnostics, 250.0);
+        }
+        let health = diagnostics.health();
+        assert!(health.worn);
+        assert!(health.margin_volts.unwrap() > MARGIN_WARNING_VOLTS);
+        assert!(session(&mut diagnostics, 250.0).is_some());
+    }
+
+    #[test]
+    fn test_margin_warns_without_baseline() {
+        let mut diagnostics = CableDiagnostics::new(None);
+        assert_eq!(session(&mut diagnostics, 100.0), None);
+        assert!(session(&mut diagnostics, 900.0).is_some());
+        assert!(diagnostics.health().baseline_ohms.is_none());
+    }
+}
@@ -0,0 +1,264 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::pilot::PILOT_FREQUENCY;
+
+// Pilot generated with DMA-timed GPIO waves of the pigpio daemon, for Pis
+// whose hardware PWM is taken by the audio. pigpiod has to run with the
+// PCM clock (pigpiod -t 1) for the same reason. The waves are built from
+// microsecond pulses, which is 0.1% duty cycle resolution at 1 kHz.
+//
+// Before it is used, the pilot is run at 50% and its edges timed through
+// pigpio's notifications. It refuses to start if the frequency is off by
+// more than J1772's 0.5% or single periods jitter more than that.
+//
+// The daemon is spoken to over its socket interface. A new wave takes
+// over at the end of the cycle of the old one, so a change of duty cycle
+// never makes a short pulse.
+
+pub const DEFAULT_PIGPIO_ADDRESS: &str = "127.0.0.1:8888";
+
+const FREQUENCY_TOLERANCE: f64 = 0.005;
+// Of the period. pigpio samples the levels every 5 µs by default, so the
+// periods it reports are only good to that.
+const MAX_JITTER_US: f64 = 5.0;
+const MEASURED_CYCLES: usize = 200;
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Commands of the socket interface.
+const MODES: u32 = 0;
+const WRITE: u32 = 4;
+const NB: u32 = 19;
+const NC: u32 = 21;
+const WVAG: u32 = 28;
+const WVHLT: u32 = 33;
+const WVCRE: u32 = 49;
+const WVDEL: u32 = 50;
+const NOIB: u32 = 99;
+const WVTXM: u32 = 100;
+const OUTPUT: u32 = 1;
+const WAVE_MODE_REPEAT_SYNC: u32 = 3;
+// Size of a level change report on the notification socket.
+const REPORT_SIZE: usize = 12;
+
+fn pigpio_error(command: u32, code: i32) -> io::Error {
+    io::Error::other(format!("pigpio command {} failed with {}", command, code))
+}
+
+struct PigpioClient {
+    stream: TcpStream,
+}
+
+impl PigpioClient {
+    fn connect(address: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(SOCKET_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream })
+    }
+
+    // Sends a command and returns its result.
+    fn command(&mut self, command: u32, p1: u32, p2: u32, extension: &[u8]) -> io::Result<u32> {
+        let mut request = Vec::with_capacity(16 + extension.len());
+        for word in [command, p1, p2, extension.len() as u32] {
+            request.extend_from_slice(&word.to_le_bytes());
+        }
+        request.extend_from_slice(extension);
+        self.stream.write_all(&request)?;
+
+        let mut response = [0u8; 16];
+        self.stream.read_exact(&mut response)?;
+        let result = i32::from_le_bytes([response[12], response[13], response[14], response[15]]);
+        if result < 0 {
+            return Err(pigpio_error(command, result));
+        }
+        Ok(result as u32)
+    }
+
+    fn write_level(&mut self, gpio: u8, high: bool) -> io::Result<()> {
+        self.command(WVHLT, 0, 0, &[])?;
+        self.command(WRITE, gpio as u32, high as u32, &[])?;
+        Ok(())
+    }
+}
+
+// The high and low time of one cycle in whole microseconds. None for the
+// steady levels.
+fn pulse_lengths(frequency: f64, duty_cycle: f64) -> Option<(u32, u32)> {
+    let period = (1_000_000.0 / frequency).round() as u32;
+    let high = (period as f64 * duty_cycle).round() as u32;
+    (high > 0 && high < period).then_some((high, period - high))
+}
+
+pub struct WavePilot {
+    client: PigpioClient,
+    gpio: u8,
+    wave: Option<u32>,
+}
+
+impl WavePilot {
+    // Claims the GPIO and checks the timing. Fails if it is not good
+    // enough for a pilot.
+    pub fn start(address: &str, gpio: u8) -> io::Result<(Self, JitterReport)> {
+        let mut client = PigpioClient::connect(address)?;
+        client.command(MODES, gpio as u32, OUTPUT, &[])?;
+        let mut pilot = Self {
+            client,
+            gpio,
+            wave: None,
+        };
+        pilot.set(PILOT_FREQUENCY, 0.5)?;
+        let report = measure_jitter(address, gpio, MEASURED_CYCLES);
+        // The pilot must not be left running on a failed check.
+        pilot.set(PILOT_FREQUENCY, 1.0)?;
+        let report = report?;
+        if !report.meets_tolerance() {
+            return Err(io::Error::other(format!(
+                "pigpio pilot timing out of tolerance: {:.1} Hz, {:.1} us jitter",
+                report.frequency, report.max_jitter_us
+            )));
+        }
+        Ok((pilot, report))
+    }
+
+    // A duty cycle of 0 or 1 holds the line low or high.
+    pub fn set(&mut self, frequency: f64, duty_cycle: f64) -> io::Result<()> {
+        let Some((high, low)) = pulse_lengths(frequency, duty_cycle) else {
+            self.client.write_level(self.gpio, duty_cycle >= 1.0)?;
+            return self.delete_wave();
+        };
+        let mask = 1u32 << self.gpio;
+        let mut pulses = Vec::with_capacity(24);
+        for (on, off, delay) in [(mask, 0, high), (0, mask, low)] {
+            for word in [on, off, delay] {
+                pulses.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        self.client.command(WVAG, 0, 0, &pulses)?;
+        let wave = self.client.command(WVCRE, 0, 0, &[])?;
+        self.client.command(WVTXM, wave, WAVE_MODE_REPEAT_SYNC, &[])?;
+        self.delete_wave()?;
+        self.wave = Some(wave);
+        Ok(())
+    }
+
+    fn delete_wave(&mut self) -> io::Result<()> {
+        if let Some(wave) = self.wave.take() {
+            self.client.command(WVDEL, wave, 0, &[])?;
+        }
+        Ok(())
+    }
+
+    // A handle with its own connection, for the control watchdog.
+    pub fn reserved_handle(&self, address: &str) -> io::Result<ReservedWave> {
+        Ok(ReservedWave {
+            client: PigpioClient::connect(address)?,
+            gpio: self.gpio,
+        })
+    }
+}
+
+pub struct ReservedWave {
+    client: PigpioClient,
+    gpio: u8,
+}
+
+impl ReservedWave {
+    // Stops the wave and holds the line high: no offer.
+    pub fn force_no_offer(&mut self) -> io::Result<()> {
+        self.client.write_level(self.gpio, true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct JitterReport {
+    pub cycles: usize,
+    pub frequency: f64,
+    // Largest deviation of a single period from the mean.
+    pub max_jitter_us: f64,
+}
+
+impl JitterReport {
+    // From the ticks (µs) of consecutive rising edges.
+    pub fn from_rising_edges(ticks: &[u32]) -> Option<Self> {
+        let periods: Vec<f64> = ticks.windows(2).map(|pair| pair[1].wrapping_sub(pair[0]) as f64).collect();
+        if periods.is_empty() {
+            return None;
+        }
+        let mean = periods.iter().sum::<f64>() / periods.len() as f64;
+        Some(Self {
+            cycles: periods.len(),
+            frequency: 1_000_000.0 / mean,
+            max_jitter_us: periods.iter().map(|period| (period - mean).abs()).fold(0.0, f64::max),
+        })
+    }
+
+    pub fn meets_tolerance(&self) -> bool {
+        (self.frequency - PILOT_FREQUENCY).abs() <= FREQUENCY_TOLERANCE * PILOT_FREQUENCY
+            && self.max_jitter_us <= MAX_JITTER_US
+    }
+}
+
+// Times the rising edges of the GPIO through a notification socket.
+fn measure_jitter(address: &str, gpio: u8, cycles: usize) -> io::Result<JitterReport> {
+    let mut notifications = PigpioClient::connect(address)?;
+    let handle = notifications.command(NOIB, 0, 0, &[])?;
+    let mut control = PigpioClient::connect(address)?;
+    control.command(NB, handle, 1 << gpio, &[])?;
+
+    let mut rising_edges = Vec::with_capacity(cycles + 1);
+    let mut last_level = None;
+    let mut report = [0u8; REPORT_SIZE];
+    let result = loop {
+        if rising_edges.len() > cycles {
+            break Ok(());
+        }
+        if let Err(error) = notifications.stream.read_exact(&mut report) {
+            break Err(error);
+        }
+        let tick = u32::from_le_bytes([report[4], report[5], report[6], report[7]]);
+        let levels = u32::from_le_bytes([report[8], report[9], report[10], report[11]]);
+        let level = levels & (1 << gpio) != 0;
+        if level && last_level == Some(false) {
+            rising_edges.push(tick);
+        }
+        last_level = Some(level);
+    };
+    let _ = control.command(NC, handle, 0, &[]);
+    result?;
+    JitterReport::from_rising_edges(&rising_edges).ok_or_else(|| io::Error::other("no pilot edges seen"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pulse_lengths() {
+        assert_eq!(pulse_lengths(1000.0, 0.5), Some((500, 500)));
+        assert_eq!(pulse_lengths(1000.0, 0.1), Some((100, 900)));
+        assert_eq!(pulse_lengths(1020.0, 0.25), Some((245, 735)));
+        assert_eq!(pulse_lengths(1000.0, 1.0), None);
+        assert_eq!(pulse_lengths(1000.0, 0.0), None);
+    }
+
+    #[test]
+    fn test_jitter_report() {
+        // The tick counter wraps around between the second and third edge.
+        let ticks = [u32::MAX - 999, u32::MAX, 1000 - 1 + 3, 2002 + 1];
+        let report = JitterReport::from_rising_edges(&ticks).unwrap();
+        assert_eq!(report.cycles, 3);
+        assert!((report.frequency - 1000.0).abs() < 1.0);
+        assert!(report.max_jitter_us <= 3.0);
+        assert!(report.meets_tolerance());
+
+        let slow = JitterReport::from_rising_edges(&[0, 1010, 2020]).unwrap();
+        assert!(!slow.meets_tolerance());
+        let jittery = JitterReport::from_rising_edges(&[0, 990, 2000]).unwrap();
+        assert!(!jittery.meets_tolerance());
+        assert_eq!(JitterReport::from_rising_edges(&[5]), None);
+    }
+}
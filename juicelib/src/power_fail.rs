@@ -0,0 +1,114 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Support for running from a small UPS hat. When the hat reports that mains
+// power is gone the machine opens the contactor and stops; this module
+// records what was going on so the next boot can pick up cleanly, and
+// shuts the OS down before the battery runs flat.
+
+pub const DEFAULT_RECORD_PATH: &str = "/var/lib/juiced/power-fail";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerFailRecord {
+    // A vehicle was charging when the power went away.
+    pub was_charging: bool,
+    pub unix_time: u64,
+}
+
+impl PowerFailRecord {
+    pub fn new(was_charging: bool) -> Self {
+        let unix_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self {
+            was_charging,
+            unix_time,
+        }
+    }
+
+    // Writes the record to a temporary file first and renames it, so a
+    // record is either complete or not there at all.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        write!(file, "was_charging={}\nunix_time={}\n", self.was_charging, self.unix_time)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let mut was_charging = None;
+        let mut unix_time = None;
+        for line in text.lines() {
+            match line.split_once('=')? {
+                ("was_charging", value) => was_charging = Some(value.parse().ok()?),
+                ("unix_time", value) => unix_time = Some(value.parse().ok()?),
+                _ => return None,
+            }
+        }
+        Some(Self {
+            was_charging: was_charging?,
+            unix_time: unix_time?,
+        })
+    }
+
+    // Reads and removes the record. Returns None if there is no (readable)
+    // record, i.e. the last shutdown was not caused by a power failure.
+    pub fn take(path: &Path) -> Option<Self> {
+        let text = fs::read_to_string(path).ok()?;
+        let _ = fs::remove_file(path);
+        Self::parse(&text)
+    }
+}
+
+// Asks systemd to power the system off. Storage is flushed as part of the
+// regular shutdown.
+pub fn request_os_shutdown() -> io::Result<()> {
+    let status = Command::new("systemctl").arg("poweroff").status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("systemctl poweroff failed: {}", status)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_roundtrip() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("juicelib-power-fail-{}", std::process::id()));
+        let path = dir.join("power-fail");
+        let record = PowerFailRecord::new(true);
+        record.save(&path)?;
+
+        assert_eq!(PowerFailRecord::take(&path), Some(record));
+        // Taking the record removes it.
+        assert_eq!(PowerFailRecord::take(&path), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rejects_garbage() {
+        assert_eq!(PowerFailRecord::parse("was_charging=maybe\nunix_time=1\n"), None);
+        assert_eq!(PowerFailRecord::parse("was_charging=true\n"), None);
+        assert_eq!(
+            PowerFailRecord::parse("was_charging=false\nunix_time=7\n"),
+            Some(PowerFailRecord {
+                was_charging: false,
+                unix_time: 7
+            })
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+// Power quality monitor. Records an event whenever the RMS voltage or the
+// mains frequency leaves its band, with when it happened, how long it
+// lasted and how far it went. Helps with complaints like "charging stops
+// every evening at 19:00", which are often the supply sagging under the
+// neighbourhood's evening load.
+
+pub const DEFAULT_POWER_QUALITY_LOG_PATH: &str = "/var/lib/juiced/power-quality.log";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    pub low: f64,
+    pub high: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerQualityConfig {
+    // RMS volts.
+    pub voltage: Band,
+    // Hz.
+    pub frequency: Band,
+    // Excursions shorter than this are not recorded.
+    pub min_duration: Duration,
+}
+
+impl Default for PowerQualityConfig {
+    // 240V +/-10% and 60Hz +/-0.5Hz.
+    fn default() -> Self {
+        Self {
+            voltage: Band { low: 216.0, high: 264.0 },
+            frequency: Band { low: 59.5, high: 60.5 },
+            min_duration: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PowerQualityKind {
+    Sag,
+    Swell,
+    UnderFrequency,
+    OverFrequency,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PowerQualityEvent {
+    pub kind: PowerQualityKind,
+    // Start of the event.
+    pub unix_time: u64,
+    pub duration_ms: u64,
+    // Lowest voltage or frequency of a sag or under frequency event,
+    // highest of a swell or over frequency event.
+    pub extreme: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Excursion {
+    kind: PowerQualityKind,
+    start: SystemTime,
+    extreme: f64,
+}
+
+// Tracks one quantity against its band.
+#[derive(Debug, Default)]
+struct BandTracker {
+    excursion: Option<Excursion>,
+}
+
+impl BandTracker {
+    // Returns the excursion that ended with this value, if any.
+    fn update(
+        &mut self,
+        at: SystemTime,
+        value: f64,
+        band: Band,
+        kinds: (PowerQualityKind, PowerQualityKind),
+    ) -> Option<Excursion> {
+        // A failed measurement neither starts nor ends an excursion.
+        if value.is_nan() {
+            return None;
+        }
+        let kind = if value < band.low {
+            Some(kinds.0)
+        } else if value > band.high {
+            Some(kinds.1)
+        } else {
+            None
+        };
+
+        match (&mut self.excursion, kind) {
+            (Some(excursion), Some(kind)) if excursion.kind == kind => {
+                excursion.extreme = if kind == kinds.0 {
+                    excursion.extreme.min(value)
+                } else {
+                    excursion.extreme.max(value)
+                };
+                None
+            }
+            _ => {
+                let ended = self.excursion.take();
+                self.excursion = kind.map(|kind| Excursion {
+                    kind,
+                    start: at,
+                    extreme: value,
+                });
+                ended
+            }
+        }
+    }
+}
+
+pub struct PowerQualityMonitor {
+    config: PowerQualityConfig,
+    voltage: BandTracker,
+    frequency: BandTracker,
+}
+
+impl PowerQualityMonitor {
+    pub fn new(config: PowerQualityConfig) -> Self {
+        Self {
+            config,
+            voltage: BandTracker::default(),
+            frequency: BandTracker::default(),
+        }
+    }
+
+    // Feeds one measurement. Returns the events that ended with it; an
+    // event is only known once the value is back in its band.
+    pub fn update(&mut self, at: SystemTime, rms_volts: f64, frequency_hz: f64) -> Vec<PowerQualityEvent> {
+        let voltage_kinds = (PowerQualityKind::Sag, PowerQualityKind::Swell);
+        let frequency_kinds = (PowerQualityKind::UnderFrequency, PowerQualityKind::OverFrequency);
+        let ended = [
+            self.voltage.update(at, rms_volts, self.config.voltage, voltage_kinds),
+            self.frequency.update(at, frequency_hz, self.config.frequency, frequency_kinds),
+        ];
+
+        ended
+            .into_iter()
+            .flatten()
+            .map(|excursion| (excursion, at.duration_since(excursion.start).unwrap_or_default()))
+            .filter(|&(_, duration)| duration >= self.config.min_duration)
+            .map(|(excursion, duration)| PowerQualityEvent {
+                kind: excursion.kind,
+                unix_time: excursion
+                    .start
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                duration_ms: duration.as_millis() as u64,
+                extreme: excursion.extreme,
+            })
+            .collect()
+    }
+}
+
+impl Default for PowerQualityMonitor {
+    fn default() -> Self {
+        Self::new(PowerQualityConfig::default())
+    }
+}
+
+// Append-only log of power quality events, one JSON object per line.
+#[derive(Debug, Clone, Default)]
+pub struct PowerQualityLog {
+    // None disables the log.
+    path: Option<PathBuf>,
+}
+
+impl PowerQualityLog {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: Some(path.to_path_buf()),
+        }
+    }
+
+    pub fn record(&self, event: &PowerQualityEvent) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", serde_json::to_string(event)?)?;
+        file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000) + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_sag() {
+        let mut monitor = PowerQualityMonitor::default();
+        assert!(monitor.update(at(0), 240.0, 60.0).is_empty());
+        assert!(monitor.update(at(100), 205.0, 60.0).is_empty());
+        assert!(monitor.update(at(200), 198.5, 60.0).is_empty());
+        assert!(monitor.update(at(300), 210.0, 60.0).is_empty());
+        assert_eq!(
+            monitor.update(at(1100), 239.0, 60.0),
+            vec![PowerQualityEvent {
+                kind: PowerQualityKind::Sag,
+                unix_time: 1_700_000_000,
+                duration_ms: 1000,
+                extreme: 198.5,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_short_and_unknown_excursions() {
+        let mut monitor = PowerQualityMonitor::default();
+        monitor.update(at(0), 270.0, 60.0);
+        assert!(monitor.update(at(50), 240.0, 60.0).is_empty());
+
+        // A failed measurement does not end the swell.
+        monitor.update(at(100), 270.0, 60.0);
+        monitor.update(at(200), f64::NAN, f64::NAN);
+        let events = monitor.update(at(300), 240.0, 60.0);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, PowerQualityKind::Swell);
+        assert_eq!(events[0].duration_ms, 200);
+    }
+
+    #[test]
+    fn test_frequency_and_voltage_together() {
+        let mut monitor = PowerQualityMonitor::default();
+        monitor.update(at(0), 200.0, 59.2);
+        // The sag turns into a swell, the frequency stays low.
+        let events = monitor.update(at(500), 270.0, 59.3);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, PowerQualityKind::Sag);
+
+        let kinds: Vec<PowerQualityKind> = monitor.update(at(1000), 240.0, 60.0).iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, vec![PowerQualityKind::Swell, PowerQualityKind::UnderFrequency]);
+    }
+
+    #[test]
+    fn test_log() {
+        let path = std::env::temp_dir().join(format!("juicelib-power-quality-{}.log", std::process::id()));
+        let log = PowerQualityLog::new(&path);
+        let event = PowerQualityEvent {
+            kind: PowerQualityKind::OverFrequency,
+            unix_time: 1_700_000_000,
+            duration_ms: 250,
+            extreme: 60.7,
+        };
+        log.record(&event).unwrap();
+        log.record(&event).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert_eq!(
+            text.lines().next().unwrap(),
+            r#"{"kind":"OverFrequency","unix_time":1700000000,"duration_ms":250,"extreme":60.7}"#
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
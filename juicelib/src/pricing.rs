@@ -0,0 +1,272 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::evse::{EvseCommand, EvseController};
+
+// Dynamic tariffs. A provider fetches the hourly energy prices, which go
+// to the machine as they come in. While a price cap is set, the charge
+// pauses in the hours priced above it; with cheapest_hours set, it also
+// pauses outside the cheapest of the hours known ahead, so the charge is
+// shifted to them. The pause is a cap of 0 under the price limiter: the
+// offer is taken away and the session stays open.
+//
+// Without a price for the hour, from a provider that is down for
+// instance, the charge runs as without a tariff.
+//
+// The aWATTar provider reads market data in the format of the aWATTar API
+// over plain HTTP, e.g. from a relay on the local network; there is no
+// TLS client in juiced.
+
+pub const DEFAULT_PRICES_PATH: &str = "/v1/marketdata";
+// How often the prices are fetched, and retried after a failure.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const HOUR_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PricingSettings {
+    // Host and port of the market data, e.g. "localhost:8080".
+    pub address: String,
+    #[serde(default = "default_prices_path")]
+    pub path: String,
+    // Hours priced higher are skipped.
+    pub price_cap_per_kwh: f64,
+    // Charges in the cheapest hours known ahead only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cheapest_hours: Option<u32>,
+}
+
+fn default_prices_path() -> String {
+    DEFAULT_PRICES_PATH.to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HourlyPrice {
+    // The start of the hour.
+    pub unix_time: u64,
+    pub price_per_kwh: f64,
+}
+
+impl HourlyPrice {
+    fn contains(&self, unix_time: u64) -> bool {
+        (self.unix_time..self.unix_time + HOUR_SECS).contains(&unix_time)
+    }
+}
+
+#[derive(Debug)]
+pub enum PricingError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Http(String),
+}
+
+impl From<io::Error> for PricingError {
+    fn from(error: io::Error) -> Self {
+        PricingError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for PricingError {
+    fn from(error: serde_json::Error) -> Self {
+        PricingError::Json(error)
+    }
+}
+
+impl fmt::Display for PricingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PricingError::Io(error) => write!(f, "{}", error),
+            PricingError::Json(error) => write!(f, "prices do not parse: {}", error),
+            PricingError::Http(status) => write!(f, "prices not served: {}", status),
+        }
+    }
+}
+
+pub trait PriceProvider: Send + 'static {
+    // The hourly prices known, past hours included.
+    fn fetch(&mut self) -> Result<Vec<HourlyPrice>, PricingError>;
+}
+
+#[derive(Deserialize)]
+struct MarketData {
+    data: Vec<MarketPrice>,
+}
+
+#[derive(Deserialize)]
+struct MarketPrice {
+    start_timestamp: u64,
+    // EUR/MWh.
+    marketprice: f64,
+}
+
+// The market data of the aWATTar API, with times in milliseconds and
+// prices in EUR/MWh.
+pub fn parse_awattar(body: &str) -> Result<Vec<HourlyPrice>, PricingError> {
+    let market: MarketData = serde_json::from_str(body)?;
+    Ok(market
+        .data
+        .into_iter()
+        .map(|price| HourlyPrice {
+            unix_time: price.start_timestamp / 1000,
+            price_per_kwh: price.marketprice / 1000.0,
+        })
+        .collect())
+}
+
+pub struct AwattarProvider {
+    address: String,
+    path: String,
+}
+
+impl AwattarProvider {
+    pub fn new(settings: &PricingSettings) -> Self {
+        Self {
+            address: settings.address.clone(),
+            path: settings.path.clone(),
+        }
+    }
+}
+
+impl PriceProvider for AwattarProvider {
+    fn fetch(&mut self) -> Result<Vec<HourlyPrice>, PricingError> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        // HTTP/1.0 for a body that is neither chunked nor kept open.
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", self.path, self.address)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status_line = head.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => parse_awattar(body),
+            _ => Err(PricingError::Http(status_line.trim().to_string())),
+        }
+    }
+}
+
+// Decides from the prices whether the charge runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PricePlanner {
+    settings: PricingSettings,
+    prices: Vec<HourlyPrice>,
+}
+
+impl PricePlanner {
+    pub fn new(settings: PricingSettings) -> Self {
+        Self {
+            settings,
+            prices: Vec::new(),
+        }
+    }
+
+    pub fn set_prices(&mut self, prices: Vec<HourlyPrice>) {
+        self.prices = prices;
+    }
+
+    // Whether the charge runs at `unix_time`. None without a price for the
+    // hour.
+    pub fn charges_at(&self, unix_time: u64) -> Option<bool> {
+        let now = self.prices.iter().find(|price| price.contains(unix_time))?;
+        let cap = self.settings.price_cap_per_kwh;
+        if now.price_per_kwh > cap {
+            return Some(false);
+        }
+        let Some(hours) = self.settings.cheapest_hours else {
+            return Some(true);
+        };
+        let mut ahead: Vec<&HourlyPrice> = self
+            .prices
+            .iter()
+            .filter(|price| price.unix_time + HOUR_SECS > unix_time && price.price_per_kwh <= cap)
+            .collect();
+        // Of equal prices, the earlier hour.
+        ahead.sort_by(|a, b| a.price_per_kwh.total_cmp(&b.price_per_kwh).then(a.unix_time.cmp(&b.unix_time)));
+        Some(ahead.iter().take(hours as usize).any(|price| price.unix_time == now.unix_time))
+    }
+
+    // The cap of the price limiter: 0 while the charge pauses.
+    pub fn cap(&self, unix_time: u64) -> Option<f64> {
+        match self.charges_at(unix_time) {
+            Some(false) => Some(0.0),
+            _ => None,
+        }
+    }
+}
+
+// Fetches the prices every REFRESH_INTERVAL and hands them to the machine
+// until it stops.
+pub fn watch_prices<P: PriceProvider>(mut provider: P, controller: EvseController) {
+    thread::spawn(move || loop {
+        let wait = match provider.fetch() {
+            Ok(prices) => {
+                if controller.send_command(EvseCommand::SetPrices(prices)).is_err() {
+                    return;
+                }
+                REFRESH_INTERVAL
+            }
+            Err(error) => {
+                eprintln!("Energy prices not fetched: {}", error);
+                RETRY_INTERVAL
+            }
+        };
+        thread::sleep(wait);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: u64 = 1_700_000_000 / HOUR_SECS * HOUR_SECS;
+
+    #[test]
+    fn test_parse_awattar() {
+        let body = r#"{"object": "list", "data": [
+            {"start_timestamp": 1700000000000, "end_timestamp": 1700003600000, "marketprice": 95.5, "unit": "Eur/MWh"}
+        ], "url": "/at/v1/marketdata"}"#;
+        assert_eq!(
+            parse_awattar(body).unwrap(),
+            vec![HourlyPrice {
+                unix_time: 1_700_000_000,
+                price_per_kwh: 0.0955
+            }]
+        );
+        assert!(matches!(parse_awattar("{}"), Err(PricingError::Json(_))));
+    }
+
+    #[test]
+    fn test_cheapest_hours_below_cap() {
+        let mut planner = PricePlanner::new(PricingSettings {
+            address: "localhost:8080".to_string(),
+            path: default_prices_path(),
+            price_cap_per_kwh: 0.30,
+            cheapest_hours: None,
+        });
+        assert_eq!(planner.cap(START), None);
+        let prices = [0.25, 0.35, 0.10, 0.20, 0.28]
+            .iter()
+            .enumerate()
+            .map(|(hour, &price_per_kwh)| HourlyPrice {
+                unix_time: START + hour as u64 * HOUR_SECS,
+                price_per_kwh,
+            })
+            .collect();
+        planner.set_prices(prices);
+        let hour = |hour: u64| START + hour * HOUR_SECS + 600;
+        assert_eq!(planner.charges_at(hour(0)), Some(true));
+        assert_eq!(planner.cap(hour(1)), Some(0.0));
+        assert_eq!(planner.charges_at(hour(5)), None);
+
+        // The two cheapest hours ahead.
+        planner.settings.cheapest_hours = Some(2);
+        let charging: Vec<Option<bool>> = (0..5).map(|h| planner.charges_at(hour(h))).collect();
+        assert_eq!(charging, [Some(false), Some(false), Some(true), Some(true), Some(true)]);
+    }
+}
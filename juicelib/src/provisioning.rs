@@ -0,0 +1,562 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::breaker::{BreakerConfig, DEFAULT_CONTINUOUS_FRACTION, DEFAULT_TIME_CONSTANT};
+use crate::channels::ChannelRegistry;
+use crate::config_file::{self, Loaded};
+use crate::evse::{EVSEHardware, EVSEMachineInput, PilotClassifier};
+use crate::fault_policy::AlarmPolicy;
+use crate::features::Feature;
+use crate::gfi_retry::GfiRetryPolicy;
+use crate::guest::GuestExposure;
+use crate::imbalance::ImbalanceConfig;
+use crate::influx::InfluxConfig;
+use crate::interruption::InterruptionWebhook;
+use crate::load_test::{run_load_test, LoadTestConfig, LoadTestReport};
+use crate::pilot::PilotSignal;
+use crate::pricing::PricingSettings;
+use crate::recovery::ErrorRecovery;
+use crate::shadow::PilotCandidate;
+use crate::solar::SolarHysteresis;
+use crate::theme::Theme;
+use crate::time::{parse_time_zone, DEFAULT_TIME_ZONE};
+
+// First boot provisioning. A station without settings looks for a
+// provisioning file on a USB stick, takes the site settings from it, runs
+// the commissioning tests and writes a report signed with a key that never
+// leaves the station.
+
+pub const DEFAULT_CONFIG_DIR: &str = "/etc/juiced";
+// USB sticks are mounted below this directory.
+pub const DEFAULT_MEDIA_DIR: &str = "/media";
+pub const PROVISIONING_FILE_NAME: &str = "juiced-provisioning.json";
+
+const SETTINGS_FILE_NAME: &str = "site.json";
+const REPORT_FILE_NAME: &str = "commissioning-report.json";
+pub(crate) const KEY_FILE_NAME: &str = "device.key";
+const KEY_LEN: usize = 32;
+
+// How long to wait for a pilot reading during the commissioning tests.
+const PILOT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SiteSettings {
+    pub serial_number: String,
+    pub site_name: String,
+    // Rating of the breaker feeding the station.
+    pub breaker_amps: f64,
+    // Highest current offered to vehicles.
+    pub max_current: f64,
+    // Enables the breaker thermal model, which allows offers up to the
+    // breaker rating for a while and derates them for continuous loads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub breaker_model: Option<BreakerModelSettings>,
+    // What the public guest status endpoint exposes, if it runs at all.
+    #[serde(default)]
+    pub guest_status: GuestExposure,
+    // IANA time zone the schedules are kept in. UTC if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_zone: Option<String>,
+    // Serial port speaking the OpenEVSE RAPI protocol, e.g. /dev/ttyGS0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rapi_port: Option<PathBuf>,
+    // Feature toggles that differ from their safe defaults.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub features: BTreeMap<Feature, bool>,
+    // A candidate pilot classifier to trial in shadow mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shadow_pilot_classifier: Option<PilotCandidate>,
+    // Allows pilot test patterns for certification labs.
+    #[serde(default)]
+    pub lab_mode: bool,
+    // Whether warnings assert the alarm output too.
+    #[serde(default)]
+    pub alarm_policy: AlarmPolicy,
+    // Logo, fault contact and texts of the pages the station shows.
+    #[serde(default)]
+    pub theme: Theme,
+    // The ADC inputs of the signals, for boards other than the hat.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adc_channels: Option<ChannelRegistry>,
+    // When the solar mode stops and starts the charge.
+    #[serde(default)]
+    pub solar_hysteresis: SolarHysteresis,
+    // How long after a resettable error unplugging the vehicle resets the
+    // station. None leaves the reset to the operator.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grace_unplug_secs: Option<u64>,
+    // The dynamic tariff the charge follows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pricing: Option<PricingSettings>,
+    // The current while the control contact of the grid operator is
+    // asserted. None, or 0, stops the charge.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub control_contact_amps: Option<f64>,
+    // Recovers from a resettable error once the vehicle is unplugged,
+    // without the operator.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_recovery: Option<ErrorRecovery>,
+    // How long the machine waits for a pilot reading before it opens the
+    // contactor. None is the default of two seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pilot_timeout_ms: Option<u64>,
+    // Where sessions that stop on a fault or the control contact are
+    // told, with a link to resume them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interruption_webhook: Option<InterruptionWebhook>,
+    // Keeps the state and the error counters across restarts, so that a
+    // station that failed for a GFI fault stays locked out after a power
+    // cycle.
+    #[serde(default)]
+    pub persist_state: bool,
+    // The cooldown and the retries after a GFI trip, with the
+    // auto_retry_gfi feature on.
+    #[serde(default)]
+    pub gfi_retry: GfiRetryPolicy,
+    // When the difference of the line currents of a split-phase
+    // installation warns and stops the charge.
+    #[serde(default)]
+    pub line_imbalance: ImbalanceConfig,
+    // Where the sensor samples are exported, see influx.rs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub influx: Option<InfluxConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BreakerModelSettings {
+    #[serde(default = "default_continuous_fraction")]
+    pub continuous_fraction: f64,
+    #[serde(default = "default_time_constant_secs")]
+    pub time_constant_secs: u64,
+}
+
+fn default_continuous_fraction() -> f64 {
+    DEFAULT_CONTINUOUS_FRACTION
+}
+
+fn default_time_constant_secs() -> u64 {
+    DEFAULT_TIME_CONSTANT.as_secs()
+}
+
+impl SiteSettings {
+    fn validate(&self) -> Result<(), ProvisioningError> {
+        if self.serial_number.trim().is_empty() {
+            return Err(ProvisioningError::Invalid("serial number is empty"));
+        }
+        if self.breaker_amps.is_nan() || self.breaker_amps <= 0.0 {
+            return Err(ProvisioningError::Invalid("breaker rating must be positive"));
+        }
+        // J1772 does not allow offers below 6A.
+        if !(6.0..=80.0).contains(&self.max_current) {
+            return Err(ProvisioningError::Invalid("max current must be between 6 and 80 A"));
+        }
+        if self.max_current > self.breaker_amps {
+            return Err(ProvisioningError::Invalid("max current exceeds the breaker rating"));
+        }
+        if let Some(model) = &self.breaker_model {
+            if !(model.continuous_fraction > 0.0 && model.continuous_fraction <= 1.0) {
+                return Err(ProvisioningError::Invalid("continuous fraction must be between 0 and 1"));
+            }
+        }
+        if self.time_zone.as_deref().is_some_and(|name| parse_time_zone(name).is_none()) {
+            return Err(ProvisioningError::Invalid("unknown time zone"));
+        }
+        if let Some(amps) = self.control_contact_amps {
+            if amps != 0.0 && !(6.0..=self.max_current).contains(&amps) {
+                return Err(ProvisioningError::Invalid(
+                    "control contact current must be 0 or between 6 A and the max current",
+                ));
+            }
+        }
+        if self.pilot_timeout_ms == Some(0) {
+            return Err(ProvisioningError::Invalid("pilot timeout must be positive"));
+        }
+        let imbalance = &self.line_imbalance;
+        if !(imbalance.warn_amps > 0.0 && imbalance.warn_amps <= imbalance.stop_amps) {
+            return Err(ProvisioningError::Invalid("line imbalance warning must be positive and below the stop"));
+        }
+        if self.gfi_retry.cooldown_secs == 0 {
+            return Err(ProvisioningError::Invalid("GFI retry cooldown must be positive"));
+        }
+        if let Some(influx) = &self.influx {
+            if influx.address.trim().is_empty() || !influx.path.starts_with('/') {
+                return Err(ProvisioningError::Invalid("influx export needs an address and a path"));
+            }
+        }
+        if let Some(webhook) = &self.interruption_webhook {
+            if webhook.address.trim().is_empty() || !webhook.path.starts_with('/') {
+                return Err(ProvisioningError::Invalid("interruption webhook needs an address and a path"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn breaker_config(&self) -> Option<BreakerConfig> {
+        self.breaker_model.map(|model| BreakerConfig {
+            rating_amps: self.breaker_amps,
+            continuous_fraction: model.continuous_fraction,
+            time_constant: Duration::from_secs(model.time_constant_secs),
+        })
+    }
+
+    pub fn time_zone(&self) -> Tz {
+        self.time_zone
+            .as_deref()
+            .and_then(parse_time_zone)
+            .unwrap_or(DEFAULT_TIME_ZONE)
+    }
+}
+
+#[derive(Debug)]
+pub enum ProvisioningError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Invalid(&'static str),
+}
+
+impl From<io::Error> for ProvisioningError {
+    fn from(error: io::Error) -> Self {
+        ProvisioningError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for ProvisioningError {
+    fn from(error: serde_json::Error) -> Self {
+        ProvisioningError::Json(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommissioningStep {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommissioningReport {
+    pub settings: SiteSettings,
+    pub unix_time: u64,
+    pub steps: Vec<CommissioningStep>,
+    // The test of the rated current, once run with a load plugged in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_test: Option<LoadTestReport>,
+}
+
+impl CommissioningReport {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|step| step.passed) && self.load_test.as_ref().is_none_or(|test| test.passed)
+    }
+}
+
+// The report as written to disk. The signature is a hex encoded
+// HMAC-SHA256 of the JSON encoded report.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedReport {
+    pub report: CommissioningReport,
+    pub signature: String,
+}
+
+impl SignedReport {
+    pub fn sign(report: CommissioningReport, key: &[u8]) -> Result<Self, ProvisioningError> {
+        let signature = to_hex(&hmac_sha256(key, serde_json::to_string(&report)?.as_bytes()));
+        Ok(Self { report, signature })
+    }
+
+    pub fn verify(&self, key: &[u8]) -> Result<bool, ProvisioningError> {
+        let expected = to_hex(&hmac_sha256(key, serde_json::to_string(&self.report)?.as_bytes()));
+        Ok(expected == self.signature)
+    }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let inner_pad: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    let outer_pad: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    let inner = Sha256::new().chain_update(&inner_pad).chain_update(message).finalize();
+    Sha256::new().chain_update(&outer_pad).chain_update(inner).finalize().into()
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// A station is provisioned once its site settings are written.
+pub fn is_provisioned(config_dir: &Path) -> bool {
+    config_dir.join(SETTINGS_FILE_NAME).is_file()
+}
+
+pub fn load_settings(config_dir: &Path) -> Result<SiteSettings, ProvisioningError> {
+    Ok(load_settings_with_recovery(config_dir)?.value)
+}
+
+// Falls back to the last known good settings if they are corrupt. The
+// warning says when it did.
+pub fn load_settings_with_recovery(config_dir: &Path) -> Result<Loaded<SiteSettings>, ProvisioningError> {
+    config_file::load(&config_dir.join(SETTINGS_FILE_NAME), |text| Ok(serde_json::from_str(text)?))
+}
+
+// Validates and writes the site settings. They apply on the next start.
+pub fn save_settings(config_dir: &Path, settings: &SiteSettings) -> Result<(), ProvisioningError> {
+    settings.validate()?;
+    config_file::write(&config_dir.join(SETTINGS_FILE_NAME), &serde_json::to_string_pretty(settings)?)?;
+    Ok(())
+}
+
+// Looks for a provisioning file in the top directory of every mounted
+// stick.
+pub fn find_provisioning_file(media_dir: &Path) -> Option<PathBuf> {
+    let mut candidates: Vec<PathBuf> = fs::read_dir(media_dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().join(PROVISIONING_FILE_NAME))
+        .filter(|path| path.is_file())
+        .collect();
+    candidates.sort();
+    candidates.into_iter().next()
+}
+
+pub fn read_provisioning_file(path: &Path) -> Result<SiteSettings, ProvisioningError> {
+    let settings: SiteSettings = serde_json::from_str(&fs::read_to_string(path)?)?;
+    settings.validate()?;
+    Ok(settings)
+}
+
+// Reads the signing key, creating it on first use.
+fn device_key(config_dir: &Path) -> Result<Vec<u8>, ProvisioningError> {
+    let path = config_dir.join(KEY_FILE_NAME);
+    if let Ok(key) = fs::read(&path) {
+        if key.len() == KEY_LEN {
+            return Ok(key);
+        }
+    }
+
+    let mut key = vec![0u8; KEY_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut key)?;
+    let mut file = File::create(&path)?;
+    file.write_all(&key)?;
+    file.sync_all()?;
+    Ok(key)
+}
+
+// Waits for a fresh pilot reading and checks how it is classified.
+fn check_pilot<H: EVSEHardware>(evse: &H, name: &str, expected: EVSEMachineInput) -> CommissioningStep {
+    let pilot_rx = evse.pilot_channel();
+    // Readings taken before the pilot was switched are stale.
+    while pilot_rx.try_recv().is_ok() {}
+    let _ = pilot_rx.recv_timeout(PILOT_TIMEOUT);
+
+    let (passed, detail) = match pilot_rx.recv_timeout(PILOT_TIMEOUT) {
+        Ok(reading) => {
+            let state = PilotClassifier::default().get_pilot_state(reading.high);
+            (state == expected, format!("high {:.1} V, low {:.1} V, {:?}", reading.high, reading.low, state))
+        }
+        Err(_) => (false, "no pilot reading".to_string()),
+    };
+    CommissioningStep {
+        name: name.to_string(),
+        passed,
+        detail,
+    }
+}
+
+// Runs the commissioning tests. Nothing may be plugged into the station.
+pub fn run_commissioning<H: EVSEHardware>(evse: &mut H, settings: SiteSettings) -> CommissioningReport {
+    let mut steps = Vec::new();
+
+    let gfi = evse.run_gfi_self_test();
+    steps.push(CommissioningStep {
+        name: "GFI self test".to_string(),
+        passed: gfi.is_ok(),
+        detail: match gfi {
+            Ok(()) => "ok".to_string(),
+            Err(error) => format!("{:?}", error),
+        },
+    });
+
+    let waiting = evse.set_pilot(PilotSignal::SteadyPlus12);
+    steps.push(match waiting {
+        Ok(()) => check_pilot(evse, "Pilot +12V", EVSEMachineInput::PilotIs12V),
+        Err(error) => CommissioningStep {
+            name: "Pilot +12V".to_string(),
+            passed: false,
+            detail: format!("{:?}", error),
+        },
+    });
+
+    let error = evse.set_pilot(PilotSignal::ErrorMinus12);
+    steps.push(match error {
+        Ok(()) => check_pilot(evse, "Pilot -12V", EVSEMachineInput::PilotInError),
+        Err(error) => CommissioningStep {
+            name: "Pilot -12V".to_string(),
+            passed: false,
+            detail: format!("{:?}", error),
+        },
+    });
+
+    let unix_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    CommissioningReport {
+        settings,
+        unix_time,
+        steps,
+        load_test: None,
+    }
+}
+
+// Provisions the station from a provisioning file. The settings are only
+// written if commissioning passes; the signed report is written either way.
+pub fn provision<H: EVSEHardware>(
+    evse: &mut H,
+    provisioning_file: &Path,
+    config_dir: &Path,
+) -> Result<SignedReport, ProvisioningError> {
+    let settings = read_provisioning_file(provisioning_file)?;
+    fs::create_dir_all(config_dir)?;
+
+    let report = run_commissioning(evse, settings.clone());
+    let signed = SignedReport::sign(report, &device_key(config_dir)?)?;
+    config_file::write(&config_dir.join(REPORT_FILE_NAME), &serde_json::to_string_pretty(&signed)?)?;
+
+    if signed.report.passed() {
+        save_settings(config_dir, &settings)?;
+    }
+    Ok(signed)
+}
+
+// Runs the test of the rated current with a test load or a vehicle plugged
+// in, and adds it to the signed report of the commissioning. A report that
+// does not verify is not signed again.
+pub fn record_load_test<H: EVSEHardware>(evse: &mut H, config_dir: &Path) -> Result<SignedReport, ProvisioningError> {
+    let path = config_dir.join(REPORT_FILE_NAME);
+    let signed: SignedReport = serde_json::from_str(&fs::read_to_string(&path)?)?;
+    let key = device_key(config_dir)?;
+    if !signed.verify(&key)? {
+        return Err(ProvisioningError::Invalid("commissioning report signature does not match"));
+    }
+
+    let mut report = signed.report;
+    report.load_test = Some(run_load_test(evse, LoadTestConfig::new(report.settings.max_current)));
+    let signed = SignedReport::sign(report, &key)?;
+    config_file::write(&path, &serde_json::to_string_pretty(&signed)?)?;
+    Ok(signed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> SiteSettings {
+        SiteSettings {
+            serial_number: "JD-0001".to_string(),
+            site_name: "Garage".to_string(),
+            breaker_amps: 40.0,
+            max_current: 32.0,
+            breaker_model: None,
+            guest_status: GuestExposure::Disabled,
+            time_zone: Some("Europe/Berlin".to_string()),
+            rapi_port: None,
+            features: BTreeMap::new(),
+            shadow_pilot_classifier: None,
+            lab_mode: false,
+            alarm_policy: AlarmPolicy::Faults,
+            theme: Theme::default(),
+            adc_channels: None,
+            solar_hysteresis: SolarHysteresis::default(),
+            grace_unplug_secs: None,
+            pricing: None,
+            control_contact_amps: None,
+            error_recovery: None,
+            pilot_timeout_ms: None,
+            interruption_webhook: None,
+            persist_state: false,
+            gfi_retry: GfiRetryPolicy::default(),
+            line_imbalance: ImbalanceConfig::default(),
+            influx: None,
+        }
+    }
+
+    #[test]
+    fn test_validate_settings() {
+        assert!(settings().validate().is_ok());
+        let mut bad = settings();
+        bad.max_current = 50.0;
+        assert!(matches!(bad.validate(), Err(ProvisioningError::Invalid(_))));
+        let mut bad = settings();
+        bad.serial_number = " ".to_string();
+        assert!(bad.validate().is_err());
+
+        let json = r#"{"serial_number": "JD-0001", "site_name": "Garage", "breaker_amps": 40,
+                       "max_current": 40, "breaker_model": {}}"#;
+        let with_model: SiteSettings = serde_json::from_str(json).unwrap();
+        assert!(with_model.validate().is_ok());
+        assert_eq!(with_model.breaker_config().unwrap().continuous_amps(), 32.0);
+        assert_eq!(settings().breaker_config(), None);
+        assert_eq!(settings().time_zone(), chrono_tz::Europe::Berlin);
+        assert_eq!(with_model.time_zone(), DEFAULT_TIME_ZONE);
+        let mut bad = settings();
+        bad.time_zone = Some("CEST".to_string());
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test case 2.
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            to_hex(&mac),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_signed_report() {
+        let report = CommissioningReport {
+            settings: settings(),
+            unix_time: 1_700_000_000,
+            steps: vec![CommissioningStep {
+                name: "GFI self test".to_string(),
+                passed: true,
+                detail: "ok".to_string(),
+            }],
+            load_test: None,
+        };
+        let mut signed = SignedReport::sign(report, b"key").unwrap();
+        assert!(signed.verify(b"key").unwrap());
+        assert!(!signed.verify(b"other key").unwrap());
+
+        signed.report.settings.max_current = 40.0;
+        assert!(!signed.verify(b"key").unwrap());
+    }
+
+    #[test]
+    fn test_find_provisioning_file() {
+        let media = std::env::temp_dir().join(format!("juicelib-media-{}", std::process::id()));
+        fs::create_dir_all(media.join("usb0")).unwrap();
+        fs::create_dir_all(media.join("usb1")).unwrap();
+        assert_eq!(find_provisioning_file(&media), None);
+
+        let path = media.join("usb1").join(PROVISIONING_FILE_NAME);
+        fs::write(&path, serde_json::to_string(&settings()).unwrap()).unwrap();
+        assert_eq!(find_provisioning_file(&media), Some(path.clone()));
+        assert_eq!(read_provisioning_file(&path).unwrap(), settings());
+
+        fs::remove_dir_all(&media).unwrap();
+    }
+}
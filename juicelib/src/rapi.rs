@@ -0,0 +1,274 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::thread;
+
+use crate::evse::{EVSEMachineState, EvseCommand, EvseController};
+
+// Subset of the OpenEVSE RAPI serial protocol, so displays and tools made
+// for OpenEVSE can talk to juiced. A command is a line ending in CR:
+//   $GS*BE\r
+// with an optional checksum, either *HH (sum of the bytes before it) or
+// ^HH (XOR of the bytes before it). Responses carry an XOR checksum:
+//   $OK 03 1234^2C\r
+//
+// Commands:
+//   GV          -> version, RAPI version
+//   GS          -> state (hex), seconds in the state while charging
+//   GC          -> lowest and highest offer in amps
+//   GE          -> current limit in amps, flags
+//   SC amps     -> sets the current limit, answers the limit
+//   GU          -> session energy in Ws, total in Wh; always 0, juiced
+//                  does not meter energy
+//   FR          -> restarts juiced: the machine stops and the service
+//                  manager starts it again
+
+// The RAPI version whose subset is implemented.
+const RAPI_VERSION: &str = "5.0.1";
+// The lowest offer J1772 allows.
+const MIN_AMPS: u32 = 6;
+
+// RAPI EVSE states.
+const STATE_NOT_CONNECTED: u8 = 0x01;
+const STATE_CONNECTED: u8 = 0x02;
+const STATE_CHARGING: u8 = 0x03;
+const STATE_VENT_REQUIRED: u8 = 0x04;
+const STATE_DIODE_CHECK_FAILED: u8 = 0x05;
+const STATE_GFI_FAULT: u8 = 0x06;
+const STATE_DISABLED: u8 = 0xff;
+
+fn rapi_state(state: EVSEMachineState) -> u8 {
+    use EVSEMachineState::*;
+
+    match state {
+        Standby => STATE_NOT_CONNECTED,
+        VehicleDetected | StartCharging | SuspendedEV | StopCharging => STATE_CONNECTED,
+        Charging => STATE_CHARGING,
+        VentilationNeeded => STATE_VENT_REQUIRED,
+        // A pilot that reads as an error, like a missing diode.
+        ResetableError => STATE_DIODE_CHECK_FAILED,
+        // OpenEVSE shows its GFI retry countdown as a GFI fault too.
+        FailedStation | GFITripped => STATE_GFI_FAULT,
+        PowerFailure | TamperLockout => STATE_DISABLED,
+    }
+}
+
+fn xor_checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+fn sum_checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |checksum, byte| checksum.wrapping_add(*byte))
+}
+
+// Strips and verifies the checksum of a command. None if the checksum is
+// wrong or the line is not a command.
+fn verify(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if !line.starts_with('$') {
+        return None;
+    }
+    let Some(position) = line.rfind(['*', '^']) else {
+        return Some(line);
+    };
+    let (command, checksum) = line.split_at(position);
+    let expected = u8::from_str_radix(&checksum[1..], 16).ok()?;
+    let actual = match checksum.as_bytes()[0] {
+        b'*' => sum_checksum(command.as_bytes()),
+        _ => xor_checksum(command.as_bytes()),
+    };
+    (actual == expected).then_some(command)
+}
+
+fn with_checksum(response: &str) -> String {
+    format!("{}^{:02X}", response, xor_checksum(response.as_bytes()))
+}
+
+// Handles one line and returns the response, checksum included, without
+// the CR.
+pub fn handle_command(controller: &EvseController, line: &str) -> String {
+    let response = match verify(line) {
+        Some(command) => respond(controller, &command[1..]),
+        None => None,
+    };
+    with_checksum(&response.unwrap_or_else(|| "$NK".to_string()))
+}
+
+fn respond(controller: &EvseController, command: &str) -> Option<String> {
+    let mut parts = command.split_whitespace();
+    let response = match (parts.next()?, parts.next()) {
+        ("GV", None) => format!("$OK {} {}", env!("CARGO_PKG_VERSION"), RAPI_VERSION),
+        ("GS", None) => {
+            let state = controller.state();
+            let elapsed = match state {
+                EVSEMachineState::Charging => controller.time_in_state().as_secs(),
+                _ => 0,
+            };
+            format!("$OK {:02x} {}", rapi_state(state), elapsed)
+        }
+        ("GC", None) => format!("$OK {} {}", MIN_AMPS, controller.max_current() as u32),
+        ("GE", None) => format!("$OK {} 0000", controller.current_limit() as u32),
+        ("SC", Some(amps)) => {
+            let amps: u32 = amps.parse().ok()?;
+            if amps < MIN_AMPS {
+                return None;
+            }
+            let amps = amps.min(controller.max_current() as u32);
+            controller.send_command(EvseCommand::SetCurrentLimit(amps as f64)).ok()?;
+            format!("$OK {}", amps)
+        }
+        ("GU", None) => "$OK 0 0".to_string(),
+        ("FR", None) => {
+            controller.send_command(EvseCommand::Stop).ok()?;
+            "$OK".to_string()
+        }
+        _ => return None,
+    };
+    Some(response)
+}
+
+// Serves one connection until it closes.
+pub fn serve_stream<S: Read + Write>(stream: S, controller: &EvseController) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\r', &mut line)? == 0 {
+            return Ok(());
+        }
+        let text = String::from_utf8_lossy(&line);
+        if text.trim().is_empty() {
+            continue;
+        }
+        let response = handle_command(controller, &text);
+        write!(reader.get_mut(), "{}\r", response)?;
+        reader.get_mut().flush()?;
+    }
+}
+
+// Serves the serial port, or USB gadget serial, at the path on a background
+// thread. The line settings (115200 8N1 for OpenEVSE tools) are left to
+// the system.
+pub fn serve(path: &Path, controller: EvseController) -> io::Result<()> {
+    let port = OpenOptions::new().read(true).write(true).open(path)?;
+    thread::spawn(move || {
+        if let Err(error) = serve_stream(port, &controller) {
+            eprintln!("RAPI port closed: {}", error);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evse::{start_machine, EVSEError, EVSEHardware, EVSEMachineInput, EvseHandle, PilotReading};
+    use crate::pilot::PilotSignal;
+    use crossbeam_channel::{unbounded, Receiver, Sender};
+    use std::time::{Duration, Instant};
+
+    struct IdleHardware {
+        pilot_rx: Receiver<PilotReading>,
+        fault_rx: Receiver<EVSEMachineInput>,
+    }
+
+    impl EVSEHardware for IdleHardware {
+        fn set_pilot(&mut self, _signal: PilotSignal) -> Result<(), EVSEError> {
+            Ok(())
+        }
+
+        fn set_contactor(&mut self, _on: bool) -> Result<(), EVSEError> {
+            Ok(())
+        }
+
+        fn run_gfi_self_test(&mut self) -> Result<(), EVSEError> {
+            Ok(())
+        }
+
+        fn pilot_channel(&self) -> Receiver<PilotReading> {
+            self.pilot_rx.clone()
+        }
+
+        fn fault_channel(&self) -> Receiver<EVSEMachineInput> {
+            self.fault_rx.clone()
+        }
+    }
+
+    fn idle_machine() -> (EvseHandle, Sender<PilotReading>, Sender<EVSEMachineInput>) {
+        let (pilot_tx, pilot_rx) = unbounded();
+        let (fault_tx, fault_rx) = unbounded();
+        (start_machine(IdleHardware { pilot_rx, fault_rx }), pilot_tx, fault_tx)
+    }
+
+    #[test]
+    fn test_checksums() {
+        // From the RAPI documentation.
+        assert_eq!(verify("$GS*BE"), Some("$GS"));
+        assert_eq!(verify("$GS^30"), Some("$GS"));
+        assert_eq!(verify("$GS*BF"), None);
+        assert_eq!(verify("$GS\r"), Some("$GS"));
+        assert_eq!(verify("GS"), None);
+        assert_eq!(with_checksum("$OK"), "$OK^20");
+    }
+
+    #[test]
+    fn test_commands() {
+        let (handle, _pilot_tx, _fault_tx) = idle_machine();
+        let controller = handle.controller();
+
+        assert_eq!(handle_command(&controller, "$GS*BE"), with_checksum("$OK 01 0"));
+        assert_eq!(handle_command(&controller, "$GC"), with_checksum("$OK 6 32"));
+        assert_eq!(handle_command(&controller, "$SC 16"), with_checksum("$OK 16"));
+        assert_eq!(handle_command(&controller, "$SC 40"), with_checksum("$OK 32"));
+        assert_eq!(handle_command(&controller, "$SC 5"), with_checksum("$NK"));
+        assert_eq!(handle_command(&controller, "$GS*00"), with_checksum("$NK"));
+        assert_eq!(handle_command(&controller, "$XX"), with_checksum("$NK"));
+
+        let start = Instant::now();
+        while handle_command(&controller, "$GE") != with_checksum("$OK 32 0000") {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(handle_command(&controller, "$FR"), with_checksum("$OK"));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_serve_stream() {
+        let (handle, _pilot_tx, _fault_tx) = idle_machine();
+
+        // A fake port: the commands to read, the responses written.
+        struct Port {
+            input: io::Cursor<Vec<u8>>,
+            output: Vec<u8>,
+        }
+        impl Read for Port {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.input.read(buf)
+            }
+        }
+        impl Write for Port {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.output.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut port = Port {
+            input: io::Cursor::new(b"$GV\r\r$GS*BE\r".to_vec()),
+            output: Vec::new(),
+        };
+        serve_stream(&mut port, &handle.controller()).unwrap();
+        let output = String::from_utf8(port.output).unwrap();
+        let responses: Vec<&str> = output.split_terminator('\r').collect();
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].starts_with("$OK "));
+        assert_eq!(responses[1], with_checksum("$OK 01 0"));
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+}
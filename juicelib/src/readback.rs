@@ -0,0 +1,278 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::evse::PilotReading;
+use crate::pilot::{ampere_to_duty_cycle, PilotSignal};
+
+// Shadow model of what the hardware is told to do against what its inputs
+// read back: the contactor against the relay test line, the pilot against
+// the pilot readings and the GFI test against the GFI status. A reading
+// that disagrees with the command for longer than the output takes to
+// settle is a discrepancy, reported once until the two agree again or a
+// new command is given. The GFI has to trip at some point while its test
+// runs.
+//
+// The pilot offer ramps up under the slew rate, so its duty cycle only
+// has to lie between the lowest offer and the one commanded.
+
+pub const CONTACTOR_SETTLE: Duration = Duration::from_millis(250);
+// Two of the longest pilot windows.
+pub const PILOT_SETTLE: Duration = Duration::from_secs(1);
+
+// How far a steady pilot level may be off, and the duty cycle.
+const LEVEL_TOLERANCE_VOLTS: f32 = 3.0;
+const DUTY_CYCLE_TOLERANCE: f64 = 0.02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Output {
+    Contactor,
+    Pilot,
+    GfiTest,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Discrepancy {
+    pub output: Output,
+    pub commanded: String,
+    pub measured: String,
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} commanded {}, read back {}", self.output, self.commanded, self.measured)
+    }
+}
+
+// One output, told how long it takes to settle.
+#[derive(Debug)]
+struct Tracked {
+    settle: Duration,
+    commanded_at: Instant,
+    disagrees_since: Option<Instant>,
+    reported: bool,
+}
+
+impl Tracked {
+    fn new(settle: Duration) -> Self {
+        Self {
+            settle,
+            commanded_at: Instant::now(),
+            disagrees_since: None,
+            reported: false,
+        }
+    }
+
+    fn command(&mut self, now: Instant) {
+        self.commanded_at = now;
+        self.disagrees_since = None;
+        self.reported = false;
+    }
+
+    // True once the reading has disagreed for the whole settle time.
+    fn observe(&mut self, agrees: bool, now: Instant) -> bool {
+        if agrees {
+            self.disagrees_since = None;
+            self.reported = false;
+            return false;
+        }
+        let since = (*self.disagrees_since.get_or_insert(now)).max(self.commanded_at);
+        if self.reported || now.saturating_duration_since(since) < self.settle {
+            return false;
+        }
+        self.reported = true;
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct ReadbackMonitor {
+    contactor: bool,
+    contactor_track: Tracked,
+    pilot: Option<PilotSignal>,
+    pilot_track: Tracked,
+    gfi_test: bool,
+    gfi_tripped: bool,
+    discrepancies: Vec<Discrepancy>,
+}
+
+impl Default for ReadbackMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn contactor_name(closed: bool) -> &'static str {
+    if closed {
+        "closed"
+    } else {
+        "open"
+    }
+}
+
+// Whether the pilot reads as the signal drives it. None for the lab test
+// vectors, which are not checked.
+fn pilot_agrees(signal: PilotSignal, reading: &PilotReading) -> Option<bool> {
+    if matches!(signal, PilotSignal::TestVector { .. }) {
+        return None;
+    }
+    let duty_cycle = signal.duty_cycle();
+    Some(if duty_cycle >= 1.0 {
+        // Steady positive at whatever level the vehicle pulls it to.
+        reading.low > 0.0
+    } else if duty_cycle <= 0.0 {
+        reading.high <= -12.0 + LEVEL_TOLERANCE_VOLTS
+    } else {
+        let measured = f64::from(reading.duty_cycle);
+        let lowest = duty_cycle.min(ampere_to_duty_cycle(6.0));
+        reading.low <= -12.0 + LEVEL_TOLERANCE_VOLTS
+            && measured >= lowest - DUTY_CYCLE_TOLERANCE
+            && measured <= duty_cycle + DUTY_CYCLE_TOLERANCE
+    })
+}
+
+impl ReadbackMonitor {
+    pub fn new() -> Self {
+        Self {
+            contactor: false,
+            contactor_track: Tracked::new(CONTACTOR_SETTLE),
+            pilot: None,
+            pilot_track: Tracked::new(PILOT_SETTLE),
+            gfi_test: false,
+            gfi_tripped: false,
+            discrepancies: Vec::new(),
+        }
+    }
+
+    pub fn command_contactor(&mut self, closed: bool, now: Instant) {
+        self.contactor = closed;
+        self.contactor_track.command(now);
+    }
+
+    pub fn command_pilot(&mut self, signal: PilotSignal, now: Instant) {
+        self.pilot = Some(signal);
+        self.pilot_track.command(now);
+    }
+
+    // A GFI test that ends without a trip is a discrepancy.
+    pub fn command_gfi_test(&mut self, active: bool) {
+        if self.gfi_test && !active && !self.gfi_tripped {
+            self.discrepancies.push(Discrepancy {
+                output: Output::GfiTest,
+                commanded: "test current".to_string(),
+                measured: "GFI status never set".to_string(),
+            });
+        }
+        self.gfi_test = active;
+        self.gfi_tripped = false;
+    }
+
+    // What the relay test line reads.
+    pub fn measure_contactor(&mut self, closed: bool, now: Instant) {
+        if self.contactor_track.observe(closed == self.contactor, now) {
+            self.discrepancies.push(Discrepancy {
+                output: Output::Contactor,
+                commanded: contactor_name(self.contactor).to_string(),
+                measured: format!("relay test line {}", contactor_name(closed)),
+            });
+        }
+    }
+
+    // Unusable readings and readings before the first command are skipped.
+    pub fn measure_pilot(&mut self, reading: &PilotReading, now: Instant) {
+        if reading.high.is_nan() || reading.low.is_nan() {
+            return;
+        }
+        let Some(signal) = self.pilot else {
+            return;
+        };
+        let Some(agrees) = pilot_agrees(signal, reading) else {
+            return;
+        };
+        if self.pilot_track.observe(agrees, now) {
+            self.discrepancies.push(Discrepancy {
+                output: Output::Pilot,
+                commanded: format!("{:?}", signal),
+                measured: format!(
+                    "high {:.1} V, low {:.1} V, duty cycle {:.1}%",
+                    reading.high,
+                    reading.low,
+                    reading.duty_cycle * 100.0
+                ),
+            });
+        }
+    }
+
+    // A GFI set outside of a test is a fault, not a discrepancy.
+    pub fn measure_gfi(&mut self, set: bool) {
+        if self.gfi_test && set {
+            self.gfi_tripped = true;
+        }
+    }
+
+    // The discrepancies since the last call.
+    pub fn take_discrepancies(&mut self) -> Vec<Discrepancy> {
+        std::mem::take(&mut self.discrepancies)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(high: f32, low: f32, duty_cycle: f32) -> PilotReading {
+        PilotReading {
+            high,
+            low,
+            duty_cycle,
+            frequency: 1000.0,
+        }
+    }
+
+    #[test]
+    fn test_discrepancies_after_settling() {
+        let mut monitor = ReadbackMonitor::new();
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+
+        // The relay follows within the settle time.
+        monitor.command_contactor(true, t0);
+        monitor.measure_contactor(false, t0 + ms(50));
+        monitor.measure_contactor(true, t0 + ms(100));
+        monitor.measure_contactor(true, t0 + ms(400));
+        assert!(monitor.take_discrepancies().is_empty());
+        // Then drops out, and is reported once.
+        monitor.measure_contactor(false, t0 + ms(500));
+        monitor.measure_contactor(false, t0 + ms(800));
+        monitor.measure_contactor(false, t0 + ms(900));
+        let discrepancies = monitor.take_discrepancies();
+        assert_eq!(discrepancies.len(), 1);
+        assert_eq!(
+            discrepancies[0].to_string(),
+            "Contactor commanded closed, read back relay test line open"
+        );
+
+        // A ramping offer is fine, no PWM is not.
+        monitor.command_pilot(PilotSignal::OfferAmps(32.0), t0);
+        monitor.measure_pilot(&reading(9.0, -12.0, 0.2), t0 + ms(1500));
+        monitor.measure_pilot(&reading(f32::NAN, f32::NAN, 0.0), t0 + ms(1600));
+        monitor.measure_pilot(&reading(9.0, 9.0, 1.0), t0 + ms(2000));
+        monitor.measure_pilot(&reading(9.0, 9.0, 1.0), t0 + ms(3000));
+        assert_eq!(monitor.take_discrepancies()[0].output, Output::Pilot);
+        monitor.command_pilot(PilotSignal::SteadyPlus12, t0 + ms(3000));
+        monitor.measure_pilot(&reading(9.0, 9.0, 1.0), t0 + ms(5000));
+        assert!(monitor.take_discrepancies().is_empty());
+
+        monitor.command_gfi_test(true);
+        monitor.measure_gfi(true);
+        monitor.command_gfi_test(false);
+        // Set outside of a test.
+        monitor.measure_gfi(true);
+        assert!(monitor.take_discrepancies().is_empty());
+        monitor.command_gfi_test(true);
+        monitor.measure_gfi(false);
+        monitor.command_gfi_test(false);
+        assert_eq!(monitor.take_discrepancies()[0].output, Output::GfiTest);
+    }
+}
@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::evse::EVSEMachineInput;
+
+// Automatic recovery from a resettable error. The offer is taken away and
+// the pilot rests at +12 V, so that an unplug shows. Once the pilot has
+// read +12 V for the whole debounce time, the vehicle is gone and the
+// station goes back to Standby by itself. A vehicle that leaves the
+// station in a resettable error again and again is not retried forever:
+// after max_retries recoveries in a row the next error fails the station.
+// A session that ends without an error starts the count over.
+//
+// Without the recovery, a resettable error waits for the operator unless
+// the grace window is set (see MachineOptions::grace_unplug). With both,
+// the unplug has to come within the window.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErrorRecovery {
+    pub debounce_ms: u64,
+    pub max_retries: u32,
+}
+
+impl Default for ErrorRecovery {
+    fn default() -> Self {
+        Self {
+            debounce_ms: 1000,
+            max_retries: 3,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RecoveryTracker {
+    recovery: Option<ErrorRecovery>,
+    // Recoveries since the last session without an error.
+    retries: u32,
+    // Since when the pilot reads unplugged.
+    unplugged_since: Option<Instant>,
+}
+
+impl RecoveryTracker {
+    pub fn new(recovery: Option<ErrorRecovery>) -> Self {
+        Self {
+            recovery,
+            retries: 0,
+            unplugged_since: None,
+        }
+    }
+
+    // Goes on from the recoveries before a restart.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.recovery.is_some()
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    // Whether another resettable error fails the station.
+    pub fn is_exhausted(&self) -> bool {
+        self.recovery.is_some_and(|recovery| self.retries >= recovery.max_retries)
+    }
+
+    // A resettable error was entered; the unplug is looked for from now on.
+    pub fn error_entered(&mut self) {
+        self.unplugged_since = None;
+    }
+
+    // True once the pilot has read unplugged for the debounce time. Without
+    // the recovery, the first reading does.
+    pub fn observe(&mut self, input: EVSEMachineInput, now: Instant) -> bool {
+        if input != EVSEMachineInput::PilotIs12V {
+            self.unplugged_since = None;
+            return false;
+        }
+        let debounce = self.recovery.map_or(Duration::ZERO, |recovery| Duration::from_millis(recovery.debounce_ms));
+        now.saturating_duration_since(*self.unplugged_since.get_or_insert(now)) >= debounce
+    }
+
+    // The station went back to Standby after an unplug.
+    pub fn recovered(&mut self) {
+        self.retries += 1;
+        self.unplugged_since = None;
+    }
+
+    // A session ended without an error.
+    pub fn session_ended(&mut self) {
+        self.retries = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use EVSEMachineInput::*;
+
+    #[test]
+    fn test_debounce_and_retries() {
+        let mut tracker = RecoveryTracker::new(Some(ErrorRecovery {
+            debounce_ms: 500,
+            max_retries: 2,
+        }));
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+
+        tracker.error_entered();
+        assert!(!tracker.observe(PilotIs12V, t0));
+        // A bounce starts the debounce over.
+        assert!(!tracker.observe(PilotIs9V, t0 + ms(300)));
+        assert!(!tracker.observe(PilotIs12V, t0 + ms(400)));
+        assert!(!tracker.observe(PilotIs12V, t0 + ms(800)));
+        assert!(tracker.observe(PilotIs12V, t0 + ms(900)));
+        tracker.recovered();
+        assert!(!tracker.is_exhausted());
+        tracker.error_entered();
+        tracker.recovered();
+        assert!(tracker.is_exhausted());
+        tracker.session_ended();
+        assert_eq!(tracker.retries(), 0);
+
+        // Without the recovery, nothing to debounce or count.
+        let mut tracker = RecoveryTracker::new(None);
+        assert!(tracker.observe(PilotIs12V, t0));
+        tracker.recovered();
+        assert!(!tracker.is_exhausted());
+    }
+}
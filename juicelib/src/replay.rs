@@ -0,0 +1,496 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crossbeam_channel::{never, Receiver};
+use serde::{Deserialize, Serialize};
+
+use crate::evse::{
+    do_state_transition, next_state, EVSEError, EVSEHardware, EVSEMachineInput, EVSEMachineState, PilotClassifier,
+    PilotReading,
+};
+use crate::peripherals::PeripheralsError;
+use crate::pilot::PilotSignal;
+
+// Offline replay of a black box recording. The recorded pilot readings and
+// faults are fed through the pilot classifier and the state machine of this
+// build, and what they decide is set against what the station decided at
+// the time, so a field incident can be replayed against a fix:
+//
+//   juiced replay /var/lib/juiced/blackbox/blackbox-1700000000-FailedStation.json
+//
+// Only the J1772 state machine is replayed. Commands are shown but not
+// acted on, the GFI self tests pass or fail as they did in the field, and
+// the offer is the one the station made.
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(error: io::Error) -> Self {
+        ReplayError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for ReplayError {
+    fn from(error: serde_json::Error) -> Self {
+        ReplayError::Json(error)
+    }
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(error) => write!(f, "{}", error),
+            ReplayError::Json(error) => write!(f, "not a black box recording: {}", error),
+        }
+    }
+}
+
+// A pilot reading as written to a recording, where NaN becomes null.
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct RecordedReading {
+    high: Option<f32>,
+    low: Option<f32>,
+    duty_cycle: Option<f32>,
+    frequency: Option<f32>,
+}
+
+impl From<RecordedReading> for PilotReading {
+    fn from(reading: RecordedReading) -> Self {
+        Self {
+            high: reading.high.unwrap_or(f32::NAN),
+            low: reading.low.unwrap_or(f32::NAN),
+            duty_cycle: reading.duty_cycle.unwrap_or(f32::NAN),
+            frequency: reading.frequency.unwrap_or(f32::NAN),
+        }
+    }
+}
+
+// The entries of blackbox::BlackBoxEntry. Commands are only shown, so they
+// are kept as they were written.
+#[derive(Debug, Clone, Deserialize)]
+enum RecordedEntry {
+    Input(EVSEMachineInput),
+    Pilot(RecordedReading),
+    Command(serde_json::Value),
+    State(EVSEMachineState),
+    SetPilot(PilotSignal),
+    SetContactor(bool),
+    GfiSelfTest { passed: bool },
+    ResidualCurrent(f64),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RecordedFrame {
+    age_ms: u64,
+    entry: RecordedEntry,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Recording {
+    pub fault: EVSEMachineState,
+    pub unix_time: u64,
+    // Missing in recordings of older versions.
+    #[serde(default)]
+    initial_state: Option<EVSEMachineState>,
+    entries: Vec<RecordedFrame>,
+}
+
+pub fn load_recording(path: &Path) -> Result<Recording, ReplayError> {
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum ReplayEvent {
+    // A recorded pilot reading and how this build classifies it.
+    Pilot(PilotReading, EVSEMachineInput),
+    Input(EVSEMachineInput),
+    Transition(EVSEMachineState, EVSEMachineState),
+    SetPilot(PilotSignal),
+    SetContactor(bool),
+    // Recorded, not replayed.
+    Command(String),
+    ResidualCurrent(f64),
+    // Where this build decides other than the station did.
+    Divergence(String),
+}
+
+impl fmt::Display for ReplayEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayEvent::Pilot(reading, input) => write!(
+                f,
+                "pilot {:+.2} V / {:+.2} V, {:.0}% at {:.0} Hz -> {:?}",
+                reading.high,
+                reading.low,
+                reading.duty_cycle * 100.0,
+                reading.frequency,
+                input
+            ),
+            ReplayEvent::Input(input) => write!(f, "input {:?}", input),
+            ReplayEvent::Transition(from, to) => write!(f, "{:?} => {:?}", from, to),
+            ReplayEvent::SetPilot(signal) => write!(f, "  set pilot {:?}", signal),
+            ReplayEvent::SetContactor(on) => write!(f, "  contactor {}", if *on { "on" } else { "off" }),
+            ReplayEvent::Command(command) => write!(f, "command {} (not replayed)", command),
+            ReplayEvent::ResidualCurrent(milliamps) => write!(f, "residual current {:.1} mA", milliamps),
+            ReplayEvent::Divergence(what) => write!(f, "!! {}", what),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReplayStep {
+    // Milliseconds before the fault.
+    pub age_ms: u64,
+    pub event: ReplayEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Replay {
+    pub fault: EVSEMachineState,
+    pub initial_state: EVSEMachineState,
+    // False if the recording did not say and Standby was assumed.
+    pub initial_state_known: bool,
+    pub final_state: EVSEMachineState,
+    pub steps: Vec<ReplayStep>,
+}
+
+impl Replay {
+    pub fn divergences(&self) -> usize {
+        self.steps
+            .iter()
+            .filter(|step| matches!(step.event, ReplayEvent::Divergence(_)))
+            .count()
+    }
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Replay of a {:?} recording from {:?}{}",
+            self.fault,
+            self.initial_state,
+            if self.initial_state_known { "" } else { " (assumed)" }
+        )?;
+        for step in &self.steps {
+            writeln!(f, "{:>9} ms  {}", -(step.age_ms as i64), step.event)?;
+        }
+        match self.divergences() {
+            0 => write!(f, "Ends in {:?}, as recorded", self.final_state),
+            count => write!(f, "Ends in {:?}, {} divergences from the recording", self.final_state, count),
+        }
+    }
+}
+
+// Issues nothing, but keeps what the machine asks of it.
+struct ReplayHardware {
+    commands: Vec<ReplayEvent>,
+    // The recorded outcomes of the GFI self tests, in order.
+    gfi_self_tests: VecDeque<bool>,
+}
+
+impl EVSEHardware for ReplayHardware {
+    fn set_pilot(&mut self, signal: PilotSignal) -> Result<(), EVSEError> {
+        self.commands.push(ReplayEvent::SetPilot(signal));
+        Ok(())
+    }
+
+    fn set_contactor(&mut self, on: bool) -> Result<(), EVSEError> {
+        self.commands.push(ReplayEvent::SetContactor(on));
+        Ok(())
+    }
+
+    fn run_gfi_self_test(&mut self) -> Result<(), EVSEError> {
+        // Passes if the station did not get to run it.
+        match self.gfi_self_tests.pop_front() {
+            Some(false) => Err(EVSEError::Peripherals(PeripheralsError::GfiSelfTestFailed("recorded"))),
+            _ => Ok(()),
+        }
+    }
+
+    fn pilot_channel(&self) -> Receiver<PilotReading> {
+        never()
+    }
+
+    fn fault_channel(&self) -> Receiver<EVSEMachineInput> {
+        never()
+    }
+}
+
+struct Replayer<'a> {
+    entries: &'a [RecordedFrame],
+    hardware: ReplayHardware,
+    state: EVSEMachineState,
+    steps: Vec<ReplayStep>,
+    // Contactor commands the replay issued that the recording has yet to
+    // show.
+    contactor: VecDeque<bool>,
+}
+
+impl Replayer<'_> {
+    fn push(&mut self, age_ms: u64, event: ReplayEvent) {
+        self.steps.push(ReplayStep { age_ms, event });
+    }
+
+    // The offer the station made next, or the one before if it made no
+    // other.
+    fn offer_at(&self, index: usize) -> f64 {
+        let offer = |frame: &RecordedFrame| match frame.entry {
+            RecordedEntry::SetPilot(PilotSignal::OfferAmps(amps)) => Some(amps),
+            _ => None,
+        };
+        self.entries[index..]
+            .iter()
+            .find_map(offer)
+            .or_else(|| self.entries[..index].iter().rev().find_map(offer))
+            .unwrap_or(self.hardware.max_current())
+    }
+
+    // Feeds an input and the inputs the new states produce.
+    fn feed(&mut self, index: usize, input: EVSEMachineInput) {
+        let age_ms = self.entries[index].age_ms;
+        let mut next = Some(input);
+        while let Some(input) = next.take() {
+            let Some(state) = next_state(self.state, input) else {
+                continue;
+            };
+            self.push(age_ms, ReplayEvent::Transition(self.state, state));
+            self.state = state;
+            let offer = self.offer_at(index);
+            next = do_state_transition(&mut self.hardware, state, offer).unwrap_or_default();
+            for command in std::mem::take(&mut self.hardware.commands) {
+                if let ReplayEvent::SetContactor(on) = command {
+                    self.contactor.push_back(on);
+                }
+                self.push(age_ms, command);
+            }
+            if let Some(input) = next {
+                self.push(age_ms, ReplayEvent::Input(input));
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        let mut classifier = PilotClassifier::default();
+        // How this build classified the last pilot reading, until the
+        // recorded input for it comes up.
+        let mut classified: Option<(usize, EVSEMachineInput)> = None;
+        for index in 0..self.entries.len() {
+            let frame = &self.entries[index];
+            let age_ms = frame.age_ms;
+            let entry = frame.entry.clone();
+            if let Some((at, input)) = classified.filter(|_| !matches!(entry, RecordedEntry::Input(_))) {
+                classified = None;
+                self.feed(at, input);
+            }
+            match entry {
+                RecordedEntry::Pilot(reading) => {
+                    let reading = PilotReading::from(reading);
+                    let input = classifier.classify(&reading);
+                    self.push(age_ms, ReplayEvent::Pilot(reading, input));
+                    classified = Some((index, input));
+                }
+                RecordedEntry::Input(recorded) => match classified.take() {
+                    // The machine's own timers, not the classifier.
+                    Some(_)
+                        if matches!(
+                            recorded,
+                            EVSEMachineInput::PauseTimedOut | EVSEMachineInput::UnpluggedInGrace
+                        ) =>
+                    {
+                        self.push(age_ms, ReplayEvent::Input(recorded));
+                        self.feed(index, recorded);
+                    }
+                    Some((_, input)) => {
+                        if input != recorded {
+                            self.push(
+                                age_ms,
+                                ReplayEvent::Divergence(format!("the station classified the reading as {:?}", recorded)),
+                            );
+                        }
+                        self.feed(index, input);
+                    }
+                    // The replay runs the self tests itself.
+                    None if matches!(recorded, EVSEMachineInput::SelfTestOk | EVSEMachineInput::SelfTestFailed) => {}
+                    None => {
+                        self.push(age_ms, ReplayEvent::Input(recorded));
+                        self.feed(index, recorded);
+                    }
+                },
+                RecordedEntry::State(recorded) => {
+                    if recorded != self.state {
+                        self.push(
+                            age_ms,
+                            ReplayEvent::Divergence(format!("the station went to {:?}", recorded)),
+                        );
+                    }
+                }
+                RecordedEntry::SetContactor(on) => {
+                    if self.contactor.front() == Some(&on) {
+                        self.contactor.pop_front();
+                    } else {
+                        self.push(
+                            age_ms,
+                            ReplayEvent::Divergence(format!("the station switched the contactor {}", on_off(on))),
+                        );
+                    }
+                }
+                RecordedEntry::Command(command) => self.push(age_ms, ReplayEvent::Command(command.to_string())),
+                RecordedEntry::ResidualCurrent(milliamps) => self.push(age_ms, ReplayEvent::ResidualCurrent(milliamps)),
+                RecordedEntry::SetPilot(_) | RecordedEntry::GfiSelfTest { .. } => {}
+            }
+        }
+        if let Some((at, input)) = classified {
+            self.feed(at, input);
+        }
+        for on in std::mem::take(&mut self.contactor) {
+            self.push(
+                0,
+                ReplayEvent::Divergence(format!("the station did not switch the contactor {}", on_off(on))),
+            );
+        }
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+// Replays a recording from its initial state.
+pub fn replay(recording: &Recording) -> Replay {
+    let entries = &recording.entries;
+    // Without an initial state, a state recorded before any input is as
+    // good.
+    let first_state = entries
+        .iter()
+        .take_while(|frame| !matches!(frame.entry, RecordedEntry::Input(_) | RecordedEntry::Pilot(_)))
+        .find_map(|frame| match frame.entry {
+            RecordedEntry::State(state) => Some(state),
+            _ => None,
+        });
+    let initial_state = recording.initial_state.or(first_state);
+    let mut replayer = Replayer {
+        entries,
+        hardware: ReplayHardware {
+            commands: Vec::new(),
+            gfi_self_tests: entries
+                .iter()
+                .filter_map(|frame| match frame.entry {
+                    RecordedEntry::GfiSelfTest { passed } => Some(passed),
+                    _ => None,
+                })
+                .collect(),
+        },
+        state: initial_state.unwrap_or(EVSEMachineState::Standby),
+        steps: Vec::new(),
+        contactor: VecDeque::new(),
+    };
+    replayer.run();
+    if replayer.state != recording.fault {
+        let fault = recording.fault;
+        replayer.push(0, ReplayEvent::Divergence(format!("the station ended in {:?}", fault)));
+    }
+    Replay {
+        fault: recording.fault,
+        initial_state: initial_state.unwrap_or(EVSEMachineState::Standby),
+        initial_state_known: initial_state.is_some(),
+        final_state: replayer.state,
+        steps: replayer.steps,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording(entries: serde_json::Value) -> Recording {
+        serde_json::from_value(serde_json::json!({
+            "fault": "FailedStation",
+            "unix_time": 1_700_000_000,
+            "initial_state": "Standby",
+            "entries": entries,
+        }))
+        .unwrap()
+    }
+
+    fn pilot(high: f32) -> serde_json::Value {
+        serde_json::json!({ "Pilot": { "high": high, "low": -12.0, "duty_cycle": 0.27, "frequency": 1000.0 } })
+    }
+
+    #[test]
+    fn test_replays_a_recording() {
+        let recording = recording(serde_json::json!([
+            { "age_ms": 3000, "entry": pilot(9.0) },
+            { "age_ms": 3000, "entry": { "Input": "PilotIs9V" } },
+            { "age_ms": 3000, "entry": { "SetPilot": { "OfferAmps": 16.0 } } },
+            { "age_ms": 2990, "entry": { "State": "VehicleDetected" } },
+            { "age_ms": 2000, "entry": { "Command": { "SetCurrentLimit": 10.0 } } },
+            { "age_ms": 1000, "entry": pilot(6.0) },
+            { "age_ms": 1000, "entry": { "Input": "PilotIs6V" } },
+            { "age_ms": 1000, "entry": { "GfiSelfTest": { "passed": true } } },
+            { "age_ms": 1000, "entry": { "Input": "SelfTestOk" } },
+            { "age_ms": 1000, "entry": { "SetContactor": true } },
+            { "age_ms": 990, "entry": { "State": "Charging" } },
+            { "age_ms": 10, "entry": { "Input": "GFIInterrupted" } },
+            { "age_ms": 10, "entry": { "SetContactor": false } },
+            { "age_ms": 10, "entry": { "SetPilot": "ErrorMinus12" } },
+            { "age_ms": 0, "entry": { "State": "FailedStation" } },
+        ]));
+        let replay = replay(&recording);
+        assert_eq!(replay.divergences(), 0, "{}", replay);
+        assert_eq!(replay.final_state, EVSEMachineState::FailedStation);
+        let events: Vec<&ReplayEvent> = replay.steps.iter().map(|step| &step.event).collect();
+        assert!(events.contains(&&ReplayEvent::SetPilot(PilotSignal::OfferAmps(16.0))));
+        assert!(events.contains(&&ReplayEvent::Transition(
+            EVSEMachineState::StartCharging,
+            EVSEMachineState::Charging
+        )));
+        assert!(replay.to_string().ends_with("Ends in FailedStation, as recorded"));
+    }
+
+    #[test]
+    fn test_reports_divergences() {
+        // The station took 8.2 V for state C.
+        let unusable = serde_json::json!({ "Pilot": { "high": null, "low": null, "duty_cycle": null, "frequency": null } });
+        let recording = recording(serde_json::json!([
+            { "age_ms": 2000, "entry": pilot(8.2) },
+            { "age_ms": 2000, "entry": { "Input": "PilotIs6V" } },
+            { "age_ms": 2000, "entry": { "State": "ResetableError" } },
+            { "age_ms": 1000, "entry": unusable },
+            { "age_ms": 1000, "entry": { "Input": "PilotInError" } },
+            { "age_ms": 1000, "entry": { "SetContactor": false } },
+        ]));
+        let replay = replay(&recording);
+        assert_eq!(
+            replay.steps.iter().map(|step| step.event.clone()).collect::<Vec<_>>()[..4],
+            [
+                ReplayEvent::Pilot(
+                    PilotReading {
+                        high: 8.2,
+                        low: -12.0,
+                        duty_cycle: 0.27,
+                        frequency: 1000.0
+                    },
+                    EVSEMachineInput::PilotIs9V
+                ),
+                ReplayEvent::Divergence("the station classified the reading as PilotIs6V".to_string()),
+                ReplayEvent::Transition(EVSEMachineState::Standby, EVSEMachineState::VehicleDetected),
+                ReplayEvent::SetPilot(PilotSignal::OfferAmps(32.0)),
+            ]
+        );
+        // The station went to ResetableError, then never to FailedStation.
+        assert_eq!(replay.final_state, EVSEMachineState::ResetableError);
+        assert_eq!(replay.divergences(), 3);
+    }
+}
@@ -0,0 +1,670 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::certificates::{unix_now, CertificateError};
+use crate::charging_curve::curve_csv;
+use crate::evse::{EVSEError, EvseCommand, EvseController};
+use crate::features::Feature;
+use crate::guest_token::GuestTokenError;
+use crate::interruption::ResumeError;
+use crate::lab::LabPattern;
+use crate::limits::Limiter;
+use crate::schema::{all_schemas, payload_schema};
+use crate::session_id::SessionId;
+use crate::telemetry::TelemetryVerbosity;
+use crate::tenants::{report_csv, Tenant, TenantError};
+
+// Local control socket speaking JSON-RPC 2.0, one request per line. Access
+// is controlled by the permissions of the socket file, so there is no
+// authentication on top.
+//
+// Methods:
+//   get_state                          -> "Charging"
+//   get_telemetry {"diagnostic": bool} -> telemetry sample
+//   set_current_limit {"amps": number} -> null
+//   set_max_pause {"seconds": number}  -> null
+//   adjust_current_offer {"amps": number} -> null, also while charging (see offer_pacing.rs)
+//   set_max_current {"amps": number}   -> null, 6 to 80 A, refused while charging
+//   get_features                       -> [{"feature", "enabled", "source"}]
+//   set_feature {"feature": name, "enabled": bool or null} -> null
+//   get_limits                         -> {"offer", "binding", "vehicle_floor", "caps"}
+//   set_limit {"limiter": name, "amps": number or null} -> null
+//   set_power_budget {"watts": number or null} -> null
+//   get_phases                         -> {"phases_in_use", "usable_power_w", "power_budget_w"}
+//   get_brownout                       -> {"level", "latency_ms", "backlog"}
+//   get_cable_health                   -> {"sessions", "baseline_ohms", "recent_ohms", "margin_volts", "worn"}
+//   identify_vehicle {"vehicle": string} -> null
+//   set_battery_energy {"wh": number}  -> null, the energy the battery took in (see session.rs)
+//   get_shadow_report                  -> shadow comparison report or null
+//   get_fault_report                   -> fault analytics or null without a store
+//   get_metrics                        -> {"time_in_state_ms", "transitions", "faults", "self_tests"}
+//   get_error_counters                 -> {"faults", "gfi_trips", "recovery_retries"}, across restarts
+//                                      with the state file (see machine_state.rs)
+//   get_lifetime_counters              -> {"energy_wh", "sessions", "gfi_trips", "contactor_cycles",
+//                                      "uptime_secs"} (see counters.rs)
+//   run_lab_pattern {"steps": [...]}   -> null, lab mode only (see lab.rs)
+//   get_lab_report                     -> responses to the last lab pattern or null
+//   issue_guest_token {"label": string, "valid_hours": number, "max_current": number,
+//                      "max_energy_kwh": number} -> {"id", "secret", "redeem_path", ...}
+//   redeem_guest_token {"token": string} -> the redeemed token
+//   get_guest_session                  -> the guest session or null
+//   save_tenant {"id": string, "name": string, "max_current": number, "monthly_energy_wh": number,
+//                "price_per_kwh": number} -> null
+//   get_tenants                        -> [tenant]
+//   add_tenant_credential {"tenant": id, "credential": RFID UID or token} -> null
+//   authorize_tenant {"credential": string} -> the tenant
+//   get_tenant_session                 -> the tenant session or null
+//   get_tenant_report {"year": number, "month": number} -> {"csv": string, "rows": [...]}
+//   get_charging_curve {"session_id": string or absent for the running session}
+//                                      -> {"session_id", "points": [...], "csv": string} or null
+//   get_control_contact                -> true while asserted, false, or null without a contact
+//   get_session_log                    -> {"current", "last"}, each session statistics or null
+//   get_schema {"payload": name or absent} -> JSON Schema of the payload, or all of them by name
+//                                      (see schema.rs)
+//   get_daily_peaks                    -> [{"day", "offered_amps", "measured_amps"}] or null without a store
+//   request_certificate {"common_name": string, "organization": string} -> {"csr": PEM}
+//   install_certificate {"pem": string} -> the installed certificate
+//   get_certificate                    -> {"certificate", "expiry"} or null without one
+//   reset_tamper                       -> null
+//   reset_lockout                      -> null, back in service if the GFI self test passes
+//   resume_charging {"token": string}  -> the cause of the interruption (see interruption.rs)
+//   stop                               -> null
+
+pub const DEFAULT_SOCKET_PATH: &str = "/run/juiced.sock";
+
+// Owner and group may connect.
+const SOCKET_MODE: u32 = 0o660;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const MACHINE_STOPPED: i64 = -32000;
+const STORE_UNAVAILABLE: i64 = -32001;
+const LAB_MODE_OFF: i64 = -32002;
+const TOKEN_REJECTED: i64 = -32003;
+const CERTIFICATE_REJECTED: i64 = -32004;
+const CHARGING: i64 = -32005;
+
+// Binds the socket and serves it on a background thread.
+pub fn serve(path: &Path, controller: EvseController) -> io::Result<()> {
+    // A socket left over from an earlier run would make the bind fail.
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(SOCKET_MODE))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let controller = controller.clone();
+            thread::spawn(move || {
+                let _ = serve_connection(stream, &controller);
+            });
+        }
+    });
+    Ok(())
+}
+
+fn serve_connection(stream: UnixStream, controller: &EvseController) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = handle_request(controller, &line) {
+            writeln!(writer, "{}", response)?;
+        }
+    }
+    Ok(())
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "error": {"code": code, "message": message}, "id": id})
+}
+
+// Handles one request. Notifications (requests without an id) get no
+// response.
+pub fn handle_request(controller: &EvseController, request: &str) -> Option<String> {
+    let request: Value = match serde_json::from_str(request) {
+        Ok(request) => request,
+        Err(_) => return Some(error_response(Value::Null, PARSE_ERROR, "Parse error").to_string()),
+    };
+    let id = request.get("id").cloned();
+    let method = match (request.get("jsonrpc"), request.get("method")) {
+        (Some(Value::String(version)), Some(Value::String(method))) if version == "2.0" => method,
+        _ => {
+            let id = id.unwrap_or(Value::Null);
+            return Some(error_response(id, INVALID_REQUEST, "Invalid Request").to_string());
+        }
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+    let result = call(controller, method, &params);
+    let id = id?;
+    let response = match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": id}),
+        Err((code, message)) => error_response(id, code, message),
+    };
+    Some(response.to_string())
+}
+
+fn call(controller: &EvseController, method: &str, params: &Value) -> Result<Value, (i64, &'static str)> {
+    let send = |command| {
+        controller
+            .send_command(command)
+            .map(|_| Value::Null)
+            .map_err(|_| (MACHINE_STOPPED, "State machine stopped"))
+    };
+
+    match method {
+        "get_state" => Ok(json!(controller.state())),
+        "get_telemetry" => {
+            let verbosity = match params.get("diagnostic").and_then(Value::as_bool) {
+                Some(true) => TelemetryVerbosity::Diagnostic,
+                _ => TelemetryVerbosity::Normal,
+            };
+            serde_json::to_value(controller.telemetry(verbosity)).map_err(|_| (INVALID_PARAMS, "Invalid params"))
+        }
+        "set_current_limit" => match params.get("amps").and_then(Value::as_f64) {
+            Some(amps) if amps >= 0.0 => send(EvseCommand::SetCurrentLimit(amps)),
+            _ => Err((INVALID_PARAMS, "Invalid params")),
+        },
+        "adjust_current_offer" => match params.get("amps").and_then(Value::as_f64) {
+            Some(amps) => match controller.adjust_current_offer(amps) {
+                Ok(()) => Ok(Value::Null),
+                Err(EVSEError::InvalidOffer(_)) => Err((INVALID_PARAMS, "Invalid params")),
+                Err(_) => Err((MACHINE_STOPPED, "State machine stopped")),
+            },
+            None => Err((INVALID_PARAMS, "Invalid params")),
+        },
+        "set_max_current" => match params.get("amps").and_then(Value::as_f64) {
+            Some(amps) => match controller.set_max_current(amps) {
+                Ok(()) => Ok(Value::Null),
+                Err(EVSEError::InvalidMaxCurrent(_)) => Err((INVALID_PARAMS, "Invalid params")),
+                Err(EVSEError::Charging) => Err((CHARGING, "Refused while charging")),
+                Err(_) => Err((MACHINE_STOPPED, "State machine stopped")),
+            },
+            None => Err((INVALID_PARAMS, "Invalid params")),
+        },
+        "set_max_pause" => match params.get("seconds").and_then(Value::as_u64) {
+            Some(seconds) => send(EvseCommand::SetMaxPause(Duration::from_secs(seconds))),
+            _ => Err((INVALID_PARAMS, "Invalid params")),
+        },
+        "get_features" => Ok(json!(controller.features().snapshot())),
+        "set_feature" => {
+            let feature = params.get("feature").cloned().map(serde_json::from_value::<Feature>);
+            match (feature, params.get("enabled")) {
+                (Some(Ok(feature)), Some(Value::Bool(enabled))) => send(EvseCommand::SetFeature(feature, Some(*enabled))),
+                (Some(Ok(feature)), Some(Value::Null)) => send(EvseCommand::SetFeature(feature, None)),
+                _ => Err((INVALID_PARAMS, "Invalid params")),
+            }
+        }
+        "get_limits" => Ok(json!(controller.limits())),
+        "set_limit" => {
+            let limiter = params.get("limiter").cloned().map(serde_json::from_value::<Limiter>);
+            match (limiter, params.get("amps")) {
+                // The hardware cap is not for the API to change.
+                (Some(Ok(Limiter::Hardware | Limiter::Guest | Limiter::Tenant)), _) => Err((INVALID_PARAMS, "Invalid params")),
+                (Some(Ok(limiter)), Some(Value::Null)) => send(EvseCommand::SetLimit(limiter, None)),
+                (Some(Ok(limiter)), Some(amps)) => match amps.as_f64() {
+                    Some(amps) if amps >= 0.0 => send(EvseCommand::SetLimit(limiter, Some(amps))),
+                    _ => Err((INVALID_PARAMS, "Invalid params")),
+                },
+                _ => Err((INVALID_PARAMS, "Invalid params")),
+            }
+        }
+        "set_power_budget" => match params.get("watts") {
+            Some(Value::Null) => send(EvseCommand::SetPowerBudget(None)),
+            Some(watts) => match watts.as_f64() {
+                Some(watts) if watts >= 0.0 => send(EvseCommand::SetPowerBudget(Some(watts))),
+                _ => Err((INVALID_PARAMS, "Invalid params")),
+            },
+            None => Err((INVALID_PARAMS, "Invalid params")),
+        },
+        "get_phases" => Ok(json!(controller.phase_status())),
+        "get_brownout" => Ok(json!(controller.brownout_status())),
+        "get_cable_health" => Ok(json!(controller.cable_health())),
+        "identify_vehicle" => match params.get("vehicle").and_then(Value::as_str) {
+            Some(vehicle) if !vehicle.is_empty() => send(EvseCommand::IdentifyVehicle(vehicle.to_string())),
+            _ => Err((INVALID_PARAMS, "Invalid params")),
+        },
+        "set_battery_energy" => match params.get("wh").and_then(Value::as_f64) {
+            Some(wh) if wh >= 0.0 => send(EvseCommand::SetBatteryEnergy(wh)),
+            _ => Err((INVALID_PARAMS, "Invalid params")),
+        },
+        "get_shadow_report" => Ok(json!(controller.shadow_report())),
+        "get_metrics" => Ok(json!(controller.metrics())),
+        "get_error_counters" => Ok(json!(controller.error_counters())),
+        "get_lifetime_counters" => Ok(json!(controller.lifetime_counters())),
+        "get_fault_report" => match controller.fault_report().transpose() {
+            Ok(report) => Ok(json!(report)),
+            Err(_) => Err((STORE_UNAVAILABLE, "Store unavailable")),
+        },
+        "run_lab_pattern" => {
+            if !controller.lab_mode() {
+                return Err((LAB_MODE_OFF, "Lab mode is off"));
+            }
+            match serde_json::from_value::<LabPattern>(params.clone()) {
+                Ok(pattern) if pattern.validate().is_ok() => send(EvseCommand::RunLabPattern(pattern)),
+                _ => Err((INVALID_PARAMS, "Invalid params")),
+            }
+        }
+        "get_lab_report" => Ok(json!(controller.lab_report())),
+        "issue_guest_token" => {
+            let label = params.get("label").and_then(Value::as_str);
+            let hours = params.get("valid_hours").and_then(Value::as_u64);
+            let max_current = params.get("max_current").and_then(Value::as_f64);
+            let max_energy_wh = params.get("max_energy_kwh").and_then(Value::as_f64).map(|kwh| kwh * 1000.0);
+            if max_current.is_some_and(|amps| amps < 6.0) || max_energy_wh.is_some_and(|wh| wh <= 0.0) {
+                return Err((INVALID_PARAMS, "Invalid params"));
+            }
+            match (label, hours) {
+                (Some(label), Some(hours)) => controller
+                    .issue_guest_token(label, Duration::from_secs(hours * 3600), max_current, max_energy_wh)
+                    .map(|issued| json!(issued))
+                    .map_err(guest_token_error),
+                _ => Err((INVALID_PARAMS, "Invalid params")),
+            }
+        }
+        "redeem_guest_token" => match params.get("token").and_then(Value::as_str) {
+            Some(secret) => controller
+                .redeem_guest_token(secret)
+                .map(|token| json!(token))
+                .map_err(guest_token_error),
+            None => Err((INVALID_PARAMS, "Invalid params")),
+        },
+        "get_guest_session" => Ok(json!(controller.guest_session())),
+        "save_tenant" => match serde_json::from_value::<Tenant>(params.clone()) {
+            Ok(tenant) => controller.save_tenant(&tenant).map(|_| Value::Null).map_err(tenant_error),
+            Err(_) => Err((INVALID_PARAMS, "Invalid params")),
+        },
+        "get_tenants" => controller.tenants().map(|tenants| json!(tenants)).map_err(tenant_error),
+        "add_tenant_credential" => {
+            let tenant = params.get("tenant").and_then(Value::as_str);
+            let credential = params.get("credential").and_then(Value::as_str);
+            match (tenant, credential) {
+                (Some(tenant), Some(credential)) if !credential.trim().is_empty() => controller
+                    .add_tenant_credential(tenant, credential)
+                    .map(|_| Value::Null)
+                    .map_err(tenant_error),
+                _ => Err((INVALID_PARAMS, "Invalid params")),
+            }
+        }
+        "authorize_tenant" => match params.get("credential").and_then(Value::as_str) {
+            Some(credential) => controller
+                .authorize_tenant(credential)
+                .map(|tenant| json!(tenant))
+                .map_err(tenant_error),
+            None => Err((INVALID_PARAMS, "Invalid params")),
+        },
+        "get_tenant_session" => Ok(json!(controller.tenant_session())),
+        "get_tenant_report" => {
+            let year = params.get("year").and_then(Value::as_i64);
+            let month = params.get("month").and_then(Value::as_u64);
+            match (year, month) {
+                (Some(year), Some(month)) if (1..=12).contains(&month) => controller
+                    .tenant_report(year as i32, month as u32)
+                    .map(|rows| json!({ "csv": report_csv(&rows), "rows": rows }))
+                    .map_err(tenant_error),
+                _ => Err((INVALID_PARAMS, "Invalid params")),
+            }
+        }
+        "get_charging_curve" => {
+            let session_id = match params.get("session_id") {
+                None | Some(Value::Null) => None,
+                Some(id) => match id.as_str().map(str::parse::<SessionId>) {
+                    Some(Ok(id)) => Some(id),
+                    _ => return Err((INVALID_PARAMS, "Invalid params")),
+                },
+            };
+            match controller.charging_curve(session_id) {
+                Ok(Some((id, points))) => Ok(json!({ "session_id": id, "csv": curve_csv(&points), "points": points })),
+                Ok(None) => Ok(Value::Null),
+                Err(_) => Err((STORE_UNAVAILABLE, "Store unavailable")),
+            }
+        }
+        "get_control_contact" => Ok(json!(controller.control_contact())),
+        "get_session_log" => Ok(json!(controller.session_log())),
+        "get_schema" => match params.get("payload") {
+            None | Some(Value::Null) => Ok(json!(all_schemas())),
+            Some(payload) => match payload.as_str().and_then(payload_schema) {
+                Some(schema) => Ok(json!(schema)),
+                None => Err((INVALID_PARAMS, "Invalid params")),
+            },
+        },
+        "get_daily_peaks" => match controller.daily_peaks().transpose() {
+            Ok(peaks) => Ok(json!(peaks)),
+            Err(_) => Err((STORE_UNAVAILABLE, "Store unavailable")),
+        },
+        "request_certificate" => {
+            let certificates = controller.certificates().ok_or((STORE_UNAVAILABLE, "Store unavailable"))?;
+            let common_name = params.get("common_name").and_then(Value::as_str);
+            let organization = params.get("organization").and_then(Value::as_str);
+            match (common_name, organization) {
+                (Some(common_name), Some(organization)) if !common_name.is_empty() => certificates
+                    .request(common_name, organization)
+                    .map(|csr| json!({ "csr": csr }))
+                    .map_err(certificate_error),
+                _ => Err((INVALID_PARAMS, "Invalid params")),
+            }
+        }
+        "install_certificate" => {
+            let certificates = controller.certificates().ok_or((STORE_UNAVAILABLE, "Store unavailable"))?;
+            match params.get("pem").and_then(Value::as_str) {
+                Some(pem) => certificates
+                    .install(pem)
+                    .map(|info| json!(info))
+                    .map_err(certificate_error),
+                None => Err((INVALID_PARAMS, "Invalid params")),
+            }
+        }
+        "get_certificate" => match controller.certificates().map(|certificates| certificates.current()) {
+            Some(Ok(Some(info))) => Ok(json!({ "expiry": info.expiry(unix_now()), "certificate": info })),
+            Some(Ok(None)) | None => Ok(Value::Null),
+            Some(Err(error)) => Err(certificate_error(error)),
+        },
+        "reset_tamper" => send(EvseCommand::ResetTamper),
+        "reset_lockout" => send(EvseCommand::ResetLockout),
+        "resume_charging" => match params.get("token").and_then(Value::as_str) {
+            Some(secret) => controller
+                .resume_charging(secret)
+                .map(|cause| json!(cause))
+                .map_err(resume_error),
+            None => Err((INVALID_PARAMS, "Invalid params")),
+        },
+        "stop" => send(EvseCommand::Stop),
+        _ => Err((METHOD_NOT_FOUND, "Method not found")),
+    }
+}
+
+fn certificate_error(error: CertificateError) -> (i64, &'static str) {
+    match error {
+        CertificateError::Invalid => (INVALID_PARAMS, "Invalid params"),
+        CertificateError::NoPendingKey => (CERTIFICATE_REJECTED, "No certificate request pending"),
+        CertificateError::KeyMismatch => (CERTIFICATE_REJECTED, "Certificate is not for the requested key"),
+        CertificateError::Expired => (CERTIFICATE_REJECTED, "Certificate has expired"),
+        CertificateError::Io(_) | CertificateError::Generation(_) => (STORE_UNAVAILABLE, "Store unavailable"),
+    }
+}
+
+fn guest_token_error(error: GuestTokenError) -> (i64, &'static str) {
+    match error {
+        GuestTokenError::Unknown | GuestTokenError::Expired => (TOKEN_REJECTED, "Unknown or expired token"),
+        GuestTokenError::InUse => (TOKEN_REJECTED, "Station in use by another guest"),
+        GuestTokenError::InvalidValidity => (INVALID_PARAMS, "Invalid params"),
+        GuestTokenError::MachineStopped => (MACHINE_STOPPED, "State machine stopped"),
+        GuestTokenError::Store(_) | GuestTokenError::Io(_) | GuestTokenError::Disabled => {
+            (STORE_UNAVAILABLE, "Store unavailable")
+        }
+    }
+}
+
+fn resume_error(error: ResumeError) -> (i64, &'static str) {
+    match error {
+        ResumeError::Unknown | ResumeError::Expired => (TOKEN_REJECTED, "Unknown or expired token"),
+        ResumeError::ControlContact => (TOKEN_REJECTED, "Control contact asserted"),
+        ResumeError::MachineStopped | ResumeError::Io(_) => (MACHINE_STOPPED, "State machine stopped"),
+    }
+}
+
+fn tenant_error(error: TenantError) -> (i64, &'static str) {
+    match error {
+        TenantError::Unknown | TenantError::UnknownTenant => (TOKEN_REJECTED, "Unknown credential or tenant"),
+        TenantError::QuotaUsedUp => (TOKEN_REJECTED, "Monthly energy used up"),
+        TenantError::InUse => (TOKEN_REJECTED, "Station in use"),
+        TenantError::InvalidTenant => (INVALID_PARAMS, "Invalid params"),
+        TenantError::MachineStopped => (MACHINE_STOPPED, "State machine stopped"),
+        TenantError::Store(_) | TenantError::Disabled => (STORE_UNAVAILABLE, "Store unavailable"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evse::{start_machine, EVSEError, EVSEHardware, EVSEMachineInput, EvseHandle, PilotReading};
+    use crate::pilot::PilotSignal;
+    use crossbeam_channel::{unbounded, Receiver, Sender};
+
+    // Hardware that never sees a vehicle.
+    struct IdleHardware {
+        pilot_rx: Receiver<PilotReading>,
+        fault_rx: Receiver<EVSEMachineInput>,
+    }
+
+    impl EVSEHardware for IdleHardware {
+        fn set_pilot(&mut self, _signal: PilotSignal) -> Result<(), EVSEError> {
+            Ok(())
+        }
+
+        fn set_contactor(&mut self, _on: bool) -> Result<(), EVSEError> {
+            Ok(())
+        }
+
+        fn run_gfi_self_test(&mut self) -> Result<(), EVSEError> {
+            Ok(())
+        }
+
+        fn pilot_channel(&self) -> Receiver<PilotReading> {
+            self.pilot_rx.clone()
+        }
+
+        fn fault_channel(&self) -> Receiver<EVSEMachineInput> {
+            self.fault_rx.clone()
+        }
+    }
+
+    fn idle_machine() -> (EvseHandle, Sender<PilotReading>, Sender<EVSEMachineInput>) {
+        let (pilot_tx, pilot_rx) = unbounded();
+        let (fault_tx, fault_rx) = unbounded();
+        let handle = start_machine(IdleHardware { pilot_rx, fault_rx });
+        (handle, pilot_tx, fault_tx)
+    }
+
+    fn request(controller: &EvseController, request: &str) -> Value {
+        serde_json::from_str(&handle_request(controller, request).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_methods() {
+        let (handle, _pilot_tx, _fault_tx) = idle_machine();
+        let controller = handle.controller();
+
+        let response = request(&controller, r#"{"jsonrpc": "2.0", "method": "get_state", "id": 1}"#);
+        assert_eq!(response, json!({"jsonrpc": "2.0", "result": "Standby", "id": 1}));
+
+        let response = request(&controller, r#"{"jsonrpc": "2.0", "method": "get_telemetry", "id": 2}"#);
+        assert_eq!(response["result"], json!({"state": "Standby"}));
+
+        let response = request(&controller, r#"{"jsonrpc": "2.0", "method": "get_metrics", "id": 2}"#);
+        assert!(response["result"]["transitions"].is_array());
+        assert!(response["result"]["self_tests"]["runs"].is_u64());
+
+        let response = request(&controller, r#"{"jsonrpc": "2.0", "method": "get_error_counters", "id": 2}"#);
+        assert_eq!(response["result"]["gfi_trips"], 0);
+
+        let response = request(&controller, r#"{"jsonrpc": "2.0", "method": "get_lifetime_counters", "id": 2}"#);
+        assert_eq!(response["result"]["sessions"], 0);
+
+        // No session was interrupted.
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "resume_charging", "params": {"token": "ab12"}, "id": 2}"#,
+        );
+        assert_eq!(response["error"]["code"], TOKEN_REJECTED);
+
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "set_current_limit", "params": {"amps": 16}, "id": 3}"#,
+        );
+        assert_eq!(response["result"], Value::Null);
+
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "set_max_pause", "params": {"seconds": 3600}, "id": 4}"#,
+        );
+        assert_eq!(response["result"], Value::Null);
+
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "get_schema", "params": {"payload": "session_log"}, "id": 4}"#,
+        );
+        assert_eq!(response["result"]["title"], "SessionLog");
+
+        // No session running.
+        let response = request(&controller, r#"{"jsonrpc": "2.0", "method": "get_charging_curve", "id": 4}"#);
+        assert_eq!(response["result"], Value::Null);
+
+        let response = request(&controller, r#"{"jsonrpc": "2.0", "method": "stop", "id": 4}"#);
+        assert_eq!(response["result"], Value::Null);
+        handle.join().unwrap();
+
+        let response = request(&controller, r#"{"jsonrpc": "2.0", "method": "stop", "id": 5}"#);
+        assert_eq!(response["error"]["code"], MACHINE_STOPPED);
+    }
+
+    #[test]
+    fn test_features() {
+        let (handle, _pilot_tx, _fault_tx) = idle_machine();
+        let controller = handle.controller();
+
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "set_feature", "params": {"feature": "solar_mode", "enabled": true}, "id": 1}"#,
+        );
+        assert_eq!(response["result"], Value::Null);
+        let solar_mode = json!({"feature": "solar_mode", "enabled": true, "source": "override"});
+        let start = std::time::Instant::now();
+        while request(&controller, r#"{"jsonrpc": "2.0", "method": "get_features", "id": 2}"#)["result"][1] != solar_mode
+        {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "set_feature", "params": {"feature": "warp_drive", "enabled": true}, "id": 3}"#,
+        );
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_limits() {
+        let (handle, _pilot_tx, _fault_tx) = idle_machine();
+        let controller = handle.controller();
+
+        // The surplus only counts in solar mode.
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "set_feature", "params": {"feature": "solar_mode", "enabled": true}, "id": 1}"#,
+        );
+        assert_eq!(response["result"], Value::Null);
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "set_limit", "params": {"limiter": "solar", "amps": 10}, "id": 1}"#,
+        );
+        assert_eq!(response["result"], Value::Null);
+        let start = std::time::Instant::now();
+        loop {
+            let limits = request(&controller, r#"{"jsonrpc": "2.0", "method": "get_limits", "id": 2}"#)["result"].clone();
+            if limits["binding"] == "solar" {
+                assert_eq!(limits["offer"], 10.0);
+                assert_eq!(limits["vehicle_floor"], 6.0);
+                assert_eq!(limits["caps"][1], json!({"limiter": "solar", "amps": 10.0}));
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "set_limit", "params": {"limiter": "hardware", "amps": 80}, "id": 3}"#,
+        );
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "set_max_current", "params": {"amps": 5}, "id": 3}"#,
+        );
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "adjust_current_offer", "params": {"amps": -1}, "id": 3}"#,
+        );
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "identify_vehicle", "params": {"vehicle": "WVWZZZ1JZXW000001"}, "id": 4}"#,
+        );
+        assert_eq!(response["result"], Value::Null);
+
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "set_battery_energy", "params": {"wh": -5}, "id": 5}"#,
+        );
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let (handle, _pilot_tx, _fault_tx) = idle_machine();
+        let controller = handle.controller();
+
+        assert_eq!(request(&controller, "{")["error"]["code"], PARSE_ERROR);
+        assert_eq!(request(&controller, r#"{"method": "stop", "id": 1}"#)["error"]["code"], INVALID_REQUEST);
+        let response = request(&controller, r#"{"jsonrpc": "2.0", "method": "reboot", "id": 1}"#);
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "set_current_limit", "params": {"amps": "lots"}, "id": 1}"#,
+        );
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        let response = request(
+            &controller,
+            r#"{"jsonrpc": "2.0", "method": "get_charging_curve", "params": {"session_id": "last"}, "id": 1}"#,
+        );
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+
+        // Notifications are not answered.
+        assert_eq!(handle_request(&controller, r#"{"jsonrpc": "2.0", "method": "get_state"}"#), None);
+
+        handle.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_socket() {
+        let (handle, _pilot_tx, _fault_tx) = idle_machine();
+        let path = std::env::temp_dir().join(format!("juicelib-rpc-{}.sock", std::process::id()));
+        serve(&path, handle.controller()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, SOCKET_MODE);
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        writeln!(stream, r#"{{"jsonrpc": "2.0", "method": "get_state", "id": "a"}}"#).unwrap();
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response, json!({"jsonrpc": "2.0", "result": "Standby", "id": "a"}));
+
+        handle.stop();
+        handle.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
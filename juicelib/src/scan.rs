@@ -0,0 +1,223 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+
+// Channel scan scheduler for the ADC. Switching channels on the MCP300x
+// without giving the sample-and-hold capacitor time to settle makes a
+// channel with a high source impedance read partly as the channel before
+// it. Every step of a scan can therefore throw away a few conversions and/or
+// wait before the conversion that is kept.
+
+// Something that converts a single ADC channel.
+pub trait ChannelReader {
+    type Error;
+
+    fn read_channel(&mut self, channel: u8) -> Result<u16, Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanStep {
+    pub channel: u8,
+    // Conversions that are done and discarded after switching to the channel.
+    pub dummy_reads: u8,
+    // Delay after the dummy reads, before the conversion that is kept.
+    pub settle: Duration,
+}
+
+impl ScanStep {
+    pub fn new(channel: u8) -> Self {
+        Self {
+            channel,
+            dummy_reads: 0,
+            settle: Duration::ZERO,
+        }
+    }
+
+    pub fn with_dummy_reads(mut self, dummy_reads: u8) -> Self {
+        self.dummy_reads = dummy_reads;
+        self
+    }
+
+    pub fn with_settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+}
+
+// An ordered list of steps. The results of a scan come in the same order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelScan {
+    steps: Vec<ScanStep>,
+}
+
+impl ChannelScan {
+    pub fn new(steps: Vec<ScanStep>) -> Self {
+        Self { steps }
+    }
+
+    pub fn steps(&self) -> &[ScanStep] {
+        &self.steps
+    }
+
+    pub fn run<R: ChannelReader>(&self, reader: &mut R) -> Result<Vec<u16>, R::Error> {
+        let mut readings = Vec::with_capacity(self.steps.len());
+        for step in &self.steps {
+            for _ in 0..step.dummy_reads {
+                reader.read_channel(step.channel)?;
+            }
+            if !step.settle.is_zero() {
+                thread::sleep(step.settle);
+            }
+            readings.push(reader.read_channel(step.channel)?);
+        }
+        Ok(readings)
+    }
+}
+
+// Result of comparing two scans of the same channels. The errors are the
+// mean absolute deviation (in ADC counts) of the scanned readings from the
+// settled value of each channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScanComparison {
+    pub baseline_error: f64,
+    pub candidate_error: f64,
+}
+
+impl ScanComparison {
+    // How many times smaller the error of the candidate is.
+    pub fn improvement(&self) -> f64 {
+        if self.candidate_error == 0.0 {
+            return f64::INFINITY;
+        }
+        self.baseline_error / self.candidate_error
+    }
+}
+
+// Reads used to find the settled value of a channel.
+const REFERENCE_DUMMY_READS: usize = 8;
+const REFERENCE_READS: usize = 16;
+
+fn settled_value<R: ChannelReader>(reader: &mut R, channel: u8) -> Result<f64, R::Error> {
+    for _ in 0..REFERENCE_DUMMY_READS {
+        reader.read_channel(channel)?;
+    }
+    let mut sum = 0.0;
+    for _ in 0..REFERENCE_READS {
+        sum += reader.read_channel(channel)? as f64;
+    }
+    Ok(sum / REFERENCE_READS as f64)
+}
+
+fn scan_error<R: ChannelReader>(
+    reader: &mut R,
+    scan: &ChannelScan,
+    references: &BTreeMap<u8, f64>,
+    rounds: usize,
+) -> Result<f64, R::Error> {
+    let mut total = 0.0;
+    let mut count = 0;
+    for _ in 0..rounds {
+        let readings = scan.run(reader)?;
+        for (step, reading) in scan.steps().iter().zip(readings) {
+            total += (reading as f64 - references[&step.channel]).abs();
+            count += 1;
+        }
+    }
+    Ok(if count == 0 { 0.0 } else { total / count as f64 })
+}
+
+// Bench helper: measures how much closer to the settled channel values the
+// candidate scan reads compared to the baseline. Meant to be run on the
+// bench with fixed inputs on the channels.
+pub fn compare_scans<R: ChannelReader>(
+    reader: &mut R,
+    baseline: &ChannelScan,
+    candidate: &ChannelScan,
+    rounds: usize,
+) -> Result<ScanComparison, R::Error> {
+    let mut references = BTreeMap::new();
+    for step in baseline.steps().iter().chain(candidate.steps()) {
+        if let Entry::Vacant(entry) = references.entry(step.channel) {
+            entry.insert(settled_value(reader, step.channel)?);
+        }
+    }
+
+    Ok(ScanComparison {
+        baseline_error: scan_error(reader, baseline, &references, rounds)?,
+        candidate_error: scan_error(reader, candidate, &references, rounds)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Models a high impedance source: right after switching channels the
+    // conversion still carries half of the previous channel's value.
+    struct CrosstalkReader {
+        values: [u16; 4],
+        last_channel: Option<u8>,
+        reads: usize,
+    }
+
+    impl CrosstalkReader {
+        fn new(values: [u16; 4]) -> Self {
+            Self {
+                values,
+                last_channel: None,
+                reads: 0,
+            }
+        }
+    }
+
+    impl ChannelReader for CrosstalkReader {
+        type Error = ();
+
+        fn read_channel(&mut self, channel: u8) -> Result<u16, ()> {
+            self.reads += 1;
+            let value = self.values[channel as usize];
+            let reading = match self.last_channel {
+                Some(last) if last != channel => (value + self.values[last as usize]) / 2,
+                _ => value,
+            };
+            self.last_channel = Some(channel);
+            Ok(reading)
+        }
+    }
+
+    #[test]
+    fn test_scan_order_and_dummy_reads() {
+        let mut reader = CrosstalkReader::new([100, 900, 500, 0]);
+        let scan = ChannelScan::new(vec![
+            ScanStep::new(1).with_dummy_reads(2),
+            ScanStep::new(0).with_dummy_reads(1),
+            ScanStep::new(2),
+        ]);
+        assert_eq!(scan.run(&mut reader), Ok(vec![900, 100, 300]));
+        assert_eq!(reader.reads, 6);
+    }
+
+    #[test]
+    fn test_settle_delay_is_applied() {
+        let mut reader = CrosstalkReader::new([100, 900, 500, 0]);
+        let scan = ChannelScan::new(vec![ScanStep::new(0).with_settle(Duration::from_millis(5))]);
+        let start = std::time::Instant::now();
+        scan.run(&mut reader).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_compare_scans_quantifies_crosstalk() {
+        let mut reader = CrosstalkReader::new([100, 900, 500, 0]);
+        let baseline = ChannelScan::new(vec![ScanStep::new(0), ScanStep::new(1), ScanStep::new(2)]);
+        let candidate = ChannelScan::new(
+            baseline.steps().iter().map(|step| step.with_dummy_reads(1)).collect(),
+        );
+
+        let comparison = compare_scans(&mut reader, &baseline, &candidate, 10).unwrap();
+        assert!(comparison.baseline_error > 100.0);
+        assert_eq!(comparison.candidate_error, 0.0);
+        assert_eq!(comparison.improvement(), f64::INFINITY);
+    }
+}
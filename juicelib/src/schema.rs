@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+
+use crate::analytics::FaultReport;
+use crate::config::Config;
+use crate::events::EVSEEvent;
+use crate::session::SessionLog;
+use crate::telemetry::TelemetrySample;
+
+// JSON Schemas of the payloads juiced hands out, generated from the types
+// themselves so they cannot drift from what is sent. Integrators generate
+// clients from them or validate payloads against them. They are served by
+// the get_schema method of the control socket and printed by
+// `juiced schema [payload]`.
+
+// The payloads by name.
+pub const PAYLOADS: [&str; 5] = ["telemetry", "session_log", "fault_report", "config", "event"];
+
+// None for an unknown payload.
+pub fn payload_schema(payload: &str) -> Option<RootSchema> {
+    Some(match payload {
+        // get_telemetry, at diagnostic verbosity.
+        "telemetry" => schema_for!(TelemetrySample),
+        "session_log" => schema_for!(SessionLog),
+        "fault_report" => schema_for!(FaultReport),
+        // The board's config file, see config.rs.
+        "config" => schema_for!(Config),
+        // What start_machine_with_events sends.
+        "event" => schema_for!(EVSEEvent),
+        _ => return None,
+    })
+}
+
+pub fn all_schemas() -> BTreeMap<&'static str, RootSchema> {
+    PAYLOADS
+        .iter()
+        .filter_map(|&payload| payload_schema(payload).map(|schema| (payload, schema)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_schemas() {
+        assert_eq!(all_schemas().len(), PAYLOADS.len());
+        assert!(payload_schema("status").is_none());
+
+        let telemetry = serde_json::to_value(payload_schema("telemetry").unwrap()).unwrap();
+        assert_eq!(telemetry["title"], "TelemetrySample");
+        assert_eq!(telemetry["required"], json!(["state"]));
+        assert_eq!(telemetry["definitions"]["SessionId"], json!({"type": "string", "format": "uuid"}));
+        let states = &telemetry["definitions"]["EVSEMachineState"]["enum"];
+        assert!(states.as_array().unwrap().contains(&json!("Charging")));
+
+        let config = serde_json::to_value(payload_schema("config").unwrap()).unwrap();
+        assert_eq!(config["additionalProperties"], false);
+        assert_eq!(config["properties"]["max_current"]["default"], 32.0);
+    }
+}
@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::session_id::SessionId;
+
+// Statistics of the charging session, from plug-in to unplug, for the
+// front-ends: how long the vehicle charged, the mean and peak current and
+// the energy. The vehicle may start and stop charging several times in a
+// session, e.g. when it pauses; only the time in Charging counts.
+//
+// The current is the one measured on L1, or the offer without a current
+// sense, and the energy is reckoned like the charging curve (see
+// charging_curve.rs), so it is an estimate unless the station measures the
+// current and the mains voltage.
+//
+// Once the energy the battery took in is known, reported by the vehicle or
+// entered by the driver, the difference is what the on-board charger and
+// the cable lost. A vehicle that keeps losing more than usual has a failing
+// charger or a bad cable.
+
+// Below this share of the energy reaching the battery, the session is
+// flagged. Healthy on-board chargers manage 85 to 95%.
+pub const LOW_EFFICIENCY: f64 = 0.8;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, JsonSchema)]
+pub struct SessionStats {
+    pub session_id: Option<SessionId>,
+    // When the vehicle first started to charge.
+    pub started_at: u64,
+    // When it last stopped charging. None while it charges.
+    pub stopped_at: Option<u64>,
+    // When it was unplugged. None while the session runs.
+    pub ended_at: Option<u64>,
+    // How often it started to charge.
+    pub charging_periods: u32,
+    pub charging_secs: f64,
+    // Weighted by time.
+    pub average_amps: f64,
+    pub peak_amps: f64,
+    pub energy_wh: f64,
+    // Energy the battery took in. None until the vehicle or the driver
+    // tells.
+    pub battery_wh: Option<f64>,
+    pub losses_wh: Option<f64>,
+    // The share of energy_wh that reached the battery.
+    pub efficiency: Option<f64>,
+}
+
+impl SessionStats {
+    // Whether noticeably less reached the battery than a healthy on-board
+    // charger lets through.
+    pub fn low_efficiency(&self) -> bool {
+        self.efficiency.is_some_and(|efficiency| efficiency < LOW_EFFICIENCY)
+    }
+
+    fn update_losses(&mut self) {
+        let Some(battery_wh) = self.battery_wh else {
+            return;
+        };
+        if self.energy_wh > 0.0 {
+            self.losses_wh = Some(self.energy_wh - battery_wh);
+            self.efficiency = Some(battery_wh / self.energy_wh);
+        }
+    }
+}
+
+// The running session and the one before it. The machine keeps it up to
+// date; EvseController::session_log() hands out a copy.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, JsonSchema)]
+pub struct SessionLog {
+    current: Option<SessionStats>,
+    last: Option<SessionStats>,
+}
+
+impl SessionLog {
+    // The running session, once the vehicle has started to charge.
+    pub fn current(&self) -> Option<&SessionStats> {
+        self.current.as_ref()
+    }
+
+    // The last session that ended.
+    pub fn last(&self) -> Option<&SessionStats> {
+        self.last.as_ref()
+    }
+
+    pub fn charging_started(&mut self, unix_time: u64, session_id: Option<SessionId>) {
+        let session = self.current.get_or_insert_with(|| SessionStats {
+            session_id,
+            started_at: unix_time,
+            ..SessionStats::default()
+        });
+        session.stopped_at = None;
+        session.charging_periods += 1;
+    }
+
+    pub fn charging_stopped(&mut self, unix_time: u64) {
+        if let Some(session) = self.current.as_mut() {
+            session.stopped_at = Some(unix_time);
+        }
+    }
+
+    // Adds the time since the last reading, charging at `amps` and
+    // `power_w`.
+    pub fn add_charging(&mut self, amps: f64, power_w: f64, duration: Duration) {
+        let Some(session) = self.current.as_mut() else {
+            return;
+        };
+        let secs = duration.as_secs_f64();
+        session.charging_secs += secs;
+        if session.charging_secs > 0.0 {
+            session.average_amps += (amps - session.average_amps) * secs / session.charging_secs;
+        }
+        session.peak_amps = session.peak_amps.max(amps);
+        session.energy_wh += power_w * secs / 3600.0;
+        session.update_losses();
+    }
+
+    // The energy the battery took in so far. It goes to the running
+    // session, or to the last one if none runs: drivers read it off the
+    // vehicle after the unplug. Returns the session, None without one.
+    pub fn set_battery_energy(&mut self, battery_wh: f64) -> Option<&SessionStats> {
+        let session = match self.current.as_mut() {
+            Some(session) => session,
+            None => self.last.as_mut()?,
+        };
+        session.battery_wh = Some(battery_wh);
+        session.update_losses();
+        Some(session)
+    }
+
+    // The vehicle was unplugged. A session it never charged in is not
+    // kept.
+    pub fn end(&mut self, unix_time: u64) {
+        if let Some(mut session) = self.current.take() {
+            session.stopped_at.get_or_insert(unix_time);
+            session.ended_at = Some(unix_time);
+            self.last = Some(session);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_with_pause() {
+        let mut log = SessionLog::default();
+        let start = 1_700_000_000;
+        // Plugged in without charging.
+        log.add_charging(16.0, 3680.0, Duration::from_secs(60));
+        log.end(start);
+        assert_eq!(log.current(), None);
+        assert_eq!(log.last(), None);
+
+        let id = SessionId::new();
+        log.charging_started(start, Some(id));
+        log.add_charging(16.0, 3680.0, Duration::from_secs(30 * 60));
+        log.charging_stopped(start + 1800);
+        log.charging_started(start + 2400, None);
+        log.add_charging(10.0, 2300.0, Duration::from_secs(10 * 60));
+        log.add_charging(10.0, 2300.0, Duration::from_secs(20 * 60));
+        let current = log.current().unwrap();
+        assert_eq!(current.session_id, Some(id));
+        assert_eq!(current.stopped_at, None);
+        assert_eq!(current.charging_periods, 2);
+        assert_eq!(current.charging_secs, 3600.0);
+        assert!((current.average_amps - 13.0).abs() < 1e-9);
+        assert_eq!(current.peak_amps, 16.0);
+        assert!((current.energy_wh - 2990.0).abs() < 1e-9);
+
+        log.end(start + 6000);
+        let last = log.last().unwrap();
+        assert_eq!((last.started_at, last.stopped_at, last.ended_at), (start, Some(start + 6000), Some(start + 6000)));
+        assert_eq!(log.current(), None);
+    }
+
+    #[test]
+    fn test_efficiency() {
+        let mut log = SessionLog::default();
+        assert_eq!(log.set_battery_energy(1000.0), None);
+
+        log.charging_started(1_700_000_000, None);
+        log.add_charging(16.0, 3600.0, Duration::from_secs(3600));
+        let session = log.set_battery_energy(3240.0).unwrap();
+        assert!((session.losses_wh.unwrap() - 360.0).abs() < 1e-9);
+        assert!((session.efficiency.unwrap() - 0.9).abs() < 1e-9);
+        assert!(!session.low_efficiency());
+
+        // The reported battery energy lags behind while charging.
+        log.add_charging(16.0, 3600.0, Duration::from_secs(3600));
+        assert!((log.current().unwrap().efficiency.unwrap() - 0.45).abs() < 1e-9);
+
+        // Entered after the unplug.
+        log.end(1_700_007_200);
+        let session = log.set_battery_energy(5400.0).unwrap();
+        assert!((session.efficiency.unwrap() - 0.75).abs() < 1e-9);
+        assert!(session.low_efficiency());
+    }
+}
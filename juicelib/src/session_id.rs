@@ -0,0 +1,125 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::backup::random_bytes;
+
+// A random (version 4) UUID for each session, from plug-in to unplug. It
+// goes with the session into the store, the telemetry and the meter
+// samples, so the backends can tell the sessions apart no matter how often
+// they hear about one.
+//
+// A report about a session that is sent again after a connection blip
+// carries the same idempotency key as the first attempt. Backends that
+// keep the keys they have seen then count the session once. The store
+// does the same with the session id itself.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId([u8; 16]);
+
+impl SessionId {
+    pub fn new() -> Self {
+        let mut bytes = [0u8; 16];
+        if random_bytes(&mut bytes).is_err() {
+            // Unique enough for a station without a random source.
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+            let seed = format!("{}-{}", nanos, std::process::id());
+            bytes.copy_from_slice(&Sha256::digest(seed.as_bytes())[..16]);
+        }
+        Self::from_random(bytes)
+    }
+
+    fn from_random(mut bytes: [u8; 16]) -> Self {
+        // Version 4, variant 1.
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Self(bytes)
+    }
+
+    // The key of a report about the session, e.g. "stopped" or
+    // "meter-values-12". The same report always has the same key.
+    pub fn idempotency_key(&self, report: &str) -> String {
+        format!("{}:{}", self, report)
+    }
+}
+
+impl Default for SessionId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SessionId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex: String = s.chars().filter(|&c| c != '-').collect();
+        let invalid = || format!("invalid session id \"{}\"", s);
+        if s.len() != 36 || hex.len() != 32 {
+            return Err(invalid());
+        }
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl Serialize for SessionId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl JsonSchema for SessionId {
+    fn schema_name() -> String {
+        "SessionId".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            format: Some("uuid".to_string()),
+            ..SchemaObject::default()
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_and_parse() {
+        let id = SessionId::from_random([0xff; 16]);
+        assert_eq!(id.to_string(), "ffffffff-ffff-4fff-bfff-ffffffffffff");
+        assert_eq!(id.to_string().parse(), Ok(id));
+        assert_eq!(serde_json::to_string(&id).unwrap(), "\"ffffffff-ffff-4fff-bfff-ffffffffffff\"");
+        assert!("ffffffff-ffff-4fff-bfff-fffffffffffg".parse::<SessionId>().is_err());
+        assert!("ffffffff".parse::<SessionId>().is_err());
+
+        let id = SessionId::new();
+        assert_ne!(id, SessionId::new());
+        assert_eq!(id.idempotency_key("stopped"), format!("{}:stopped", id));
+    }
+}
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+
+use crate::evse::{EVSEMachineInput, PilotClassifier, PilotReading};
+
+// Shadow runs of candidate algorithms. A candidate gets the same live data
+// as the algorithm in charge and decides along, but its decisions are only
+// compared and never acted on. Divergences go to the audit log and the
+// comparison report tells whether the candidate is fit to take over, so a
+// change can be trialled on a charger in service without risk.
+
+// How often the live and the shadow algorithm decided a given pair.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Disagreement<D> {
+    pub live: D,
+    pub shadow: D,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ShadowReport<D> {
+    pub candidate: String,
+    pub decisions: u64,
+    pub agreements: u64,
+    pub disagreements: Vec<Disagreement<D>>,
+}
+
+// Compares the decisions of the live and the shadow algorithm.
+#[derive(Debug, Clone)]
+pub struct ShadowComparison<D> {
+    report: ShadowReport<D>,
+    diverged: bool,
+}
+
+impl<D: Copy + PartialEq> ShadowComparison<D> {
+    pub fn new(candidate: &str) -> Self {
+        Self {
+            report: ShadowReport {
+                candidate: candidate.to_string(),
+                decisions: 0,
+                agreements: 0,
+                disagreements: Vec::new(),
+            },
+            diverged: false,
+        }
+    }
+
+    // Counts one decision. Returns true when the shadow starts to diverge
+    // from the live algorithm, so a run of disagreements is logged once.
+    pub fn compare(&mut self, live: D, shadow: D) -> bool {
+        let report = &mut self.report;
+        report.decisions += 1;
+        if live == shadow {
+            report.agreements += 1;
+            self.diverged = false;
+            return false;
+        }
+        match report
+            .disagreements
+            .iter_mut()
+            .find(|pair| pair.live == live && pair.shadow == shadow)
+        {
+            Some(pair) => pair.count += 1,
+            None => report.disagreements.push(Disagreement { live, shadow, count: 1 }),
+        }
+        !std::mem::replace(&mut self.diverged, true)
+    }
+
+    pub fn report(&self) -> &ShadowReport<D> {
+        &self.report
+    }
+}
+
+// Candidate pilot classifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "algorithm")]
+pub enum PilotCandidate {
+    // The classifier in charge with another hysteresis margin.
+    Hysteresis { margin: f32 },
+}
+
+impl PilotCandidate {
+    fn name(&self) -> String {
+        match self {
+            PilotCandidate::Hysteresis { margin } => format!("hysteresis {} V", margin),
+        }
+    }
+
+    fn classifier(&self) -> PilotClassifier {
+        match self {
+            PilotCandidate::Hysteresis { margin } => PilotClassifier::new(*margin),
+        }
+    }
+}
+
+// A candidate pilot classifier running on the live pilot readings.
+pub struct ShadowPilot {
+    classifier: PilotClassifier,
+    comparison: ShadowComparison<EVSEMachineInput>,
+}
+
+impl ShadowPilot {
+    pub fn new(candidate: PilotCandidate) -> Self {
+        Self {
+            classifier: candidate.classifier(),
+            comparison: ShadowComparison::new(&candidate.name()),
+        }
+    }
+
+    // Classifies the reading the live classifier turned into `live`.
+    // Returns a line for the audit log when the candidate starts to
+    // diverge.
+    pub fn observe(&mut self, reading: &PilotReading, live: EVSEMachineInput) -> Option<String> {
+        let shadow = self.classifier.get_pilot_state(reading.high);
+        self.comparison.compare(live, shadow).then(|| {
+            format!(
+                "shadow pilot classifier ({}) decided {:?} where the live one decided {:?}, pilot at {:.2} V",
+                self.comparison.report().candidate,
+                shadow,
+                live,
+                reading.high
+            )
+        })
+    }
+
+    pub fn report(&self) -> &ShadowReport<EVSEMachineInput> {
+        self.comparison.report()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evse::DEFAULT_PILOT_HYSTERESIS;
+
+    #[test]
+    fn test_comparison() {
+        let mut comparison = ShadowComparison::new("candidate");
+        assert!(!comparison.compare(1, 1));
+        assert!(comparison.compare(1, 2));
+        // Still the same divergence.
+        assert!(!comparison.compare(1, 2));
+        assert!(!comparison.compare(3, 3));
+        assert!(comparison.compare(1, 2));
+
+        assert_eq!(
+            *comparison.report(),
+            ShadowReport {
+                candidate: "candidate".to_string(),
+                decisions: 5,
+                agreements: 2,
+                disagreements: vec![Disagreement {
+                    live: 1,
+                    shadow: 2,
+                    count: 3
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn test_shadow_pilot() {
+        let mut live = PilotClassifier::new(DEFAULT_PILOT_HYSTERESIS);
+        let mut shadow = ShadowPilot::new(PilotCandidate::Hysteresis { margin: 0.0 });
+        let mut log = Vec::new();
+        // 7.4 V is just below the 9V band; the wider live hysteresis keeps
+        // it at 9V, the candidate without hysteresis does not.
+        for high in [9.0, 7.4, 7.4, 9.0] {
+            let reading = PilotReading {
+                high,
+                low: -12.0,
+                duty_cycle: 0.5,
+                frequency: 1000.0,
+            };
+            let decision = live.get_pilot_state(high);
+            log.extend(shadow.observe(&reading, decision));
+        }
+        assert_eq!(log.len(), 1);
+        assert!(log[0].contains("decided PilotIs6V where the live one decided PilotIs9V"));
+        let report = shadow.report();
+        assert_eq!((report.decisions, report.agreements), (4, 2));
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+// Start/stop hysteresis of the solar mode. The solar cap follows the PV
+// surplus, but no vehicle charges on less than its floor, so once the
+// surplus is short of it the charge stops for want of an offer. A surplus
+// hovering around the floor would start and stop the charge, and cycle the
+// contactor, every few seconds. Instead the charge stops once the surplus
+// is stop_margin below the floor and starts again once it is start_margin
+// above it. A charge runs for min_on and rests for min_off at least, and
+// starts at most max_starts_per_hour times an hour.
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SolarHysteresis {
+    pub start_margin_amps: f64,
+    pub stop_margin_amps: f64,
+    pub min_on_secs: u64,
+    pub min_off_secs: u64,
+    pub max_starts_per_hour: u32,
+}
+
+impl Default for SolarHysteresis {
+    fn default() -> Self {
+        Self {
+            start_margin_amps: 1.0,
+            stop_margin_amps: 1.0,
+            min_on_secs: 5 * 60,
+            min_off_secs: 5 * 60,
+            max_starts_per_hour: 6,
+        }
+    }
+}
+
+pub struct SolarController {
+    hysteresis: SolarHysteresis,
+    // The surplus the solar mode was last given. None outside solar mode.
+    surplus: Option<f64>,
+    charging: bool,
+    // When the charge last started or stopped.
+    switched_at: Option<Instant>,
+    // The starts of the last hour.
+    starts: VecDeque<Instant>,
+}
+
+impl SolarController {
+    pub fn new(hysteresis: SolarHysteresis) -> Self {
+        Self {
+            hysteresis,
+            surplus: None,
+            charging: false,
+            switched_at: None,
+            starts: VecDeque::new(),
+        }
+    }
+
+    pub fn set_surplus(&mut self, amps: Option<f64>) {
+        self.surplus = amps;
+    }
+
+    // Decides whether the charge runs on the surplus and the floor of the
+    // vehicle. Returns the decision when it changes.
+    pub fn update(&mut self, floor: f64, now: Instant) -> Option<bool> {
+        let surplus = self.surplus?;
+        let hold = Duration::from_secs(match self.charging {
+            true => self.hysteresis.min_on_secs,
+            false => self.hysteresis.min_off_secs,
+        });
+        if self.switched_at.is_some_and(|at| now.duration_since(at) < hold) {
+            return None;
+        }
+        while self.starts.front().is_some_and(|&start| now.duration_since(start) >= HOUR) {
+            self.starts.pop_front();
+        }
+
+        let switch = if self.charging {
+            surplus < floor - self.hysteresis.stop_margin_amps
+        } else {
+            surplus >= floor + self.hysteresis.start_margin_amps
+                && self.starts.len() < self.hysteresis.max_starts_per_hour as usize
+        };
+        if !switch {
+            return None;
+        }
+        self.charging = !self.charging;
+        self.switched_at = Some(now);
+        if self.charging {
+            self.starts.push_back(now);
+        }
+        Some(self.charging)
+    }
+
+    // The cap of the solar limiter: the surplus while the charge runs, 0
+    // while it rests.
+    pub fn cap(&self) -> Option<f64> {
+        self.surplus.map(|surplus| if self.charging { surplus } else { 0.0 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hysteresis() {
+        let mut solar = SolarController::new(SolarHysteresis::default());
+        let start = Instant::now();
+        assert_eq!(solar.update(6.0, start), None);
+        assert_eq!(solar.cap(), None);
+
+        // Hovering around the floor does not start the charge.
+        solar.set_surplus(Some(6.5));
+        assert_eq!(solar.update(6.0, start), None);
+        assert_eq!(solar.cap(), Some(0.0));
+        solar.set_surplus(Some(7.0));
+        assert_eq!(solar.update(6.0, start), Some(true));
+        assert_eq!(solar.cap(), Some(7.0));
+
+        // Nor stops it, and a charge runs for its minimum time.
+        solar.set_surplus(Some(5.5));
+        assert_eq!(solar.update(6.0, start), None);
+        solar.set_surplus(Some(2.0));
+        assert_eq!(solar.update(6.0, start + Duration::from_secs(60)), None);
+        assert_eq!(solar.update(6.0, start + Duration::from_secs(300)), Some(false));
+        assert_eq!(solar.cap(), Some(0.0));
+
+        // Rests for its minimum time.
+        solar.set_surplus(Some(10.0));
+        assert_eq!(solar.update(6.0, start + Duration::from_secs(400)), None);
+        assert_eq!(solar.update(6.0, start + Duration::from_secs(600)), Some(true));
+        // Against the vehicle's floor.
+        solar.set_surplus(Some(8.5));
+        assert_eq!(solar.update(10.0, start + Duration::from_secs(900)), Some(false));
+    }
+
+    #[test]
+    fn test_starts_per_hour() {
+        let mut solar = SolarController::new(SolarHysteresis {
+            min_on_secs: 0,
+            min_off_secs: 0,
+            max_starts_per_hour: 2,
+            ..SolarHysteresis::default()
+        });
+        let start = Instant::now();
+        let mut starts = 0;
+        for minute in 0..60 {
+            solar.set_surplus(Some(if minute % 2 == 0 { 8.0 } else { 4.0 }));
+            if solar.update(6.0, start + Duration::from_secs(minute * 60)) == Some(true) {
+                starts += 1;
+            }
+        }
+        assert_eq!(starts, 2);
+        solar.set_surplus(Some(8.0));
+        assert_eq!(solar.update(6.0, start + Duration::from_secs(61 * 60)), Some(true));
+    }
+}
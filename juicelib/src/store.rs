@@ -0,0 +1,570 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection};
+
+use crate::analytics::FaultRecord;
+use crate::charging_curve::CurvePoint;
+use crate::config_history::ConfigSnapshot;
+use crate::guest_token::GuestToken;
+use crate::peaks::DailyPeak;
+use crate::session_id::SessionId;
+use crate::tenants::{Tenant, TenantUsage};
+use crate::vehicle_floor::VehicleFloor;
+
+// SQLite store of the sessions and events. The schema is versioned with
+// the user_version pragma and migrated when the store is opened, so an
+// upgraded juiced picks up an old database on its first start. A database
+// written by a newer juiced is refused rather than misread.
+
+pub const DEFAULT_STORE_PATH: &str = "/var/lib/juiced/juiced.db";
+
+// Migration n takes the schema from version n to version n + 1. Released
+// migrations must never change; changes go into a new one.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/0001_sessions_and_events.sql"),
+    include_str!("../migrations/0002_vehicle_floors.sql"),
+    include_str!("../migrations/0003_fault_conditions.sql"),
+    include_str!("../migrations/0004_guest_tokens.sql"),
+    include_str!("../migrations/0005_pilot_cable.sql"),
+    include_str!("../migrations/0006_tenants.sql"),
+    include_str!("../migrations/0007_session_ids.sql"),
+    include_str!("../migrations/0008_charging_curves.sql"),
+    include_str!("../migrations/0009_daily_peaks.sql"),
+    include_str!("../migrations/0010_residual_current.sql"),
+    include_str!("../migrations/0011_config_snapshots.sql"),
+    include_str!("../migrations/0012_fault_vehicle.sql"),
+];
+
+pub fn schema_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    Io(std::io::Error),
+    // The database was written by a newer juiced.
+    NewerSchema { found: u32, supported: u32 },
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(error: rusqlite::Error) -> Self {
+        StoreError::Sqlite(error)
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(error: std::io::Error) -> Self {
+        StoreError::Io(error)
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Sqlite(error) => write!(f, "database error: {}", error),
+            StoreError::Io(error) => write!(f, "cannot open the database: {}", error),
+            StoreError::NewerSchema { found, supported } => write!(
+                f,
+                "the database has schema version {} but this juiced only supports up to {}; \
+                 downgrading is not supported, install a newer juiced or restore a backup",
+                found, supported
+            ),
+        }
+    }
+}
+
+pub struct Store {
+    connection: Connection,
+}
+
+impl Store {
+    // Opens the store, creating it if needed, and migrates it to the
+    // current schema.
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    fn with_connection(mut connection: Connection) -> Result<Self, StoreError> {
+        migrate(&mut connection, MIGRATIONS)?;
+        Ok(Self { connection })
+    }
+
+    pub fn version(&self) -> Result<u32, StoreError> {
+        Ok(self.connection.pragma_query_value(None, "user_version", |row| row.get(0))?)
+    }
+
+    pub fn record_event(&self, kind: &str, detail: Option<&str>) -> Result<(), StoreError> {
+        let unix_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.connection.execute(
+            "INSERT INTO events (unix_time, kind, detail) VALUES (?1, ?2, ?3)",
+            params![unix_time as i64, kind, detail],
+        )?;
+        Ok(())
+    }
+
+    // Faults are events of kind "fault" with the latched state as detail.
+    pub fn record_fault(&self, fault: &FaultRecord) -> Result<(), StoreError> {
+        self.connection.execute(
+            "INSERT INTO events (unix_time, kind, detail, cause, temperature_c, mains_volts, residual_ma, \
+             residual_peak_ma, vehicle) VALUES (?1, 'fault', ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                fault.unix_time as i64,
+                fault.fault,
+                fault.cause,
+                fault.temperature_c,
+                fault.mains_volts,
+                fault.residual_ma,
+                fault.residual_peak_ma,
+                fault.vehicle
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn faults(&self) -> Result<Vec<FaultRecord>, StoreError> {
+        let mut statement = self.connection.prepare(
+            "SELECT unix_time, detail, cause, temperature_c, mains_volts, residual_ma, residual_peak_ma, vehicle \
+             FROM events WHERE kind = 'fault' ORDER BY unix_time",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok(FaultRecord {
+                unix_time: row.get::<_, i64>(0)? as u64,
+                fault: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                cause: row.get(2)?,
+                temperature_c: row.get(3)?,
+                mains_volts: row.get(4)?,
+                residual_ma: row.get(5)?,
+                residual_peak_ma: row.get(6)?,
+                vehicle: row.get(7)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn save_guest_token(&self, secret_hash: &str, token: &GuestToken) -> Result<(), StoreError> {
+        self.connection.execute(
+            "INSERT INTO guest_tokens (secret_hash, id, label, expires_at, max_current, max_energy_wh) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                secret_hash,
+                token.id,
+                token.label,
+                token.expires_at as i64,
+                token.max_current,
+                token.max_energy_wh
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn guest_token(&self, secret_hash: &str) -> Result<Option<GuestToken>, StoreError> {
+        let mut statement = self.connection.prepare(
+            "SELECT id, label, expires_at, max_current, max_energy_wh FROM guest_tokens WHERE secret_hash = ?1",
+        )?;
+        let mut rows = statement.query_map([secret_hash], |row| {
+            Ok(GuestToken {
+                id: row.get(0)?,
+                label: row.get(1)?,
+                expires_at: row.get::<_, i64>(2)? as u64,
+                max_current: row.get(3)?,
+                max_energy_wh: row.get(4)?,
+            })
+        })?;
+        Ok(rows.next().transpose()?)
+    }
+
+    // A finished session, with the guest token it ran under, if any.
+    pub fn record_session(
+        &self,
+        started_at: u64,
+        ended_at: u64,
+        energy_wh: f64,
+        guest_token: Option<&str>,
+        session_id: Option<&SessionId>,
+    ) -> Result<(), StoreError> {
+        // A session recorded again keeps its first record.
+        self.connection.execute(
+            "INSERT OR IGNORE INTO sessions (started_at, ended_at, energy_wh, guest_token, session_id) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                started_at as i64,
+                ended_at as i64,
+                energy_wh,
+                guest_token,
+                session_id.map(SessionId::to_string)
+            ],
+        )?;
+        Ok(())
+    }
+
+    // The learned minimum currents, by vehicle.
+    pub fn vehicle_floors(&self) -> Result<BTreeMap<String, VehicleFloor>, StoreError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT vehicle, lowest_charging, highest_refused FROM vehicle_floors")?;
+        let rows = statement.query_map([], |row| {
+            let floor = VehicleFloor {
+                lowest_charging: row.get(1)?,
+                highest_refused: row.get(2)?,
+            };
+            Ok((row.get(0)?, floor))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn save_vehicle_floor(&self, vehicle: &str, floor: &VehicleFloor) -> Result<(), StoreError> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO vehicle_floors (vehicle, lowest_charging, highest_refused) VALUES (?1, ?2, ?3)",
+            params![vehicle, floor.lowest_charging, floor.highest_refused],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_cable_estimate(&self, unix_time: u64, series_ohms: f64) -> Result<(), StoreError> {
+        self.connection.execute(
+            "INSERT INTO pilot_cable_estimates (unix_time, series_ohms) VALUES (?1, ?2)",
+            params![unix_time as i64, series_ohms],
+        )?;
+        Ok(())
+    }
+
+    pub fn save_tenant(&self, tenant: &Tenant) -> Result<(), StoreError> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO tenants (id, name, max_current, monthly_energy_wh, price_per_kwh) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                tenant.id,
+                tenant.name,
+                tenant.max_current,
+                tenant.monthly_energy_wh,
+                tenant.price_per_kwh
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn query_tenants(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<Tenant>, StoreError> {
+        let mut statement = self.connection.prepare(sql)?;
+        let rows = statement.query_map(params, |row| {
+            Ok(Tenant {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                max_current: row.get(2)?,
+                monthly_energy_wh: row.get(3)?,
+                price_per_kwh: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn tenants(&self) -> Result<Vec<Tenant>, StoreError> {
+        self.query_tenants(
+            "SELECT id, name, max_current, monthly_energy_wh, price_per_kwh FROM tenants ORDER BY id",
+            [],
+        )
+    }
+
+    pub fn tenant(&self, id: &str) -> Result<Option<Tenant>, StoreError> {
+        Ok(self
+            .query_tenants(
+                "SELECT id, name, max_current, monthly_energy_wh, price_per_kwh FROM tenants WHERE id = ?1",
+                [id],
+            )?
+            .pop())
+    }
+
+    pub fn save_tenant_credential(&self, credential_hash: &str, tenant_id: &str) -> Result<(), StoreError> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO tenant_credentials (credential_hash, tenant_id) VALUES (?1, ?2)",
+            params![credential_hash, tenant_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn tenant_for_credential(&self, credential_hash: &str) -> Result<Option<Tenant>, StoreError> {
+        Ok(self
+            .query_tenants(
+                "SELECT t.id, t.name, t.max_current, t.monthly_energy_wh, t.price_per_kwh FROM tenants t \
+                 JOIN tenant_credentials c ON c.tenant_id = t.id WHERE c.credential_hash = ?1",
+                [credential_hash],
+            )?
+            .pop())
+    }
+
+    pub fn record_tenant_session(
+        &self,
+        started_at: u64,
+        ended_at: u64,
+        energy_wh: f64,
+        tenant_id: &str,
+        session_id: Option<&SessionId>,
+    ) -> Result<(), StoreError> {
+        self.connection.execute(
+            "INSERT OR IGNORE INTO sessions (started_at, ended_at, energy_wh, tenant_id, session_id) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                started_at as i64,
+                ended_at as i64,
+                energy_wh,
+                tenant_id,
+                session_id.map(SessionId::to_string)
+            ],
+        )?;
+        Ok(())
+    }
+
+    // The sessions of each tenant that started in the period.
+    pub fn tenant_usage(&self, since: u64, until: u64) -> Result<Vec<TenantUsage>, StoreError> {
+        let mut statement = self.connection.prepare(
+            "SELECT tenant_id, COUNT(*), SUM(energy_wh) FROM sessions \
+             WHERE tenant_id IS NOT NULL AND started_at >= ?1 AND started_at < ?2 GROUP BY tenant_id",
+        )?;
+        let rows = statement.query_map(params![since as i64, until as i64], |row| {
+            Ok(TenantUsage {
+                tenant_id: row.get(0)?,
+                sessions: row.get(1)?,
+                energy_wh: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    // A curve saved again replaces the points of the same minutes.
+    pub fn save_charging_curve(&mut self, session_id: &SessionId, points: &[CurvePoint]) -> Result<(), StoreError> {
+        let transaction = self.connection.transaction()?;
+        for point in points {
+            transaction.execute(
+                "INSERT OR REPLACE INTO charging_curves (session_id, minute, unix_time, power_w, amps) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    session_id.to_string(),
+                    point.minute,
+                    point.unix_time as i64,
+                    point.power_w,
+                    point.amps
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    // Empty for a session without a curve.
+    pub fn charging_curve(&self, session_id: &SessionId) -> Result<Vec<CurvePoint>, StoreError> {
+        let mut statement = self.connection.prepare(
+            "SELECT minute, unix_time, power_w, amps FROM charging_curves WHERE session_id = ?1 ORDER BY minute",
+        )?;
+        let rows = statement.query_map([session_id.to_string()], |row| {
+            Ok(CurvePoint {
+                minute: row.get(0)?,
+                unix_time: row.get::<_, i64>(1)? as u64,
+                power_w: row.get(2)?,
+                amps: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    // Saves the peak of a day and drops the days before `since`.
+    pub fn save_daily_peak(&self, peak: &DailyPeak, since: &str) -> Result<(), StoreError> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO daily_peaks (day, offered_amps, measured_amps) VALUES (?1, ?2, ?3)",
+            params![peak.day, peak.offered_amps, peak.measured_amps],
+        )?;
+        self.connection.execute("DELETE FROM daily_peaks WHERE day < ?1", [since])?;
+        Ok(())
+    }
+
+    fn query_daily_peaks(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<DailyPeak>, StoreError> {
+        let mut statement = self.connection.prepare(sql)?;
+        let rows = statement.query_map(params, |row| {
+            Ok(DailyPeak {
+                day: row.get(0)?,
+                offered_amps: row.get(1)?,
+                measured_amps: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn daily_peak(&self, day: &str) -> Result<Option<DailyPeak>, StoreError> {
+        Ok(self
+            .query_daily_peaks(
+                "SELECT day, offered_amps, measured_amps FROM daily_peaks WHERE day = ?1",
+                [day],
+            )?
+            .pop())
+    }
+
+    // Oldest first.
+    pub fn daily_peaks(&self) -> Result<Vec<DailyPeak>, StoreError> {
+        self.query_daily_peaks("SELECT day, offered_amps, measured_amps FROM daily_peaks ORDER BY day", [])
+    }
+
+    pub fn save_config_snapshot(&self, applied_at: u64, hash: &str, settings: &str) -> Result<i64, StoreError> {
+        self.connection.execute(
+            "INSERT INTO config_snapshots (applied_at, hash, settings) VALUES (?1, ?2, ?3)",
+            params![applied_at as i64, hash, settings],
+        )?;
+        Ok(self.connection.last_insert_rowid())
+    }
+
+    // Oldest first.
+    pub fn config_snapshots(&self) -> Result<Vec<ConfigSnapshot>, StoreError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT id, applied_at, hash, settings FROM config_snapshots ORDER BY id")?;
+        let rows = statement.query_map([], |row| {
+            Ok(ConfigSnapshot {
+                id: row.get(0)?,
+                applied_at: row.get::<_, i64>(1)? as u64,
+                hash: row.get(2)?,
+                settings: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    // The pilot cable estimates of the sessions, oldest first.
+    pub fn cable_estimates(&self) -> Result<Vec<f64>, StoreError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT series_ohms FROM pilot_cable_estimates ORDER BY id")?;
+        let rows = statement.query_map([], |row| row.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+// Applies the pending migrations, each in its own transaction together with
+// the version bump. Returns the number of migrations applied.
+fn migrate(connection: &mut Connection, migrations: &[&str]) -> Result<usize, StoreError> {
+    let found: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let supported = migrations.len() as u32;
+    if found > supported {
+        return Err(StoreError::NewerSchema { found, supported });
+    }
+
+    for (version, migration) in migrations.iter().enumerate().skip(found as usize) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", version as u32 + 1)?;
+        transaction.commit()?;
+    }
+    Ok((supported - found) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrates_on_open() {
+        let path = std::env::temp_dir().join(format!("juicelib-store-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = Store::open(&path).unwrap();
+        assert_eq!(store.version().unwrap(), schema_version());
+        store.record_event("gfi trip", Some("6 mA")).unwrap();
+        let fault = FaultRecord {
+            unix_time: 1_700_000_000,
+            fault: "FailedStation".to_string(),
+            cause: Some("GFIInterrupted".to_string()),
+            temperature_c: Some(31.5),
+            mains_volts: None,
+            residual_ma: Some(31.0),
+            residual_peak_ma: Some(33.5),
+            vehicle: Some("5YJ3E1EA7KF317000".to_string()),
+        };
+        store.record_fault(&fault).unwrap();
+        assert_eq!(store.faults().unwrap(), vec![fault]);
+        drop(store);
+
+        // Reopening the current schema changes nothing.
+        let store = Store::open(&path).unwrap();
+        let count: i64 = store
+            .connection
+            .query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+
+        // A session recorded twice counts once.
+        let session_id = SessionId::new();
+        for _ in 0..2 {
+            store.record_session(1, 2, 7400.0, None, Some(&session_id)).unwrap();
+        }
+        store.record_session(1, 2, 7400.0, None, None).unwrap();
+        let count: i64 = store
+            .connection
+            .query_row("SELECT COUNT(*) FROM sessions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+
+        let mut store = store;
+        let point = CurvePoint {
+            minute: 0,
+            unix_time: 1,
+            power_w: 7400.0,
+            amps: 10.7,
+        };
+        store.save_charging_curve(&session_id, &[point]).unwrap();
+        assert_eq!(store.charging_curve(&session_id).unwrap(), vec![point]);
+        assert!(store.charging_curve(&SessionId::new()).unwrap().is_empty());
+
+        let peak = |day: &str| DailyPeak {
+            day: day.to_string(),
+            offered_amps: 32.0,
+            measured_amps: None,
+        };
+        store.save_daily_peak(&peak("2023-03-31"), "2023-01-01").unwrap();
+        store.save_daily_peak(&peak("2024-05-01"), "2023-04-01").unwrap();
+        assert_eq!(store.daily_peaks().unwrap(), vec![peak("2024-05-01")]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_keeps_data() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        let v1 = &MIGRATIONS[..1];
+        assert_eq!(migrate(&mut connection, v1).unwrap(), 1);
+        connection
+            .execute("INSERT INTO sessions (started_at, energy_wh) VALUES (1, 7400)", [])
+            .unwrap();
+
+        let v2 = [MIGRATIONS[0], "ALTER TABLE sessions ADD COLUMN vehicle TEXT;"];
+        assert_eq!(migrate(&mut connection, &v2).unwrap(), 1);
+        let energy: f64 = connection
+            .query_row("SELECT energy_wh FROM sessions WHERE vehicle IS NULL", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(energy, 7400.0);
+
+        // Back to the older juiced.
+        let error = migrate(&mut connection, v1).unwrap_err();
+        assert!(matches!(error, StoreError::NewerSchema { found: 2, supported: 1 }));
+        assert!(error.to_string().contains("downgrading is not supported"));
+    }
+
+    #[test]
+    fn test_failed_migration_is_rolled_back() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        let broken = [MIGRATIONS[0], "CREATE TABLE meters (id INTEGER); SELECT * FROM missing;"];
+        assert!(migrate(&mut connection, &broken).is_err());
+        let store = Store::with_connection(connection).unwrap();
+        assert_eq!(store.version().unwrap(), schema_version());
+        let meters: i64 = store
+            .connection
+            .query_row("SELECT COUNT(*) FROM sqlite_master WHERE name = 'meters'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(meters, 0);
+    }
+}
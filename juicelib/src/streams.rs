@@ -0,0 +1,116 @@
+use std::sync::{Arc, Mutex};
+
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_core::Stream;
+use serde::Serialize;
+
+use crate::evse::{EVSEMachineInput, EVSEMachineState, PilotReading};
+use crate::session_id::SessionId;
+
+// The measurements of the machine as async streams, for consumers running
+// on an executor (OCPP, HTTP server-sent events) that would otherwise have
+// to bridge the crossbeam channels themselves. The machine publishes every
+// pilot reading and a meter sample with it; each subscriber gets its own
+// copy.
+//
+// The streams are unbounded, so a subscriber that stops polling collects
+// samples until it is dropped. Dropping the stream unsubscribes.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PilotSample {
+    pub reading: PilotReading,
+    pub classification: EVSEMachineInput,
+    // Nanoseconds since the Unix epoch.
+    pub timestamp_ns: u128,
+}
+
+// What the station knows about the supply. The current is the current
+// sense reading, or the offer while charging for hardware without one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MeterSample {
+    pub state: EVSEMachineState,
+    pub amps: f64,
+    pub mains_volts: Option<f64>,
+    pub temperature_c: Option<f64>,
+    pub session_id: Option<SessionId>,
+    pub timestamp_ns: u128,
+}
+
+// Fans samples out to the subscribed streams.
+pub struct SampleBroadcast<T> {
+    subscribers: Arc<Mutex<Vec<UnboundedSender<T>>>>,
+}
+
+impl<T> Clone for SampleBroadcast<T> {
+    fn clone(&self) -> Self {
+        Self {
+            subscribers: self.subscribers.clone(),
+        }
+    }
+}
+
+impl<T> Default for SampleBroadcast<T> {
+    fn default() -> Self {
+        Self {
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> SampleBroadcast<T> {
+    pub fn subscribe(&self) -> impl Stream<Item = T> + Send + Unpin {
+        let (sender, receiver): (_, UnboundedReceiver<T>) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    // Drops the subscribers whose stream is gone.
+    pub fn publish(&self, sample: &T) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.unbounded_send(sample.clone()).is_ok());
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.lock().unwrap().is_empty()
+    }
+}
+
+// The streams a machine publishes to.
+#[derive(Clone, Default)]
+pub struct MachineStreams {
+    pub pilot: SampleBroadcast<PilotSample>,
+    pub meter: SampleBroadcast<MeterSample>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    fn poll<S: Stream + Unpin>(stream: &mut S) -> Poll<Option<S::Item>> {
+        Pin::new(stream).poll_next(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let broadcast = SampleBroadcast::default();
+        broadcast.publish(&0);
+        let mut first = broadcast.subscribe();
+        let mut second = broadcast.subscribe();
+        assert_eq!(poll(&mut first), Poll::Pending);
+
+        broadcast.publish(&1);
+        assert_eq!(poll(&mut first), Poll::Ready(Some(1)));
+        assert_eq!(poll(&mut second), Poll::Ready(Some(1)));
+
+        drop(first);
+        broadcast.publish(&2);
+        assert!(broadcast.has_subscribers());
+        drop(second);
+        broadcast.publish(&3);
+        assert!(!broadcast.has_subscribers());
+    }
+}
@@ -0,0 +1,163 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::acquisition::AcquisitionHealth;
+use crate::breaker::BreakerStatus;
+use crate::completion::CompletionEstimate;
+use crate::evse::{EVSEMachineInput, EVSEMachineState, PilotReading};
+use crate::features::FeatureState;
+use crate::session_id::SessionId;
+
+// Payloads for periodic telemetry (meter values, MQTT status messages).
+// The pilot diagnostics and the feature toggles are only included at
+// diagnostic verbosity; they help with remote troubleshooting of vehicles
+// that refuse to charge.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TelemetryVerbosity {
+    #[default]
+    Normal,
+    Diagnostic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+pub struct PilotDiagnostics {
+    pub high_voltage: f32,
+    pub low_voltage: f32,
+    pub duty_cycle: f32,
+    pub frequency: f32,
+    pub classification: EVSEMachineInput,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct TelemetrySample {
+    pub state: EVSEMachineState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<SessionId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pilot: Option<PilotDiagnostics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion: Option<CompletionEstimate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub breaker: Option<BreakerStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acquisition: Option<AcquisitionHealth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<Vec<FeatureState>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connector_temperatures_c: Option<Vec<f64>>,
+}
+
+impl TelemetrySample {
+    pub fn new(
+        state: EVSEMachineState,
+        pilot: Option<(PilotReading, EVSEMachineInput)>,
+        verbosity: TelemetryVerbosity,
+    ) -> Self {
+        let pilot = match verbosity {
+            TelemetryVerbosity::Normal => None,
+            TelemetryVerbosity::Diagnostic => pilot.map(|(reading, classification)| PilotDiagnostics {
+                high_voltage: reading.high,
+                low_voltage: reading.low,
+                duty_cycle: reading.duty_cycle,
+                frequency: reading.frequency,
+                classification,
+            }),
+        };
+        Self {
+            state,
+            session_id: None,
+            pilot,
+            completion: None,
+            breaker: None,
+            acquisition: None,
+            features: None,
+            connector_temperatures_c: None,
+        }
+    }
+
+    // Adds the id of the session in progress.
+    pub fn with_session(mut self, session_id: SessionId) -> Self {
+        self.session_id = Some(session_id);
+        self
+    }
+
+    // Adds the charging complete estimate, for sessions where the current
+    // drawn by the vehicle is measured.
+    pub fn with_completion(mut self, completion: CompletionEstimate) -> Self {
+        self.completion = Some(completion);
+        self
+    }
+
+    // Adds the state of the breaker thermal model and the derating it
+    // applies.
+    pub fn with_breaker(mut self, breaker: BreakerStatus) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    // Adds the health of the pilot acquisition loop.
+    pub fn with_acquisition(mut self, acquisition: AcquisitionHealth) -> Self {
+        self.acquisition = Some(acquisition);
+        self
+    }
+
+    // Adds the feature toggles and where their state comes from; part of
+    // the diagnostics.
+    pub fn with_features(mut self, features: Vec<FeatureState>) -> Self {
+        self.features = Some(features);
+        self
+    }
+
+    // Adds the temperatures of the power contacts of the socket, for
+    // sockets with sensors.
+    pub fn with_connector_temperatures(mut self, temperatures_c: Vec<f64>) -> Self {
+        self.connector_temperatures_c = Some(temperatures_c);
+        self
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading() -> (PilotReading, EVSEMachineInput) {
+        let reading = PilotReading {
+            high: 6.1,
+            low: -11.9,
+            duty_cycle: 0.1,
+            frequency: 998.0,
+        };
+        (reading, EVSEMachineInput::PilotIs6V)
+    }
+
+    #[test]
+    fn test_normal_verbosity_omits_pilot() {
+        let sample = TelemetrySample::new(EVSEMachineState::Charging, Some(reading()), TelemetryVerbosity::Normal);
+        assert_eq!(sample.pilot, None);
+        assert_eq!(sample.to_json().unwrap(), r#"{"state":"Charging"}"#);
+    }
+
+    #[test]
+    fn test_diagnostic_verbosity_includes_pilot() {
+        let sample = TelemetrySample::new(EVSEMachineState::Charging, Some(reading()), TelemetryVerbosity::Diagnostic);
+        let json = sample.to_json().unwrap();
+        assert!(json.contains(r#""duty_cycle":0.1"#));
+        assert!(json.contains(r#""frequency":998.0"#));
+        assert!(json.contains(r#""classification":"PilotIs6V""#));
+    }
+
+    #[test]
+    fn test_completion_estimate() {
+        let sample = TelemetrySample::new(EVSEMachineState::Charging, None, TelemetryVerbosity::Normal)
+            .with_completion(CompletionEstimate::ApproachingFull { eta_secs: Some(600) });
+        assert_eq!(
+            sample.to_json().unwrap(),
+            r#"{"state":"Charging","completion":{"ApproachingFull":{"eta_secs":600}}}"#
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{Datelike, NaiveDate, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::certificates::unix_now;
+use crate::guest_token::hash_secret;
+use crate::session_id::SessionId;
+use crate::store::{Store, StoreError};
+use crate::time::resolve;
+
+// Tenant accounts, for a charger shared by the residents of a building.
+// Each tenant has credentials, the UIDs of their RFID cards or tokens of
+// their own, and the session that follows an authorization is recorded
+// against the tenant. A tenant may have a current cap and a monthly
+// energy quota; once the quota is used the offer goes to 0 until the next
+// month. The energy of each month is reported per tenant as CSV, with the
+// cost at the tenant's price.
+//
+// Like the guest tokens, credentials are only kept as hashes. A session
+// counts towards the month it started in, in the site's time zone.
+//
+// The energy is the power the session log counts, as for the guest
+// sessions.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tenant {
+    // E.g. the apartment number.
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub max_current: Option<f64>,
+    #[serde(default)]
+    pub monthly_energy_wh: Option<f64>,
+    #[serde(default)]
+    pub price_per_kwh: Option<f64>,
+}
+
+// The session of a tenant.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TenantSession {
+    pub tenant: Tenant,
+    pub started_at: u64,
+    pub energy_wh: f64,
+    // Used in the month before this session.
+    pub month_energy_wh: f64,
+}
+
+impl TenantSession {
+    pub fn new(tenant: Tenant, month_energy_wh: f64) -> Self {
+        Self {
+            tenant,
+            started_at: unix_now(),
+            energy_wh: 0.0,
+            month_energy_wh,
+        }
+    }
+
+    pub fn add_charging(&mut self, power_w: f64, duration: Duration) {
+        self.energy_wh += power_w * duration.as_secs_f64() / 3600.0;
+    }
+
+    pub fn quota_used_up(&self) -> bool {
+        self.tenant
+            .monthly_energy_wh
+            .is_some_and(|quota| self.month_energy_wh + self.energy_wh >= quota)
+    }
+
+    pub fn record(&self, store_path: &Path, session_id: Option<&SessionId>) -> Result<(), StoreError> {
+        Store::open(store_path)?.record_tenant_session(
+            self.started_at,
+            unix_now(),
+            self.energy_wh,
+            &self.tenant.id,
+            session_id,
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum TenantError {
+    Store(StoreError),
+    // No tenant has the credential.
+    Unknown,
+    UnknownTenant,
+    InvalidTenant,
+    QuotaUsedUp,
+    // Another session runs under a tenant or guest token.
+    InUse,
+    // No store to keep the tenants in.
+    Disabled,
+    MachineStopped,
+}
+
+impl From<StoreError> for TenantError {
+    fn from(error: StoreError) -> Self {
+        TenantError::Store(error)
+    }
+}
+
+impl fmt::Display for TenantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TenantError::Store(error) => write!(f, "{}", error),
+            TenantError::Unknown => write!(f, "unknown credential"),
+            TenantError::UnknownTenant => write!(f, "unknown tenant"),
+            TenantError::InvalidTenant => write!(f, "a tenant needs an id and valid caps"),
+            TenantError::QuotaUsedUp => write!(f, "the energy of this month is used up"),
+            TenantError::InUse => write!(f, "the station is in use"),
+            TenantError::Disabled => write!(f, "tenant accounts are not available"),
+            TenantError::MachineStopped => write!(f, "the station is stopped"),
+        }
+    }
+}
+
+// RFID readers differ in the case of the UID.
+fn credential_hash(credential: &str) -> String {
+    hash_secret(&credential.to_ascii_lowercase())
+}
+
+pub fn save_tenant(store_path: &Path, tenant: &Tenant) -> Result<(), TenantError> {
+    if tenant.id.trim().is_empty()
+        || tenant.max_current.is_some_and(|amps| amps < 6.0)
+        || tenant.monthly_energy_wh.is_some_and(|wh| wh <= 0.0)
+        || tenant.price_per_kwh.is_some_and(|price| price < 0.0)
+    {
+        return Err(TenantError::InvalidTenant);
+    }
+    Store::open(store_path)?.save_tenant(tenant)?;
+    Ok(())
+}
+
+pub fn add_credential(store_path: &Path, tenant_id: &str, credential: &str) -> Result<(), TenantError> {
+    let store = Store::open(store_path)?;
+    if store.tenant(tenant_id)?.is_none() {
+        return Err(TenantError::UnknownTenant);
+    }
+    store.save_tenant_credential(&credential_hash(credential), tenant_id)?;
+    Ok(())
+}
+
+// The start and end of a month in the time zone.
+pub fn month_range(tz: Tz, year: i32, month: u32) -> Option<(u64, u64)> {
+    let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+    let next = if month == 12 {
+        NaiveDate::from_ymd_opt(year + 1, 1, 1)?
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1)?
+    };
+    let start = resolve(tz, first.and_time(NaiveTime::MIN)).timestamp();
+    let end = resolve(tz, next.and_time(NaiveTime::MIN)).timestamp();
+    Some((start.max(0) as u64, end.max(0) as u64))
+}
+
+fn current_month(tz: Tz, unix_time: u64) -> Option<(u64, u64)> {
+    let local = tz.timestamp_opt(unix_time as i64, 0).single()?;
+    month_range(tz, local.year(), local.month())
+}
+
+// A session for the tenant with the credential, if it has energy left.
+pub fn authorize(store_path: &Path, tz: Tz, credential: &str) -> Result<TenantSession, TenantError> {
+    let store = Store::open(store_path)?;
+    let tenant = store
+        .tenant_for_credential(&credential_hash(credential))?
+        .ok_or(TenantError::Unknown)?;
+    let (start, end) = current_month(tz, unix_now()).ok_or(TenantError::Unknown)?;
+    let used = store
+        .tenant_usage(start, end)?
+        .into_iter()
+        .find(|usage| usage.tenant_id == tenant.id)
+        .map_or(0.0, |usage| usage.energy_wh);
+    let session = TenantSession::new(tenant, used);
+    if session.quota_used_up() {
+        return Err(TenantError::QuotaUsedUp);
+    }
+    Ok(session)
+}
+
+// The sessions and energy of a tenant in a period.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TenantUsage {
+    pub tenant_id: String,
+    pub sessions: u32,
+    pub energy_wh: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TenantReportRow {
+    pub tenant_id: String,
+    pub name: String,
+    pub sessions: u32,
+    pub energy_wh: f64,
+    // None without a price.
+    pub cost: Option<f64>,
+}
+
+// Every tenant, with or without sessions in the month.
+pub fn monthly_report(store_path: &Path, tz: Tz, year: i32, month: u32) -> Result<Vec<TenantReportRow>, TenantError> {
+    let (start, end) = month_range(tz, year, month).ok_or(TenantError::InvalidTenant)?;
+    let store = Store::open(store_path)?;
+    let usage = store.tenant_usage(start, end)?;
+    Ok(store
+        .tenants()?
+        .into_iter()
+        .map(|tenant| {
+            let (sessions, energy_wh) = usage
+                .iter()
+                .find(|usage| usage.tenant_id == tenant.id)
+                .map_or((0, 0.0), |usage| (usage.sessions, usage.energy_wh));
+            TenantReportRow {
+                cost: tenant.price_per_kwh.map(|price| price * energy_wh / 1000.0),
+                tenant_id: tenant.id,
+                name: tenant.name,
+                sessions,
+                energy_wh,
+            }
+        })
+        .collect())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn report_csv(rows: &[TenantReportRow]) -> String {
+    let mut csv = String::from("tenant,name,sessions,energy_kwh,cost\n");
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{:.3},{}\n",
+            csv_field(&row.tenant_id),
+            csv_field(&row.name),
+            row.sessions,
+            row.energy_wh / 1000.0,
+            row.cost.map_or(String::new(), |cost| format!("{:.2}", cost))
+        ));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenant(id: &str, quota_wh: Option<f64>) -> Tenant {
+        Tenant {
+            id: id.to_string(),
+            name: format!("Apartment {}", id),
+            max_current: Some(16.0),
+            monthly_energy_wh: quota_wh,
+            price_per_kwh: Some(0.30),
+        }
+    }
+
+    #[test]
+    fn test_authorize_and_report() {
+        let path = std::env::temp_dir().join(format!("juicelib-tenants-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        save_tenant(&path, &tenant("1A", Some(10_000.0))).unwrap();
+        save_tenant(&path, &tenant("2B", None)).unwrap();
+        add_credential(&path, "1A", "04A2B3C4").unwrap();
+        assert!(matches!(add_credential(&path, "9Z", "x"), Err(TenantError::UnknownTenant)));
+        assert!(matches!(authorize(&path, Tz::UTC, "nope"), Err(TenantError::Unknown)));
+
+        let mut session = authorize(&path, Tz::UTC, "04a2b3c4").unwrap();
+        assert_eq!(session.tenant.id, "1A");
+        session.add_charging(3840.0, Duration::from_secs(3600));
+        session.record(&path, Some(&SessionId::new())).unwrap();
+        // 3.84 kWh of 10 used; two more hours use the quota up.
+        let mut session = authorize(&path, Tz::UTC, "04A2B3C4").unwrap();
+        assert!((session.month_energy_wh - 3840.0).abs() < 1e-6);
+        session.add_charging(3840.0, Duration::from_secs(2 * 3600));
+        assert!(session.quota_used_up());
+        session.record(&path, Some(&SessionId::new())).unwrap();
+        assert!(matches!(authorize(&path, Tz::UTC, "04A2B3C4"), Err(TenantError::QuotaUsedUp)));
+
+        let now = chrono::Utc::now();
+        let rows = monthly_report(&path, Tz::UTC, now.year(), now.month()).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].sessions, 2);
+        assert!((rows[0].cost.unwrap() - 3.456).abs() < 1e-6);
+        let csv = report_csv(&rows);
+        assert!(csv.starts_with("tenant,name,sessions,energy_kwh,cost\n1A,Apartment 1A,2,11.520,3.46\n"));
+        assert!(csv.ends_with("2B,Apartment 2B,0,0.000,0.00\n"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_month_range() {
+        let (start, end) = month_range(chrono_tz::Europe::Berlin, 2024, 12).unwrap();
+        // Midnight in Berlin is 23:00 UTC in winter.
+        assert_eq!(start, 1_733_007_600);
+        assert_eq!(end - start, 31 * 24 * 3600);
+        assert_eq!(month_range(Tz::UTC, 2024, 13), None);
+        assert_eq!(csv_field("Doe, Jane \"JD\""), "\"Doe, Jane \"\"JD\"\"\"");
+    }
+}
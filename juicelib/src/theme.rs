@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+// Per-deployment content of the pages the station shows, declared in the
+// site settings: a logo while the station is available, who to call when
+// it is out of order, and the texts of the pages, e.g. translated. Every
+// text left out stays the English default.
+//
+// The content comes from the settings, not the visitor, but is escaped
+// all the same so a stray "<" does not break the page.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Message {
+    Available,
+    InUse,
+    PluggedIn,
+    Charging,
+    Fault,
+    // Heading before the fault contact.
+    FaultContact,
+    GuestCharging,
+    StartCharging,
+    ChargingAuthorized,
+    NotAuthorized,
+    NotAvailable,
+    // In the grace window of a resettable error.
+    UnplugToRetry,
+    // The resume link of an interrupted session, see interruption.rs.
+    ResumeCharging,
+    ChargingResumed,
+}
+
+impl Message {
+    fn default_text(&self) -> &'static str {
+        match self {
+            Message::Available => "Available",
+            Message::InUse => "In use",
+            Message::PluggedIn => "Vehicle plugged in",
+            Message::Charging => "Charging",
+            Message::Fault => "Out of order",
+            Message::FaultContact => "Please call",
+            Message::GuestCharging => "Guest charging",
+            Message::StartCharging => "Start charging",
+            Message::ChargingAuthorized => "Charging authorized",
+            Message::NotAuthorized => "Not authorized",
+            Message::NotAvailable => "Not available",
+            Message::UnplugToRetry => "Unplug and plug in again to retry",
+            Message::ResumeCharging => "Resume charging",
+            Message::ChargingResumed => "Charging resumed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Theme {
+    // Shown while the station is available.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logo_url: Option<String>,
+    // Phone number or address shown while the station is out of order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fault_contact: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub messages: BTreeMap<Message, String>,
+    // Language of the pages, e.g. "de".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Theme {
+    // Escaped for the page.
+    pub fn text(&self, message: Message) -> String {
+        escape_html(self.messages.get(&message).map_or(message.default_text(), String::as_str))
+    }
+
+    // A page with the heading and body, in the language of the theme.
+    pub fn page(&self, heading: Message, body: &str) -> String {
+        let language = self.language.as_deref().map_or(String::new(), |language| {
+            format!(" lang=\"{}\"", escape_html(language))
+        });
+        format!(
+            "<!DOCTYPE html><html{}><head><meta charset=\"utf-8\"><title>Charger</title></head>\
+             <body><h1>{}</h1>{}</body></html>",
+            language,
+            self.text(heading),
+            body
+        )
+    }
+
+    // What the status page adds for the state it shows.
+    pub fn status_body(&self, status: Message) -> String {
+        match (status, &self.logo_url, &self.fault_contact) {
+            (Message::Available, Some(logo), _) => format!("<img src=\"{}\" alt=\"\">", escape_html(logo)),
+            (Message::Fault, _, Some(contact)) => format!(
+                "<p>{} {}</p>",
+                self.text(Message::FaultContact),
+                escape_html(contact)
+            ),
+            _ => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_theme() {
+        let theme: Theme = serde_json::from_str(
+            r#"{"logo_url": "/logo.png", "fault_contact": "+49 30 1234 & co",
+                "messages": {"available": "Frei", "fault": "Außer Betrieb"}, "language": "de"}"#,
+        )
+        .unwrap();
+        assert_eq!(theme.text(Message::Available), "Frei");
+        assert_eq!(theme.text(Message::Charging), "Charging");
+        assert_eq!(theme.status_body(Message::Available), "<img src=\"/logo.png\" alt=\"\">");
+        assert_eq!(theme.status_body(Message::Fault), "<p>Please call +49 30 1234 &amp; co</p>");
+        assert_eq!(theme.status_body(Message::Charging), "");
+        assert!(theme.page(Message::Fault, "").contains("<html lang=\"de\">"));
+        assert!(Theme::default().page(Message::Fault, "").contains("<h1>Out of order</h1>"));
+    }
+}
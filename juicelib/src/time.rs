@@ -0,0 +1,138 @@
+use chrono::{DateTime, Duration, LocalResult, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+
+// Local time for everything scheduled by the wall clock: tariffs, quiet
+// hours, the nightly self test. Schedules are kept in the site's IANA time
+// zone and turned into instants only when needed, so a window set for
+// 23:00 stays at 23:00 across the DST changes instead of moving by an hour.
+
+pub const DEFAULT_TIME_ZONE: Tz = Tz::UTC;
+
+// None if the name is not an IANA time zone.
+pub fn parse_time_zone(name: &str) -> Option<Tz> {
+    name.parse().ok()
+}
+
+// The instant a local wall clock time happens. A time skipped when the
+// clocks go forward happens when the clocks jump past it; a time repeated
+// when they go back happens the first time.
+pub fn resolve(tz: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    let mut candidate = local;
+    loop {
+        match tz.from_local_datetime(&candidate) {
+            LocalResult::Single(at) | LocalResult::Ambiguous(at, _) => return at.with_timezone(&Utc),
+            // Inside the gap. The clocks change on whole minutes, so the
+            // first whole minute that exists is the jump.
+            LocalResult::None => {
+                candidate = candidate.with_second(0).unwrap_or(candidate) + Duration::minutes(1);
+            }
+        }
+    }
+}
+
+// The first instant after `after` at which the local wall clock reads
+// `time`. Happens once a day, DST changes included.
+pub fn next_occurrence(tz: Tz, time: NaiveTime, after: DateTime<Utc>) -> DateTime<Utc> {
+    let today = after.with_timezone(&tz).date_naive();
+    (-1..=2)
+        .map(|days| resolve(tz, (today + Duration::days(days)).and_time(time)))
+        .find(|&at| at > after)
+        .expect("a local time happens every day")
+}
+
+// A daily window in local wall clock time, like an overnight tariff from
+// 23:00 to 06:00. The end is not in the window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DailyWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl DailyWindow {
+    pub fn new(start: NaiveTime, end: NaiveTime) -> Self {
+        Self { start, end }
+    }
+
+    // Goes by the wall clock, so the window is an hour shorter or longer
+    // on the nights the clocks change.
+    pub fn contains(&self, tz: Tz, at: DateTime<Utc>) -> bool {
+        let time = at.with_timezone(&tz).time();
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    pub fn next_start(&self, tz: Tz, after: DateTime<Utc>) -> DateTime<Utc> {
+        next_occurrence(tz, self.start, after)
+    }
+
+    pub fn next_end(&self, tz: Tz, after: DateTime<Utc>) -> DateTime<Utc> {
+        next_occurrence(tz, self.end, after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::{America::New_York, Europe::Berlin};
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    fn hm(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_parse_time_zone() {
+        assert_eq!(parse_time_zone("Europe/Berlin"), Some(Berlin));
+        assert_eq!(parse_time_zone("Mars/Olympus_Mons"), None);
+    }
+
+    #[test]
+    fn test_window_keeps_wall_clock_across_dst() {
+        let tariff = DailyWindow::new(hm(23, 0), hm(6, 0));
+
+        // EST the night before the clocks go forward, EDT the night after.
+        assert_eq!(tariff.next_start(New_York, utc("2024-03-09T12:00:00Z")), utc("2024-03-10T04:00:00Z"));
+        assert_eq!(tariff.next_end(New_York, utc("2024-03-10T04:00:00Z")), utc("2024-03-10T10:00:00Z"));
+        assert_eq!(tariff.next_start(New_York, utc("2024-03-10T12:00:00Z")), utc("2024-03-11T03:00:00Z"));
+
+        // 05:30 and 06:30 EDT.
+        assert!(tariff.contains(New_York, utc("2024-03-11T09:30:00Z")));
+        assert!(!tariff.contains(New_York, utc("2024-03-11T10:30:00Z")));
+
+        // And back in the autumn.
+        assert_eq!(tariff.next_start(Berlin, utc("2024-10-26T12:00:00Z")), utc("2024-10-26T21:00:00Z"));
+        assert_eq!(tariff.next_end(Berlin, utc("2024-10-26T21:00:00Z")), utc("2024-10-27T05:00:00Z"));
+        assert!(tariff.contains(Berlin, utc("2024-10-27T04:30:00Z")));
+    }
+
+    #[test]
+    fn test_time_skipped_by_dst() {
+        // 02:30 does not happen on 2024-03-10 in New York; it is reached
+        // when the clocks jump from 02:00 EST to 03:00 EDT.
+        let self_test = hm(2, 30);
+        assert_eq!(
+            next_occurrence(New_York, self_test, utc("2024-03-10T05:00:00Z")),
+            utc("2024-03-10T07:00:00Z")
+        );
+        assert_eq!(
+            next_occurrence(New_York, self_test, utc("2024-03-10T07:00:00Z")),
+            utc("2024-03-11T06:30:00Z")
+        );
+    }
+
+    #[test]
+    fn test_time_repeated_by_dst() {
+        // 01:30 happens twice on 2024-11-03 in New York; only the first
+        // counts.
+        let self_test = hm(1, 30);
+        let first = next_occurrence(New_York, self_test, utc("2024-11-03T00:00:00Z"));
+        assert_eq!(first, utc("2024-11-03T05:30:00Z"));
+        assert_eq!(next_occurrence(New_York, self_test, first), utc("2024-11-04T06:30:00Z"));
+    }
+}
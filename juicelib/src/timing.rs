@@ -0,0 +1,109 @@
+use std::fmt;
+use std::time::Duration;
+
+// The J1772 timings in one place. Each has a default and the bounds the
+// standard allows, in a table checked at compile time. Installations may
+// tune the timings of the EVSE within their bounds, never outside of them.
+// The timings of the vehicle are here for the checks that rely on them.
+
+pub struct TimingSpec {
+    pub name: &'static str,
+    pub default: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+// A new pilot level is acted on once a whole sampling window shows it.
+pub const STATE_DEBOUNCE: TimingSpec = TimingSpec {
+    name: "state_debounce",
+    default: Duration::from_millis(200),
+    min: Duration::from_millis(50),
+    max: Duration::from_millis(500),
+};
+
+// After the offer is taken away from a charging vehicle, it has this long
+// to stop drawing current before the contactor opens.
+pub const STOP_RESPONSE: TimingSpec = TimingSpec {
+    name: "stop_response",
+    default: Duration::from_secs(3),
+    min: Duration::ZERO,
+    max: Duration::from_secs(3),
+};
+
+// A vehicle follows a lower offer within this time. Binds the vehicle.
+pub const OFFER_RESPONSE: TimingSpec = TimingSpec {
+    name: "offer_response",
+    default: Duration::from_secs(5),
+    min: Duration::ZERO,
+    max: Duration::from_secs(5),
+};
+
+pub const TIMINGS: [&TimingSpec; 3] = [&STATE_DEBOUNCE, &STOP_RESPONSE, &OFFER_RESPONSE];
+
+const fn within(spec: &TimingSpec, value: Duration) -> bool {
+    spec.min.as_nanos() <= value.as_nanos() && value.as_nanos() <= spec.max.as_nanos()
+}
+
+const _: () = {
+    let mut i = 0;
+    while i < TIMINGS.len() {
+        assert!(within(TIMINGS[i], TIMINGS[i].default), "J1772 timing default out of bounds");
+        i += 1;
+    }
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingError {
+    pub name: &'static str,
+    pub value: Duration,
+}
+
+impl fmt::Display for TimingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {:?} is outside of what J1772 allows", self.name, self.value)
+    }
+}
+
+// The timings of the EVSE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct J1772Timing {
+    pub state_debounce: Duration,
+    pub stop_response: Duration,
+}
+
+impl Default for J1772Timing {
+    fn default() -> Self {
+        Self {
+            state_debounce: STATE_DEBOUNCE.default,
+            stop_response: STOP_RESPONSE.default,
+        }
+    }
+}
+
+impl J1772Timing {
+    pub fn validate(&self) -> Result<(), TimingError> {
+        for (spec, value) in [(&STATE_DEBOUNCE, self.state_debounce), (&STOP_RESPONSE, self.stop_response)] {
+            if !within(spec, value) {
+                return Err(TimingError { name: spec.name, value });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(J1772Timing::default().validate(), Ok(()));
+        let timing = J1772Timing {
+            stop_response: Duration::from_secs(4),
+            ..J1772Timing::default()
+        };
+        let error = timing.validate().unwrap_err();
+        assert_eq!(error.name, "stop_response");
+        assert!(error.to_string().contains("outside of what J1772 allows"));
+    }
+}
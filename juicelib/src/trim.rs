@@ -0,0 +1,72 @@
+// Closed-loop trim of the offer, with the ClosedLoopTrim feature on. A
+// vehicle follows the pilot only roughly, and some draw an amp or more
+// above the offer. The trim compares the measured current with the offer
+// the limits allow and takes the excess off the pilot, so that the current
+// that flows, not just the offer, stays within the limits.
+//
+// The excess is integrated slowly, the pilot moves in TRIM_STEP steps so
+// it is not rewritten on every reading, and no more than MAX_TRIM_AMPS is
+// taken off. A vehicle that draws less than offered is left alone: the
+// offer is a maximum, not a setpoint.
+
+const TRIM_GAIN: f64 = 0.1;
+const TRIM_STEP: f64 = 0.5;
+const MAX_TRIM_AMPS: f64 = 4.0;
+
+#[derive(Debug, Default)]
+pub struct OfferTrim {
+    trim: f64,
+}
+
+impl OfferTrim {
+    // Feeds a current reading against the offer the limits allow. Returns
+    // the cap of the trim limiter, None while nothing is taken off.
+    pub fn update(&mut self, target: f64, measured: f64) -> Option<f64> {
+        if target <= 0.0 {
+            return None;
+        }
+        self.trim = (self.trim + (measured - target) * TRIM_GAIN).clamp(0.0, MAX_TRIM_AMPS);
+        let trim = (self.trim / TRIM_STEP).floor() * TRIM_STEP;
+        (trim > 0.0).then_some(target - trim)
+    }
+
+    // The charge ended, or the current is not measured.
+    pub fn reset(&mut self) {
+        self.trim = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs a vehicle that draws `bias` above the pilot against a target
+    // offer. Returns the last offer.
+    fn settle(trim: &mut OfferTrim, target: f64, bias: f64) -> f64 {
+        let mut offer = target;
+        for _ in 0..200 {
+            offer = trim.update(target, offer + bias).unwrap_or(target);
+        }
+        offer
+    }
+
+    #[test]
+    fn test_trims_the_excess() {
+        let mut trim = OfferTrim::default();
+        assert_eq!(settle(&mut trim, 16.0, 1.5), 14.5);
+        // No more than the maximum trim.
+        assert_eq!(settle(&mut trim, 16.0, 6.0), 12.0);
+        // The trim goes away with the excess.
+        assert_eq!(settle(&mut trim, 16.0, 0.0), 16.0);
+    }
+
+    #[test]
+    fn test_draw_below_the_offer() {
+        let mut trim = OfferTrim::default();
+        assert_eq!(settle(&mut trim, 16.0, -3.0), 16.0);
+        assert_eq!(trim.update(0.0, 1.0), None);
+        settle(&mut trim, 16.0, 1.5);
+        trim.reset();
+        assert_eq!(trim.update(16.0, 16.0), None);
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::clock::{system_clock, Clock};
+use crate::evse::EVSEMachineState;
+use crate::store::Store;
+
+// Vehicle minimum current. J1772 lets the EVSE offer as little as 6 A, but
+// some vehicles stop charging below 8 to 10 A. The machine learns, per
+// identified vehicle, the lowest offer the vehicle charged at and the
+// highest it refused, and the limiter chain raises the caps of the load
+// manager and the solar mode to that floor instead of letting them drive
+// the vehicle into pointless stop and start cycles.
+//
+// Without a current measurement a vehicle counts as charging at an offer
+// when it stays in state C for CONFIRM_TIME, and as refusing it when it
+// falls back to state B sooner.
+
+// The J1772 minimum, and the floor of vehicles that have not refused less.
+pub const MIN_FLOOR: f64 = 6.0;
+const CONFIRM_TIME: Duration = Duration::from_secs(60);
+// Above this a vehicle that stops charging has its own reasons, like a full
+// battery.
+const MAX_LEARNED_FLOOR: f64 = 16.0;
+// How far above the highest refused offer the floor is.
+const FLOOR_STEP: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct VehicleFloor {
+    pub lowest_charging: Option<f64>,
+    pub highest_refused: Option<f64>,
+}
+
+impl VehicleFloor {
+    pub fn floor(&self) -> f64 {
+        // Just above the highest refused offer. Should the vehicle refuse
+        // that as well, the floor goes up another step.
+        self.highest_refused
+            .map_or(MIN_FLOOR, |refused| refused + FLOOR_STEP)
+            .clamp(MIN_FLOOR, MAX_LEARNED_FLOOR)
+    }
+
+    // The latest observation wins over older ones it contradicts.
+    pub fn record_charging(&mut self, amps: f64) {
+        self.lowest_charging = Some(self.lowest_charging.map_or(amps, |lowest| lowest.min(amps)));
+        self.highest_refused = self.highest_refused.filter(|&refused| refused < amps);
+    }
+
+    pub fn record_refused(&mut self, amps: f64) {
+        self.highest_refused = Some(self.highest_refused.map_or(amps, |highest| highest.max(amps)));
+        self.lowest_charging = self.lowest_charging.filter(|&charging| charging > amps);
+    }
+}
+
+// Follows the sessions of the machine and keeps the floors of the vehicles.
+pub struct FloorTracker {
+    floors: BTreeMap<String, VehicleFloor>,
+    // Where the floors are kept. None keeps them in memory only.
+    store_path: Option<PathBuf>,
+    // The vehicle of the session, once identified.
+    vehicle: Option<String>,
+    // The offer the vehicle is charging at, and since when.
+    charging: Option<(f64, Instant)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl FloorTracker {
+    pub fn new(store_path: Option<PathBuf>) -> Self {
+        let floors = match store_path.as_deref().map(|path| Store::open(path)?.vehicle_floors()) {
+            Some(Ok(floors)) => floors,
+            Some(Err(error)) => {
+                eprintln!("Vehicle floors not loaded: {}", error);
+                BTreeMap::new()
+            }
+            None => BTreeMap::new(),
+        };
+        Self {
+            floors,
+            store_path,
+            vehicle: None,
+            charging: None,
+            clock: system_clock(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Identifies the vehicle of the session.
+    pub fn identify(&mut self, vehicle: String) {
+        self.vehicle = Some(vehicle);
+    }
+
+    pub fn vehicle(&self) -> Option<&str> {
+        self.vehicle.as_deref()
+    }
+
+    pub fn vehicle_floor(&self, vehicle: &str) -> Option<VehicleFloor> {
+        self.floors.get(vehicle).copied()
+    }
+
+    // The floor of the vehicle of the session.
+    pub fn floor(&self) -> f64 {
+        self.vehicle
+            .as_deref()
+            .and_then(|vehicle| self.vehicle_floor(vehicle))
+            .map_or(MIN_FLOOR, |floor| floor.floor())
+    }
+
+    // Called when the machine enters a state, with what the vehicle is
+    // offered.
+    pub fn state_changed(&mut self, state: EVSEMachineState, offered: f64) {
+        if state == EVSEMachineState::Charging {
+            self.charging = Some((offered, self.clock.now()));
+            return;
+        }
+        if let Some((offered, since)) = self.charging.take() {
+            // Only falling back to B says something about the offer; a
+            // fault, an unplugged vehicle or an offer taken away does not.
+            if self.clock.elapsed(since) >= CONFIRM_TIME {
+                self.record(offered, true);
+            } else if state == EVSEMachineState::SuspendedEV && (MIN_FLOOR..=MAX_LEARNED_FLOOR).contains(&offered) {
+                self.record(offered, false);
+            }
+        }
+        if state == EVSEMachineState::Standby {
+            self.vehicle = None;
+        }
+    }
+
+    // Called when the offer changes while the vehicle charges.
+    pub fn offer_changed(&mut self, offered: f64) {
+        if let Some((previous, since)) = self.charging {
+            if self.clock.elapsed(since) >= CONFIRM_TIME {
+                self.record(previous, true);
+            }
+            self.charging = Some((offered, self.clock.now()));
+        }
+    }
+
+    fn record(&mut self, offered: f64, charged: bool) {
+        let Some(vehicle) = self.vehicle.clone() else {
+            return;
+        };
+        let floor = self.floors.entry(vehicle.clone()).or_default();
+        let before = *floor;
+        if charged {
+            floor.record_charging(offered);
+        } else {
+            floor.record_refused(offered);
+        }
+        let floor = *floor;
+        if floor == before {
+            return;
+        }
+        if let Some(path) = &self.store_path {
+            if let Err(error) = Store::open(path).and_then(|store| store.save_vehicle_floor(&vehicle, &floor)) {
+                eprintln!("Vehicle floor of {} not saved: {}", vehicle, error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_floor() {
+        let mut floor = VehicleFloor::default();
+        assert_eq!(floor.floor(), MIN_FLOOR);
+
+        floor.record_charging(16.0);
+        assert_eq!(floor.floor(), MIN_FLOOR);
+        floor.record_refused(6.0);
+        floor.record_refused(8.0);
+        assert_eq!(floor.floor(), 9.0);
+        floor.record_refused(9.0);
+        assert_eq!(floor.floor(), 10.0);
+
+        // Charging at a refused offer overrides the refusal.
+        floor.record_charging(8.0);
+        assert_eq!(floor.highest_refused, None);
+        assert_eq!(floor.lowest_charging, Some(8.0));
+        assert_eq!(floor.floor(), MIN_FLOOR);
+
+        // Refusing the lowest offer it charged at forgets that.
+        floor.record_refused(8.0);
+        assert_eq!(floor.lowest_charging, None);
+        assert_eq!(floor.floor(), 9.0);
+    }
+
+    #[test]
+    fn test_tracker_learns_refusal() {
+        let mut tracker = FloorTracker::new(None);
+        tracker.state_changed(EVSEMachineState::VehicleDetected, 8.0);
+        tracker.identify("WVWZZZ1JZXW000001".to_string());
+        tracker.state_changed(EVSEMachineState::Charging, 8.0);
+        tracker.state_changed(EVSEMachineState::SuspendedEV, 8.0);
+        assert_eq!(tracker.floor(), 9.0);
+
+        // Only for this session's vehicle.
+        tracker.state_changed(EVSEMachineState::Standby, 0.0);
+        assert_eq!(tracker.floor(), MIN_FLOOR);
+        tracker.identify("WVWZZZ1JZXW000001".to_string());
+        assert_eq!(tracker.floor(), 9.0);
+
+        // A charge that ends with a fault teaches nothing.
+        tracker.state_changed(EVSEMachineState::Charging, 9.0);
+        tracker.state_changed(EVSEMachineState::FailedStation, 9.0);
+        assert_eq!(
+            tracker.vehicle_floor("WVWZZZ1JZXW000001"),
+            Some(VehicleFloor {
+                lowest_charging: None,
+                highest_refused: Some(8.0)
+            })
+        );
+
+        // Charging for CONFIRM_TIME at an offer confirms it.
+        let clock = MockClock::new();
+        let mut tracker = FloorTracker::new(None).with_clock(Arc::new(clock.clone()));
+        tracker.identify("WVWZZZ1JZXW000002".to_string());
+        tracker.state_changed(EVSEMachineState::Charging, 10.0);
+        clock.advance(CONFIRM_TIME);
+        tracker.offer_changed(7.0);
+        tracker.state_changed(EVSEMachineState::SuspendedEV, 7.0);
+        assert_eq!(
+            tracker.vehicle_floor("WVWZZZ1JZXW000002"),
+            Some(VehicleFloor {
+                lowest_charging: Some(10.0),
+                highest_refused: Some(7.0)
+            })
+        );
+    }
+
+    #[test]
+    fn test_floors_are_stored() {
+        let path = std::env::temp_dir().join(format!("juicelib-floors-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut tracker = FloorTracker::new(Some(path.clone()));
+        tracker.identify("car".to_string());
+        tracker.state_changed(EVSEMachineState::Charging, 10.0);
+        tracker.state_changed(EVSEMachineState::SuspendedEV, 10.0);
+
+        let mut tracker = FloorTracker::new(Some(path.clone()));
+        tracker.identify("car".to_string());
+        assert_eq!(tracker.floor(), 11.0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
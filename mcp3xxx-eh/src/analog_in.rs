@@ -1,35 +1,28 @@
 // Next, we'll define "analog_in.rs"
 
-use super::mcp3xxx::{MCP3xxx, SPIDevice};
-use embedded_hal::spi::FullDuplex;
+use super::channel::Input;
+use super::mcp3xxx::SPIDevice;
+use embedded_hal::blocking::spi::Transfer;
 use embedded_hal::digital::v2::OutputPin;
 
-pub struct AnalogIn<SPI, CS> {
+pub struct AnalogIn<SPI, CS, I> {
     mcp: SPIDevice<SPI, CS>,
-    pin_setting: u8,
-    is_differential: bool,
+    input: I,
 }
 
-impl<SPI, CS> AnalogIn<SPI, CS>
+impl<SPI, CS, I> AnalogIn<SPI, CS, I>
 where
-    SPI: FullDuplex<u8>,
+    SPI: Transfer<u8>,
     CS: OutputPin,
+    I: Input,
 {
-    pub fn new(mcp: SPIDevice<SPI, CS>, positive_pin: u8, negative_pin: Option<u8>) -> Self {
-        let is_differential = negative_pin.is_some();
-        let pin_setting = if is_differential {
-            match negative_pin {
-                Some(np) => mcp.diff_pins.get(&(positive_pin, np)),
-                None => panic!("Invalid differential pin mapping"),
-            }
-        } else {
-            positive_pin
-        };
-        AnalogIn { mcp, pin_setting, is_differential }
+    // Takes a single ended channel or a differential pair.
+    pub fn new(mcp: SPIDevice<SPI, CS>, input: impl Into<I>) -> Self {
+        AnalogIn { mcp, input: input.into() }
     }
 
     pub fn value(&mut self) -> u16 {
-        self.mcp.read(self.pin_setting, self.is_differential) << 6
+        self.mcp.read(self.input) << 6
     }
 
     pub fn voltage(&mut self) -> f32 {
@@ -40,31 +33,25 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use embedded_hal_mock::{spi::Transaction as SPITransaction, spi::Mock as MockSPI, pin::Mock as MockPin};
+    use crate::channel::{Mcp3002Channel, Mcp3002Input};
+    use embedded_hal_mock::pin::{Mock as MockPin, State, Transaction as PinTransaction};
+    use embedded_hal_mock::spi::{Mock as MockSPI, Transaction as SPITransaction};
+
+    fn analog_in() -> AnalogIn<MockSPI, MockPin, Mcp3002Input> {
+        let expectations = [SPITransaction::transfer(vec![0x00, 0x68, 0x00], vec![0x00, 0x03, 0xFF])];
+        let mock_spi = MockSPI::new(&expectations);
+        let mock_pin = MockPin::new(&[PinTransaction::set(State::Low), PinTransaction::set(State::High)]);
+        let device = SPIDevice::new(mock_spi, mock_pin);
+        AnalogIn::new(device, Mcp3002Channel::Ch0)
+    }
 
     #[test]
     fn it_reads_value() {
-        let expectations = [
-            SPITransaction::write(vec![0x80, 0x00]),
-            SPITransaction::transfer(vec![0x00, 0x00], vec![0x03, 0xFF]),
-        ];
-        let mock_spi = MockSPI::new(&expectations);
-        let mock_pin = MockPin::new(&[Ok(())]);
-        let mut device = SPIDevice::new(mock_spi, mock_pin);
-        let mut analog_in = AnalogIn::new(device, 0, None);
-        assert_eq!(analog_in.value(), 65472);
+        assert_eq!(analog_in().value(), 65472);
     }
 
     #[test]
     fn it_reads_voltage() {
-        let expectations = [
-            SPITransaction::write(vec![0x80, 0x00]),
-            SPITransaction::transfer(vec![0x00, 0x00], vec![0x03, 0xFF]),
-        ];
-        let mock_spi = MockSPI::new(&expectations);
-        let mock_pin = MockPin::new(&[Ok(())]);
-        let mut device = SPIDevice::new(mock_spi, mock_pin);
-        let mut analog_in = AnalogIn::new(device, 0, None);
-        assert_eq!(analog_in.voltage(), 3.3);
+        assert!((analog_in().voltage() - 3.297).abs() < 0.001);
     }
 }
@@ -0,0 +1,217 @@
+// Typed inputs of the MCP3xxx chips. Every chip has its own channel and
+// differential pair enums, so a channel the chip does not have cannot be
+// passed to it.
+//
+// A conversion is a three byte transfer. The command is right aligned so
+// that the 10 bit result ends up in the last bits read back:
+//   MCP3004/3008: [0x01 (start), SGL/DIFF | D2..D0 << 4, don't care]
+//   MCP3002:      [0x00, start | SGL/DIFF | ODD/SIGN | MSBF, don't care]
+
+use core::fmt;
+
+pub trait Input: Copy {
+    fn is_differential(&self) -> bool;
+    // The channel selection bits of the command (D2..D0, or ODD/SIGN on
+    // the two channel chips).
+    fn select_bits(&self) -> u8;
+    // The bytes to send for a conversion of the input.
+    fn command(&self) -> [u8; 3];
+}
+
+// The result of a conversion, from the bytes read back.
+pub fn decode(rx: &[u8; 3]) -> u16 {
+    ((rx[1] as u16 & 0x03) << 8) | rx[2] as u16
+}
+
+fn two_channel_command(single: bool, select: u8) -> [u8; 3] {
+    [0x00, 0x40 | (single as u8) << 5 | select << 4 | 0x08, 0x00]
+}
+
+fn multi_channel_command(single: bool, select: u8) -> [u8; 3] {
+    [0x01, (single as u8) << 7 | select << 4, 0x00]
+}
+
+// A channel number the chip does not have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidChannel(pub u8);
+
+impl fmt::Display for InvalidChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no channel {}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mcp3002Channel {
+    Ch0 = 0,
+    Ch1 = 1,
+}
+
+// Differential pairs, named positive input first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mcp3002Differential {
+    Ch0Ch1 = 0,
+    Ch1Ch0 = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mcp3004Channel {
+    Ch0 = 0,
+    Ch1 = 1,
+    Ch2 = 2,
+    Ch3 = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mcp3004Differential {
+    Ch0Ch1 = 0,
+    Ch1Ch0 = 1,
+    Ch2Ch3 = 2,
+    Ch3Ch2 = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mcp3008Channel {
+    Ch0 = 0,
+    Ch1 = 1,
+    Ch2 = 2,
+    Ch3 = 3,
+    Ch4 = 4,
+    Ch5 = 5,
+    Ch6 = 6,
+    Ch7 = 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mcp3008Differential {
+    Ch0Ch1 = 0,
+    Ch1Ch0 = 1,
+    Ch2Ch3 = 2,
+    Ch3Ch2 = 3,
+    Ch4Ch5 = 4,
+    Ch5Ch4 = 5,
+    Ch6Ch7 = 6,
+    Ch7Ch6 = 7,
+}
+
+// Channels named by number, e.g. in a config file.
+macro_rules! channel_from_number {
+    ($channel:ident, $($number:literal => $variant:ident),+) => {
+        impl TryFrom<u8> for $channel {
+            type Error = InvalidChannel;
+
+            fn try_from(channel: u8) -> Result<Self, InvalidChannel> {
+                match channel {
+                    $($number => Ok($channel::$variant),)+
+                    _ => Err(InvalidChannel(channel)),
+                }
+            }
+        }
+    };
+}
+
+channel_from_number!(Mcp3002Channel, 0 => Ch0, 1 => Ch1);
+channel_from_number!(Mcp3004Channel, 0 => Ch0, 1 => Ch1, 2 => Ch2, 3 => Ch3);
+channel_from_number!(
+    Mcp3008Channel,
+    0 => Ch0, 1 => Ch1, 2 => Ch2, 3 => Ch3, 4 => Ch4, 5 => Ch5, 6 => Ch6, 7 => Ch7
+);
+
+// A single ended channel or a differential pair of one chip.
+macro_rules! chip_input {
+    ($input:ident, $channel:ident, $differential:ident, $command:ident) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $input {
+            Single($channel),
+            Differential($differential),
+        }
+
+        impl From<$channel> for $input {
+            fn from(channel: $channel) -> Self {
+                $input::Single(channel)
+            }
+        }
+
+        impl From<$differential> for $input {
+            fn from(pair: $differential) -> Self {
+                $input::Differential(pair)
+            }
+        }
+
+        impl Input for $input {
+            fn is_differential(&self) -> bool {
+                matches!(self, $input::Differential(_))
+            }
+
+            fn select_bits(&self) -> u8 {
+                match *self {
+                    $input::Single(channel) => channel as u8,
+                    $input::Differential(pair) => pair as u8,
+                }
+            }
+
+            fn command(&self) -> [u8; 3] {
+                $command(!self.is_differential(), self.select_bits())
+            }
+        }
+
+        // A channel reads as single ended.
+        impl Input for $channel {
+            fn is_differential(&self) -> bool {
+                false
+            }
+
+            fn select_bits(&self) -> u8 {
+                *self as u8
+            }
+
+            fn command(&self) -> [u8; 3] {
+                $input::from(*self).command()
+            }
+        }
+    };
+}
+
+chip_input!(Mcp3002Input, Mcp3002Channel, Mcp3002Differential, two_channel_command);
+chip_input!(Mcp3004Input, Mcp3004Channel, Mcp3004Differential, multi_channel_command);
+chip_input!(Mcp3008Input, Mcp3008Channel, Mcp3008Differential, multi_channel_command);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_selects_inputs() {
+        let input = Mcp3008Input::from(Mcp3008Channel::Ch5);
+        assert!(!input.is_differential());
+        assert_eq!(input.select_bits(), 5);
+
+        let input = Mcp3008Input::from(Mcp3008Differential::Ch3Ch2);
+        assert!(input.is_differential());
+        assert_eq!(input.select_bits(), 3);
+
+        assert_eq!(Mcp3002Input::from(Mcp3002Differential::Ch1Ch0).select_bits(), 1);
+        assert_eq!(Mcp3004Input::from(Mcp3004Channel::Ch3).select_bits(), 3);
+    }
+
+    #[test]
+    fn it_builds_commands() {
+        assert_eq!(Mcp3004Channel::Ch0.command(), [0x01, 0x80, 0x00]);
+        assert_eq!(Mcp3004Channel::Ch2.command(), [0x01, 0xa0, 0x00]);
+        assert_eq!(Mcp3008Input::from(Mcp3008Differential::Ch5Ch4).command(), [0x01, 0x50, 0x00]);
+        assert_eq!(Mcp3002Channel::Ch0.command(), [0x00, 0x68, 0x00]);
+        assert_eq!(Mcp3002Input::from(Mcp3002Differential::Ch1Ch0).command(), [0x00, 0x58, 0x00]);
+
+        assert_eq!(decode(&[0xff, 0xfb, 0xff]), 1023);
+        assert_eq!(decode(&[0x00, 0x02, 0x00]), 512);
+        assert_eq!(decode(&[0x00, 0x00, 0xb8]), 184);
+    }
+
+    #[test]
+    fn it_numbers_channels() {
+        assert_eq!(Mcp3004Channel::try_from(3), Ok(Mcp3004Channel::Ch3));
+        assert_eq!(Mcp3004Channel::try_from(4), Err(InvalidChannel(4)));
+        assert_eq!(Mcp3008Channel::try_from(7), Ok(Mcp3008Channel::Ch7));
+        assert_eq!(Mcp3002Channel::try_from(2), Err(InvalidChannel(2)));
+    }
+}
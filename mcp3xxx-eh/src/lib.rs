@@ -1,3 +1,8 @@
+// The tests need std even for the no_std build.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
 pub mod mcp3xxx;
 pub mod analog_in;
 pub mod mcp3002;
+pub mod channel;
+pub mod scan;
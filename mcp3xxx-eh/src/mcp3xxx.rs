@@ -1,13 +1,14 @@
 // We'll start with the "mcp3xxx.rs"
 
-use embedded_hal::spi::{Mode, MODE_0};
+use embedded_hal::blocking::spi::Transfer;
 use embedded_hal::digital::v2::OutputPin;
-use embedded_hal::spi::FullDuplex;
-use embedded_hal::blocking::spi::Write;
+
+use super::channel::{decode, Input};
 
 pub trait MCP3xxx {
+    type Input: Input;
     fn reference_voltage(&self) -> f32;
-    fn read(&mut self, pin: u8, is_differential: bool) -> u16;
+    fn read(&mut self, input: Self::Input) -> u16;
 }
 
 pub struct SPIDevice<SPI, CS> {
@@ -17,7 +18,7 @@ pub struct SPIDevice<SPI, CS> {
 
 impl<SPI, CS> SPIDevice<SPI, CS>
 where
-    SPI: FullDuplex<u8>,
+    SPI: Transfer<u8>,
     CS: OutputPin,
 {
     pub fn new(spi: SPI, cs: CS) -> Self {
@@ -28,31 +29,46 @@ where
         3.3
     }
 
-    pub fn read(&mut self, pin: u8, is_differential: bool) -> u16 {
-        let out_buf = [0x40 | ((!is_differential) as u8) << 5 | pin << 4, 0x00];
-        let mut in_buf = [0x00, 0x00];
+    // Reads any chip's input; the input knows the command of its chip. A
+    // failed transfer reads as 0.
+    pub fn read<I: Input>(&mut self, input: I) -> u16 {
+        let mut buf = input.command();
         self.cs.set_low().ok();
-        let _ = self.spi.write(&out_buf);
-        let _ = self.spi.write(&mut in_buf);
+        let result = self.spi.transfer(&mut buf).map(|rx| decode(&[rx[0], rx[1], rx[2]]));
         self.cs.set_high().ok();
-        ((in_buf[0] & 0x03) as u16) << 8 | in_buf[1] as u16
+        result.unwrap_or(0)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use embedded_hal_mock::{spi::Transaction as SPITransaction, spi::Mock as MockSPI, pin::Mock as MockPin};
+    use crate::channel::{Mcp3002Channel, Mcp3004Channel, Mcp3008Differential, Mcp3008Input};
+    use embedded_hal_mock::pin::{Mock as MockPin, State, Transaction as PinTransaction};
+    use embedded_hal_mock::spi::{Mock as MockSPI, Transaction as SPITransaction};
+
+    fn mock_device(tx: [u8; 3], rx: [u8; 3]) -> (SPIDevice<MockSPI, MockPin>, MockSPI, MockPin) {
+        let spi = MockSPI::new(&[SPITransaction::transfer(tx.to_vec(), rx.to_vec())]);
+        let cs = MockPin::new(&[PinTransaction::set(State::Low), PinTransaction::set(State::High)]);
+        (SPIDevice::new(spi.clone(), cs.clone()), spi, cs)
+    }
 
     #[test]
     fn it_reads_value() {
-        let expectations = [
-            SPITransaction::write(vec![0x80, 0x00]),
-            SPITransaction::transfer(vec![0x00, 0x00], vec![0x03, 0xFF]),
-        ];
-        let mock_spi = MockSPI::new(&expectations);
-        let mock_pin = MockPin::new(&[Ok(())]);
-        let mut device = SPIDevice::new(mock_spi, mock_pin);
-        assert_eq!(device.read(0, false), 1023);
+        let (mut device, mut spi, mut cs) = mock_device([0x00, 0x68, 0x00], [0x00, 0x03, 0xFF]);
+        assert_eq!(device.read(Mcp3002Channel::Ch0), 1023);
+        spi.done();
+        cs.done();
+    }
+
+    #[test]
+    fn it_reads_any_chip() {
+        let (mut device, mut spi, _) = mock_device([0x01, 0xa0, 0x00], [0x00, 0x02, 0x00]);
+        assert_eq!(device.read(Mcp3004Channel::Ch2), 512);
+        spi.done();
+
+        let (mut device, mut spi, _) = mock_device([0x01, 0x10, 0x00], [0x00, 0x00, 0xb8]);
+        assert_eq!(device.read(Mcp3008Input::from(Mcp3008Differential::Ch1Ch0)), 184);
+        spi.done();
     }
 }
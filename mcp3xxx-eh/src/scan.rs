@@ -0,0 +1,224 @@
+// Timed conversion loop over a set of inputs. Every scan reads each input
+// once, in order, and hands the values to a callback as one batch.
+//
+// On std targets `scan` runs the loop on its own thread. Without std the
+// application polls a `Scanner` from its main loop or a timer interrupt
+// and passes the time, as there is no clock to take it from.
+
+use super::mcp3xxx::MCP3xxx;
+
+// The MCP3008 has eight single ended inputs.
+pub const MAX_CHANNELS: usize = 8;
+
+// The values of one scan, in the order of the inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Batch {
+    // When the scan was due, in microseconds of the caller's clock.
+    pub timestamp_us: u64,
+    values: [u16; MAX_CHANNELS],
+    len: usize,
+}
+
+impl Batch {
+    pub fn values(&self) -> &[u16] {
+        &self.values[..self.len]
+    }
+}
+
+pub struct Scanner<'c, I> {
+    inputs: &'c [I],
+    interval_us: u64,
+    next_us: Option<u64>,
+    // Scans skipped because the poll came too late for them.
+    missed: u64,
+}
+
+impl<'c, I: Copy> Scanner<'c, I> {
+    // Takes up to MAX_CHANNELS inputs; the rest are ignored. A rate of 0
+    // is taken as 1 Hz.
+    pub fn new(inputs: &'c [I], rate_hz: u32) -> Self {
+        Scanner {
+            inputs: &inputs[..inputs.len().min(MAX_CHANNELS)],
+            interval_us: 1_000_000 / rate_hz.max(1) as u64,
+            next_us: None,
+            missed: 0,
+        }
+    }
+
+    // When the next scan is due; the first poll always scans.
+    pub fn next_due_us(&self) -> Option<u64> {
+        self.next_us
+    }
+
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+
+    // Scans if a scan is due at `now_us`. Returns whether it did.
+    pub fn poll<A, F>(&mut self, adc: &mut A, now_us: u64, mut callback: F) -> bool
+    where
+        A: MCP3xxx<Input = I>,
+        F: FnMut(&Batch),
+    {
+        let due = match self.next_us {
+            Some(due) if due > now_us => return false,
+            Some(due) => due,
+            None => now_us,
+        };
+        let mut batch = Batch {
+            timestamp_us: due,
+            values: [0; MAX_CHANNELS],
+            len: self.inputs.len(),
+        };
+        for (value, input) in batch.values.iter_mut().zip(self.inputs) {
+            *value = adc.read(*input);
+        }
+        callback(&batch);
+
+        // Keeps to the grid of the first scan unless a whole interval was
+        // lost, then starts over from now rather than catching up.
+        let next = due + self.interval_us;
+        self.next_us = Some(if next > now_us {
+            next
+        } else {
+            self.missed += (now_us - due) / self.interval_us;
+            now_us + self.interval_us
+        });
+        true
+    }
+}
+
+#[cfg(feature = "std")]
+pub use self::thread::{scan, ScanHandle};
+
+#[cfg(feature = "std")]
+mod thread {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+
+    use super::{Batch, Scanner};
+    use crate::mcp3xxx::MCP3xxx;
+
+    // The scan thread. Dropping the handle leaves the thread running.
+    pub struct ScanHandle<A> {
+        stop: Arc<AtomicBool>,
+        thread: JoinHandle<A>,
+    }
+
+    impl<A> ScanHandle<A> {
+        // Stops the scans and hands the chip back.
+        pub fn stop(self) -> A {
+            self.stop.store(true, Ordering::Relaxed);
+            self.thread.join().expect("scan callback panicked")
+        }
+    }
+
+    // Scans the inputs at the given rate on a new thread until stopped.
+    // Timestamps count from the start of the scans.
+    pub fn scan<A, F>(mut adc: A, inputs: Vec<A::Input>, rate_hz: u32, mut callback: F) -> ScanHandle<A>
+    where
+        A: MCP3xxx + Send + 'static,
+        A::Input: Send + 'static,
+        F: FnMut(&Batch) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut scanner = Scanner::new(&inputs, rate_hz);
+            let start = Instant::now();
+            while !stopped.load(Ordering::Relaxed) {
+                let now_us = start.elapsed().as_micros() as u64;
+                scanner.poll(&mut adc, now_us, &mut callback);
+                if let Some(due) = scanner.next_due_us() {
+                    let now_us = start.elapsed().as_micros() as u64;
+                    thread::sleep(Duration::from_micros(due.saturating_sub(now_us)));
+                }
+            }
+            adc
+        });
+        ScanHandle { stop, thread }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::{Input, Mcp3008Channel, Mcp3008Input};
+
+    // Reads the channel number times 100.
+    struct FakeAdc {
+        reads: usize,
+    }
+
+    impl MCP3xxx for FakeAdc {
+        type Input = Mcp3008Input;
+
+        fn reference_voltage(&self) -> f32 {
+            3.3
+        }
+
+        fn read(&mut self, input: Mcp3008Input) -> u16 {
+            self.reads += 1;
+            input.select_bits() as u16 * 100
+        }
+    }
+
+    #[test]
+    fn it_scans_when_due() {
+        let inputs = [Mcp3008Channel::Ch2.into(), Mcp3008Channel::Ch5.into()];
+        let mut scanner = Scanner::new(&inputs, 1000);
+        let mut adc = FakeAdc { reads: 0 };
+        let mut batches = Vec::new();
+
+        assert!(scanner.poll(&mut adc, 10, |batch| batches.push(*batch)));
+        assert!(!scanner.poll(&mut adc, 500, |batch| batches.push(*batch)));
+        assert!(scanner.poll(&mut adc, 1200, |batch| batches.push(*batch)));
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].values(), &[200, 500]);
+        assert_eq!(batches[1].timestamp_us, 1010);
+        assert_eq!(adc.reads, 4);
+
+        // Late by more than an interval: the lost scans are skipped.
+        assert!(scanner.poll(&mut adc, 5500, |batch| batches.push(*batch)));
+        assert_eq!(scanner.missed(), 3);
+        assert_eq!(scanner.next_due_us(), Some(6500));
+    }
+
+    #[test]
+    fn it_scans_a_chip() {
+        use crate::channel::{Mcp3002Channel, Mcp3002Input};
+        use crate::mcp3002::MCP3002;
+        use embedded_hal_mock::pin::{Mock as MockPin, State, Transaction as PinTransaction};
+        use embedded_hal_mock::spi::{Mock as MockSPI, Transaction as SPITransaction};
+
+        let mut spi = MockSPI::new(&[
+            SPITransaction::transfer(vec![0x00, 0x68, 0x00], vec![0x00, 0x01, 0x00]),
+            SPITransaction::transfer(vec![0x00, 0x78, 0x00], vec![0x00, 0x03, 0xff]),
+        ]);
+        let select = [PinTransaction::set(State::Low), PinTransaction::set(State::High)];
+        let cs = MockPin::new(select.iter().chain(&select));
+        let mut adc = MCP3002::new(spi.clone(), cs);
+        let inputs: [Mcp3002Input; 2] = [Mcp3002Channel::Ch0.into(), Mcp3002Channel::Ch1.into()];
+        let mut scanner = Scanner::new(&inputs, 100);
+        let mut values = Vec::new();
+        assert!(scanner.poll(&mut adc, 0, |batch| values.extend_from_slice(batch.values())));
+        assert_eq!(values, [256, 1023]);
+        spi.done();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_scans_on_a_thread() {
+        use std::sync::mpsc::channel;
+
+        let (tx, rx) = channel();
+        let handle = scan(FakeAdc { reads: 0 }, vec![Mcp3008Channel::Ch1.into()], 1000, move |batch| {
+            let _ = tx.send(batch.values()[0]);
+        });
+        assert_eq!(rx.recv().unwrap(), 100);
+        assert_eq!(rx.recv().unwrap(), 100);
+        assert!(handle.stop().reads >= 2);
+    }
+}
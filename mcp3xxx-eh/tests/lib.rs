@@ -1,42 +1,37 @@
-use my_crate::mcp3xxx::MCP3xxx;
-use my_crate::analog_in::AnalogIn;
-use my_crate::mcp3002::MCP3002;
-use embedded_hal_mock::{spi::Transaction as SPITransaction, spi::Mock as MockSPI, pin::Mock as MockPin};
+use mcp3xxx_eh::analog_in::AnalogIn;
+use mcp3xxx_eh::channel::{Mcp3002Channel, Mcp3002Input};
+use mcp3xxx_eh::mcp3002::MCP3002;
+use mcp3xxx_eh::mcp3xxx::{MCP3xxx, SPIDevice};
+use embedded_hal_mock::pin::{Mock as MockPin, State, Transaction as PinTransaction};
+use embedded_hal_mock::spi::{Mock as MockSPI, Transaction as SPITransaction};
+
+fn mocks() -> (MockSPI, MockPin) {
+    let expectations = [SPITransaction::transfer(vec![0x00, 0x68, 0x00], vec![0x00, 0x03, 0xFF])];
+    let mock_spi = MockSPI::new(&expectations);
+    let mock_pin = MockPin::new(&[PinTransaction::set(State::Low), PinTransaction::set(State::High)]);
+    (mock_spi, mock_pin)
+}
 
 #[test]
 fn reads_value_mcp3xxx() {
-    let expectations = [
-        SPITransaction::write(vec![0x80, 0x00]),
-        SPITransaction::transfer(vec![0x00, 0x00], vec![0x03, 0xFF]),
-    ];
-    let mock_spi = MockSPI::new(&expectations);
-    let mock_pin = MockPin::new(&[Ok(())]);
-    let mut device = MCP3xxx::new(mock_spi, mock_pin, 3.3);
-    assert_eq!(device.read(0, false), 1023);
+    let (mock_spi, mock_pin) = mocks();
+    let mut device = MCP3002::new(mock_spi, mock_pin);
+    assert_eq!(device.read(Mcp3002Channel::Ch0.into()), 1023);
+    assert_eq!(device.reference_voltage(), 3.3);
 }
 
 #[test]
 fn reads_value_analog_in() {
-    let expectations = [
-        SPITransaction::write(vec![0x80, 0x00]),
-        SPITransaction::transfer(vec![0x00, 0x00], vec![0x03, 0xFF]),
-    ];
-    let mock_spi = MockSPI::new(&expectations);
-    let mock_pin = MockPin::new(&[Ok(())]);
-    let mut device = MCP3002::new(mock_spi, mock_pin, 3.3);
-    let mut analog_in = AnalogIn::new(device, 0, None);
+    let (mock_spi, mock_pin) = mocks();
+    let device = SPIDevice::new(mock_spi, mock_pin);
+    let mut analog_in: AnalogIn<_, _, Mcp3002Input> = AnalogIn::new(device, Mcp3002Channel::Ch0);
     assert_eq!(analog_in.value(), 65472);
 }
 
 #[test]
 fn reads_voltage_analog_in() {
-    let expectations = [
-        SPITransaction::write(vec![0x80, 0x00]),
-        SPITransaction::transfer(vec![0x00, 0x00], vec![0x03, 0xFF]),
-    ];
-    let mock_spi = MockSPI::new(&expectations);
-    let mock_pin = MockPin::new(&[Ok(())]);
-    let mut device = MCP3002::new(mock_spi, mock_pin, 3.3);
-    let mut analog_in = AnalogIn::new(device, 0, None);
-    assert_eq!(analog_in.voltage(), 3.3);
+    let (mock_spi, mock_pin) = mocks();
+    let device = SPIDevice::new(mock_spi, mock_pin);
+    let mut analog_in: AnalogIn<_, _, Mcp3002Input> = AnalogIn::new(device, Mcp3002Channel::Ch0);
+    assert!((analog_in.voltage() - 3.297).abs() < 0.001);
 }